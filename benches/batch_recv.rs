@@ -0,0 +1,79 @@
+//! Receiving packets with a `recvmsg` call each, against `recvmmsg` batches
+//!
+//! Each round queues a burst of detector-sized packets on a loopback UDP
+//! socket, then times reading them all back, so that only the receiving
+//! is measured. Run with `cargo bench --bench batch_recv`.
+
+use std::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use morgul::{
+    SlsDetectorHeader,
+    transport::{PacketReceiver, ReceiveCounts, UdpReceiver},
+};
+use socket2::{Domain, Socket, Type};
+
+const PACKET_SIZE: usize = size_of::<SlsDetectorHeader>() + 8192;
+/// Packets queued per round, which has to fit in the receive buffer
+const BURST: usize = 128;
+const ROUNDS: usize = 400;
+
+/// Time spent receiving every round, with `batch_size` packets per call at most
+fn measure(batch_size: usize) -> (Duration, ReceiveCounts) {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+    socket.set_recv_buffer_size(4 * 1024 * 1024).unwrap();
+    socket
+        .bind(
+            &"127.0.0.1:0"
+                .parse::<std::net::SocketAddr>()
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
+    let socket: UdpSocket = socket.into();
+    let address = socket.local_addr().unwrap();
+    let mut receiver = UdpReceiver::new(socket);
+    receiver.set_batch_size(batch_size, PACKET_SIZE);
+    receiver.set_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let packet = vec![0x5A; PACKET_SIZE];
+    let mut buffer = vec![0; PACKET_SIZE];
+    let mut receiving = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        for _ in 0..BURST {
+            sender.send_to(&packet, address).unwrap();
+        }
+        let start = Instant::now();
+        for _ in 0..BURST {
+            receiver
+                .recv_packet(&mut buffer)
+                .unwrap()
+                .expect("Packet lost; is the receive buffer too small for a burst?");
+        }
+        receiving += start.elapsed();
+    }
+    (receiving, receiver.receive_counts().unwrap())
+}
+
+fn main() {
+    println!(
+        "{BURST} packets of {PACKET_SIZE} bytes queued at a time, {} in all",
+        BURST * ROUNDS
+    );
+    println!(
+        "{:>6} {:>13} {:>12} {:>11}",
+        "batch", "packets/s", "packets/call", "ns/packet"
+    );
+    for batch_size in [1, 8, 32, 64, 128] {
+        let (time, counts) = measure(batch_size);
+        println!(
+            "{batch_size:>6} {:>13.0} {:>12.1} {:>11.0}",
+            counts.packets as f64 / time.as_secs_f64(),
+            counts.packets as f64 / counts.calls as f64,
+            time.as_nanos() as f64 / counts.packets as f64,
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Receiving packets and copying their payloads into the image, against
+//! receiving each payload straight into its place as `--receive-in-place` does
+//!
+//! Frames are sent in order, so every packet but the first of each frame
+//! is predicted. Over the in-memory loopback transport this isolates the
+//! copy that is saved; over a loopback UDP socket it includes the system
+//! calls. Run with `cargo bench --bench receive_in_place`.
+
+use std::{
+    io::IoSliceMut,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use bytemuck::Zeroable;
+use morgul::{
+    GeometryMap, SLS_HEADER_VERSION, SlsDetectorHeader, SlsDetectorType,
+    assembler::FrameAssembler,
+    transport::{PacketReceiver, PacketSender, UdpSender, loopback},
+};
+use socket2::{Domain, Socket, Type};
+
+const HEADER_SIZE: usize = size_of::<SlsDetectorHeader>();
+const PAYLOAD_SIZE: usize = 8192;
+const PACKETS_PER_FRAME: u32 = 64;
+/// Frames queued per round, which have to fit in the receive buffer
+const BURST_FRAMES: u64 = 2;
+const ROUNDS: u64 = 200;
+
+/// Queue the packets of the next `BURST_FRAMES` frames
+fn send_burst(sender: &mut impl PacketSender, header: &mut SlsDetectorHeader) {
+    let mut packet = vec![0x5A; HEADER_SIZE + PAYLOAD_SIZE];
+    for _ in 0..BURST_FRAMES {
+        header.frame_number += 1;
+        for packet_number in 0..PACKETS_PER_FRAME {
+            header.packet_number = packet_number;
+            packet[..HEADER_SIZE].copy_from_slice(bytemuck::bytes_of(header));
+            sender.send_packet(&packet).unwrap();
+        }
+    }
+}
+
+/// Receive and assemble one burst, returning how many packets were placed
+fn receive_burst(
+    receiver: &mut impl PacketReceiver,
+    assembler: &mut FrameAssembler,
+    buffer: &mut [u8],
+    in_place: bool,
+) -> usize {
+    let mut placed = 0;
+    for _ in 0..BURST_FRAMES * PACKETS_PER_FRAME as u64 {
+        let prediction = in_place.then(|| assembler.predict_next_packet()).flatten();
+        let (len, slot) = match prediction {
+            None => (receiver.recv_packet(buffer).unwrap().unwrap().len, None),
+            Some((slot, image_slot)) => {
+                let (head, rest) = buffer.split_at_mut(HEADER_SIZE);
+                let received = receiver
+                    .recv_packet_vectored(&mut [
+                        IoSliceMut::new(head),
+                        IoSliceMut::new(image_slot),
+                        IoSliceMut::new(rest),
+                    ])
+                    .unwrap()
+                    .unwrap();
+                (received.len, Some(slot))
+            }
+        };
+        let header = *SlsDetectorHeader::from_packet(&buffer[..len]).unwrap();
+        match slot {
+            Some(slot) => {
+                assert!(slot.matches(&header, len - HEADER_SIZE), "Mispredicted");
+                assembler.push_placed_packet(&header, slot, drop).unwrap();
+                placed += 1;
+            }
+            None => assembler
+                .push_packet(&header, &buffer[HEADER_SIZE..len], drop)
+                .unwrap(),
+        }
+    }
+    placed
+}
+
+/// Packets received and assembled per second, and the fraction placed
+fn measure(
+    sender: &mut impl PacketSender,
+    receiver: &mut impl PacketReceiver,
+    in_place: bool,
+) -> (f64, f64) {
+    let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+    let mut header = SlsDetectorHeader::zeroed();
+    header.det_type = SlsDetectorType::Jungfrau as u8;
+    header.version = SLS_HEADER_VERSION;
+    let mut buffer = vec![0; HEADER_SIZE + 2 * PAYLOAD_SIZE];
+    let mut receiving = Duration::ZERO;
+    let mut placed = 0;
+    for _ in 0..ROUNDS {
+        send_burst(sender, &mut header);
+        let start = Instant::now();
+        placed += receive_burst(receiver, &mut assembler, &mut buffer, in_place);
+        receiving += start.elapsed();
+    }
+    let packets = (ROUNDS * BURST_FRAMES * PACKETS_PER_FRAME as u64) as f64;
+    (packets / receiving.as_secs_f64(), placed as f64 / packets)
+}
+
+fn udp_pair() -> (UdpSender, morgul::transport::UdpReceiver) {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+    socket.set_recv_buffer_size(4 * 1024 * 1024).unwrap();
+    socket
+        .bind(
+            &"127.0.0.1:0"
+                .parse::<std::net::SocketAddr>()
+                .unwrap()
+                .into(),
+        )
+        .unwrap();
+    let socket: UdpSocket = socket.into();
+    let address = socket.local_addr().unwrap();
+    let mut receiver = morgul::transport::UdpReceiver::new(socket);
+    receiver.set_timeout(Some(Duration::from_secs(1))).unwrap();
+    let sender = UdpSender::new(UdpSocket::bind("127.0.0.1:0").unwrap(), address);
+    (sender, receiver)
+}
+
+fn main() {
+    println!(
+        "{} Jungfrau frames, {BURST_FRAMES} queued at a time",
+        ROUNDS * BURST_FRAMES
+    );
+    println!(
+        "{:>10} {:>10} {:>13} {:>8}",
+        "transport", "receive", "packets/s", "placed"
+    );
+    for in_place in [false, true] {
+        let mode = if in_place { "in place" } else { "copy" };
+        let (mut sender, mut receiver) = loopback();
+        let (rate, placed) = measure(&mut sender, &mut receiver, in_place);
+        println!(
+            "{:>10} {mode:>10} {rate:>13.0} {:>7.0}%",
+            "memory",
+            placed * 100.0
+        );
+        let (mut sender, mut receiver) = udp_pair();
+        let (rate, placed) = measure(&mut sender, &mut receiver, in_place);
+        println!(
+            "{:>10} {mode:>10} {rate:>13.0} {:>7.0}%",
+            "udp",
+            placed * 100.0
+        );
+    }
+}
@@ -0,0 +1,72 @@
+/* C interface to the morgul frame assembler
+ *
+ * Link against the morgul cdylib (libmorgul.so). Packets are pushed in
+ * one at a time, and every frame the assembler emits (complete, or
+ * abandoned as incomplete) is passed to the registered callback.
+ */
+#ifndef MORGUL_H
+#define MORGUL_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MORGUL_OK 0
+#define MORGUL_ERR_NULL_POINTER -1
+#define MORGUL_ERR_MALFORMED_HEADER -2
+#define MORGUL_ERR_UNKNOWN_DETECTOR_TYPE -3
+#define MORGUL_ERR_BUFFER_POOL_EXHAUSTED -4
+
+/* Identical in layout to slsDetectorDefs::sls_detector_header */
+typedef struct {
+    uint64_t frame_number;
+    uint32_t exposure_length;
+    uint32_t packet_number;
+    uint64_t bunch_id;
+    uint64_t timestamp;
+    uint16_t module_id;
+    uint16_t row;
+    uint16_t column;
+    uint16_t det_spec_2;
+    uint32_t daq_info;
+    uint16_t det_spec_4;
+    uint8_t det_type;
+    uint8_t version;
+} morgul_sls_detector_header;
+
+/* A frame emitted by the assembler. data is only valid during the callback. */
+typedef struct {
+    morgul_sls_detector_header header;
+    bool complete;
+    uint64_t received_mask;
+    const uint8_t *data;
+    size_t data_len;
+} morgul_frame;
+
+typedef struct MorgulAssembler morgul_assembler;
+
+typedef void (*morgul_frame_callback)(void *user_data, const morgul_frame *frame);
+
+/* Returns NULL if eiger_dynamic_range is not one of 4, 8, 16 or 32 */
+morgul_assembler *morgul_assembler_new(size_t eiger_dynamic_range, size_t pool_size);
+void morgul_assembler_free(morgul_assembler *assembler);
+int morgul_assembler_set_callback(morgul_assembler *assembler,
+                                  morgul_frame_callback callback,
+                                  void *user_data);
+/* header does not need to be aligned; len is the payload length in bytes */
+int morgul_push_packet(morgul_assembler *assembler,
+                       const morgul_sls_detector_header *header,
+                       const uint8_t *data,
+                       size_t len);
+/* End the acquisition, emitting any frames still in progress as incomplete */
+int morgul_assembler_flush(morgul_assembler *assembler);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MORGUL_H */
@@ -0,0 +1,1723 @@
+//! Assembling the packets received on one port into complete frames
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    AlignedBuffer, CompletedFrame, DEFAULT_BUFFER_ALIGNMENT, GeometryMap, MAX_PACKETS_PER_FRAME,
+    MorgulError, PacketLayout, PooledBuffer, PortGeometry, SlsDetectorHeader, SlsDetectorType,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct AcquisitionStats {
+    /// How many images have we seen at least one packet for
+    pub images_seen: usize,
+    /// How many images received all packet data
+    pub complete_images: usize,
+    /// How many packets were copied into images
+    pub packets_received: usize,
+    /// How many packets were we expecting but didn't arrive
+    pub packets_dropped: usize,
+    /// How many packets did we get too late to assemble, because their
+    /// frame had already been handed on. They will also have been counted
+    /// in packets_dropped, as missing from that frame.
+    pub out_of_order: usize,
+    /// How many frames were skipped in the sequence of frame numbers,
+    /// allowing for the expected stride between them
+    pub missing_frames: usize,
+    /// How many frames had a frame number that wasn't a whole number of
+    /// strides on from the one before
+    pub off_stride_frames: usize,
+    /// The stride between frame numbers that gaps were measured against.
+    /// When merged, this is the largest from any listener.
+    pub frame_stride: Option<u64>,
+    /// How many already-completed frames did we see again
+    pub duplicate_frames: usize,
+    /// How many packets were discarded as belonging to a duplicate frame,
+    /// or as a repeat of a packet already received for their frame
+    pub duplicate_packets: usize,
+    /// How many packets were discarded because we don't know their det_type
+    pub unknown_det_type_packets: usize,
+    /// How many packets were discarded because their payload was an
+    /// unexpected length for their det_type and position in the frame
+    pub payload_mismatch_packets: usize,
+    /// How many packets were discarded because their header made no sense,
+    /// e.g. was truncated or had an impossible packet number
+    pub malformed_packets: usize,
+    /// How many packets were discarded because every image buffer was in
+    /// use, i.e. the sinks weren't keeping up, so their frame couldn't be
+    /// started. Those frames will usually show up in missing_frames.
+    pub no_buffer_packets: usize,
+    /// How many packets the kernel dropped because the socket queue was full.
+    /// These will usually also show up in packets_dropped, as missing
+    /// parts of an image, so the two should not be added together.
+    pub kernel_dropped: usize,
+    /// How low did the image buffer queue length get?
+    pub min_spare_image_buffers: Option<usize>,
+    /// The most frames in flight at once; see [`FrameAssembler::frames_in_flight`].
+    /// When merged, this is the most on any one listener.
+    pub max_frames_in_flight: usize,
+    /// How many image buffers were added to the pool because it ran out
+    pub image_buffers_grown: usize,
+    /// How many image buffers were given back to the shared budget, at the
+    /// end of the acquisition, because they weren't needed
+    pub image_buffers_released: usize,
+    /// How many bytes of packet data were copied into images
+    pub bytes_copied: usize,
+    /// How many packets were received straight into their image, so didn't
+    /// need copying; see [`FrameAssembler::predict_next_packet`]
+    pub packets_placed: usize,
+    /// Time spent copying packet data into images, if measured
+    pub copy_time: Duration,
+    /// Rate of copying packet data into images in GB/s, while copying.
+    /// When merged, this is the sum across listeners running in parallel.
+    pub copy_bandwidth: Option<f64>,
+    /// If a set of expected frame numbers was given, what became of them
+    pub expected_frames: Option<ExpectedFrameReport>,
+    /// CPU time used by the listener thread during the acquisition, if measured
+    pub cpu_time: Duration,
+    /// Time from the first packet to the end of the acquisition, if measured
+    pub wall_time: Duration,
+    /// How fast the detector clock (header timestamps) ran relative to our
+    /// wall clock, in parts per million; positive if the detector is fast.
+    /// When merged, this is the mean weighted by images seen.
+    pub clock_drift_ppm: Option<f64>,
+}
+
+impl AcquisitionStats {
+    /// Fraction of the time that listener threads were using the CPU,
+    /// rather than waiting for packets. When merged, this is the average
+    /// across listeners.
+    pub fn cpu_utilization(&self) -> Option<f64> {
+        (!self.wall_time.is_zero())
+            .then(|| self.cpu_time.as_secs_f64() / self.wall_time.as_secs_f64())
+    }
+
+    /// Fold the stats from another port (or listener) into these
+    pub fn merge(&mut self, other: &AcquisitionStats) {
+        self.clock_drift_ppm = match (self.clock_drift_ppm, other.clock_drift_ppm) {
+            (Some(a), Some(b)) => {
+                let (wa, wb) = (self.images_seen as f64, other.images_seen as f64);
+                Some(if wa + wb > 0.0 {
+                    (a * wa + b * wb) / (wa + wb)
+                } else {
+                    (a + b) / 2.0
+                })
+            }
+            (a, b) => a.or(b),
+        };
+        self.images_seen += other.images_seen;
+        self.complete_images += other.complete_images;
+        self.packets_received += other.packets_received;
+        self.packets_dropped += other.packets_dropped;
+        self.out_of_order += other.out_of_order;
+        self.missing_frames += other.missing_frames;
+        self.off_stride_frames += other.off_stride_frames;
+        self.frame_stride = self.frame_stride.max(other.frame_stride);
+        self.duplicate_frames += other.duplicate_frames;
+        self.duplicate_packets += other.duplicate_packets;
+        self.unknown_det_type_packets += other.unknown_det_type_packets;
+        self.payload_mismatch_packets += other.payload_mismatch_packets;
+        self.malformed_packets += other.malformed_packets;
+        self.no_buffer_packets += other.no_buffer_packets;
+        self.kernel_dropped += other.kernel_dropped;
+        self.image_buffers_grown += other.image_buffers_grown;
+        self.image_buffers_released += other.image_buffers_released;
+        self.bytes_copied += other.bytes_copied;
+        self.packets_placed += other.packets_placed;
+        self.copy_time += other.copy_time;
+        self.cpu_time += other.cpu_time;
+        self.wall_time += other.wall_time;
+        self.copy_bandwidth = match (self.copy_bandwidth, other.copy_bandwidth) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        self.max_frames_in_flight = self.max_frames_in_flight.max(other.max_frames_in_flight);
+        self.min_spare_image_buffers =
+            match (self.min_spare_image_buffers, other.min_spare_image_buffers) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        match (&mut self.expected_frames, &other.expected_frames) {
+            (Some(ours), Some(theirs)) => ours.merge(theirs),
+            (ours @ None, Some(theirs)) => *ours = Some(theirs.clone()),
+            (_, None) => {}
+        }
+    }
+}
+
+/// Which of the expected frame numbers of an acquisition actually arrived
+///
+/// Every expected frame is in exactly one of `complete`, `incomplete` or
+/// `missing`. Frames that arrived without being expected are listed
+/// separately, in `unexpected`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExpectedFrameReport {
+    /// Expected frames that arrived with every packet
+    pub complete: BTreeSet<u64>,
+    /// Expected frames that arrived with some packets missing
+    pub incomplete: BTreeSet<u64>,
+    /// Expected frames that we never saw a packet for
+    pub missing: BTreeSet<u64>,
+    /// Frames that arrived but were not expected
+    pub unexpected: BTreeSet<u64>,
+}
+
+impl ExpectedFrameReport {
+    /// Compare the frames delivered (and whether they were complete) against the expected set
+    fn new(expected: &BTreeSet<u64>, delivered: &HashMap<u64, bool>) -> Self {
+        let mut report = ExpectedFrameReport::default();
+        for &frame_number in expected {
+            match delivered.get(&frame_number) {
+                Some(true) => report.complete.insert(frame_number),
+                Some(false) => report.incomplete.insert(frame_number),
+                None => report.missing.insert(frame_number),
+            };
+        }
+        report.unexpected = delivered
+            .keys()
+            .filter(|frame_number| !expected.contains(frame_number))
+            .copied()
+            .collect();
+        report
+    }
+
+    /// Combine with the report from another port of the same detector
+    ///
+    /// A frame is only complete if it was complete on every port, and only
+    /// missing if it was missing on every port.
+    pub fn merge(&mut self, other: &ExpectedFrameReport) {
+        let complete: BTreeSet<u64> = self
+            .complete
+            .intersection(&other.complete)
+            .copied()
+            .collect();
+        let missing: BTreeSet<u64> = self.missing.intersection(&other.missing).copied().collect();
+        let incomplete = [&self.complete, &self.incomplete, &self.missing]
+            .into_iter()
+            .chain([&other.complete, &other.incomplete, &other.missing])
+            .flatten()
+            .filter(|frame_number| {
+                !complete.contains(frame_number) && !missing.contains(frame_number)
+            })
+            .copied()
+            .collect();
+        self.complete = complete;
+        self.incomplete = incomplete;
+        self.missing = missing;
+        self.unexpected.extend(&other.unexpected);
+    }
+}
+
+/// Parse a list of frame numbers and inclusive ranges, e.g. `1-100,200,300-310`
+pub fn parse_frame_ranges(value: &str) -> Result<BTreeSet<u64>, String> {
+    let mut frames = BTreeSet::new();
+    for part in value.split(',').map(str::trim) {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid frame number '{v}': {e}"))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if end < start {
+                    return Err(format!("Frame range {part} is backwards"));
+                }
+                frames.extend(start..=end);
+            }
+            None => {
+                frames.insert(parse(part)?);
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Format frame numbers compactly, as [`parse_frame_ranges`] would read them
+pub fn format_frame_ranges(frames: &BTreeSet<u64>) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &frame_number in frames {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == frame_number => *end = frame_number,
+            _ => ranges.push((frame_number, frame_number)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                format!("{start}")
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// An acquisition that some listeners are still running
+#[derive(Default)]
+struct RunningAcquisition {
+    /// How many listeners have started it but not ended it
+    running: usize,
+    /// The merged stats from the listeners that have ended it
+    stats: AcquisitionStats,
+    /// When the first listener ended it
+    first_ended: Option<Instant>,
+}
+
+/// Aggregates the per-listener start and end of acquisitions
+///
+/// An acquisition is fully ended once every listener that started it has
+/// ended it. At that point `on_acquisition_end` is called, once, with the
+/// acquisition number and the stats merged across all of those listeners.
+///
+/// A listener that dies, or stops hearing from its module, would hold an
+/// acquisition open forever, so [`AcquisitionTracker::expire_stragglers`]
+/// gives up on listeners that are too far behind the first to finish.
+pub struct AcquisitionTracker<F: FnMut(usize, &AcquisitionStats)> {
+    in_progress: HashMap<usize, RunningAcquisition>,
+    /// Acquisitions ended without some of their listeners
+    abandoned: BTreeSet<usize>,
+    on_acquisition_end: F,
+}
+
+impl<F: FnMut(usize, &AcquisitionStats)> AcquisitionTracker<F> {
+    pub fn new(on_acquisition_end: F) -> Self {
+        AcquisitionTracker {
+            in_progress: HashMap::new(),
+            abandoned: BTreeSet::new(),
+            on_acquisition_end,
+        }
+    }
+
+    /// Is every acquisition that was started now finished?
+    pub fn is_idle(&self) -> bool {
+        self.in_progress.is_empty()
+    }
+
+    /// A listener received the first packet of an acquisition
+    pub fn listener_started(&mut self, acquisition_number: usize) {
+        self.in_progress
+            .entry(acquisition_number)
+            .or_default()
+            .running += 1;
+    }
+
+    /// A listener has finished an acquisition
+    pub fn listener_ended(&mut self, acquisition_number: usize, stats: &AcquisitionStats) {
+        let Some(acquisition) = self.in_progress.get_mut(&acquisition_number) else {
+            if self.abandoned.remove(&acquisition_number) {
+                println!(
+                    "Warning: A listener ended acquisition {acquisition_number} after it had been given up on"
+                );
+            } else {
+                println!(
+                    "Warning: Got end of acquisition {acquisition_number} that was never started"
+                );
+            }
+            return;
+        };
+        acquisition.stats.merge(stats);
+        acquisition.running -= 1;
+        acquisition.first_ended.get_or_insert_with(Instant::now);
+        if acquisition.running == 0 {
+            let acquisition = self.in_progress.remove(&acquisition_number).unwrap();
+            (self.on_acquisition_end)(acquisition_number, &acquisition.stats);
+        }
+    }
+
+    /// End any acquisition whose first listener ended more than `grace` ago
+    ///
+    /// The acquisition is ended with the stats of the listeners that did
+    /// finish, as if the rest had never started it.
+    pub fn expire_stragglers(&mut self, grace: Duration) {
+        let expired: Vec<usize> = self
+            .in_progress
+            .iter()
+            .filter(|(_, a)| a.first_ended.is_some_and(|ended| ended.elapsed() > grace))
+            .map(|(&acquisition_number, _)| acquisition_number)
+            .collect();
+        for acquisition_number in expired {
+            let acquisition = self.in_progress.remove(&acquisition_number).unwrap();
+            println!(
+                "Warning: Gave up waiting for {} listener(s) to end acquisition {acquisition_number}",
+                acquisition.running
+            );
+            self.abandoned.insert(acquisition_number);
+            (self.on_acquisition_end)(acquisition_number, &acquisition.stats);
+        }
+    }
+}
+
+/// Where in an image a packet is expected to go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketSlot {
+    pub det_type: u8,
+    pub frame_number: u64,
+    pub packet_number: u32,
+    pub payload_size: usize,
+}
+
+impl PacketSlot {
+    /// Is this the slot for a packet with this header and payload size?
+    pub fn matches(&self, header: &SlsDetectorHeader, payload_size: usize) -> bool {
+        header.det_type == self.det_type
+            && header.frame_number == self.frame_number
+            && header.packet_number == self.packet_number
+            && payload_size == self.payload_size
+    }
+}
+
+/// An image that is still having packets added to it
+pub struct PartialFrame {
+    header: SlsDetectorHeader,
+    geometry: PortGeometry,
+    received_packets: usize,
+    /// Bit N is set if packet_number N has been received
+    received_mask: u64,
+    /// Payload bytes copied in so far, which a complete frame fills exactly
+    received_bytes: usize,
+    data: AlignedBuffer,
+    /// When the first packet for this image arrived
+    started: Instant,
+}
+
+impl PartialFrame {
+    /// Header of the first packet received for this frame
+    pub fn header(&self) -> &SlsDetectorHeader {
+        &self.header
+    }
+    pub fn received_mask(&self) -> u64 {
+        self.received_mask
+    }
+    pub fn is_complete(&self) -> bool {
+        self.received_mask == self.geometry.full_mask()
+    }
+    /// The image data assembled so far
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    /// How long since the first packet of this frame arrived
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Finish assembly of this image, ready to pass on to a sink
+    ///
+    /// The image buffer will be sent back to `pool` once the sink drops it.
+    fn into_completed(self, pool: Sender<AlignedBuffer>) -> CompletedFrame {
+        CompletedFrame {
+            header: self.header,
+            complete: self.is_complete(),
+            received_mask: self.received_mask,
+            data: PooledBuffer::new(self.data, pool),
+        }
+    }
+}
+
+impl std::fmt::Debug for PartialFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PartialFrame")
+            .field("header", &self.header)
+            .field("received_packets", &self.received_packets)
+            .field(
+                "received_mask",
+                &format_args!("{:#018x}", self.received_mask),
+            )
+            .finish()
+    }
+}
+
+/// How far apart consecutive frame numbers are expected to be
+///
+/// Some readout modes skip frame numbers deterministically, e.g. only
+/// reading out every other frame, so that gaps of the stride are normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStride {
+    Fixed(u64),
+    /// Learn the stride from the first frames of every acquisition
+    Learn,
+}
+
+impl Default for FrameStride {
+    fn default() -> Self {
+        FrameStride::Fixed(1)
+    }
+}
+
+/// Parse from a positive number, or `learn`
+impl FromStr for FrameStride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "learn" {
+            return Ok(FrameStride::Learn);
+        }
+        match s.parse::<u64>() {
+            Ok(0) => Err("Frame stride must be at least 1".to_string()),
+            Ok(stride) => Ok(FrameStride::Fixed(stride)),
+            Err(_) => Err(format!("Expected a frame stride or 'learn', got '{s}'")),
+        }
+    }
+}
+
+/// Counts frames missing from the sequence of frame numbers
+///
+/// Frames can start out of order within the assembler's reorder window, so
+/// gaps are only counted once that many newer frames have started, and
+/// every frame that could still fill them is in. Anything that starts
+/// later still, behind a frame already counted, is left to the packet
+/// accounting.
+#[derive(Default)]
+struct FrameGapDetector {
+    configured: FrameStride,
+    /// The stride in use; None while still learning it
+    stride: Option<u64>,
+    /// The newest frame counted so far
+    last: Option<u64>,
+    /// Frames that have started but not been counted yet, as older ones
+    /// could still start before them
+    pending: BTreeSet<u64>,
+    /// How many frames can be pending, as for the reorder window
+    window: usize,
+    /// While learning, the gaps seen so far
+    learning: Vec<u64>,
+    missing: usize,
+    off_stride: usize,
+}
+
+impl FrameGapDetector {
+    /// How many gaps to see before deciding on a learned stride
+    const LEARNING_GAPS: usize = 8;
+
+    fn new(configured: FrameStride, window: usize) -> Self {
+        FrameGapDetector {
+            configured,
+            stride: match configured {
+                FrameStride::Fixed(stride) => Some(stride),
+                FrameStride::Learn => None,
+            },
+            window,
+            ..Default::default()
+        }
+    }
+
+    /// Record the start of a new frame
+    fn observe(&mut self, frame_number: u64) {
+        if self.last.is_some_and(|last| frame_number <= last) {
+            return;
+        }
+        self.pending.insert(frame_number);
+        while self.pending.len() > self.window {
+            let oldest = self.pending.pop_first().unwrap();
+            self.count_frame(oldest);
+        }
+    }
+
+    /// Count any gap before a frame, now that everything before it has started
+    fn count_frame(&mut self, frame_number: u64) {
+        let Some(last) = self.last.replace(frame_number) else {
+            return;
+        };
+        let gap = frame_number - last;
+        match self.stride {
+            Some(stride) => self.count_gap(gap, stride),
+            None => {
+                self.learning.push(gap);
+                if self.learning.len() == Self::LEARNING_GAPS {
+                    self.decide_stride();
+                }
+            }
+        }
+    }
+
+    /// Take the most common gap seen while learning as the stride, so
+    /// that a genuinely missing frame doesn't throw it off
+    fn decide_stride(&mut self) {
+        let mut counts = HashMap::<u64, usize>::new();
+        for &gap in &self.learning {
+            *counts.entry(gap).or_default() += 1;
+        }
+        let Some(stride) = counts
+            .into_iter()
+            .max_by_key(|&(gap, count)| (count, std::cmp::Reverse(gap)))
+            .map(|(gap, _)| gap)
+        else {
+            return;
+        };
+        self.stride = Some(stride);
+        for gap in std::mem::take(&mut self.learning) {
+            self.count_gap(gap, stride);
+        }
+    }
+
+    fn count_gap(&mut self, gap: u64, stride: u64) {
+        if !gap.is_multiple_of(stride) {
+            self.off_stride += 1;
+        }
+        self.missing += (gap.div_ceil(stride) - 1) as usize;
+    }
+
+    /// Put the counts into `stats`, and start again for the next acquisition
+    fn finish(&mut self, stats: &mut AcquisitionStats) {
+        while let Some(frame_number) = self.pending.pop_first() {
+            self.count_frame(frame_number);
+        }
+        if self.stride.is_none() {
+            self.decide_stride();
+        }
+        stats.missing_frames = self.missing;
+        stats.off_stride_frames = self.off_stride;
+        stats.frame_stride = self.stride;
+        *self = FrameGapDetector::new(self.configured, self.window);
+    }
+}
+
+/// Remembers recently completed frame numbers, so that a frame delivered
+/// twice (e.g. via multiple network paths) is only passed on once.
+///
+/// Frame numbers are only unique within an acquisition, so this must be
+/// reset at every acquisition boundary.
+struct FrameDeduplicator {
+    window: usize,
+    /// Completed frame numbers, oldest first
+    recent: VecDeque<u64>,
+    /// Completed frame numbers, and whether we have seen a duplicate of it yet
+    completed: HashMap<u64, bool>,
+    /// How many distinct duplicated frames we have seen
+    duplicate_frames: usize,
+}
+
+impl FrameDeduplicator {
+    fn new(window: usize) -> Self {
+        FrameDeduplicator {
+            window,
+            recent: VecDeque::with_capacity(window),
+            completed: HashMap::with_capacity(window),
+            duplicate_frames: 0,
+        }
+    }
+
+    /// Record that a frame was completed and delivered
+    fn record_completed(&mut self, frame_number: u64) {
+        if self.window == 0 || self.completed.insert(frame_number, false).is_some() {
+            return;
+        }
+        self.recent.push_back(frame_number);
+        if self.recent.len() > self.window {
+            let expired = self.recent.pop_front().unwrap();
+            self.completed.remove(&expired);
+        }
+    }
+
+    /// Does a packet for this frame number belong to an already-completed frame?
+    fn is_duplicate(&mut self, frame_number: u64) -> bool {
+        match self.completed.get_mut(&frame_number) {
+            None => false,
+            Some(seen_before) => {
+                if !*seen_before {
+                    *seen_before = true;
+                    self.duplicate_frames += 1;
+                }
+                true
+            }
+        }
+    }
+
+    /// Forget everything, ready for a new acquisition
+    fn reset(&mut self) {
+        self.recent.clear();
+        self.completed.clear();
+        self.duplicate_frames = 0;
+    }
+}
+
+/// Compares the detector's frame timestamps against when the frames arrived
+///
+/// The first frame of an acquisition sets the reference point for both
+/// clocks. After that, a least-squares fit of detector time against wall
+/// time gives their relative rate, which averages out network jitter in
+/// the arrival times.
+#[derive(Default)]
+struct ClockDriftTracker {
+    /// Detector timestamp and arrival time of the reference frame
+    reference: Option<(u64, Instant)>,
+    /// Number of frames fitted, and the sums for the fit, in seconds since the reference
+    n: usize,
+    sum_wall: f64,
+    sum_detector: f64,
+    sum_wall_detector: f64,
+    sum_wall_squared: f64,
+}
+
+impl ClockDriftTracker {
+    /// Header timestamps are in units of 100 ns
+    const TIMESTAMP_TICK: f64 = 1e-7;
+
+    /// Record the arrival of the first packet of a frame
+    fn observe(&mut self, timestamp: u64, arrived: Instant) {
+        let Some((reference_timestamp, reference_arrival)) = self.reference else {
+            self.reference = Some((timestamp, arrived));
+            return;
+        };
+        // A timestamp going backwards means a new measurement has started
+        // on the detector, so start again from here
+        if timestamp < reference_timestamp {
+            *self = ClockDriftTracker::default();
+            self.reference = Some((timestamp, arrived));
+            return;
+        }
+        let wall = (arrived - reference_arrival).as_secs_f64();
+        let detector = (timestamp - reference_timestamp) as f64 * Self::TIMESTAMP_TICK;
+        self.n += 1;
+        self.sum_wall += wall;
+        self.sum_detector += detector;
+        self.sum_wall_detector += wall * detector;
+        self.sum_wall_squared += wall * wall;
+    }
+
+    /// The drift of the detector clock in ppm, if there is enough to tell
+    fn drift_ppm(&self) -> Option<f64> {
+        // Fewer than two points past the reference can't separate drift from jitter
+        if self.n < 2 || self.sum_detector == 0.0 {
+            return None;
+        }
+        let n = self.n as f64;
+        let variance = self.sum_wall_squared - self.sum_wall * self.sum_wall / n;
+        if variance <= 0.0 {
+            return None;
+        }
+        let covariance = self.sum_wall_detector - self.sum_wall * self.sum_detector / n;
+        Some((covariance / variance - 1.0) * 1e6)
+    }
+}
+
+/// A limit on the total number of image buffers across many assemblers
+///
+/// Assemblers with an adaptive pool take buffers from this when they run
+/// out, and give back buffers they didn't need at the end of each
+/// acquisition, so that memory moves to whichever ports are busiest.
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: usize,
+    allocated: AtomicUsize,
+}
+
+impl BufferBudget {
+    pub fn new(limit: usize) -> Self {
+        BufferBudget {
+            limit,
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// How many buffers are currently allocated against the budget
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Allocate one buffer against the budget, if there is room
+    fn try_take(&self) -> bool {
+        self.allocated
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
+                (allocated < self.limit).then_some(allocated + 1)
+            })
+            .is_ok()
+    }
+
+    /// Count buffers that already exist against the budget, even if over it
+    fn take_existing(&self, count: usize) {
+        self.allocated.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn give_back(&self, count: usize) {
+        self.allocated.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+/// How an assembler's pool of image buffers grows and shrinks
+struct AdaptivePool {
+    budget: Arc<BufferBudget>,
+    /// Never shrink to fewer buffers than this
+    min: usize,
+    /// Never grow to more buffers than this
+    max: usize,
+}
+
+/// Works out the geometry of a detector by watching the packets it sends
+///
+/// The payload size is taken from the packets themselves, and the packets
+/// per frame from the highest packet number in a clean frame: one with
+/// every packet from zero upwards exactly once. The first frame seen may
+/// have started before we were listening, so it is never used, and two
+/// consecutive clean frames must agree in case the last packets of one
+/// were lost.
+#[derive(Default)]
+struct GeometryDetector {
+    payload_size: Option<usize>,
+    /// Frame number, received mask and packet count of the frame being watched
+    frame: Option<(u64, u64, usize)>,
+    /// Have we seen a frame start since we began watching?
+    seen_frame_start: bool,
+    /// Packets per frame of the last frame, if it was clean
+    candidate: Option<usize>,
+}
+
+impl GeometryDetector {
+    /// Watch one packet, returning the geometry once it is certain
+    fn observe(&mut self, header: &SlsDetectorHeader, payload_size: usize) -> Option<PortGeometry> {
+        if header.packet_number as usize >= MAX_PACKETS_PER_FRAME || payload_size == 0 {
+            return None;
+        }
+        if self.payload_size != Some(payload_size) {
+            // Inconsistent packets; start again
+            *self = GeometryDetector {
+                payload_size: Some(payload_size),
+                ..Default::default()
+            };
+        }
+        let bit = 1u64 << header.packet_number;
+        match self.frame.as_mut() {
+            Some((frame_number, mask, count)) if *frame_number == header.frame_number => {
+                *mask |= bit;
+                *count += 1;
+                return None;
+            }
+            Some((_, mask, count)) => {
+                let clean =
+                    *count == mask.count_ones() as usize && mask.wrapping_add(1) & *mask == 0;
+                let packets_per_frame = clean.then_some(*count);
+                let agreed = self.seen_frame_start
+                    && packets_per_frame.is_some()
+                    && self.candidate == packets_per_frame;
+                self.candidate = packets_per_frame.filter(|_| self.seen_frame_start);
+                self.seen_frame_start = true;
+                self.frame = Some((header.frame_number, bit, 1));
+                if agreed {
+                    let packets_per_frame = packets_per_frame.unwrap();
+                    // We can't know the pixel layout, so describe each
+                    // packet as one row of 8-bit pixels
+                    return Some(PortGeometry {
+                        packets_per_frame,
+                        payload_size,
+                        size_x: payload_size,
+                        size_y: packets_per_frame,
+                        bit_depth: 8,
+                        layout: PacketLayout::Contiguous,
+                    });
+                }
+            }
+            None => self.frame = Some((header.frame_number, bit, 1)),
+        }
+        None
+    }
+}
+
+/// How many incomplete frames an assembler keeps open, unless told otherwise
+pub const DEFAULT_REORDER_WINDOW: usize = 2;
+
+/// Assembles the packets arriving on one port into frames
+///
+/// Completed frames are handed to an `emit` callback along with ownership
+/// of their image buffer, so no copy is made. Buffers come back to the
+/// assembler's pool once the receiver of the frame drops it, so the pool
+/// has to be large enough to cover every frame that is being held on to.
+pub struct FrameAssembler {
+    geometry: GeometryMap,
+    /// Alignment of the start of every image buffer
+    alignment: usize,
+    /// Image buffers ready to use for a new image
+    spare_buffers: Vec<AlignedBuffer>,
+    /// Handle given out with each completed frame, to return its buffer
+    buffer_return: Sender<AlignedBuffer>,
+    /// Buffers that sinks have finished with
+    returned_buffers: Receiver<AlignedBuffer>,
+    /// How many image buffers belong to this assembler, including those
+    /// held by sinks
+    pool_size: usize,
+    adaptive_pool: Option<AdaptivePool>,
+    dedup: Option<FrameDeduplicator>,
+    /// Should we time the copy of every packet into its image?
+    measure_copy_time: bool,
+    /// If auto-detecting geometry, the detector types currently being watched
+    auto_detect: Option<HashMap<SlsDetectorType, GeometryDetector>>,
+    /// The frame numbers that we expect every acquisition to contain, if known
+    expected_frames: Option<BTreeSet<u64>>,
+    /// Frame numbers delivered this acquisition, and whether they were
+    /// complete. Only tracked if there are expected frames to compare against.
+    delivered_frames: HashMap<u64, bool>,
+    clock_drift: ClockDriftTracker,
+    frame_gaps: FrameGapDetector,
+    /// Every frame below this has been delivered or given up on, this
+    /// acquisition
+    closed_below: Option<u64>,
+    /// How many incomplete images to keep open at once, so that packets
+    /// from frames arriving interleaved can still be assembled
+    reorder_window: usize,
+    /// The images still being assembled, by frame number. When a new frame
+    /// arrives with the window full, the oldest is sent off incomplete.
+    in_progress: BTreeMap<u64, PartialFrame>,
+    stats: AcquisitionStats,
+}
+
+impl FrameAssembler {
+    /// Create an assembler with `pool_size` preallocated image buffers
+    ///
+    /// We don't know what detector will be sending to us, so buffers start
+    /// sized for a Jungfrau frame, and are reallocated if a different
+    /// detector arrives.
+    pub fn new(geometry: GeometryMap, pool_size: usize) -> Self {
+        Self::with_alignment(geometry, pool_size, DEFAULT_BUFFER_ALIGNMENT)
+    }
+
+    /// Create an assembler whose image buffers all start at a multiple of `alignment`
+    ///
+    /// Panics if `alignment` is not a power of two.
+    pub fn with_alignment(geometry: GeometryMap, pool_size: usize, alignment: usize) -> Self {
+        let initial_size = geometry
+            .get(SlsDetectorType::Jungfrau as u8)
+            .map_or(0, |g| g.frame_size());
+        let spare_buffers = std::iter::repeat_n((), pool_size)
+            .map(|()| AlignedBuffer::new(initial_size, alignment))
+            .collect();
+        let (buffer_return, returned_buffers) = mpsc::channel();
+        FrameAssembler {
+            geometry,
+            alignment,
+            spare_buffers,
+            buffer_return,
+            returned_buffers,
+            pool_size,
+            adaptive_pool: None,
+            dedup: None,
+            measure_copy_time: false,
+            auto_detect: None,
+            expected_frames: None,
+            delivered_frames: HashMap::new(),
+            clock_drift: ClockDriftTracker::default(),
+            frame_gaps: FrameGapDetector::new(FrameStride::default(), DEFAULT_REORDER_WINDOW),
+            closed_below: None,
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            in_progress: BTreeMap::new(),
+            stats: AcquisitionStats::default(),
+        }
+    }
+
+    /// Size the spare image buffers for frames from this detector type
+    ///
+    /// Buffers are otherwise sized for a Jungfrau, and reallocated once
+    /// packets from another detector arrive, so this saves reallocating
+    /// every buffer on the first acquisition.
+    pub fn set_expected_detector(&mut self, det_type: SlsDetectorType) -> Result<(), MorgulError> {
+        let size = self
+            .geometry
+            .get(det_type as u8)
+            .ok_or(MorgulError::UnknownDetectorType(det_type as u8))?
+            .frame_size();
+        for buffer in &mut self.spare_buffers {
+            if buffer.len() != size {
+                *buffer = AlignedBuffer::new(size, self.alignment);
+            }
+        }
+        Ok(())
+    }
+
+    /// Suppress frames whose frame number was already completed within the
+    /// last `window` completed frames of the acquisition
+    pub fn set_dedup_window(&mut self, window: Option<usize>) {
+        self.dedup = window.map(FrameDeduplicator::new);
+    }
+
+    /// Time every packet copy, so that copy bandwidth can be reported
+    ///
+    /// This costs two clock reads per packet, so is off by default.
+    pub fn set_measure_copy_time(&mut self, measure: bool) {
+        self.measure_copy_time = measure;
+    }
+
+    /// Work out the geometry of detector types that we don't know yet
+    ///
+    /// Packets from a detector type with no geometry are watched until the
+    /// geometry can be determined, then it is fixed for this assembler. Any
+    /// geometry already known, e.g. from a geometry file, takes priority.
+    pub fn set_auto_detect_geometry(&mut self, auto_detect: bool) {
+        self.auto_detect = auto_detect.then(HashMap::new);
+    }
+
+    /// Compare the frames of every acquisition against this set of frame numbers
+    ///
+    /// The stats returned at the end of each acquisition then report which
+    /// expected frames were complete, incomplete or missing, and any frames
+    /// that arrived without being expected.
+    pub fn set_expected_frames(&mut self, expected: Option<BTreeSet<u64>>) {
+        self.expected_frames = expected;
+    }
+
+    /// Keep up to `window` incomplete frames open at once, to tolerate
+    /// packets from neighbouring frames arriving interleaved
+    ///
+    /// Every open frame holds an image buffer, so the pool needs to be
+    /// comfortably larger than this. A window of zero is treated as one.
+    pub fn set_reorder_window(&mut self, window: usize) {
+        self.reorder_window = window.max(1);
+        self.frame_gaps.window = self.reorder_window;
+    }
+
+    /// Set the step between frame numbers that isn't counted as missing frames
+    pub fn set_frame_stride(&mut self, stride: FrameStride) {
+        self.frame_gaps = FrameGapDetector::new(stride, self.reorder_window);
+    }
+
+    /// Grow and shrink the pool of image buffers to follow demand
+    ///
+    /// When the pool runs out, another buffer is allocated if `budget` and
+    /// `max` allow, rather than failing with
+    /// [`MorgulError::BufferPoolExhausted`]. At the end of each acquisition,
+    /// half of the buffers that were never needed are given back to
+    /// `budget`, down to `min`. The existing pool is counted against the
+    /// budget straight away.
+    pub fn set_adaptive_pool(&mut self, budget: Arc<BufferBudget>, min: usize, max: usize) {
+        if let Some(previous) = self.adaptive_pool.take() {
+            previous.budget.give_back(self.pool_size);
+        }
+        budget.take_existing(self.pool_size);
+        self.adaptive_pool = Some(AdaptivePool { budget, min, max });
+    }
+
+    /// How many image buffers belong to this assembler, including those held by sinks
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    /// How many frames are still open here, or handed on but not yet finished with
+    ///
+    /// This counts the image buffers that are out of the pool: frames
+    /// being assembled, and completed frames queued for, or held by, the
+    /// sinks. If it keeps growing then the sinks aren't keeping up, and
+    /// frames will soon be lost when the pool runs out.
+    pub fn frames_in_flight(&mut self) -> usize {
+        self.spare_buffers.extend(self.returned_buffers.try_iter());
+        self.pool_size - self.spare_buffers.len()
+    }
+
+    pub fn geometry(&self) -> &GeometryMap {
+        &self.geometry
+    }
+
+    /// The statistics for the acquisition so far
+    pub fn stats(&self) -> &AcquisitionStats {
+        &self.stats
+    }
+
+    /// The most recent frame still being assembled, if any
+    pub fn current_frame(&self) -> Option<&PartialFrame> {
+        self.in_progress.last_key_value().map(|(_, image)| image)
+    }
+
+    /// Take a spare buffer from the pool, resizing it to fit the frame if needed
+    fn take_buffer(&mut self, size: usize) -> Result<AlignedBuffer, MorgulError> {
+        // Reclaim anything the sink has finished with
+        self.spare_buffers.extend(self.returned_buffers.try_iter());
+        if self.spare_buffers.is_empty()
+            && let Some(pool) = &self.adaptive_pool
+            && self.pool_size < pool.max
+            && pool.budget.try_take()
+        {
+            self.pool_size += 1;
+            self.stats.image_buffers_grown += 1;
+            self.stats.min_spare_image_buffers = Some(0);
+            self.stats.max_frames_in_flight = self.stats.max_frames_in_flight.max(self.pool_size);
+            return Ok(AlignedBuffer::new(size, self.alignment));
+        }
+        let buffer = self
+            .spare_buffers
+            .pop()
+            .ok_or(MorgulError::BufferPoolExhausted)?;
+        let spare = self.spare_buffers.len();
+        self.stats.max_frames_in_flight =
+            self.stats.max_frames_in_flight.max(self.pool_size - spare);
+        self.stats.min_spare_image_buffers = Some(
+            self.stats
+                .min_spare_image_buffers
+                .map_or(spare, |min| min.min(spare)),
+        );
+        if buffer.len() == size {
+            Ok(buffer)
+        } else {
+            Ok(AlignedBuffer::new(size, self.alignment))
+        }
+    }
+
+    /// Give back half of the buffers that stayed spare all acquisition
+    ///
+    /// One spare is always kept as headroom, so that a pool which is just
+    /// big enough doesn't flip between growing and shrinking.
+    fn shrink_pool(&mut self) {
+        let Some(pool) = &self.adaptive_pool else {
+            return;
+        };
+        self.spare_buffers.extend(self.returned_buffers.try_iter());
+        // If no images arrived, nothing was needed
+        let unused = self
+            .stats
+            .min_spare_image_buffers
+            .unwrap_or(self.spare_buffers.len());
+        let release = unused
+            .saturating_sub(1)
+            .div_ceil(2)
+            .min(self.pool_size.saturating_sub(pool.min))
+            .min(self.spare_buffers.len());
+        self.spare_buffers
+            .truncate(self.spare_buffers.len() - release);
+        self.pool_size -= release;
+        pool.budget.give_back(release);
+        self.stats.image_buffers_released = release;
+    }
+
+    /// Hand a finished (or abandoned) image over to `emit`, counting any missing packets
+    fn deliver_image(&mut self, image: PartialFrame, emit: &mut impl FnMut(CompletedFrame)) {
+        // Nothing can be assembled below the oldest frame still open
+        let frame_number = image.header.frame_number;
+        if self
+            .in_progress
+            .first_key_value()
+            .is_none_or(|(&oldest, _)| frame_number < oldest)
+        {
+            self.closed_below = self.closed_below.max(Some(frame_number.saturating_add(1)));
+        }
+        self.stats.packets_dropped += image.geometry.packets_per_frame - image.received_packets;
+        if self.expected_frames.is_some() {
+            // A frame could arrive twice; count it complete if either was
+            *self
+                .delivered_frames
+                .entry(image.header.frame_number)
+                .or_default() |= image.is_complete();
+        }
+        emit(image.into_completed(self.buffer_return.clone()));
+    }
+
+    /// Add a received packet to the frame that it belongs to
+    ///
+    /// Any frames that are completed, or abandoned as incomplete, as a
+    /// result are passed to `emit`. If the packet starts a new frame but
+    /// every image buffer is still held by the sinks, it is discarded and
+    /// counted, and [`MorgulError::BufferPoolExhausted`] is returned.
+    pub fn push_packet(
+        &mut self,
+        header: &SlsDetectorHeader,
+        payload: &[u8],
+        mut emit: impl FnMut(CompletedFrame),
+    ) -> Result<(), MorgulError> {
+        // Work out what shape of data this detector sends
+        let Some(&geometry) = self.geometry.get(header.det_type) else {
+            if let Some(detectors) = self.auto_detect.as_mut()
+                && let Ok(det_type) = header.detector_type()
+            {
+                let detector = detectors.entry(det_type).or_default();
+                if let Some(geometry) = detector.observe(header, payload.len()) {
+                    println!(
+                        "Detected geometry for {det_type:?}: {} packets of {} bytes",
+                        geometry.packets_per_frame, geometry.payload_size
+                    );
+                    detectors.remove(&det_type);
+                    self.geometry.insert(det_type, geometry);
+                    // This packet starts a new frame, so can be assembled
+                    return self.push_packet(header, payload, emit);
+                }
+                self.stats.unknown_det_type_packets += 1;
+                return Ok(());
+            }
+            self.stats.unknown_det_type_packets += 1;
+            return Err(MorgulError::UnknownDetectorType(header.det_type));
+        };
+
+        // Basic header validation
+        if header.packet_number as usize >= geometry.packets_per_frame {
+            self.stats.malformed_packets += 1;
+            return Err(MorgulError::MalformedHeader {
+                reason: format!(
+                    "Got packet number {} but only expected {} packets per image; are you running in half-module mode?",
+                    header.packet_number, geometry.packets_per_frame
+                ),
+            });
+        }
+        // Don't try to assemble packets that can't be from what they claim
+        let expected_size = geometry.packet_payload_size(header.packet_number as usize);
+        if payload.len() != expected_size {
+            self.stats.payload_mismatch_packets += 1;
+            return Err(MorgulError::PayloadSizeMismatch {
+                det_type: header.det_type,
+                expected: expected_size,
+                observed: payload.len(),
+            });
+        }
+
+        // Drop anything belonging to a frame we have already delivered
+        if let Some(dedup) = self.dedup.as_mut()
+            && dedup.is_duplicate(header.frame_number)
+        {
+            self.stats.duplicate_packets += 1;
+            return Ok(());
+        }
+
+        // Anything older than the frames still being assembled has missed
+        // its chance, as has anything that would be pushed straight back
+        // out of a full window
+        let frame_number = header.frame_number;
+        if !self.in_progress.contains_key(&frame_number) {
+            let window_full = self.in_progress.len() >= self.reorder_window;
+            let too_old = self
+                .closed_below
+                .is_some_and(|closed| frame_number < closed)
+                || (window_full
+                    && self
+                        .in_progress
+                        .first_key_value()
+                        .is_some_and(|(&oldest, _)| frame_number < oldest));
+            if too_old {
+                self.stats.out_of_order += 1;
+                return Ok(());
+            }
+            if window_full {
+                // Oh dear, this isn't going well; make room by sending off
+                // the oldest image even though it is incomplete, and count
+                // the dropped packets.
+                let (_, oldest) = self.in_progress.pop_first().unwrap();
+                self.deliver_image(oldest, &mut emit);
+            }
+            let data = self.take_buffer(geometry.frame_size()).inspect_err(|_| {
+                self.stats.no_buffer_packets += 1;
+            })?;
+            self.stats.images_seen += 1;
+            let started = Instant::now();
+            self.clock_drift.observe(header.timestamp, started);
+            self.frame_gaps.observe(frame_number);
+            self.in_progress.insert(
+                frame_number,
+                PartialFrame {
+                    header: *header,
+                    geometry,
+                    received_packets: 0,
+                    received_mask: 0,
+                    received_bytes: 0,
+                    data,
+                    started,
+                },
+            );
+        }
+        self.add_packet(
+            frame_number,
+            header.packet_number,
+            payload.len(),
+            Some(payload),
+            emit,
+        );
+        Ok(())
+    }
+
+    /// Where the next packet will probably go, so that it can be received straight there
+    ///
+    /// This guesses that the packet after the last one received for the
+    /// newest frame is next, and returns where its payload goes in that
+    /// frame's image. Whatever is received there, the slot had no data yet.
+    /// If the packet turns out to be the one predicted, pass it to
+    /// [`FrameAssembler::push_placed_packet`]; otherwise, copy it out and
+    /// pass it to [`FrameAssembler::push_packet`] as usual.
+    pub fn predict_next_packet(&mut self) -> Option<(PacketSlot, &mut [u8])> {
+        let (&frame_number, image) = self.in_progress.last_key_value()?;
+        // The packet after the highest numbered one received so far
+        let packet_number = (u64::BITS - image.received_mask.leading_zeros()) as usize;
+        if packet_number >= image.geometry.packets_per_frame {
+            return None;
+        }
+        let slot = PacketSlot {
+            det_type: image.header.det_type,
+            frame_number,
+            packet_number: packet_number as u32,
+            payload_size: image.geometry.packet_payload_size(packet_number),
+        };
+        let offset = image.geometry.packet_offset(packet_number);
+        let image = self.in_progress.get_mut(&frame_number).unwrap();
+        Some((slot, &mut image.data[offset..offset + slot.payload_size]))
+    }
+
+    /// Add a packet whose payload was received straight into its predicted slot
+    ///
+    /// The packet's header must match `slot`, e.g. by [`PacketSlot::matches`].
+    pub fn push_placed_packet(
+        &mut self,
+        header: &SlsDetectorHeader,
+        slot: PacketSlot,
+        emit: impl FnMut(CompletedFrame),
+    ) -> Result<(), MorgulError> {
+        if !slot.matches(header, slot.payload_size)
+            || !self.in_progress.contains_key(&slot.frame_number)
+        {
+            return Err(MorgulError::MalformedHeader {
+                reason: format!(
+                    "Packet {} of frame {} was not received where it was predicted",
+                    header.packet_number, header.frame_number
+                ),
+            });
+        }
+        self.stats.packets_placed += 1;
+        self.add_packet(
+            slot.frame_number,
+            slot.packet_number,
+            slot.payload_size,
+            None,
+            emit,
+        );
+        Ok(())
+    }
+
+    /// Record a packet of a frame in progress, copying in its payload unless it's already there
+    fn add_packet(
+        &mut self,
+        frame_number: u64,
+        packet_number: u32,
+        payload_size: usize,
+        payload: Option<&[u8]>,
+        mut emit: impl FnMut(CompletedFrame),
+    ) {
+        let this_image = self.in_progress.get_mut(&frame_number).unwrap();
+        let geometry = this_image.geometry;
+
+        // A packet we already have would otherwise be counted twice, and
+        // could complete the frame while another packet is still missing
+        if this_image.received_mask & (1 << packet_number) != 0 {
+            self.stats.duplicate_packets += 1;
+            return;
+        }
+
+        // Add a packet to this image
+        this_image.received_packets += 1;
+        this_image.received_mask |= 1 << packet_number;
+        this_image.received_bytes += payload_size;
+        // Copy the new data into the image data at the right place
+        if let Some(payload) = payload {
+            let offset = geometry.packet_offset(packet_number as usize);
+            let copy_start = self.measure_copy_time.then(Instant::now);
+            this_image.data[offset..offset + payload.len()].copy_from_slice(payload);
+            if let Some(copy_start) = copy_start {
+                self.stats.copy_time += copy_start.elapsed();
+            }
+            self.stats.bytes_copied += payload.len();
+        }
+        self.stats.packets_received += 1;
+
+        // If we've received an entire image, then send it
+        if this_image.received_packets == geometry.packets_per_frame {
+            debug_assert_eq!(this_image.received_bytes, geometry.frame_size());
+            self.stats.complete_images += 1;
+            if let Some(dedup) = self.dedup.as_mut() {
+                dedup.record_completed(frame_number);
+            }
+            let this_image = self.in_progress.remove(&frame_number).unwrap();
+            self.deliver_image(this_image, &mut emit);
+        }
+    }
+
+    /// End the current acquisition
+    ///
+    /// Any images still in progress are passed to `emit` as incomplete, and
+    /// the statistics for the acquisition are returned and reset.
+    pub fn finish_acquisition(&mut self, mut emit: impl FnMut(CompletedFrame)) -> AcquisitionStats {
+        while let Some((_, image)) = self.in_progress.pop_first() {
+            self.deliver_image(image, &mut emit);
+        }
+        if let Some(dedup) = self.dedup.as_mut() {
+            self.stats.duplicate_frames = dedup.duplicate_frames;
+            dedup.reset();
+        }
+        // Frame numbers restart with every acquisition
+        if let Some(detectors) = self.auto_detect.as_mut() {
+            detectors.clear();
+        }
+        if let Some(expected) = &self.expected_frames {
+            self.stats.expected_frames =
+                Some(ExpectedFrameReport::new(expected, &self.delivered_frames));
+            self.delivered_frames.clear();
+        }
+        self.shrink_pool();
+        self.frame_gaps.finish(&mut self.stats);
+        self.closed_below = None;
+        self.stats.clock_drift_ppm = self.clock_drift.drift_ppm();
+        self.clock_drift = ClockDriftTracker::default();
+        if self.measure_copy_time && !self.stats.copy_time.is_zero() {
+            self.stats.copy_bandwidth =
+                Some(self.stats.bytes_copied as f64 / self.stats.copy_time.as_secs_f64() / 1e9);
+        }
+        std::mem::take(&mut self.stats)
+    }
+}
+
+impl Drop for FrameAssembler {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.adaptive_pool {
+            pool.budget.give_back(self.pool_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    /// A Jungfrau packet header
+    fn header(frame_number: u64, packet_number: u32) -> SlsDetectorHeader {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = frame_number;
+        header.packet_number = packet_number;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = 2;
+        header
+    }
+
+    fn jungfrau() -> PortGeometry {
+        *GeometryMap::with_defaults(16)
+            .get(SlsDetectorType::Jungfrau as u8)
+            .unwrap()
+    }
+
+    /// Push every packet in `packets`, with each payload filled with its packet number
+    fn push_all(
+        assembler: &mut FrameAssembler,
+        packets: impl IntoIterator<Item = (u64, u32)>,
+        frames: &mut Vec<CompletedFrame>,
+    ) {
+        let geometry = jungfrau();
+        for (frame_number, packet_number) in packets {
+            let payload =
+                vec![packet_number as u8; geometry.packet_payload_size(packet_number as usize)];
+            assembler
+                .push_packet(&header(frame_number, packet_number), &payload, |f| {
+                    frames.push(f)
+                })
+                .unwrap();
+        }
+    }
+
+    fn whole_frame(frame_number: u64) -> impl Iterator<Item = (u64, u32)> {
+        (0..jungfrau().packets_per_frame as u32).map(move |p| (frame_number, p))
+    }
+
+    #[test]
+    fn late_packets_are_out_of_order_not_negative_drops() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_reorder_window(1);
+        let mut frames = Vec::new();
+        // Frame 1 is missing its last packet, which turns up after frame 2 has started
+        push_all(&mut assembler, whole_frame(1).take(63), &mut frames);
+        push_all(&mut assembler, whole_frame(2), &mut frames);
+        push_all(&mut assembler, [(1, 63)], &mut frames);
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+
+        assert_eq!(frames.len(), 2);
+        assert!(!frames[0].complete);
+        assert!(frames[1].complete);
+        assert_eq!(stats.images_seen, 2);
+        assert_eq!(stats.complete_images, 1);
+        assert_eq!(stats.packets_received, 127);
+        assert_eq!(stats.packets_dropped, 1);
+        assert_eq!(stats.out_of_order, 1);
+    }
+
+    #[test]
+    fn duplicate_packet_does_not_complete_frame() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        let mut frames = Vec::new();
+        // Packet 5 arrives twice, and packet 63 never does
+        let packets = whole_frame(1).take(63).chain([(1, 5)]);
+        push_all(&mut assembler, packets, &mut frames);
+        assert!(frames.is_empty());
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].complete);
+        assert_eq!(frames[0].received_mask, u64::MAX >> 1);
+        assert_eq!(stats.complete_images, 0);
+        assert_eq!(stats.packets_received, 63);
+        assert_eq!(stats.duplicate_packets, 1);
+        assert_eq!(stats.packets_dropped, 1);
+    }
+
+    #[test]
+    fn interleaved_frames_within_window_are_both_completed() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_reorder_window(2);
+        let mut frames = Vec::new();
+        // Alternate between the packets of two frames
+        let packets = whole_frame(1).zip(whole_frame(2)).flat_map(|(a, b)| [a, b]);
+        push_all(&mut assembler, packets, &mut frames);
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+
+        let frame_numbers: Vec<u64> = frames.iter().map(|f| f.header.frame_number).collect();
+        assert_eq!(frame_numbers, [1, 2]);
+        assert!(frames.iter().all(|f| f.complete));
+        // Every packet landed in its own frame
+        for frame in &frames {
+            assert_eq!(frame.data[8192 * 10], 10);
+        }
+        assert_eq!(stats.packets_dropped, 0);
+        assert_eq!(stats.out_of_order, 0);
+    }
+
+    #[test]
+    fn repeated_packet_in_window_is_duplicate() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_reorder_window(2);
+        let mut frames = Vec::new();
+        // Frame 1 gets packet 7 twice instead of its last packet, while frame 2 is open
+        let frame_1 = whole_frame(1).take(63).chain([(1, 7)]);
+        let packets = frame_1.zip(whole_frame(2)).flat_map(|(a, b)| [a, b]);
+        push_all(&mut assembler, packets, &mut frames);
+
+        // Only frame 2 is complete; frame 1 is still waiting for packet 63
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].header.frame_number, 2);
+        assert!(frames[0].complete);
+        assert_eq!(assembler.stats().duplicate_packets, 1);
+        assert_eq!(assembler.stats().complete_images, 1);
+
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+        assert_eq!(frames.len(), 2);
+        assert!(!frames[1].complete);
+        assert_eq!(frames[1].received_mask, u64::MAX >> 1);
+        assert_eq!(stats.packets_dropped, 1);
+    }
+
+    #[test]
+    fn tracker_ends_each_acquisition_once_its_last_listener_ends() {
+        let mut ended = Vec::new();
+        let mut tracker = AcquisitionTracker::new(|n, stats: &AcquisitionStats| {
+            ended.push((n, stats.images_seen));
+        });
+        let stats = |images_seen| AcquisitionStats {
+            images_seen,
+            ..Default::default()
+        };
+        // Three listeners, finishing in a different order each time
+        for _ in 0..3 {
+            tracker.listener_started(0);
+        }
+        tracker.listener_ended(0, &stats(1));
+        tracker.listener_ended(0, &stats(2));
+        assert!(!tracker.is_idle());
+        tracker.listener_ended(0, &stats(4));
+        assert!(tracker.is_idle());
+
+        // One listener only hears the start after another has finished
+        tracker.listener_started(1);
+        tracker.listener_started(1);
+        tracker.listener_ended(1, &stats(10));
+        tracker.listener_started(1);
+        tracker.listener_ended(1, &stats(20));
+        tracker.listener_ended(1, &stats(40));
+
+        // One listener never finishes, so is given up on
+        for _ in 0..3 {
+            tracker.listener_started(2);
+        }
+        tracker.listener_ended(2, &stats(100));
+        tracker.listener_ended(2, &stats(200));
+        tracker.expire_stragglers(Duration::ZERO);
+        assert!(tracker.is_idle());
+        // Its late end mustn't end the acquisition again
+        tracker.listener_ended(2, &stats(400));
+        drop(tracker);
+
+        assert_eq!(ended, [(0, 7), (1, 70), (2, 300)]);
+    }
+
+    #[test]
+    fn dedup_suppresses_a_repeated_frame() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_dedup_window(Some(4));
+        let mut frames = Vec::new();
+        push_all(&mut assembler, whole_frame(1), &mut frames);
+        push_all(&mut assembler, whole_frame(2), &mut frames);
+        // Frame 1 delivered again by another path
+        push_all(&mut assembler, whole_frame(1), &mut frames);
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+
+        let frame_numbers: Vec<u64> = frames.iter().map(|f| f.header.frame_number).collect();
+        assert_eq!(frame_numbers, [1, 2]);
+        assert_eq!(stats.duplicate_frames, 1);
+        assert_eq!(stats.duplicate_packets, 64);
+        assert_eq!(stats.complete_images, 2);
+    }
+
+    #[test]
+    fn dedup_allows_frame_numbers_to_restart_each_acquisition() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_dedup_window(Some(4));
+        let mut frames = Vec::new();
+        push_all(&mut assembler, whole_frame(1), &mut frames);
+        assembler.finish_acquisition(|f| frames.push(f));
+        push_all(&mut assembler, whole_frame(1), &mut frames);
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.complete));
+        assert_eq!(stats.duplicate_frames, 0);
+        assert_eq!(stats.duplicate_packets, 0);
+    }
+
+    #[test]
+    fn dedup_forgets_frames_outside_its_window() {
+        let mut dedup = FrameDeduplicator::new(2);
+        for frame_number in 1..=3 {
+            dedup.record_completed(frame_number);
+        }
+        assert!(!dedup.is_duplicate(1));
+        assert!(dedup.is_duplicate(2));
+        assert!(dedup.is_duplicate(3));
+        assert!(dedup.is_duplicate(3));
+        // Each duplicated frame is only counted once
+        assert_eq!(dedup.duplicate_frames, 2);
+    }
+
+    #[test]
+    fn every_pool_buffer_has_the_configured_alignment() {
+        for alignment in [64, 4096] {
+            let mut assembler =
+                FrameAssembler::with_alignment(GeometryMap::with_defaults(16), 2, alignment);
+            // Grow the pool past its initial buffers, too
+            assembler.set_adaptive_pool(Arc::new(BufferBudget::new(8)), 1, 8);
+            let mut frames = Vec::new();
+            push_all(&mut assembler, (1..=5).flat_map(whole_frame), &mut frames);
+            assert_eq!(frames.len(), 5);
+            for frame in &frames {
+                assert!((frame.data.as_ptr() as usize).is_multiple_of(alignment));
+            }
+            drop(frames);
+            // Buffers reallocated for another detector keep it
+            assembler
+                .set_expected_detector(SlsDetectorType::Eiger)
+                .unwrap();
+            assert!(
+                assembler
+                    .spare_buffers
+                    .iter()
+                    .all(|b| (b.as_ptr() as usize).is_multiple_of(alignment)
+                        && b.alignment() == alignment)
+            );
+        }
+    }
+
+    #[test]
+    fn payload_of_the_wrong_size_for_its_detector_is_rejected() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        let mut frames = Vec::new();
+        // An Eiger-sized payload in a packet that says it's from a Jungfrau
+        let result = assembler.push_packet(&header(1, 0), &[0; 4096], |f| frames.push(f));
+        let Err(
+            e @ MorgulError::PayloadSizeMismatch {
+                det_type: 3,
+                expected: 8192,
+                observed: 4096,
+            },
+        ) = result
+        else {
+            panic!("Expected a payload size mismatch, got {result:?}");
+        };
+        assert!(
+            e.to_string()
+                .contains("from Jungfrau, which sends 8192 byte payloads, but have 4096 bytes")
+        );
+        // Nothing was assembled from it
+        assert_eq!(assembler.stats().payload_mismatch_packets, 1);
+        assert_eq!(assembler.stats().images_seen, 0);
+        push_all(&mut assembler, whole_frame(1), &mut frames);
+        assert!(frames[0].complete);
+    }
+
+    /// The gap counts after an acquisition of these frame numbers, with `stride`
+    fn frame_gaps(stride: FrameStride, frame_numbers: &[u64]) -> AcquisitionStats {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        assembler.set_frame_stride(stride);
+        let mut frames = Vec::new();
+        for &frame_number in frame_numbers {
+            push_all(&mut assembler, whole_frame(frame_number), &mut frames);
+            // Give the buffers back to the pool
+            frames.clear();
+        }
+        assembler.finish_acquisition(drop)
+    }
+
+    #[test]
+    fn strided_frames_are_not_missing() {
+        let stats = frame_gaps(FrameStride::Fixed(2), &[2, 4, 6, 8, 10]);
+        assert_eq!(stats.complete_images, 5);
+        assert_eq!(stats.missing_frames, 0);
+        assert_eq!(stats.off_stride_frames, 0);
+        assert_eq!(stats.frame_stride, Some(2));
+        // The same frames with the default stride of one are every other frame missing
+        assert_eq!(
+            frame_gaps(FrameStride::default(), &[2, 4, 6, 8, 10]).missing_frames,
+            4
+        );
+    }
+
+    #[test]
+    fn genuine_gaps_are_caught_on_a_stride() {
+        // Frame 8 is missing, and 13 is off the stride
+        let stats = frame_gaps(FrameStride::Fixed(2), &[2, 4, 6, 10, 12, 13, 15]);
+        assert_eq!(stats.missing_frames, 1);
+        assert_eq!(stats.off_stride_frames, 1);
+    }
+
+    #[test]
+    fn stride_is_learned_despite_a_missing_frame() {
+        let frame_numbers: Vec<u64> = (0..12).map(|n| n * 3).filter(|&n| n != 9).collect();
+        let stats = frame_gaps(FrameStride::Learn, &frame_numbers);
+        assert_eq!(stats.frame_stride, Some(3));
+        assert_eq!(stats.missing_frames, 1);
+        assert_eq!(stats.off_stride_frames, 0);
+        // A short acquisition still decides on a stride at its end
+        let stats = frame_gaps(FrameStride::Learn, &[4, 8, 12]);
+        assert_eq!(stats.frame_stride, Some(4));
+        assert_eq!(stats.missing_frames, 0);
+    }
+
+    #[test]
+    fn frames_starting_out_of_order_are_not_missing() {
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 16);
+        assembler.set_reorder_window(8);
+        let mut frames = Vec::new();
+        // Each frame starts before the one numbered just below it
+        let starts = [1, 3, 2, 5, 4, 7, 6];
+        push_all(&mut assembler, starts.map(|f| (f, 0)), &mut frames);
+        for frame_number in starts {
+            push_all(
+                &mut assembler,
+                whole_frame(frame_number).skip(1),
+                &mut frames,
+            );
+        }
+        // Frame 9 really is missing
+        push_all(&mut assembler, whole_frame(8), &mut frames);
+        push_all(&mut assembler, whole_frame(10), &mut frames);
+        frames.clear();
+        let stats = assembler.finish_acquisition(drop);
+        assert_eq!(stats.complete_images, 9);
+        assert_eq!(stats.missing_frames, 1);
+        assert_eq!(stats.off_stride_frames, 0);
+    }
+}
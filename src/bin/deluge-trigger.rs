@@ -1,7 +1,11 @@
-use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
-use morgul::DelugeTrigger;
+use morgul::{DelugeTrigger, READINESS_REPLY_MAGIC, ReadinessQuery, ReadinessReply};
 use pnet::datalink;
 
 #[derive(Parser, Debug)]
@@ -11,6 +15,18 @@ struct Args {
     numimages: usize,
     #[arg(long, short, default_value = "9999")]
     port: u16,
+    /// Before triggering, wait until this many receivers have said that
+    /// they are ready for a new acquisition
+    #[arg(long, default_value = "0")]
+    wait_for_receivers: usize,
+    /// Give up, without triggering, if the receivers are not all ready
+    /// after this many seconds
+    #[arg(long, default_value = "30")]
+    ready_timeout_s: u64,
+    /// Send to this broadcast address, instead of that of every network
+    /// interface. Can be given more than once.
+    #[arg(long)]
+    broadcast_addr: Vec<Ipv4Addr>,
 }
 
 fn get_broadcast_ips() -> Vec<Ipv4Addr> {
@@ -24,18 +40,90 @@ fn get_broadcast_ips() -> Vec<Ipv4Addr> {
         })
         .collect()
 }
+
+/// Ask the receivers whether they are ready, until `count` of them are
+///
+/// If that doesn't happen within `timeout`, returns the last answer from
+/// every receiver that replied, with whether it was ready.
+fn wait_for_receivers(
+    socket: &UdpSocket,
+    addresses: &[Ipv4Addr],
+    port: u16,
+    count: usize,
+    timeout: Duration,
+) -> Result<(), HashMap<SocketAddr, bool>> {
+    let start = Instant::now();
+    let mut buffer = [0u8; size_of::<ReadinessReply>()];
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    loop {
+        let query = ReadinessQuery::new();
+        for addr in addresses {
+            socket
+                .send_to(bytemuck::bytes_of(&query), (*addr, port))
+                .unwrap();
+        }
+        // Collect the answers to this query
+        let mut answers = HashMap::new();
+        while let Ok((size, source)) = socket.recv_from(&mut buffer) {
+            if size != size_of::<ReadinessReply>() {
+                continue;
+            }
+            let reply: &ReadinessReply = bytemuck::from_bytes(&buffer);
+            if reply.magic == READINESS_REPLY_MAGIC && reply.uuid == query.uuid {
+                answers.insert(source, reply.ready != 0);
+            }
+        }
+        if answers.values().filter(|ready| **ready).count() >= count {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(answers);
+        }
+    }
+}
+
 fn main() {
     let args = Args::parse();
-    let trig = DelugeTrigger {
-        exptime: args.exptime,
-        frames: args.numimages as u128,
-        ..Default::default()
-    };
+    let trig = DelugeTrigger::builder()
+        .exptime(args.exptime)
+        .frames(args.numimages as u128)
+        .build();
 
+    let addresses = if args.broadcast_addr.is_empty() {
+        get_broadcast_ips()
+    } else {
+        args.broadcast_addr
+    };
     let buffer = bytemuck::bytes_of(&trig);
     let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
     socket.set_broadcast(true).unwrap();
-    for addr in get_broadcast_ips().iter() {
-        socket.send_to(buffer, (*addr, args.port)).unwrap();
+    if args.wait_for_receivers > 0
+        && let Err(answers) = wait_for_receivers(
+            &socket,
+            &addresses,
+            args.port,
+            args.wait_for_receivers,
+            Duration::from_secs(args.ready_timeout_s),
+        )
+    {
+        println!(
+            "Error: Only {} of {} receivers are ready, not triggering",
+            answers.values().filter(|ready| **ready).count(),
+            args.wait_for_receivers
+        );
+        for (source, ready) in answers {
+            println!("    {source}: {}", if ready { "ready" } else { "busy" });
+        }
+        std::process::exit(75);
+    }
+    println!(
+        "Triggering {} images, trigger {}",
+        trig.frames,
+        trig.uuid_hex()
+    );
+    for addr in addresses {
+        socket.send_to(buffer, (addr, args.port)).unwrap();
     }
 }
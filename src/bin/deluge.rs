@@ -1,18 +1,108 @@
 use std::{
+    collections::HashMap,
     io::{self, Write},
     iter::{self},
     net::{Ipv4Addr, SocketAddr, UdpSocket},
-    sync::{Arc, Barrier},
+    sync::{Arc, Mutex},
     thread::{self},
     time::{Duration, Instant},
 };
 
-use bytemuck::{Zeroable, bytes_of};
+use bytemuck::Zeroable;
 use clap::Parser;
 use itertools::multizip;
-use morgul::{DelugeTrigger, SlsDetectorHeader, get_interface_addreses_with_prefix};
+use morgul::{
+    DelugeTrigger, GeometryMap, Ipv4Network, MorgulError, PortGeometry, READINESS_QUERY_MAGIC,
+    SLS_HEADER_VERSION, SlsDetectorHeader, SlsDetectorType, get_interface_addresses_in_subnet,
+    get_interfaces_in_subnet,
+    transport::{UdpSender, send_frames},
+};
 use socket2::Protocol;
 
+/// How many triggers can be queued for sender threads that are still busy
+const TRIGGER_QUEUE_LENGTH: usize = 16;
+
+/// Counts down the senders still sending each trigger, so that the last
+/// to finish can report on the whole acquisition
+///
+/// Unlike a `Barrier`, nobody waits for anybody else, so a sender thread
+/// that has died or stalled can't hold up the rest; its triggers are just
+/// never reported as finished. Senders can be several triggers apart, so
+/// each trigger is counted separately, by its sequence number.
+struct TriggerCompletion {
+    senders: usize,
+    /// Senders yet to finish, and when the first started, by trigger sequence
+    remaining: Mutex<HashMap<u64, (usize, Instant)>>,
+}
+
+impl TriggerCompletion {
+    fn new(senders: usize) -> Self {
+        TriggerCompletion {
+            senders,
+            remaining: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Note that a sender that started at `started` has finished trigger
+    /// `sequence`. For the last sender to finish it, this returns how long
+    /// the trigger took, from the first sender starting.
+    fn finish(&self, sequence: u64, started: Instant) -> Option<Duration> {
+        let mut remaining = self.remaining.lock().unwrap();
+        let (left, first_started) = remaining.entry(sequence).or_insert((self.senders, started));
+        *left -= 1;
+        *first_started = (*first_started).min(started);
+        if *left > 0 {
+            return None;
+        }
+        let first_started = *first_started;
+        remaining.remove(&sequence);
+        Some(first_started.elapsed())
+    }
+}
+
+/// Decides whether a trigger is a new acquisition, or a double-trigger to ignore
+struct RetriggerFilter {
+    /// How soon after the last trigger acted on another is ignored
+    window: Duration,
+    /// The uuid of the last trigger passed on to the senders, and when it arrived
+    last: Option<([u8; 12], Instant)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TriggerDecision {
+    Accept,
+    /// The same trigger as the last one, e.g. broadcast on several interfaces
+    Repeat,
+    /// A different trigger, but only this long after the last one
+    TooSoon(Duration),
+}
+
+impl RetriggerFilter {
+    fn new(window: Duration) -> Self {
+        RetriggerFilter { window, last: None }
+    }
+
+    /// Should a trigger arriving at `now` be acted on?
+    fn check(&self, trigger: &DelugeTrigger, now: Instant) -> TriggerDecision {
+        let Some((uuid, at)) = self.last else {
+            return TriggerDecision::Accept;
+        };
+        if uuid == trigger.uuid {
+            return TriggerDecision::Repeat;
+        }
+        let since = now.saturating_duration_since(at);
+        if since < self.window {
+            return TriggerDecision::TooSoon(since);
+        }
+        TriggerDecision::Accept
+    }
+
+    /// Note that a trigger was passed on, so later ones are measured from it
+    fn accepted(&mut self, trigger: &DelugeTrigger, now: Instant) {
+        self.last = Some((trigger.uuid, now));
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
 struct Args {
@@ -25,56 +115,82 @@ struct Args {
     /// The port to listen for broadcast triggers on
     #[arg(default_value = "9999", long)]
     trigger_port: u16,
+
+    /// Ignore any trigger arriving within this many milliseconds of the last
+    /// one acted on, as a double-trigger. Repeats of the same trigger (by
+    /// uuid) are always ignored.
+    #[arg(long, default_value = "500")]
+    retrigger_window_ms: u64,
+
+    /// Send from the addresses of every local interface in this subnet
+    #[arg(long, default_value = "192.0.0.0/8")]
+    subnet: Ipv4Network,
+
+    /// Also send from interfaces whose link is down
+    #[arg(long)]
+    include_down_interfaces: bool,
+
+    /// The detector to pretend to be
+    #[arg(long, value_enum, default_value_t = SlsDetectorType::Jungfrau)]
+    detector: SlsDetectorType,
 }
 
 fn send_data(
     source_address: &Ipv4Addr,
     target_address: &Ipv4Addr,
     target_port: u16,
-    sync: Arc<Barrier>,
-    mut trigger: bus::BusReader<DelugeTrigger>,
+    completion: Arc<TriggerCompletion>,
+    mut trigger: bus::BusReader<(u64, DelugeTrigger)>,
+    detector: SlsDetectorType,
+    geometry: PortGeometry,
 ) -> ! {
     let bind_addr: SocketAddr = format!("{source_address}:0").parse().unwrap();
     let to_addr: SocketAddr = format!("{target_address}:{target_port}").parse().unwrap();
-    let socket = UdpSocket::bind(bind_addr).unwrap();
-    let mut buff = vec![0u8; 8192 + size_of::<SlsDetectorHeader>()];
+    let mut sender = UdpSender::new(UdpSocket::bind(bind_addr).unwrap(), to_addr);
     let mut header = SlsDetectorHeader::zeroed();
+    header.det_type = detector as u8;
+    header.version = SLS_HEADER_VERSION;
+
+    // Triggers are numbered, so that we can tell if we missed any
+    let mut expected_sequence = 0;
 
-    sync.wait();
     loop {
-        let acq = trigger.recv().unwrap();
+        let (sequence, acq) = trigger.recv().unwrap();
+        if sequence != expected_sequence {
+            println!(
+                "{target_port}: Error: Missed {} trigger(s) before trigger {sequence}!",
+                sequence - expected_sequence
+            );
+        }
+        expected_sequence = sequence + 1;
         println!(
-            "{target_port}: Starting {} images at {:.0} Hz",
+            "{target_port}: Starting trigger {} for {} images at {:.0} Hz, taking {:.1} s",
+            acq.uuid_hex(),
             acq.frames,
-            1.0 / acq.exptime
+            acq.frequency_hz(),
+            acq.total_duration().as_secs_f64()
         );
         // println!("{target_port}: Starting send");
         let start_acq = Instant::now();
-        for image_num in 0..acq.frames {
-            let acq_elapsed = (Instant::now() - start_acq).as_secs_f32();
-            if acq_elapsed < image_num as f32 * acq.exptime {
-                thread::sleep(Duration::from_secs_f32(
-                    image_num as f32 * acq.exptime - acq_elapsed,
-                ));
-            }
-            for _ in 0..64 {
-                buff[..size_of::<SlsDetectorHeader>()].copy_from_slice(bytes_of(&header));
-
-                socket.send_to(&buff, to_addr).unwrap();
-                header.packet_number += 1;
-            }
-
-            header.frame_number += 1;
-            header.packet_number = 0;
+        let first_frame = header.frame_number;
+        if let Err(e) = send_frames(
+            &mut sender,
+            &acq,
+            &mut header,
+            geometry.packets_per_frame,
+            geometry.payload_size,
+        ) {
+            println!("{target_port}: Error: {e}");
         }
-        println!("{target_port}: Sent {} images", acq.frames);
+        // Only as many as were sent before any error
+        let sent = header.frame_number - first_frame;
+        println!("{target_port}: Sent {sent} images");
         std::io::stdout().flush().unwrap();
-        let sync_result = sync.wait();
-        if sync_result.is_leader() {
+        if let Some(taken) = completion.finish(sequence, start_acq) {
             println!(
-                "Sent {} images in {:.0} ms",
-                acq.frames,
-                (Instant::now() - start_acq).as_millis()
+                "Every sender finished trigger {} in {:.0} ms",
+                acq.uuid_hex(),
+                taken.as_millis()
             );
         }
     }
@@ -100,11 +216,20 @@ fn main() {
 
     println!("{args:?}");
 
-    let interfaces = get_interface_addreses_with_prefix(192);
-    if interfaces.is_empty() {
-        println!("Error: Could not find any 192. interfaces. Have you set up the network?");
-        std::process::exit(1);
-    }
+    let Some(geometry) = GeometryMap::with_defaults(16)
+        .get(args.detector as u8)
+        .copied()
+    else {
+        let e = MorgulError::UnknownDetectorType(args.detector as u8);
+        println!("Error: {e}");
+        std::process::exit(e.exit_code());
+    };
+
+    let interfaces = get_interface_addresses_in_subnet(args.subnet, args.include_down_interfaces)
+        .unwrap_or_else(|e| {
+            println!("Error: {e}");
+            std::process::exit(e.exit_code());
+        });
     // // Get a list of cores so that we can set affinity to them
     // let mut core_ids = core_affinity::get_core_ids().unwrap().into_iter().rev();
     // println!("{core_ids:?}");
@@ -112,45 +237,197 @@ fn main() {
 
     let mut threads = Vec::new();
 
-    let barrier = Arc::new(Barrier::new(interfaces.len() * 4));
-    let mut bus = bus::Bus::new(1);
+    let interface_names: HashMap<_, _> = get_interfaces_in_subnet(args.subnet)
+        .into_iter()
+        .map(|(name, address)| (address, name))
+        .collect();
 
-    for (port, source, target) in multizip((
+    let senders: Vec<_> = multizip((
         args.target_port..(args.target_port + interfaces.len() as u16 * 4),
         interfaces.iter().flat_map(|x| iter::repeat_n(*x, 4)),
         args.targets,
-    )) {
-        println!("Starting {source} -> {target}:{port}");
-        let bar = barrier.clone();
+    ))
+    .collect();
+    let completion = Arc::new(TriggerCompletion::new(senders.len()));
+    let mut bus = bus::Bus::new(TRIGGER_QUEUE_LENGTH);
+
+    for (port, source, target) in senders {
+        let interface = interface_names.get(&source).map_or("", String::as_str);
+        println!("Starting {interface} ({source}) -> {target}:{port}");
+        let completion = completion.clone();
         let trig = bus.add_rx();
         threads.push(thread::spawn(move || {
-            send_data(&source, &target, port, bar, trig);
+            send_data(
+                &source,
+                &target,
+                port,
+                completion,
+                trig,
+                args.detector,
+                geometry,
+            );
         }));
     }
 
     // drop(trigger_rx);
     // Wait for broadcasts
-    let mut buf = vec![0; size_of::<DelugeTrigger>()];
-    let broad = new_reusable_udp_socket("0.0.0.0:9999").unwrap();
-    // let broad = UdpSocket::bind("0.0.0.0:9999").unwrap();
+    // Larger than a trigger, so that anything too long can be told apart
+    let mut buf = vec![0; 2 * size_of::<DelugeTrigger>()];
+    let broad = new_reusable_udp_socket((Ipv4Addr::UNSPECIFIED, args.trigger_port)).unwrap();
     // broad.recv(buf)
-    // let mut last_trigger = None;
-    let mut last_trigger: Option<DelugeTrigger> = None;
+    let mut retrigger = RetriggerFilter::new(Duration::from_millis(args.retrigger_window_ms));
+    let mut sequence = 0u64;
     loop {
         if let Ok(size) = broad.recv(buf.as_mut_slice()) {
-            assert!(size == size_of::<DelugeTrigger>());
-            let trigger: &DelugeTrigger = bytemuck::from_bytes(&buf);
-
-            // Ignore retriggers with the same UUID
-            if let Some(last) = last_trigger
-                && last.uuid == trigger.uuid
-            {
+            // Readiness queries for the receivers also arrive on this port
+            if buf[..size].starts_with(&READINESS_QUERY_MAGIC) {
                 continue;
             }
+            let trigger = match DelugeTrigger::from_bytes(&buf[..size]) {
+                Ok(trigger) => trigger,
+                Err(e) => {
+                    println!("Warning: Ignoring packet on trigger port: {e}");
+                    continue;
+                }
+            };
 
-            bus.broadcast(*trigger);
+            // Ignore repeats of the last trigger, and anything too soon after it
+            let now = Instant::now();
+            match retrigger.check(&trigger, now) {
+                TriggerDecision::Accept => {}
+                TriggerDecision::Repeat => continue,
+                TriggerDecision::TooSoon(since) => {
+                    println!(
+                        "Warning: Ignoring trigger {} for {} images, only {} ms after the last",
+                        trigger.uuid_hex(),
+                        trigger.frames,
+                        since.as_millis()
+                    );
+                    continue;
+                }
+            }
 
-            last_trigger = Some(*trigger);
+            // Never block here, or we'd stop listening for triggers. If
+            // the queue is full, a sender is stuck, so say so loudly.
+            if bus.try_broadcast((sequence, trigger)).is_err() {
+                println!(
+                    "\n\
+                     ************************************************************\n\
+                     Error: Trigger queue is full; a sender thread is not keeping\n\
+                     up. Dropping trigger {} for {} images!\n\
+                     ************************************************************\n",
+                    trigger.uuid_hex(),
+                    trigger.frames
+                );
+                continue;
+            }
+            sequence += 1;
+            retrigger.accepted(&trigger, now);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(uuid: u8) -> DelugeTrigger {
+        DelugeTrigger::builder().frames(10).uuid([uuid; 12]).build()
+    }
+
+    #[test]
+    fn double_triggers_are_ignored_within_the_window() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut filter = RetriggerFilter::new(Duration::from_millis(500));
+        assert_eq!(filter.check(&trigger(1), start), TriggerDecision::Accept);
+        filter.accepted(&trigger(1), start);
+
+        assert_eq!(
+            filter.check(&trigger(2), ms(100)),
+            TriggerDecision::TooSoon(Duration::from_millis(100))
+        );
+        // An ignored trigger doesn't move the window on
+        assert_eq!(filter.check(&trigger(3), ms(500)), TriggerDecision::Accept);
+        filter.accepted(&trigger(3), ms(500));
+        assert!(matches!(
+            filter.check(&trigger(4), ms(999)),
+            TriggerDecision::TooSoon(_)
+        ));
+        // The same trigger again is a repeat however late it arrives
+        assert_eq!(
+            filter.check(&trigger(3), ms(10_000)),
+            TriggerDecision::Repeat
+        );
+        assert_eq!(
+            filter.check(&trigger(4), ms(10_000)),
+            TriggerDecision::Accept
+        );
+    }
+
+    #[test]
+    fn without_a_window_only_repeats_are_ignored() {
+        let now = Instant::now();
+        let mut filter = RetriggerFilter::new(Duration::ZERO);
+        filter.accepted(&trigger(1), now);
+        assert_eq!(filter.check(&trigger(1), now), TriggerDecision::Repeat);
+        assert_eq!(filter.check(&trigger(2), now), TriggerDecision::Accept);
+    }
+
+    #[test]
+    fn only_the_last_sender_of_each_trigger_reports_it() {
+        let completion = TriggerCompletion::new(3);
+        let start = Instant::now();
+        assert_eq!(completion.finish(0, start), None);
+        // A fast sender can be on to the next trigger before a slow one finishes
+        assert_eq!(completion.finish(1, start), None);
+        assert_eq!(completion.finish(0, start), None);
+        assert!(completion.finish(0, start).is_some());
+        assert_eq!(completion.finish(1, start), None);
+        assert!(completion.finish(1, start).is_some());
+        // Finished triggers are forgotten
+        assert!(completion.remaining.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn senders_racing_through_triggers_report_each_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        const SENDERS: usize = 8;
+        const TRIGGERS: usize = 200;
+        let completion = Arc::new(TriggerCompletion::new(SENDERS));
+        // How many senders have finished each trigger
+        let finished: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..TRIGGERS).map(|_| AtomicUsize::new(0)).collect());
+        let senders: Vec<_> = (0..SENDERS)
+            .map(|_| {
+                let (completion, finished) = (completion.clone(), finished.clone());
+                thread::spawn(move || {
+                    let mut reported = Vec::new();
+                    for sequence in 0..TRIGGERS {
+                        finished[sequence].fetch_add(1, Ordering::SeqCst);
+                        if completion.finish(sequence as u64, Instant::now()).is_some() {
+                            // Nobody is still sending it
+                            assert_eq!(finished[sequence].load(Ordering::SeqCst), SENDERS);
+                            reported.push(sequence);
+                        }
+                    }
+                    reported
+                })
+            })
+            .collect();
+        let mut reported: Vec<usize> = senders
+            .into_iter()
+            .flat_map(|sender| sender.join().unwrap())
+            .collect();
+        reported.sort();
+        assert_eq!(reported, (0..TRIGGERS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_trigger_takes_from_its_first_sender_starting() {
+        let completion = TriggerCompletion::new(2);
+        let early = Instant::now() - Duration::from_secs(5);
+        assert_eq!(completion.finish(7, Instant::now()), None);
+        assert!(completion.finish(7, early).unwrap() >= Duration::from_secs(5));
+    }
+}
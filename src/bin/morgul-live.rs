@@ -1,202 +1,1332 @@
-use clap::Parser;
-use itertools::multizip;
-use morgul::{SlsDetectorHeader, SlsDetectorType, get_interface_addreses_with_prefix};
-use nix::errno::Errno;
-use nix::sys::socket::{
-    ControlMessageOwned, MsgFlags, RecvMsg, SockaddrStorage, recvmsg, setsockopt, sockopt,
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use itertools::{Itertools, multizip};
+use morgul::assembler::{
+    AcquisitionStats, AcquisitionTracker, BufferBudget, DEFAULT_REORDER_WINDOW, FrameAssembler,
+    FrameStride, format_frame_ranges, parse_frame_ranges,
 };
+use morgul::jungfrau::Pedestal;
+use morgul::manifest::AcquisitionManifest;
+use morgul::mask::PixelMask;
+use morgul::orientation::{ModuleOrientation, Orienter};
+use morgul::output::{RawFileSink, TiffFrameSink};
+use morgul::pixel_stats::PixelStatsAccumulator;
+use morgul::roi::{RegionOfInterest, RoiExtractor};
+use morgul::shm::ShmRingSink;
+use morgul::sink::{SinkFilter, SinkRouter};
+use morgul::stats::LifetimeStats;
+use morgul::stitch::{DetectorStitcher, ModuleLayout};
+use morgul::stream::{BackpressurePolicy, FrameReader, TcpFrameSink};
+use morgul::trace::{AcquisitionSpan, OtlpExporter};
+use morgul::transport::{DropInjector, PacketReceiver, UdpReceiver};
+use morgul::{
+    AlignedBuffer, CompletedFrame, DelugeTrigger, GeometryMap, InterfaceChangeMonitor, Ipv4Network,
+    MorgulError, PooledBuffer, READINESS_QUERY_MAGIC, READINESS_REPLY_MAGIC, ReadinessQuery,
+    ReadinessReply, SLS_HEADER_VERSION, SlsDetectorHeader, SlsDetectorType,
+    get_interface_addresses_in_subnet, get_interface_links_in_subnet, get_interfaces_in_subnet,
+    thread_cpu_time,
+};
+use nix::sys::socket::{setsockopt, sockopt};
 
 use socket2::{Domain, Socket, Type};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::IoSliceMut;
 use std::iter;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
 use std::os::fd::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
-use thread_priority::set_current_thread_priority;
+use std::sync::{Arc, Mutex, OnceLock};
+use thread_priority::unix::{
+    RealtimeThreadSchedulePolicy, ThreadSchedulePolicy, set_thread_priority_and_policy,
+    thread_native_id,
+};
+use thread_priority::{ThreadPriority, set_current_thread_priority};
 
+use std::cell::RefCell;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 const LISTENERS_PER_PORT: usize = 9;
-const MODULE_SIZE_X: usize = 1024;
-const MODULE_SIZE_Y: usize = 256;
-const NUM_PIXELS: usize = MODULE_SIZE_X * MODULE_SIZE_Y;
-const BIT_DEPTH: usize = 2;
 const THREAD_IMAGE_BUFFER_LENGTH: usize = 10;
 
-struct ReceiveImage {
-    frame_number: u64,
-    header: SlsDetectorHeader,
-    received_packets: usize,
-    data: Box<[u8]>,
-}
+/// How often to check for listeners that are too slow to end an acquisition
+const STRAGGLER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-impl std::fmt::Debug for ReceiveImage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ReceiveImage")
-            .field("frame_number", &self.frame_number)
-            .field("header", &self.header)
-            .field("received_packets", &self.received_packets)
-            .finish()
-    }
-}
+/// How many frames each listener can have waiting for the viewer at once
+const VIEWER_BUFFER_LENGTH: usize = 2;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long, short, default_value = "30000")]
     udp_port: u16,
+    /// Assign listener cores in simple descending order, instead of
+    /// choosing cores on the same NUMA node as each listener's NIC.
+    #[arg(long)]
+    ignore_numa: bool,
+    /// Suppress frames whose frame number was already completed within the
+    /// last N completed frames of the same acquisition on a port.
+    #[arg(long)]
+    dedup_window: Option<usize>,
+    /// How many incomplete frames each listener keeps open at once, so that
+    /// packets from neighbouring frames arriving interleaved can still be
+    /// assembled.
+    #[arg(long, default_value_t = DEFAULT_REORDER_WINDOW, value_parser = clap::value_parser!(u64).range(1..).map(|w| w as usize))]
+    reorder_window: usize,
+    /// How many image buffers each listener has, for frames being assembled
+    /// or waiting for the sinks. If the sinks fall so far behind that every
+    /// one is in use, packets for new frames are discarded, and counted,
+    /// until buffers come back; listeners never wait for the sinks, as the
+    /// socket queue would overflow instead. Ignored with
+    /// --image-buffer-budget.
+    #[arg(long, default_value_t = THREAD_IMAGE_BUFFER_LENGTH, value_parser = clap::value_parser!(u64).range(1..).map(|n| n as usize))]
+    image_buffers: usize,
+    /// Number of SO_REUSEPORT sockets (each with its own listener thread) to
+    /// open per port. When more than one, a BPF program steers packets to a
+    /// socket by frame number, so every packet of a frame lands on the same
+    /// listener.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=256))]
+    sockets_per_port: u32,
+    /// File of per-detector-type geometry overrides. Each line has the form
+    /// `<det_type> <packets_per_frame> <payload_size> <size_x> <size_y> <bit_depth> [layout]`,
+    /// where layout is contiguous (default), reversed or interleaved.
+    #[arg(long)]
+    geometry: Option<PathBuf>,
+    /// Listen on the addresses of every local interface in this subnet
+    #[arg(long, default_value = "192.0.0.0/8")]
+    subnet: Ipv4Network,
+    /// Also listen on interfaces whose link is down
+    #[arg(long)]
+    include_down_interfaces: bool,
+    /// Only accept packets on each port from the interface it is listening
+    /// to (SO_BINDTODEVICE), for when several interfaces share a subnet.
+    /// Linux only; may need CAP_NET_RAW.
+    #[arg(long)]
+    bind_to_device: bool,
+    /// The detector expected to send to us, so that image buffers can be
+    /// sized for it up front. Other detectors are still assembled.
+    #[arg(long, value_enum, default_value_t = SlsDetectorType::Jungfrau)]
+    detector: SlsDetectorType,
+    /// Feed the live viewer with a snapshot of any frame still incomplete
+    /// this many milliseconds after its first packet, rather than waiting
+    /// for it to finish. Storage still receives the fully assembled frame.
+    #[arg(long, requires = "viewer_output")]
+    viewer_latency_ms: Option<u64>,
+    /// Send frames for the live viewer over TCP to this address, in the
+    /// same format as --tcp-output. Snapshots taken before a frame finished
+    /// are marked as incomplete. Frames are dropped if the viewer can't
+    /// keep up.
+    #[arg(long, requires = "viewer_latency_ms")]
+    viewer_output: Option<String>,
+    /// Dynamic range that any Eiger detectors are running with
+    #[arg(
+        long,
+        default_value = "16",
+        value_parser = PossibleValuesParser::new(["4", "8", "16", "32"]).map(|s| s.parse::<usize>().unwrap()),
+    )]
+    eiger_dynamic_range: usize,
+    /// Alignment, in bytes, of the start of every image buffer. Must be a
+    /// power of two; use 4096 for page-aligned buffers.
+    #[arg(long, default_value = "64", value_parser = parse_alignment)]
+    buffer_alignment: usize,
+    /// Work out the packet size and packets per frame of any detector type
+    /// with no known geometry, by watching the packets it sends
+    #[arg(long)]
+    auto_detect_geometry: bool,
+    /// Write completed frames into a ring buffer in this POSIX shared
+    /// memory segment (e.g. /morgul), for other processes on the host
+    #[arg(long)]
+    shm_ring: Option<String>,
+    /// Number of frame slots in the shared memory ring
+    #[arg(long, default_value = "16")]
+    shm_slots: usize,
+    /// Size of each shared memory ring slot, in bytes. Defaults to the
+    /// largest frame of any known detector.
+    #[arg(long)]
+    shm_slot_size: Option<usize>,
+    /// Start each socket with this receive buffer size, in MiB. The kernel
+    /// limits this to net.core.rmem_max.
+    #[arg(long, default_value = "512")]
+    receive_buffer_mib: usize,
+    /// When the kernel drops packets, double the socket receive buffer
+    /// (up to net.core.rmem_max) instead of keeping it a fixed size
+    #[arg(long)]
+    adaptive_receive_buffer: bool,
+    /// Warn about packets whose header is not the version we understand.
+    /// They are still assembled, as if they were.
+    #[arg(long)]
+    check_header_version: bool,
+    /// End an acquisition once no packets have arrived for this long.
+    ///
+    /// Shorter means acquisitions are reported, and their frames flushed,
+    /// sooner after the last packet; but if it is shorter than the frame
+    /// period then every frame looks like a new acquisition. For slow
+    /// acquisitions, either raise this or use --end-timeout-multiplier.
+    #[arg(long, default_value = "500", value_parser = clap::value_parser!(u64).range(1..))]
+    acquisition_timeout_ms: u64,
+    /// End an acquisition once no packets have arrived for this many frame
+    /// periods, as measured from the frames arriving, instead of after a
+    /// fixed --acquisition-timeout-ms
+    #[arg(long, value_parser = parse_positive)]
+    end_timeout_multiplier: Option<f64>,
+    /// Shortest end of acquisition timeout, with --end-timeout-multiplier
+    #[arg(long, default_value = "10")]
+    end_timeout_min_ms: u64,
+    /// Longest end of acquisition timeout, with --end-timeout-multiplier.
+    /// This is also used until the frame period is known.
+    #[arg(long, default_value = "10000")]
+    end_timeout_max_ms: u64,
+    /// Once one listener has ended an acquisition, wait at most this long
+    /// for the rest before ending it without them, so that a dead listener
+    /// or module can't hold up every acquisition after it
+    #[arg(long, default_value = "30", value_parser = parse_positive)]
+    listener_grace_s: f64,
+    /// Only pass on this region of each port's image to sinks, given as
+    /// x,y,width,height in pixels. With --orientation, this is a region of
+    /// the reoriented image.
+    #[arg(long)]
+    roi: Option<RegionOfInterest>,
+    /// Flip and rotate the images from a module before passing them on,
+    /// given as <module_id>:<orientation>, where the orientation is a comma
+    /// separated list of fliph, flipv, rot90, rot180 or rot270 (clockwise,
+    /// after flipping). Can be given once for each module.
+    #[arg(long)]
+    orientation: Vec<ModuleOrientation>,
+    /// Stitch the images from every module into one full-detector image
+    /// before passing them on, with the modules laid out as <columns>x<rows>
+    /// by the row and column in their headers. Can't be used with --roi or
+    /// --orientation.
+    #[arg(long, conflicts_with_all = ["roi", "orientation"])]
+    stitch_layout: Option<ModuleLayout>,
+    /// With --stitch-layout, how long to wait for every module's image of a
+    /// frame before passing it on with the missing modules blanked out
+    #[arg(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    stitch_timeout_ms: u64,
+    /// Time the copy of packet data into images, and report the copy
+    /// bandwidth of each listener and in total at the end of acquisitions,
+    /// and of each port and in total in the --status-interval-s report
+    #[arg(long)]
+    measure_copy_bandwidth: bool,
+    /// Keep lifetime totals of the statistics in this file, so that they
+    /// carry on counting from where they were after a restart
+    #[arg(long)]
+    stats_file: Option<PathBuf>,
+    /// The frame numbers every acquisition should contain, e.g.
+    /// 1-100,200,300-310. Acquisition summaries then list which of these
+    /// were complete, incomplete or missing, and any unexpected frames.
+    #[arg(long, value_parser = parse_frame_ranges)]
+    expected_frames: Option<BTreeSet<u64>>,
+    /// Scheduling policy for the listener threads. The default only raises
+    /// their priority as far as the normal policy allows.
+    #[arg(long, value_enum, default_value_t = SchedulingPolicy::Default)]
+    sched_policy: SchedulingPolicy,
+    /// Real-time priority of the listener threads, from 1 to 99, if
+    /// --sched-policy is fifo or rr
+    #[arg(long, default_value = "50", value_parser = clap::value_parser!(u8).range(1..=99))]
+    sched_priority: u8,
+    /// Write a JSON manifest describing each acquisition into this
+    /// directory, once all of its frames have been written out
+    #[arg(long)]
+    manifest_dir: Option<PathBuf>,
+    /// The largest fraction of lost packets for which the manifest still
+    /// gives the acquisition a good verdict
+    #[arg(long, default_value = "0", value_parser = parse_fraction)]
+    max_loss_fraction: f64,
+    /// Testing only: Discard this fraction of received packets, as if they
+    /// had been lost on the network. Needs MORGUL_ALLOW_DROP_INJECTION=1.
+    #[arg(long, hide = true, value_parser = parse_fraction)]
+    inject_drop_rate: Option<f64>,
+    /// Read up to this many packets per system call with recvmmsg, instead
+    /// of one per recvmsg call, and report how many packets each call got
+    /// at the end of every acquisition
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=1024).map(|n| n as usize))]
+    batch_recv: Option<usize>,
+    /// Guess which packet will arrive next, and receive its payload straight
+    /// into its place in the image, saving a copy whenever the guess is
+    /// right. Each listener reports how many packets that was for at the end
+    /// of every acquisition.
+    #[arg(long, conflicts_with = "batch_recv")]
+    receive_in_place: bool,
+    /// What to do if a data interface goes down, comes back, or changes
+    /// address while running
+    #[arg(long, value_enum, default_value_t = InterfaceChangeAction::Ignore)]
+    on_interface_change: InterfaceChangeAction,
+    /// Check the rest of the options against this machine, report every
+    /// problem found, and exit without receiving anything. Exits nonzero if
+    /// there were any problems. This briefly binds every data port; to
+    /// check a configuration file without doing so, use validate-config.
+    #[arg(long)]
+    check_config: bool,
+    /// Answer readiness queries from the trigger source on this port, so
+    /// that it only triggers once we have finished with the last
+    /// acquisition. The uuid of any trigger seen on the port is recorded in
+    /// the manifest of the acquisition that follows it.
+    #[arg(long)]
+    trigger_port: Option<u16>,
+    /// Accumulate the mean and variance of every pixel over each
+    /// acquisition, e.g. for noise maps from a dark run, and write them as
+    /// numpy files into this directory. There is one pair of maps for every
+    /// module, row and column seen in frame headers.
+    #[arg(long)]
+    pixel_stats_dir: Option<PathBuf>,
+    /// Subtract the pedestal in this directory from every Jungfrau frame
+    /// before passing it on. It holds pedestal_g0.raw, pedestal_g1.raw and
+    /// pedestal_g2.raw, each a frame of 16 bit ADC values for that gain.
+    /// Pixel statistics are still of the uncorrected frames.
+    #[arg(long)]
+    pedestal: Option<PathBuf>,
+    /// Zero the known-bad pixels listed in this file in every frame from
+    /// --detector before passing it on. It is either a text file of x,y
+    /// pixel positions, one per line, or a .raw image of one byte per
+    /// pixel, non-zero where bad, the size of a module.
+    #[arg(long)]
+    mask: Option<PathBuf>,
+    /// Write the raw data of every frame into this directory, as one
+    /// acquisition_<n>.raw file of concatenated frames per acquisition.
+    /// It is created if it doesn't exist.
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// With --output-dir, write Jungfrau frames as their 14 bit ADC values,
+    /// and the 2 bit gain of each pixel as one byte into a separate
+    /// acquisition_<n>_gain.raw
+    #[arg(long, requires = "output_dir")]
+    split_gain: bool,
+    /// Write frames as grayscale TIFF files into this directory, for quick
+    /// visual checks. It is created if it doesn't exist.
+    #[arg(long)]
+    tiff_dir: Option<PathBuf>,
+    /// With --tiff-dir, only write frames whose frame number is a multiple of this
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    tiff_every: u64,
+    /// Publish every completed frame, as slsReceiver does, on a ZeroMQ PUB
+    /// socket at this endpoint, e.g. tcp://*:30001
+    #[cfg(feature = "zmq")]
+    #[arg(long)]
+    zmq_pub: Option<String>,
+    /// Send every completed frame over TCP to a downstream morgul listening
+    /// with --tcp-input at this address
+    #[arg(long)]
+    tcp_output: Option<String>,
+    /// What to do when the --tcp-output connection can't keep up: block,
+    /// which holds up every sink (and eventually the listeners), or drop
+    #[arg(long, default_value = "block")]
+    tcp_backpressure: BackpressurePolicy,
+    /// Accept frames from upstream morguls' --tcp-output on this address,
+    /// and pass them on to our sinks along with our own
+    #[arg(long)]
+    tcp_input: Option<SocketAddr>,
+    /// Share this many image buffers between every listener, instead of a
+    /// fixed pool for each. Listeners that run out take more from the
+    /// shared budget, and give back buffers that they haven't needed at
+    /// the end of each acquisition.
+    #[arg(long)]
+    image_buffer_budget: Option<usize>,
+    /// With --image-buffer-budget, the fewest image buffers any one
+    /// listener keeps
+    #[arg(long, default_value = "2")]
+    min_image_buffers: usize,
+    /// With --image-buffer-budget, the most image buffers any one listener
+    /// can take. Defaults to the whole budget.
+    #[arg(long)]
+    max_image_buffers: Option<usize>,
+    /// Print how many frames are in flight (being assembled, or waiting for
+    /// the sinks), the frame and drop rates, and how busy the listener
+    /// threads are, on every port at this interval. A count that keeps growing means the sinks are
+    /// falling behind. Listeners near 100% CPU are short of CPU, rather
+    /// than waiting on the network.
+    #[arg(long)]
+    status_interval_s: Option<f32>,
+    /// For this long after an acquisition ends, treat packets for its
+    /// frames as stragglers rather than the start of a new acquisition.
+    /// This relies on frame numbers starting again with each acquisition.
+    #[arg(long)]
+    late_packet_grace_ms: Option<u64>,
+    /// What to do with straggling packets, within --late-packet-grace-ms
+    #[arg(long, value_enum, default_value_t = LatePacketAction::Discard)]
+    late_packets: LatePacketAction,
+    /// The step between consecutive frame numbers, for readout modes that
+    /// skip frames, so that only other gaps count as missing frames. Use
+    /// `learn` to work it out from the first frames of each acquisition.
+    #[arg(long, default_value = "1")]
+    frame_stride: FrameStride,
+    /// Export a trace span for every acquisition to this OpenTelemetry
+    /// collector, e.g. http://collector:4318/v1/traces
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Serve per-port counters for Prometheus at http://<address>/metrics
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
     // #[arg(default_value = "36")]
     // listeners: u16,
 }
 
-fn allocate_image_buffer() -> Box<[u8]> {
-    let mut empty_image = Vec::with_capacity(NUM_PIXELS * BIT_DEPTH);
-    empty_image.resize(MODULE_SIZE_X * MODULE_SIZE_Y * BIT_DEPTH, 0u8);
-    empty_image.into_boxed_slice()
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a configuration file without binding any ports or receiving
+    /// anything, report every problem found, and exit nonzero if there
+    /// were any.
+    ///
+    /// Each line of the file is an option as it would be given on the
+    /// command line, without the leading `--` and with any value after an
+    /// `=`, e.g. `udp-port = 30000`, or just `bind-to-device` for a flag.
+    /// Blank lines and anything after a `#` are ignored.
+    ValidateConfig { file: PathBuf },
+}
+
+/// Options that only exist with a cargo feature, and that feature
+const FEATURE_OPTIONS: [(&str, &str); 2] = [("zmq-pub", "zmq"), ("metrics-address", "metrics")];
+
+/// How the kernel should schedule listener threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SchedulingPolicy {
+    /// SCHED_OTHER, at the highest priority it allows
+    Default,
+    /// SCHED_FIFO, at a fixed real-time priority
+    Fifo,
+    /// SCHED_RR, at a fixed real-time priority
+    Rr,
+}
+
+impl SchedulingPolicy {
+    fn realtime_policy(self) -> Option<RealtimeThreadSchedulePolicy> {
+        match self {
+            SchedulingPolicy::Default => None,
+            SchedulingPolicy::Fifo => Some(RealtimeThreadSchedulePolicy::Fifo),
+            SchedulingPolicy::Rr => Some(RealtimeThreadSchedulePolicy::RoundRobin),
+        }
+    }
+}
+
+/// What to do with packets that straggle in just after their acquisition ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LatePacketAction {
+    /// Count them, but otherwise throw them away
+    Discard,
+    /// Assemble them, and pass the frames on to the sinks once the grace
+    /// window closes. They arrive at the sinks after the end of the
+    /// acquisition that they belong to.
+    Deliver,
+}
+
+/// How to react to data interfaces changing while we are running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InterfaceChangeAction {
+    /// Don't watch for changes
+    Ignore,
+    /// Log every change
+    Log,
+    /// Log every change and, if the set of data interface addresses is no
+    /// longer the one we started with, exit with RESTART_EXIT_CODE at the
+    /// next acquisition boundary, so that a supervisor can restart us on
+    /// the new interfaces
+    Restart,
 }
 
+/// Exit code asking a supervisor to restart us (EX_TEMPFAIL)
+const RESTART_EXIT_CODE: i32 = 75;
+/// Exit code for a bad configuration (EX_CONFIG)
+const CONFIG_ERROR_EXIT_CODE: i32 = 78;
+
+fn parse_alignment(value: &str) -> Result<usize, String> {
+    let alignment: usize = value.parse().map_err(|e| format!("{e}"))?;
+    if !alignment.is_power_of_two() {
+        return Err(format!("{alignment} is not a power of two"));
+    }
+    Ok(alignment)
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("{fraction} is not between 0 and 1"));
+    }
+    Ok(fraction)
+}
+
+fn parse_positive(value: &str) -> Result<f64, String> {
+    let number: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    if !(number > 0.0 && number.is_finite()) {
+        return Err(format!("{number} is not a positive number"));
+    }
+    Ok(number)
+}
+
+/// Must be set to allow --inject-drop-rate, so that it can't be left on by accident
+const ALLOW_DROP_INJECTION_VAR: &str = "MORGUL_ALLOW_DROP_INJECTION";
+
+/// The number of the current (or next) acquisition
+///
+/// Listeners read this when an acquisition starts. Only the central thread
+/// increments it, once every listener has reported the end of the
+/// acquisition, so it goes up exactly once per acquisition. Nothing else is
+/// published through it, so relaxed ordering is enough; a listener that
+/// starts the next acquisition before the increment would still see the old
+/// number, but every listener has to end an acquisition first.
 static ACQUISITION_NUMBER: AtomicUsize = AtomicUsize::new(0usize);
 
-#[derive(Debug, Default)]
-struct AcquisitionStats {
-    /// How many images have we seen at least one packet for
-    images_seen: usize,
-    /// How many images received all packet data
-    complete_images: usize,
-    /// How many packets were we expecting but didn't arrive
-    packets_dropped: usize,
-    /// How many packets did we get too late to assemble
-    out_of_order: usize,
-    /// How low did the image buffer queue length get?
-    min_spare_image_buffers: Option<usize>,
+/// Are all listeners between acquisitions?
+static LISTENERS_IDLE: AtomicBool = AtomicBool::new(true);
+/// How many acquisitions every listener has finished
+static ACQUISITIONS_ENDED: AtomicUsize = AtomicUsize::new(0);
+/// How many finished acquisitions the sinks have flushed
+static ACQUISITIONS_FLUSHED: AtomicUsize = AtomicUsize::new(0);
+/// The uuid of the last trigger seen on the trigger port, until an
+/// acquisition starts and claims it
+static PENDING_TRIGGER: Mutex<Option<[u8; 12]>> = Mutex::new(None);
+
+/// Can we take a new acquisition, with everything from the last one done with?
+fn is_ready() -> bool {
+    LISTENERS_IDLE.load(Ordering::Relaxed)
+        && ACQUISITIONS_FLUSHED.load(Ordering::Relaxed)
+            == ACQUISITIONS_ENDED.load(Ordering::Relaxed)
+}
+
+/// Answer readiness queries arriving on the trigger port, and note any triggers
+///
+/// The port is shared (with SO_REUSEPORT), as a simulator on the same host
+/// may be listening on it for the triggers themselves. Triggers are
+/// broadcast, so we see them too, and the next acquisition to start is
+/// taken to be the one that each trigger started.
+fn answer_readiness_queries(port: u16) -> std::io::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    let socket: UdpSocket = socket.into();
+    let mut buffer = [0u8; size_of::<DelugeTrigger>()];
+    loop {
+        let (size, source) = socket.recv_from(&mut buffer)?;
+        if let Ok(trigger) = DelugeTrigger::from_bytes(&buffer[..size]) {
+            *PENDING_TRIGGER.lock().unwrap() = Some(trigger.uuid);
+            continue;
+        }
+        if size != size_of::<ReadinessQuery>() {
+            continue;
+        }
+        let query: ReadinessQuery = bytemuck::pod_read_unaligned(&buffer[..size]);
+        if query.magic != READINESS_QUERY_MAGIC {
+            continue;
+        }
+        let reply = ReadinessReply {
+            magic: READINESS_REPLY_MAGIC,
+            uuid: query.uuid,
+            ready: is_ready() as u32,
+        };
+        socket.send_to(bytemuck::bytes_of(&reply), source)?;
+    }
 }
 
 /// For reporting ongoing progress/statistics to a central thread
 enum AcquisitionLifecycleState {
     /// An acquisition task is starting, along with the acquisition ID
     Starting { acquisition_number: usize },
-    ImageReceived {
-        image_number: usize,
-        dropped_packets: usize,
+    /// An acquisition was ended by a thread, with the stats from that thread
+    Ended {
+        acquisition_number: usize,
+        stats: Box<AcquisitionStats>,
+    },
+    /// The data interfaces are no longer the ones that we started with
+    InterfacesChanged,
+    /// We were asked to stop, by this signal
+    ShutdownRequested { signal: i32 },
+}
+
+/// What the listeners (and the central thread) pass on to the frame sinks
+enum SinkMessage {
+    Frame(CompletedFrame),
+    /// Every listener has ended this acquisition, so every frame of it has
+    /// already been sent
+    AcquisitionEnded {
+        acquisition_number: usize,
+        /// The uuid of the trigger that started it, if we saw one
+        trigger_uuid: Option<[u8; 12]>,
+        stats: Box<AcquisitionStats>,
     },
-    /// An acquisition was ended by a thread
-    Ended(AcquisitionStats),
+    /// Reply once everything sent before this has been handled
+    Sync(Sender<()>),
 }
 
 /// Start a UDP socket, with custom options
 ///
 /// At the moment this is just
 ///   - Turn on RX
-fn start_socket(address: SocketAddr, buffer_size: usize) -> std::io::Result<UdpSocket> {
+///   - Optionally allow other sockets to share the port via SO_REUSEPORT
+///   - Optionally only receive from one network interface, by name
+///
+/// Returns the socket with the receive buffer size that the kernel
+/// actually gave it, which can be less than `buffer_size`.
+fn start_socket(
+    address: SocketAddr,
+    buffer_size: usize,
+    reuse_port: bool,
+    device: Option<&str>,
+) -> std::io::Result<(UdpSocket, usize)> {
     let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    if let Some(device) = device {
+        bind_to_device(&socket, device)?;
+    }
     socket.set_recv_buffer_size(buffer_size)?;
+    // Linux reports double what was set, to allow for its bookkeeping
+    let actual_size = socket.recv_buffer_size()? / 2;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
     socket.bind(&address.into())?;
     setsockopt(&socket, sockopt::RxqOvfl, &1)?;
-    Ok(socket.into())
+    Ok((socket.into(), actual_size))
+}
+
+/// Only receive packets arriving on the named interface (SO_BINDTODEVICE)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(socket: &Socket, device: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_to_device(_socket: &Socket, _device: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Binding to a device is only supported on Linux",
+    ))
+}
+
+/// Build a classic BPF program that selects a reuseport socket by frame number
+///
+/// For SO_ATTACH_REUSEPORT_CBPF on a UDP socket the program sees the
+/// packet starting at the UDP payload, so the (little-endian) frame number
+/// is the first field. BPF loads are big-endian, so we assemble the low 32
+/// bits byte-by-byte before taking the modulus. The return value is the
+/// index of the socket (in bind order) that should receive the packet.
+fn frame_steering_program(num_sockets: u32) -> Vec<libc::sock_filter> {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let ldb = |offset| stmt(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, offset);
+    let lsh8 = stmt(libc::BPF_ALU | libc::BPF_LSH | libc::BPF_K, 8);
+    let tax = stmt(libc::BPF_MISC | libc::BPF_TAX, 0);
+    let or_x = stmt(libc::BPF_ALU | libc::BPF_OR | libc::BPF_X, 0);
+    vec![
+        ldb(3),
+        lsh8,
+        tax,
+        ldb(2),
+        or_x,
+        lsh8,
+        tax,
+        ldb(1),
+        or_x,
+        lsh8,
+        tax,
+        ldb(0),
+        or_x,
+        stmt(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, num_sockets),
+        stmt(libc::BPF_RET | libc::BPF_A, 0),
+    ]
+}
+
+/// Start a group of sockets sharing one port via SO_REUSEPORT
+///
+/// If there is more than one socket, a BPF program is attached to the group
+/// so that the kernel deterministically sends all packets for a given frame
+/// to the same socket (frame_number % count), instead of hashing them.
+fn start_socket_group(
+    address: SocketAddr,
+    buffer_size: usize,
+    count: u32,
+    device: Option<&str>,
+) -> std::io::Result<Vec<(UdpSocket, usize)>> {
+    if count == 1 {
+        return Ok(vec![start_socket(address, buffer_size, false, device)?]);
+    }
+    // The kernel indexes reuseport sockets in the order they were bound
+    let sockets = (0..count)
+        .map(|_| start_socket(address, buffer_size, true, device))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut program = frame_steering_program(count);
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: fprog points at a valid filter array that outlives the call,
+    // and the kernel copies the program during setsockopt.
+    let ret = unsafe {
+        libc::setsockopt(
+            sockets[0].0.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            (&fprog as *const libc::sock_fprog).cast(),
+            size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(sockets)
+}
+
+/// Accounts for packets the kernel dropped because the socket queue overflowed
+///
+/// The `SO_RXQ_OVFL` control message is *not* a per-message delta. It
+/// carries the socket's cumulative drop counter as it was when that packet
+/// was queued, for the whole lifetime of the socket, and is omitted while
+/// the counter is still zero. So:
+///
+/// - New drops are the increase over the highest value seen so far. Packets
+///   that were queued earlier can carry a smaller value, and add nothing.
+/// - Drops after the last packet of an acquisition only show up on the first
+///   packet of the *next* acquisition. To attribute them correctly, the live
+///   counter is read back with `SO_MEMINFO` at each acquisition boundary,
+///   and the acquisition total is the difference between the two readings.
+///
+/// The control message is then only used to notify about drops promptly.
+/// The counter is a `u32` that can wrap, so all differences are wrapping.
+struct OverflowCounter {
+    /// Counter value when the current acquisition started
+    baseline: u32,
+    /// Highest counter value seen so far
+    last_seen: u32,
+}
+
+impl OverflowCounter {
+    fn new(receiver: &impl PacketReceiver) -> Self {
+        let current = receiver.read_drops().unwrap_or(0);
+        OverflowCounter {
+            baseline: current,
+            last_seen: current,
+        }
+    }
+
+    /// Read back the counter, as the baseline for a new acquisition
+    fn start_acquisition(&mut self, receiver: &impl PacketReceiver) {
+        if let Ok(current) = receiver.read_drops() {
+            self.last_seen = current;
+        }
+        self.baseline = self.last_seen;
+    }
+
+    /// Take the counter from a received message, returning how many new drops it reveals
+    fn observe(&mut self, counter: u32) -> u32 {
+        let new_drops = counter.wrapping_sub(self.last_seen);
+        // Anything "negative" is an older reading from an earlier-queued packet
+        if new_drops == 0 || new_drops > u32::MAX / 2 {
+            return 0;
+        }
+        self.last_seen = counter;
+        new_drops
+    }
+
+    /// Read back the counter at the end of an acquisition, returning the acquisition total
+    fn end_acquisition(&mut self, receiver: &impl PacketReceiver) -> usize {
+        if let Ok(current) = receiver.read_drops() {
+            self.observe(current);
+        }
+        self.last_seen.wrapping_sub(self.baseline) as usize
+    }
+}
+
+/// Copies of frames for a live viewer, sent without waiting for completion
+///
+/// Viewer frames are copies (assembly of the original carries on), taken
+/// from a small dedicated pool. If the viewer hasn't finished with the
+/// previous frames then the pool is empty, and the update is skipped rather
+/// than slowing down the listener.
+struct ViewerFeed {
+    /// How long to wait for a frame to complete before sending it anyway
+    latency: Duration,
+    sink: Sender<CompletedFrame>,
+    buffer_return: Sender<AlignedBuffer>,
+    spare_buffers: mpsc::Receiver<AlignedBuffer>,
+    /// The frame number last sent, so that each frame is only sent once
+    last_sent: Option<u64>,
+}
+
+impl ViewerFeed {
+    fn new(latency: Duration, sink: Sender<CompletedFrame>, alignment: usize) -> Self {
+        let (buffer_return, spare_buffers) = mpsc::channel();
+        for _ in 0..VIEWER_BUFFER_LENGTH {
+            buffer_return
+                .send(AlignedBuffer::new(0, alignment))
+                .unwrap();
+        }
+        ViewerFeed {
+            latency,
+            sink,
+            buffer_return,
+            spare_buffers,
+            last_sent: None,
+        }
+    }
+
+    /// Forget what we have sent, ready for a new acquisition
+    fn reset(&mut self) {
+        self.last_sent = None;
+    }
+
+    /// Send a snapshot of the current state of an image, if the viewer is ready for one
+    fn send(
+        &mut self,
+        header: &SlsDetectorHeader,
+        received_mask: u64,
+        complete: bool,
+        data: &[u8],
+    ) {
+        if self.last_sent == Some(header.frame_number) {
+            return;
+        }
+        self.last_sent = Some(header.frame_number);
+        let Ok(mut buffer) = self.spare_buffers.try_recv() else {
+            return;
+        };
+        if buffer.len() != data.len() {
+            buffer = AlignedBuffer::new(data.len(), buffer.alignment());
+        }
+        buffer.copy_from_slice(data);
+        let _ = self.sink.send(CompletedFrame {
+            header: *header,
+            complete,
+            received_mask,
+            data: PooledBuffer::new(buffer, self.buffer_return.clone()),
+        });
+    }
+}
+
+/// How long to wait after the last packet before deciding that an acquisition has ended
+#[derive(Debug, Clone, Copy)]
+struct EndTimeout {
+    /// The time to wait, without a multiplier
+    fixed: Duration,
+    /// If set, wait this many frame periods, otherwise a fixed time
+    multiplier: Option<f64>,
+    min: Duration,
+    max: Duration,
+}
+
+/// Works out the end of acquisition timeout from the cadence of arriving frames
+struct CadenceTimeout {
+    config: EndTimeout,
+    /// Frame number and arrival time of the newest frame so far
+    last_frame: Option<(u64, Instant)>,
+    /// Smoothed time between frames
+    period: Option<Duration>,
+    /// The timeout currently set on the socket
+    current: Duration,
+}
+
+impl CadenceTimeout {
+    fn new(config: EndTimeout) -> Self {
+        CadenceTimeout {
+            config,
+            last_frame: None,
+            period: None,
+            current: config.fixed,
+        }
+    }
+
+    /// Forget the old cadence, returning the timeout to use at the start of an acquisition
+    fn start_acquisition(&mut self) -> Duration {
+        self.last_frame = None;
+        self.period = None;
+        // We can't know the cadence until frames arrive, so be patient
+        self.current = match self.config.multiplier {
+            None => self.config.fixed,
+            Some(_) => self.config.max,
+        };
+        self.current
+    }
+
+    /// Watch the frame number of a packet, returning a new timeout if it should change
+    fn observe(&mut self, frame_number: u64) -> Option<Duration> {
+        let multiplier = self.config.multiplier?;
+        let now = Instant::now();
+        match self.last_frame {
+            Some((last, _)) if frame_number <= last => return None,
+            Some((last, arrived)) => {
+                let period = (now - arrived).div_f64((frame_number - last) as f64);
+                self.period = Some(match self.period {
+                    None => period,
+                    Some(smoothed) => smoothed.mul_f64(0.9) + period.mul_f64(0.1),
+                });
+            }
+            None => {}
+        }
+        self.last_frame = Some((frame_number, now));
+        let timeout = self
+            .period?
+            .mul_f64(multiplier)
+            .clamp(self.config.min, self.config.max);
+        // Only touch the socket if the timeout changed noticeably
+        if timeout.abs_diff(self.current) * 10 < self.current {
+            return None;
+        }
+        self.current = timeout;
+        Some(timeout)
+    }
 }
 
-trait RecvMessageWrapper {
-    fn get_dropped_packets(&self) -> nix::Result<usize>;
+/// Per-listener behaviour that can be configured
+#[derive(Debug, Clone)]
+struct ListenerOptions {
+    /// Should the socket receive buffer grow when the kernel drops packets?
+    adaptive_receive_buffer: bool,
+    /// Should packets with an unknown header version be warned about?
+    check_header_version: bool,
+    end_timeout: EndTimeout,
+    gauges: Arc<ListenerGauges>,
+    /// How long after an acquisition ends to look out for its stragglers
+    late_packet_grace: Option<Duration>,
+    late_packet_action: LatePacketAction,
+    /// Should the packets per receive call be reported, e.g. with --batch-recv?
+    report_receive_calls: bool,
+    /// Should packets be received straight into their predicted place?
+    receive_in_place: bool,
 }
-impl<'a, 's, S> RecvMessageWrapper for RecvMsg<'a, 's, S> {
-    fn get_dropped_packets(&self) -> nix::Result<usize> {
-        for cmsg in self.cmsgs()? {
-            if let ControlMessageOwned::RxqOvfl(count) = cmsg {
-                return Ok(count as usize);
+
+/// Packets arriving just after an acquisition ended that belong to it
+struct Stragglers {
+    acquisition_number: usize,
+    /// Frame numbers start again with a new acquisition, so only packets
+    /// numbered after the first frame of the ended acquisition are from it
+    first_frame: u64,
+    /// When to stop looking out for stragglers
+    until: Instant,
+    packets: usize,
+}
+
+impl Stragglers {
+    /// How much longer to wait for stragglers; never zero, as that isn't a valid socket timeout
+    fn remaining(&self) -> Duration {
+        self.until
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1))
+    }
+}
+
+/// The stride between the frame numbers that one listener sees
+///
+/// With several sockets on a port, frames are shared between them by
+/// frame number, so each only sees some of the detector's frames.
+fn listener_frame_stride(stride: FrameStride, sockets_per_port: u32) -> FrameStride {
+    let FrameStride::Fixed(stride) = stride else {
+        return stride;
+    };
+    // Each listener sees every lcm(stride, sockets)th frame number
+    let sockets = sockets_per_port.max(1) as u64;
+    let (mut a, mut b) = (stride, sockets);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    FrameStride::Fixed(stride / a * sockets)
+}
+
+/// Periodically print the frames in flight on every port, and in total
+///
+/// Ports with more than one listener show the sum across their listeners.
+fn report_status(interval: Duration, gauges: Vec<(u16, Arc<ListenerGauges>)>) -> ! {
+    /// What one port did over the last interval
+    #[derive(Default)]
+    struct PortStatus {
+        in_flight: usize,
+        /// CPU use, as a fraction of one core
+        cpu: f64,
+        frames: usize,
+        packets_dropped: usize,
+        /// Copy bandwidth in GB/s while copying, summed over the port's
+        /// listeners, if copies are being timed
+        copy_bandwidth: Option<f64>,
+    }
+    // The cumulative gauges of each listener at the last report
+    let mut last = vec![(Duration::ZERO, 0, 0, 0, Duration::ZERO); gauges.len()];
+    let mut last_report = Instant::now();
+    loop {
+        thread::sleep(interval);
+        let elapsed = last_report.elapsed().as_secs_f64();
+        last_report = Instant::now();
+        let mut by_port = BTreeMap::<u16, PortStatus>::new();
+        for ((port, gauge), last) in gauges.iter().zip(&mut last) {
+            let now = (
+                gauge.cpu_time().unwrap_or(last.0),
+                gauge.frames.load(Ordering::Relaxed),
+                gauge.packets_dropped.load(Ordering::Relaxed),
+                gauge.bytes_copied.load(Ordering::Relaxed),
+                gauge.copy_time(),
+            );
+            let status = by_port.entry(*port).or_default();
+            status.in_flight += gauge.frames_in_flight.load(Ordering::Relaxed);
+            status.cpu += (now.0 - last.0).as_secs_f64() / elapsed;
+            status.frames += now.1 - last.1;
+            status.packets_dropped += now.2 - last.2;
+            let copy_time = now.4 - last.4;
+            if !copy_time.is_zero() {
+                let bandwidth = (now.3 - last.3) as f64 / copy_time.as_secs_f64() / 1e9;
+                *status.copy_bandwidth.get_or_insert(0.0) += bandwidth;
             }
+            *last = now;
+        }
+        let total: usize = by_port.values().map(|status| status.in_flight).sum();
+        let copy_bandwidth = |bandwidth: Option<f64>| {
+            bandwidth.map_or(String::new(), |b| format!(", {b:.2} GB/s copy"))
+        };
+        let total_bandwidth = by_port
+            .values()
+            .filter_map(|status| status.copy_bandwidth)
+            .reduce(|a, b| a + b);
+        println!(
+            "Frames in flight: {total} total{}; {}",
+            copy_bandwidth(total_bandwidth),
+            by_port
+                .iter()
+                .map(|(port, status)| format!(
+                    "{port}: {} ({:.0} frames/s, {:.0} drops/s, {:.0}% CPU{})",
+                    status.in_flight,
+                    status.frames as f64 / elapsed,
+                    status.packets_dropped as f64 / elapsed,
+                    status.cpu * 100.0,
+                    copy_bandwidth(status.copy_bandwidth)
+                ))
+                .join(", ")
+        );
+    }
+}
+
+/// Live measurements of one listener, for the status report
+#[derive(Debug, Default)]
+struct ListenerGauges {
+    /// Updated with the assembler's frames in flight as each new frame starts
+    frames_in_flight: AtomicUsize,
+    /// Frames started, ever
+    frames: AtomicUsize,
+    /// Packets dropped, ever, as of the last frame started
+    packets_dropped: AtomicUsize,
+    /// Bytes of packet data copied into images, ever, as of the last frame started
+    bytes_copied: AtomicUsize,
+    /// Nanoseconds spent on those copies, if they are being timed
+    copy_nanos: AtomicU64,
+    /// The CPU-time clock of the listener thread, once it has started
+    cpu_clock: OnceLock<libc::clockid_t>,
+    /// Has the listener thread exited, e.g. by panicking?
+    exited: AtomicBool,
+}
+
+impl ListenerGauges {
+    fn store_copied(&self, bytes: usize, time: Duration) {
+        self.bytes_copied.store(bytes, Ordering::Relaxed);
+        self.copy_nanos
+            .store(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Time spent copying packet data into images so far
+    fn copy_time(&self) -> Duration {
+        Duration::from_nanos(self.copy_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Record the calling thread as the listener, until the guard is dropped
+    ///
+    /// The guard marks the thread as exited when it is dropped, which
+    /// happens however the thread ends, including by panicking.
+    fn start_thread(self: &Arc<Self>) -> ListenerThread {
+        let mut clock = 0;
+        // SAFETY: The handle is that of the calling thread, which is alive
+        // for the whole call, and `clock` is a valid place to write to.
+        if unsafe { libc::pthread_getcpuclockid(libc::pthread_self(), &mut clock) } == 0 {
+            let _ = self.cpu_clock.set(clock);
+        }
+        ListenerThread(self.clone())
+    }
+
+    /// Total CPU time used by the listener thread so far, while it is running
+    fn cpu_time(&self) -> Option<Duration> {
+        if self.exited.load(Ordering::Acquire) {
+            return None;
+        }
+        let clock = *self.cpu_clock.get()?;
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `time` is a valid place to write to. The clock is only an
+        // ID, so no pthread handle is used after the thread could have
+        // exited. If it exits between the check above and this call, the
+        // kernel rejects the stale clock, or at worst reads a thread that
+        // has reused its ID; neither is undefined behaviour.
+        if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+            return None;
         }
-        Ok(0)
+        Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+    }
+}
+
+/// Marks a listener's thread as exited when dropped
+struct ListenerThread(Arc<ListenerGauges>);
+
+impl Drop for ListenerThread {
+    fn drop(&mut self) {
+        self.0.exited.store(true, Ordering::Release);
     }
 }
 
 struct Receiver {
-    spare_buffers: Vec<Box<[u8]>>,
+    assembler: FrameAssembler,
+    /// Where completed frames are sent
+    frame_sink: Sender<SinkMessage>,
     state_reporter: Sender<(u16, AcquisitionLifecycleState)>,
+    viewer: Option<ViewerFeed>,
+    options: ListenerOptions,
 }
 
 impl Receiver {
-    fn start(port: u16, state_reporter: Sender<(u16, AcquisitionLifecycleState)>) -> ! {
-        // Build the image data buffers we will use
-        let spare_images: Vec<_> = std::iter::repeat_n((), THREAD_IMAGE_BUFFER_LENGTH)
-            .map(|()| allocate_image_buffer())
-            .collect();
-
+    fn start(
+        port: u16,
+        socket: impl PacketReceiver,
+        state_reporter: Sender<(u16, AcquisitionLifecycleState)>,
+        frame_sink: Sender<SinkMessage>,
+        assembler: FrameAssembler,
+        viewer: Option<ViewerFeed>,
+        options: ListenerOptions,
+    ) -> ! {
         let mut recv = Receiver {
-            spare_buffers: spare_images,
+            assembler,
+            frame_sink,
             state_reporter,
+            viewer,
+            options,
         };
-        recv.listen_port(port);
+        recv.listen_port(port, socket);
     }
 
-    fn deliver_image(&mut self, image: ReceiveImage) {
-        // for now, do nothing and just return the buffer to the pool
-        self.spare_buffers.push(image.data);
-    }
+    fn listen_port(&mut self, port: u16, mut socket: impl PacketReceiver) -> ! {
+        let Receiver {
+            assembler,
+            frame_sink,
+            state_reporter,
+            viewer,
+            options,
+        } = self;
+        let mut end_timeout = CadenceTimeout::new(options.end_timeout);
+        // Packets from the last acquisition that might still arrive
+        let mut stragglers: Option<Stragglers> = None;
+        // Packets dropped in every earlier acquisition, for the status report
+        let mut packets_dropped_before = 0;
+        // Bytes copied into images, and the time spent copying them, likewise
+        let mut copied_before = (0, Duration::ZERO);
+        // Have we already warned that the receive buffer can't grow any more?
+        let mut warned_at_rmem_max = false;
+        // Packets with a header version we don't know, over the whole run
+        let mut unsupported_version_packets = 0usize;
 
-    fn listen_port(&mut self, port: u16) -> ! {
-        let bind_addr: SocketAddr = format!("0.0.0.0:{port}").parse().unwrap();
-        let socket = start_socket(bind_addr, 512 * 1024 * 1024).unwrap();
-        println!("{port}: Listening to 0.0.0.0");
+        // The UDP receive buffer. When receiving into the image, packets that
+        // weren't predicted spill over into the second half, and are moved
+        // back to follow the header.
+        const HEADER_SIZE: usize = size_of::<SlsDetectorHeader>();
+        let max_payload_size = assembler.geometry().max_payload_size();
+        let mut buffer = vec![
+            0u8;
+            HEADER_SIZE
+                + max_payload_size * if options.receive_in_place { 2 } else { 1 }
+        ];
 
-        // The UDP receive buffer
-        let mut buffer = [0u8; size_of::<SlsDetectorHeader>() + 8192];
+        let mut overflow = OverflowCounter::new(&socket);
 
-        let fd = socket.as_raw_fd();
-        let mut iov = [IoSliceMut::new(&mut buffer)];
-        let mut cmsgspace = nix::cmsg_space!(libc::c_uint);
+        // Hand completed images over to the sink. Ownership of the image
+        // buffer passes with them, and if the sink has gone away the frame
+        // is dropped, which returns the buffer straight to the assembler.
+        let deliver = |frame: CompletedFrame, viewer: &mut Option<ViewerFeed>| {
+            if let Some(viewer) = viewer
+                && frame.complete
+            {
+                viewer.send(&frame.header, frame.received_mask, true, &frame.data);
+            }
+            let _ = frame_sink.send(SinkMessage::Frame(frame));
+        };
+        let close_stragglers = |late: Stragglers,
+                                assembler: &mut FrameAssembler,
+                                viewer: &mut Option<ViewerFeed>| {
+            if late.packets == 0 {
+                return;
+            }
+            match options.late_packet_action {
+                LatePacketAction::Discard => println!(
+                    "{port}: Discarded {} packets that arrived after acquisition {} ended",
+                    late.packets, late.acquisition_number
+                ),
+                LatePacketAction::Deliver => {
+                    let stats = assembler.finish_acquisition(|frame| deliver(frame, viewer));
+                    println!(
+                        "{port}: Passed on {} frames ({} complete) from {} packets that arrived after acquisition {} ended",
+                        stats.images_seen,
+                        stats.complete_images,
+                        late.packets,
+                        late.acquisition_number
+                    );
+                }
+            }
+        };
 
         loop {
-            let mut stats = AcquisitionStats::default();
-            let acquisition_number = ACQUISITION_NUMBER.load(Ordering::Relaxed);
+            overflow.start_acquisition(&socket);
+            let receive_counts_at_start = socket.receive_counts();
+            let mut acquisition_number = 0;
             let mut is_first_image = true;
+            let mut first_frame_number = 0;
+            // For working out how busy this thread was during the acquisition
+            let mut cpu_time_at_start = Duration::ZERO;
+            let mut started_at = Instant::now();
+            let mut last_frame_number = None;
+            // Unknown detector types we've already complained about this acquisition
+            let mut reported_det_types = HashSet::new();
+            // Likewise (det_type, payload size) pairs that don't match
+            let mut reported_mismatches = HashSet::new();
+            // And packets that don't even have a header we can read
+            let mut reported_header_errors = HashSet::new();
+            let mut unreadable_packets = 0;
+            if let Some(viewer) = viewer.as_mut() {
+                viewer.reset();
+            }
 
-            // Wait forever for the first image in an acquisition
-            socket.set_read_timeout(None).unwrap();
-
-            // Potentially keep two images around; current and (incomplete)
-            // previous image. If the current image is finished, then the
-            // previous will also get flushed, but if a new image comes in
-            // while the current is incomplete it will be demoted to the
-            // previous image.
-            let mut current_image: Option<ReceiveImage> = None;
-            let mut previous_image: Option<ReceiveImage> = None;
+            // Wait forever for the first image in an acquisition, or until
+            // we stop looking out for stragglers from the last one
+            socket
+                .set_timeout(stragglers.as_ref().map(Stragglers::remaining))
+                .unwrap();
 
             // Many images in one acquisition
             loop {
-                let msg = match recvmsg::<SockaddrStorage>(
-                    fd,
-                    &mut iov,
-                    Some(&mut cmsgspace),
-                    MsgFlags::empty(),
-                ) {
-                    Ok(msg) => msg,
-                    Err(Errno::EAGAIN) => break,
+                // Set if the payload was received straight into its image
+                let mut placed = None;
+                let prediction = options
+                    .receive_in_place
+                    .then(|| assembler.predict_next_packet())
+                    .flatten();
+                let received = match prediction {
+                    None => socket.recv_packet(&mut buffer),
+                    Some((slot, image_slot)) => {
+                        let (head, rest) = buffer.split_at_mut(HEADER_SIZE);
+                        let received = socket.recv_packet_vectored(&mut [
+                            IoSliceMut::new(head),
+                            IoSliceMut::new(image_slot),
+                            IoSliceMut::new(&mut rest[max_payload_size..]),
+                        ]);
+                        if let Ok(Some(msg)) = &received
+                            && msg.len >= HEADER_SIZE
+                        {
+                            let payload_size = msg.len - HEADER_SIZE;
+                            if SlsDetectorHeader::from_packet_any_version(head)
+                                .is_ok_and(|header| slot.matches(header, payload_size))
+                            {
+                                placed = Some(slot);
+                            } else {
+                                // Not the packet we guessed, so put its payload
+                                // back together after the header
+                                let in_slot = payload_size.min(image_slot.len());
+                                rest.copy_within(
+                                    max_payload_size..max_payload_size + payload_size - in_slot,
+                                    in_slot,
+                                );
+                                rest[..in_slot].copy_from_slice(&image_slot[..in_slot]);
+                            }
+                        }
+                        received
+                    }
+                };
+                let msg = match received {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) if is_first_image => {
+                        // No more stragglers, so go back to waiting forever
+                        if let Some(late) = stragglers.take() {
+                            close_stragglers(late, assembler, viewer);
+                        }
+                        socket.set_timeout(None).unwrap();
+                        continue;
+                    }
+                    Ok(None) => break,
                     Err(e) => {
                         panic!("Error: {e}");
                     }
                 };
 
-                // If the kernel reports that we dropped packets, report it
-                if let Ok(dropped) = msg.get_dropped_packets()
-                    && dropped > 0
-                {
-                    // We can't count these in the "packets lost" counter as
-                    // we don't know how to avoid double-counting. But we
-                    // should definitely notify
-                    println!("{port}: Packet queue overflowed! {dropped} packets dropped!");
+                // If the kernel reports that we dropped packets, report it.
+                // These are only counted at the end of the acquisition.
+                if let Some(counter) = msg.drop_counter {
+                    let dropped = overflow.observe(counter);
+                    if dropped > 0 {
+                        println!("{port}: Packet queue overflowed! {dropped} packets dropped!");
+                        if options.adaptive_receive_buffer && !warned_at_rmem_max {
+                            match socket.grow_receive_buffer() {
+                                Ok(Some(size)) => println!(
+                                    "{port}: Grew receive buffer to {} MiB",
+                                    size / 1024 / 1024
+                                ),
+                                Ok(None) => {
+                                    warned_at_rmem_max = true;
+                                    println!(
+                                        "{port}: Warning: Receive buffer is already at the kernel limit and still overflowing. Raise net.core.rmem_max to allow a larger buffer."
+                                    );
+                                }
+                                Err(e) => println!("{port}: Could not grow receive buffer: {e}"),
+                            }
+                        }
+                    }
+                }
+                let header = match SlsDetectorHeader::from_packet_any_version(&buffer[..msg.len]) {
+                    Ok(header) => header,
+                    Err(e) => {
+                        unreadable_packets += 1;
+                        if reported_header_errors.insert(e) {
+                            println!("{port}: Error: {e}; discarding packets like it");
+                        }
+                        continue;
+                    }
+                };
+                if options.check_header_version && !header.is_supported_version() {
+                    unsupported_version_packets += 1;
+                    if unsupported_version_packets.is_power_of_two() {
+                        println!(
+                            "{port}: Warning: {unsupported_version_packets} packet(s) with sls_detector_header version {} (expected {SLS_HEADER_VERSION}), reading them as if they were; latest: {header}",
+                            header.version
+                        );
+                    }
+                }
+
+                // Does this belong to the acquisition that just ended?
+                if is_first_image && let Some(late) = stragglers.as_mut() {
+                    if Instant::now() < late.until && header.frame_number > late.first_frame {
+                        late.packets += 1;
+                        if options.late_packet_action == LatePacketAction::Deliver {
+                            let payload = &buffer[HEADER_SIZE..msg.len];
+                            let emit = |frame| deliver(frame, viewer);
+                            // Problems with these were already reported during the acquisition
+                            let _ = match placed {
+                                Some(slot) => assembler.push_placed_packet(header, slot, emit),
+                                None => assembler.push_packet(header, payload, emit),
+                            };
+                        }
+                        socket.set_timeout(Some(late.remaining())).unwrap();
+                        continue;
+                    }
+                    close_stragglers(stragglers.take().unwrap(), assembler, viewer);
                 }
+
                 // Is this the start of a new acquisition?
                 if is_first_image {
                     is_first_image = false;
+                    cpu_time_at_start = thread_cpu_time();
+                    started_at = Instant::now();
+                    first_frame_number = header.frame_number;
+                    acquisition_number = ACQUISITION_NUMBER.load(Ordering::Relaxed);
                     // Once we have started an acquisition, we want to expire it when the images stop
                     socket
-                        .set_read_timeout(Some(Duration::from_millis(500)))
+                        .set_timeout(Some(end_timeout.start_acquisition()))
                         .unwrap();
                     // Send a state update saying that we started
-                    self.state_reporter
+                    state_reporter
                         .send((
                             port,
                             AcquisitionLifecycleState::Starting { acquisition_number },
@@ -204,140 +1334,1552 @@ impl Receiver {
                         .unwrap();
                 }
 
-                // Unwrap the buffer data
-                let buffer = msg.iovs().next().unwrap();
-
-                let header: &SlsDetectorHeader =
-                    bytemuck::from_bytes(&buffer[..size_of::<SlsDetectorHeader>()]);
-
-                // Basic header validation
-                assert!(
-                    header.packet_number < 64,
-                    "Got too many packets per image; are you running in half-module mode?"
-                );
-                assert!(msg.bytes - size_of::<SlsDetectorHeader>() == 8192);
-                assert!(
-                    header.det_type == SlsDetectorType::Jungfrau as u8,
-                    "Unrecognised det_type in header: {} != Jungfrau ({})",
-                    header.det_type,
-                    SlsDetectorType::Jungfrau as u8
-                );
-                assert!(
-                    header.version == 2,
-                    "Unknown sls_detector_header version: {}",
-                    header.version
-                );
-
-                // If new packet is for a new image, handle any previous, incomplete images
-                if let Some(ref curr) = current_image
-                    && header.frame_number != curr.header.frame_number
-                {
-                    if let Some(old_image) = previous_image.take() {
-                        // Oh dear, this isn't going well, we have two
-                        // incomplete previous images. Let's send off the
-                        // oldest even though it is incomplete and count
-                        // the dropped packets.
-                        stats.packets_dropped += 64 - old_image.received_packets;
-                        self.deliver_image(old_image);
-                    }
-                    previous_image = current_image.take()
+                if let Some(timeout) = end_timeout.observe(header.frame_number) {
+                    socket.set_timeout(Some(timeout)).unwrap();
+                }
+                if last_frame_number != Some(header.frame_number) {
+                    last_frame_number = Some(header.frame_number);
+                    let gauges = &options.gauges;
+                    gauges
+                        .frames_in_flight
+                        .store(assembler.frames_in_flight(), Ordering::Relaxed);
+                    gauges.frames.fetch_add(1, Ordering::Relaxed);
+                    gauges.packets_dropped.store(
+                        packets_dropped_before + assembler.stats().packets_dropped,
+                        Ordering::Relaxed,
+                    );
+                    gauges.store_copied(
+                        copied_before.0 + assembler.stats().bytes_copied,
+                        copied_before.1 + assembler.stats().copy_time,
+                    );
                 }
 
-                // Get the current WIP image or make a new one
-                let mut this_image = current_image.take().unwrap_or_else(|| {
-                    stats.images_seen += 1;
-                    ReceiveImage {
-                        frame_number: header.frame_number,
-                        header: *header,
-                        received_packets: 0,
-                        data: self
-                            .spare_buffers
-                            .pop()
-                            .expect("Ran out of spare packet buffers"),
+                let payload = &buffer[HEADER_SIZE..msg.len];
+                let emit = |frame| deliver(frame, viewer);
+                let pushed = match placed {
+                    Some(slot) => assembler.push_placed_packet(header, slot, emit),
+                    None => assembler.push_packet(header, payload, emit),
+                };
+                match pushed {
+                    Ok(()) => {}
+                    Err(MorgulError::UnknownDetectorType(det_type)) => {
+                        if reported_det_types.insert(det_type) {
+                            println!(
+                                "{port}: Error: No geometry known for det_type {det_type}; discarding its packets. Add it to the --geometry file."
+                            );
+                        }
+                        continue;
                     }
-                });
+                    Err(
+                        e @ MorgulError::PayloadSizeMismatch {
+                            det_type, observed, ..
+                        },
+                    ) => {
+                        if reported_mismatches.insert((det_type, observed)) {
+                            println!(
+                                "{port}: Error: {e} Discarding these packets, starting with: {header}"
+                            );
+                        }
+                        continue;
+                    }
+                    Err(e @ MorgulError::MalformedHeader { .. }) => {
+                        let malformed = assembler.stats().malformed_packets;
+                        if malformed.is_power_of_two() {
+                            println!(
+                                "{port}: Error: {e} Discarded {malformed} such packet(s) this acquisition; latest: {header}"
+                            );
+                        }
+                        continue;
+                    }
+                    Err(MorgulError::BufferPoolExhausted) => {
+                        let discarded = assembler.stats().no_buffer_packets;
+                        if discarded.is_power_of_two() {
+                            println!(
+                                "{port}: Error: Every image buffer is waiting for the sinks; discarded {discarded} packet(s) this acquisition; latest: {header}"
+                            );
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("{port}: {e}"),
+                }
 
-                assert!(header.frame_number == this_image.frame_number);
-
-                // Add a packet to this image
-                this_image.received_packets += 1;
-                // Copy the new data into the image data at the right place
-                this_image.data[(header.packet_number as usize * 8192usize)
-                    ..((header.packet_number as usize + 1) * 8192usize)]
-                    .copy_from_slice(&buffer[size_of::<SlsDetectorHeader>()..]);
-
-                // If we've received an entire image, then send it
-                if this_image.received_packets == 64 {
-                    stats.complete_images += 1;
-                    self.deliver_image(this_image);
-                    debug_assert!(current_image.is_none());
-                } else {
-                    // Push it back
-                    current_image = Some(this_image);
+                // If this is taking too long, let the viewer see what we have so far
+                if let Some(viewer) = viewer.as_mut()
+                    && let Some(image) = assembler.current_frame()
+                    && image.age() >= viewer.latency
+                {
+                    viewer.send(
+                        image.header(),
+                        image.received_mask(),
+                        image.is_complete(),
+                        image.data(),
+                    );
                 }
             } // Acquisition loop
 
+            let mut stats = assembler.finish_acquisition(|frame| deliver(frame, viewer));
+            stats.kernel_dropped = overflow.end_acquisition(&socket);
+            stats.malformed_packets += unreadable_packets;
+            packets_dropped_before += stats.packets_dropped;
+            options
+                .gauges
+                .frames_in_flight
+                .store(assembler.frames_in_flight(), Ordering::Relaxed);
+            options
+                .gauges
+                .packets_dropped
+                .store(packets_dropped_before, Ordering::Relaxed);
+            copied_before.0 += stats.bytes_copied;
+            copied_before.1 += stats.copy_time;
+            options
+                .gauges
+                .store_copied(copied_before.0, copied_before.1);
+            stats.cpu_time = thread_cpu_time() - cpu_time_at_start;
+            stats.wall_time = started_at.elapsed();
             println!(
-                "{port}: End of acquisition, seen {is} images, {ci} complete, {pd} packets dropped, {ooo} out-of-order.",
+                "{port}: End of acquisition, seen {is} images, {ci} complete, {pd} packets dropped, {ooo} out-of-order, {df} duplicate frames ({dp} packets), {kd} dropped by kernel.",
                 is = stats.images_seen,
                 ci = stats.complete_images,
                 pd = stats.packets_dropped,
-                ooo = stats.out_of_order
+                ooo = stats.out_of_order,
+                df = stats.duplicate_frames,
+                dp = stats.duplicate_packets,
+                kd = stats.kernel_dropped,
+            );
+            if let Some(bandwidth) = stats.copy_bandwidth {
+                println!("{port}: Copy bandwidth {bandwidth:.2} GB/s");
+            }
+            println!(
+                "{port}: At most {} frames in flight, {:.0}% CPU",
+                stats.max_frames_in_flight,
+                stats.cpu_utilization().unwrap_or_default() * 100.0
             );
+            if options.receive_in_place {
+                println!(
+                    "{port}: Received {} of {} packets straight into their images",
+                    stats.packets_placed, stats.packets_received
+                );
+            }
+            if options.report_receive_calls
+                && let (Some(start), Some(end)) = (receive_counts_at_start, socket.receive_counts())
+            {
+                let (packets, calls) = (end.packets - start.packets, end.calls - start.calls);
+                println!(
+                    "{port}: Received {packets} packets in {calls} system calls, {:.1} per call",
+                    packets as f64 / calls.max(1) as f64
+                );
+            }
+            if stats.missing_frames > 0 || stats.off_stride_frames > 0 {
+                println!(
+                    "{port}: {} frames missing from the sequence, {} off the stride of {}",
+                    stats.missing_frames,
+                    stats.off_stride_frames,
+                    stats.frame_stride.unwrap_or(1)
+                );
+            }
+            if stats.payload_mismatch_packets > 0 {
+                println!(
+                    "{port}: Discarded {} packets with the wrong payload size for their detector type",
+                    stats.payload_mismatch_packets
+                );
+            }
+            if stats.malformed_packets > 0 {
+                println!(
+                    "{port}: Discarded {} packets with malformed headers",
+                    stats.malformed_packets
+                );
+            }
+            if stats.no_buffer_packets > 0 {
+                println!(
+                    "{port}: Discarded {} packets with no free image buffer, as the sinks fell behind",
+                    stats.no_buffer_packets
+                );
+            }
+            if let Some(drift) = stats.clock_drift_ppm {
+                println!("{port}: Detector clock drift {drift:+.1} ppm");
+            }
+            if stats.image_buffers_grown > 0 || stats.image_buffers_released > 0 {
+                println!(
+                    "{port}: Image buffer pool grew by {}, released {}, now {}",
+                    stats.image_buffers_grown,
+                    stats.image_buffers_released,
+                    assembler.pool_size()
+                );
+            }
+            state_reporter
+                .send((
+                    port,
+                    AcquisitionLifecycleState::Ended {
+                        acquisition_number,
+                        stats: Box::new(stats),
+                    },
+                ))
+                .unwrap();
+            stragglers = options.late_packet_grace.map(|grace| Stragglers {
+                acquisition_number,
+                first_frame: first_frame_number,
+                until: Instant::now() + grace,
+                packets: 0,
+            });
             continue;
         }
     }
 }
-fn main() {
-    let args = Args::parse();
-    println!("Args: {args:?}");
-
-    let interfaces = get_interface_addreses_with_prefix(192);
-    if interfaces.is_empty() {
-        println!("Error: Could not find any 192. interfaces. Have you set up the network?");
-        std::process::exit(1);
+/// Parse a kernel CPU list (e.g. `2-7,10`) into the set of CPU IDs
+///
+/// Non-numeric entries (such as the `domain` or `managed_irq` flags that
+/// can prefix `isolcpus=`) are ignored.
+fn parse_cpu_list(list: &str) -> HashSet<usize> {
+    let mut cpus = HashSet::new();
+    for part in list.trim().split(',') {
+        if let Some((start, end)) = part.split_once('-') {
+            if let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) {
+                cpus.extend(start..=end);
+            }
+        } else if let Ok(cpu) = part.parse::<usize>() {
+            cpus.insert(cpu);
+        }
     }
-    let num_listeners = interfaces.len() * LISTENERS_PER_PORT;
+    cpus
+}
 
-    // Get a list of cores so that we can set affinity to them
-    let mut core_ids = core_affinity::get_core_ids().unwrap().into_iter().rev();
+/// Read the set of CPUs that the kernel has isolated from general scheduling
+///
+/// Prefers the sysfs view, falling back to parsing `isolcpus=` out of the
+/// kernel command line. Returns None if neither source could be read.
+fn read_isolated_cpus() -> Option<HashSet<usize>> {
+    if let Ok(isolated) = std::fs::read_to_string("/sys/devices/system/cpu/isolated") {
+        return Some(parse_cpu_list(&isolated));
+    }
+    let cmdline = std::fs::read_to_string("/proc/cmdline").ok()?;
+    Some(
+        cmdline
+            .split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("isolcpus="))
+            .flat_map(parse_cpu_list)
+            .collect(),
+    )
+}
 
-    let (state_tx, state_rx) = mpsc::channel::<(u16, AcquisitionLifecycleState)>();
+/// Find the NUMA node that the NIC owning an address is attached to
+fn interface_numa_node(address: Ipv4Addr) -> Option<usize> {
+    let interface = pnet::datalink::interfaces()
+        .into_iter()
+        .find(|i| i.ips.iter().any(|ip| ip.ip() == address))?;
+    // This is -1 if the platform doesn't report NUMA locality
+    std::fs::read_to_string(format!(
+        "/sys/class/net/{}/device/numa_node",
+        interface.name
+    ))
+    .ok()?
+    .trim()
+    .parse()
+    .ok()
+}
 
-    let mut threads = Vec::new();
+/// Assign every listener a core on the same NUMA node as the NIC it listens on
+///
+/// Cores are handed out highest-numbered first. If a NIC's NUMA node can't be
+/// determined, or has no free cores left, the listener falls back to any
+/// free core with a warning.
+fn assign_numa_cores(listener_addresses: &[Ipv4Addr]) -> Vec<core_affinity::CoreId> {
+    let mut available: Vec<_> = core_affinity::get_core_ids()
+        .unwrap()
+        .into_iter()
+        .rev()
+        .collect();
+    let mut node_cpus: HashMap<Ipv4Addr, Option<HashSet<usize>>> = HashMap::new();
+    let mut warned = HashSet::new();
 
-    for (port, _address) in multizip((
-        args.udp_port..(args.udp_port + num_listeners as u16),
-        interfaces
-            .iter()
-            .flat_map(|x| iter::repeat_n(*x, LISTENERS_PER_PORT)),
-    )) {
-        let core = core_ids.next().unwrap();
-        let stat = state_tx.clone();
-        threads.push(thread::spawn(move || {
-            if !core_affinity::set_for_current(core) {
-                println!("{port}: Failed to set affinity to core {}", core.id);
-            } else {
-                println!("{port}: Setting affinity to CPU {}", core.id);
-            }
-            if set_current_thread_priority(thread_priority::ThreadPriority::Max).is_err() {
-                println!(
-                    "{port}: Warning: Could not set thread priority. Are you running as root?"
-                );
-            };
+    listener_addresses
+        .iter()
+        .map(|address| {
+            assert!(
+                !available.is_empty(),
+                "Not enough cores to run {} listeners",
+                listener_addresses.len()
+            );
+            let local_cpus = node_cpus.entry(*address).or_insert_with(|| {
+                let node = interface_numa_node(*address)?;
+                std::fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))
+                    .ok()
+                    .map(|list| parse_cpu_list(&list))
+            });
+            let position = local_cpus.as_ref().and_then(|local| {
+                available
+                    .iter()
+                    .position(|core| local.contains(&core.id))
+            });
+            let position = position.unwrap_or_else(|| {
+                if warned.insert(*address) {
+                    println!(
+                        "Warning: Could not find a free NUMA-local core for the interface with {address}, listeners may run on a remote node"
+                    );
+                }
+                0
+            });
+            available.remove(position)
+        })
+        .collect()
+}
 
-            Receiver::start(port, stat);
-        }));
+/// Warn loudly if any of the cores we are about to run listeners on are not isolated
+fn check_core_isolation(cores: &[core_affinity::CoreId]) {
+    let Some(isolated) = read_isolated_cpus() else {
+        println!("Warning: Could not determine isolated CPUs, skipping isolation check");
+        return;
+    };
+    let mut not_isolated: Vec<_> = cores
+        .iter()
+        .map(|core| core.id)
+        .filter(|id| !isolated.contains(id))
+        .collect();
+    if not_isolated.is_empty() {
+        return;
     }
+    not_isolated.sort();
+    println!(
+        "\n\
+         ************************************************************\n\
+         Warning: {} listener core(s) are NOT isolated: {not_isolated:?}\n\
+         Kernel work scheduled on these cores will cause packet drops.\n\
+         Add them to isolcpus= on the kernel command line.\n\
+         ************************************************************\n",
+        not_isolated.len()
+    );
+}
 
+/// Watch the data interfaces, logging any changes
+///
+/// Once the set of addresses differs from `started_with`, tells the central
+/// thread through `state_reporter`.
+fn watch_interfaces(
+    subnet: Ipv4Network,
+    include_down: bool,
+    started_with: Vec<Ipv4Addr>,
+    state_reporter: Sender<(u16, AcquisitionLifecycleState)>,
+) {
+    let mut monitor = match InterfaceChangeMonitor::new() {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            println!("Warning: Can't watch for interface changes: {e}");
+            return;
+        }
+    };
+    let mut links = get_interface_links_in_subnet(subnet);
     loop {
-        state_rx.recv().unwrap();
-        // thread::sleep(Duration::from_secs(20));
-    }
-    // #[allow(clippy::never_loop)]
-    // for thread in threads {
-    //     thread.join().unwrap();
-    // }
+        if let Err(e) = monitor.wait(Duration::from_millis(200)) {
+            println!("Warning: Stopped watching for interface changes: {e}");
+            return;
+        }
+        let new_links = get_interface_links_in_subnet(subnet);
+        for (address, up) in &new_links {
+            match links.iter().find(|(a, _)| a == address) {
+                None => println!("Warning: Interface address {address} appeared"),
+                Some((_, was_up)) if was_up != up => println!(
+                    "Warning: Interface with {address} went {}",
+                    if *up { "up" } else { "down" }
+                ),
+                Some(_) => {}
+            }
+        }
+        for (address, _) in &links {
+            if !new_links.iter().any(|(a, _)| a == address) {
+                println!("Warning: Interface address {address} disappeared");
+            }
+        }
+        links = new_links;
+        let addresses: Vec<_> = links
+            .iter()
+            .filter(|(_, up)| *up || include_down)
+            .map(|(address, _)| *address)
+            .collect();
+        if addresses != started_with {
+            let _ = state_reporter.send((0, AcquisitionLifecycleState::InterfacesChanged));
+        }
+    }
+}
+
+/// Block SIGINT and SIGTERM, so that they can be waited for by
+/// [`report_shutdown_signals`] instead of killing the process
+///
+/// This must happen before any other thread is started, as threads inherit
+/// the signal mask of the thread that started them.
+fn block_shutdown_signals() -> libc::sigset_t {
+    // SAFETY: The set is initialised by sigemptyset before any other use
+    unsafe {
+        let mut signals = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+/// Pass on every blocked shutdown signal to the central thread
+fn report_shutdown_signals(
+    signals: libc::sigset_t,
+    state_reporter: Sender<(u16, AcquisitionLifecycleState)>,
+) {
+    loop {
+        let mut signal = 0;
+        // SAFETY: signals is a valid set, and signal a valid place to write
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            println!("Warning: Stopped waiting for shutdown signals");
+            return;
+        }
+        let state = AcquisitionLifecycleState::ShutdownRequested { signal };
+        if state_reporter.send((0, state)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Can we create (or replace) a file at `path`? Nothing is written to find out.
+fn check_writable(path: &std::path::Path) -> Result<(), String> {
+    let unwritable = |reason: String| format!("Can't write to {}: {reason}", path.display());
+    // Whatever doesn't exist yet would be created in its nearest ancestor that does
+    let existing = path
+        .ancestors()
+        .map(|p| match p.as_os_str().is_empty() {
+            true => std::path::Path::new("."),
+            false => p,
+        })
+        .find(|p| p.exists())
+        .ok_or_else(|| unwritable("No part of the path exists".to_string()))?;
+    if existing != path && !existing.is_dir() {
+        return Err(unwritable(format!(
+            "{} is not a directory",
+            existing.display()
+        )));
+    }
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes())
+        .map_err(|e| unwritable(e.to_string()))?;
+    // SAFETY: c_path is a valid nul-terminated string
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(unwritable(std::io::Error::last_os_error().to_string()));
+    }
+    Ok(())
+}
+
+/// Pass a frame on to every sink that wants it
+fn route_frame(sinks: &mut SinkRouter, frame: &CompletedFrame) {
+    if let Err(e) = sinks.route(frame) {
+        println!(
+            "Error: Failed to write frame {}: {e}",
+            frame.header.frame_number
+        );
+    }
+}
+
+/// Accept connections from upstream morguls, passing their frames on to the sinks
+fn receive_upstream_frames(listener: std::net::TcpListener, frame_sink: Sender<SinkMessage>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error: Failed to accept upstream connection: {e}");
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or("unknown".to_string(), |a| a.to_string());
+        println!("Receiving frames from upstream {peer}");
+        let frame_sink = frame_sink.clone();
+        thread::spawn(move || {
+            let mut reader = FrameReader::new(std::io::BufReader::new(stream));
+            loop {
+                match reader.next_frame() {
+                    Ok(Some(frame)) => {
+                        if frame_sink.send(SinkMessage::Frame(frame)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("Error: Bad frame stream from upstream {peer}: {e}");
+                        break;
+                    }
+                }
+            }
+            println!("Upstream {peer} disconnected");
+        });
+    }
+}
+
+/// Check the options, against this machine, for problems that would stop
+/// the receiver working
+///
+/// This has no lasting side effects, and returns every problem found. With
+/// `bind_ports`, each data port is bound briefly to check that nothing
+/// else has it; otherwise nothing is bound.
+fn check_config(args: &Args, bind_ports: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let geometry = match &args.geometry {
+        Some(path) => GeometryMap::load(path, args.eiger_dynamic_range).unwrap_or_else(|e| {
+            problems.push(e.to_string());
+            GeometryMap::with_defaults(args.eiger_dynamic_range)
+        }),
+        None => GeometryMap::with_defaults(args.eiger_dynamic_range),
+    };
+
+    let interfaces = get_interface_addresses_in_subnet(args.subnet, args.include_down_interfaces)
+        .unwrap_or_else(|e| {
+            problems.push(e.to_string());
+            Vec::new()
+        });
+    let num_ports = interfaces.len() * LISTENERS_PER_PORT;
+    let num_listeners = num_ports * args.sockets_per_port as usize;
+
+    // Every port must exist, and not already be taken by something else
+    if args.udp_port as usize + num_ports > u16::MAX as usize + 1 {
+        problems.push(format!(
+            "{num_ports} ports starting from {} go past port {}",
+            args.udp_port,
+            u16::MAX
+        ));
+    } else if bind_ports {
+        for port in args.udp_port..args.udp_port + num_ports as u16 {
+            if let Err(e) = UdpSocket::bind(("0.0.0.0", port)) {
+                problems.push(format!("Can't bind to UDP port {port}: {e}"));
+            }
+        }
+    }
+    let data_ports = args.udp_port as usize..args.udp_port as usize + num_ports;
+    let mut other_ports = vec![("--trigger-port", args.trigger_port)];
+    other_ports.push(("--tcp-input", args.tcp_input.map(|a| a.port())));
+    #[cfg(feature = "metrics")]
+    other_ports.push(("--metrics-address", args.metrics_address.map(|a| a.port())));
+    for (option, port) in other_ports {
+        if let Some(port) = port
+            && data_ports.contains(&(port as usize))
+        {
+            problems.push(format!(
+                "{option} port {port} is one of the data ports, {} to {}",
+                data_ports.start,
+                data_ports.end - 1
+            ));
+        }
+    }
+
+    let cores = core_affinity::get_core_ids().map_or(0, |ids| ids.len());
+    if cores < num_listeners {
+        problems.push(format!(
+            "{num_listeners} listeners need {num_listeners} cores, but only {cores} are available"
+        ));
+    }
+
+    // Each module sends on its own port, so there must be one for each
+    if let Some(layout) = args.stitch_layout
+        && layout.modules() > num_ports
+    {
+        problems.push(format!(
+            "A {}x{} module layout needs {} ports, but only {num_ports} are listened on",
+            layout.columns,
+            layout.rows,
+            layout.modules()
+        ));
+    }
+
+    if let Some(budget) = args.image_buffer_budget
+        && budget < num_listeners * args.image_buffers
+    {
+        problems.push(format!(
+            "An image buffer budget of {budget} is less than the {} that {num_listeners} listeners start with",
+            num_listeners * args.image_buffers
+        ));
+    }
+    if args.image_buffer_budget.is_some()
+        && args.min_image_buffers > args.max_image_buffers.unwrap_or(usize::MAX)
+    {
+        problems.push(format!(
+            "--min-image-buffers {} is more than --max-image-buffers {}",
+            args.min_image_buffers,
+            args.max_image_buffers.unwrap_or_default()
+        ));
+    }
+    if args.reorder_window >= args.image_buffers {
+        problems.push(format!(
+            "--reorder-window {} would keep all {} image buffers of a listener open",
+            args.reorder_window, args.image_buffers
+        ));
+    }
+    if let Some(size) = args.shm_slot_size
+        && size < geometry.max_frame_size()
+    {
+        problems.push(format!(
+            "Shared memory slots of {size} bytes are smaller than the largest frame, of {} bytes",
+            geometry.max_frame_size()
+        ));
+    }
+    if let Some(path) = &args.stats_file {
+        if let Err(e) = LifetimeStats::load(path) {
+            problems.push(format!(
+                "Can't read statistics from {}: {e}",
+                path.display()
+            ));
+        }
+        if let Err(problem) = check_writable(path) {
+            problems.push(problem);
+        }
+    }
+    if let Some(directory) = &args.manifest_dir
+        && let Err(problem) = check_writable(&directory.join("manifest"))
+    {
+        problems.push(problem);
+    }
+    // Without creating them, check that the output directories could be
+    for directory in [&args.output_dir, &args.tiff_dir].into_iter().flatten() {
+        if let Err(problem) = check_writable(&if directory.exists() {
+            directory.join("frame")
+        } else {
+            directory.clone()
+        }) {
+            problems.push(problem);
+        }
+    }
+
+    if let Some(path) = &args.mask {
+        let loaded = geometry
+            .get(args.detector as u8)
+            .ok_or(MorgulError::UnknownDetectorType(args.detector as u8))
+            .and_then(|g| PixelMask::load(path, g));
+        if let Err(e) = loaded {
+            problems.push(format!("Can't load mask {}: {e}", path.display()));
+        }
+    }
+
+    if let Some(directory) = &args.pedestal {
+        match Pedestal::load(directory) {
+            Err(e) => problems.push(format!(
+                "Can't load pedestal from {}: {e}",
+                directory.display()
+            )),
+            Ok(pedestal) => {
+                if let Err(problem) = check_pedestal_size(&pedestal, directory, &geometry) {
+                    problems.push(problem);
+                }
+            }
+        }
+    }
+
+    if args.sched_policy != SchedulingPolicy::Default
+        && !can_use_realtime_priority(args.sched_priority)
+    {
+        problems.push(format!(
+            "No permission for real-time priority {}",
+            args.sched_priority
+        ));
+    }
+    if args.inject_drop_rate.is_some() {
+        problems.push("--inject-drop-rate is for testing only".to_string());
+    }
+    problems
+}
+
+/// The command line arguments that each line of a configuration file stands for
+///
+/// Returns the line number and arguments of every line that isn't blank.
+fn parse_config_file(text: &str) -> Vec<(usize, Vec<String>)> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                return None;
+            }
+            let arguments = match line.split_once('=') {
+                Some((option, value)) => {
+                    vec![format!("--{}", option.trim()), value.trim().to_string()]
+                }
+                None => vec![format!("--{line}")],
+            };
+            Some((index + 1, arguments))
+        })
+        .collect()
+}
+
+/// Check a configuration file, returning every problem found
+///
+/// Each line is checked on its own first, so that every bad option is
+/// reported rather than only the first, then the options are checked
+/// together and against this machine, without binding any ports.
+/// Check that a pedestal has one value per pixel of a Jungfrau module
+fn check_pedestal_size(
+    pedestal: &Pedestal,
+    directory: &std::path::Path,
+    geometry: &GeometryMap,
+) -> Result<(), String> {
+    match geometry.get(SlsDetectorType::Jungfrau as u8) {
+        Some(g) if g.size_x * g.size_y != pedestal.pixels() => Err(format!(
+            "Pedestal in {} has {} pixels, but Jungfrau modules have {}",
+            directory.display(),
+            pedestal.pixels(),
+            g.size_x * g.size_y
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_config(path: &std::path::Path) -> Vec<String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return vec![format!("Can't read {}: {e}", path.display())],
+    };
+    // Clap's errors without the usage that follows them
+    let describe = |e: clap::Error| {
+        let message = e.to_string();
+        message
+            .lines()
+            .take_while(|l| !l.is_empty() && !l.starts_with("Usage:"))
+            .map(|l| l.trim().trim_start_matches("error: "))
+            .join(" ")
+    };
+    let mut problems = Vec::new();
+    let mut arguments = vec!["morgul-live".to_string()];
+    for (line, line_arguments) in parse_config_file(&text) {
+        let option = line_arguments[0].trim_start_matches("--");
+        if let Some((_, feature)) = FEATURE_OPTIONS.iter().find(|(o, _)| *o == option)
+            && Args::command()
+                .get_arguments()
+                .all(|a| a.get_long() != Some(option))
+        {
+            problems.push(format!(
+                "Line {line}: {option} needs morgul-live built with the {feature} feature"
+            ));
+            continue;
+        }
+        // Options that need others are checked once they are all together
+        match Args::try_parse_from(
+            iter::once("morgul-live".to_string()).chain(line_arguments.clone()),
+        ) {
+            Err(e) if e.kind() != clap::error::ErrorKind::MissingRequiredArgument => {
+                problems.push(format!("Line {line}: {}", describe(e)));
+            }
+            _ => arguments.extend(line_arguments),
+        }
+    }
+    // Even if the options don't go together, check what we can of them
+    let args = Args::try_parse_from(&arguments).or_else(|e| {
+        problems.push(describe(e));
+        Args::command()
+            .ignore_errors(true)
+            .try_get_matches_from(&arguments)
+            .and_then(|matches| Args::from_arg_matches(&matches))
+    });
+    if let Ok(args) = args {
+        problems.extend(check_config(&args, false));
+    }
+    problems
+}
+
+/// Print every problem with the configuration, exiting nonzero if there were any
+fn report_config_problems(problems: &[String]) {
+    for problem in problems {
+        println!("Error: {problem}");
+    }
+    if !problems.is_empty() {
+        println!("Found {} problem(s) with the configuration", problems.len());
+        std::process::exit(CONFIG_ERROR_EXIT_CODE);
+    }
+    println!("Configuration OK");
+}
+
+/// Can this process use real-time scheduling at `priority`?
+///
+/// That needs either CAP_SYS_NICE, or an RLIMIT_RTPRIO at least as high.
+fn can_use_realtime_priority(priority: u8) -> bool {
+    const CAP_SYS_NICE: u32 = 23;
+    let has_cap_sys_nice = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let caps = status.lines().find_map(|l| l.strip_prefix("CapEff:"))?;
+            u64::from_str_radix(caps.trim(), 16).ok()
+        })
+        .is_some_and(|caps| caps & (1 << CAP_SYS_NICE) != 0);
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is a valid rlimit for getrlimit to write to
+    let rtprio_allowed = unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) } == 0
+        && limit.rlim_cur >= priority as libc::rlim_t;
+    has_cap_sys_nice || rtprio_allowed
+}
+
+/// Set the scheduling policy and priority of the calling listener thread
+///
+/// If a real-time policy can't be set, falls back to the default.
+fn set_listener_scheduling(port: u16, policy: SchedulingPolicy, priority: u8) {
+    if let Some(realtime) = policy.realtime_policy() {
+        match set_thread_priority_and_policy(
+            thread_native_id(),
+            ThreadPriority::Crossplatform(priority.try_into().unwrap()),
+            ThreadSchedulePolicy::Realtime(realtime),
+        ) {
+            Ok(()) => {
+                println!("{port}: Scheduling as {realtime:?} at priority {priority}");
+                return;
+            }
+            Err(e) => println!(
+                "{port}: Warning: Could not set {realtime:?} scheduling ({e:?}), using the default policy"
+            ),
+        }
+    }
+    if set_current_thread_priority(ThreadPriority::Max).is_err() {
+        println!("{port}: Warning: Could not set thread priority. Are you running as root?");
+    };
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Some(Command::ValidateConfig { file }) = &args.command {
+        report_config_problems(&validate_config(file));
+        return;
+    }
+    println!("Args: {args:?}");
+    if args.check_config {
+        report_config_problems(&check_config(&args, true));
+        return;
+    }
+    let shutdown_signals = block_shutdown_signals();
+    if args.inject_drop_rate.is_some()
+        && std::env::var(ALLOW_DROP_INJECTION_VAR).as_deref() != Ok("1")
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "--inject-drop-rate is for testing only, and needs {ALLOW_DROP_INJECTION_VAR}=1"
+                ),
+            )
+            .exit();
+    }
+    if let Some(rate) = args.inject_drop_rate {
+        println!(
+            "\n\
+             ************************************************************\n\
+             Warning: Deliberately discarding {:.1}% of received packets!\n\
+             ************************************************************\n",
+            rate * 100.0
+        );
+    }
+
+    let interfaces = get_interface_addresses_in_subnet(args.subnet, args.include_down_interfaces)
+        .unwrap_or_else(|e| {
+            println!("Error: {e}");
+            std::process::exit(e.exit_code());
+        });
+    let geometry = match &args.geometry {
+        Some(path) => GeometryMap::load(path, args.eiger_dynamic_range).unwrap_or_else(|e| {
+            println!("Error: {e}");
+            std::process::exit(e.exit_code());
+        }),
+        None => GeometryMap::with_defaults(args.eiger_dynamic_range),
+    };
+
+    let num_ports = interfaces.len() * LISTENERS_PER_PORT;
+    let num_listeners = num_ports * args.sockets_per_port as usize;
+
+    // The interface address that each listener will be receiving from
+    let listener_addresses: Vec<_> = interfaces
+        .iter()
+        .flat_map(|x| iter::repeat_n(*x, LISTENERS_PER_PORT * args.sockets_per_port as usize))
+        .collect();
+
+    // Get a list of cores so that we can set affinity to them
+    let core_ids: Vec<_> = if args.ignore_numa {
+        core_affinity::get_core_ids()
+            .unwrap()
+            .into_iter()
+            .rev()
+            .take(num_listeners)
+            .collect()
+    } else {
+        assign_numa_cores(&listener_addresses)
+    };
+    assert!(
+        core_ids.len() == num_listeners,
+        "Not enough cores to run {num_listeners} listeners"
+    );
+    check_core_isolation(&core_ids);
+    if args.sched_policy != SchedulingPolicy::Default
+        && !can_use_realtime_priority(args.sched_priority)
+    {
+        println!(
+            "\n\
+             ************************************************************\n\
+             Warning: No permission for real-time priority {}. Run as\n\
+             root, grant CAP_SYS_NICE, or raise RLIMIT_RTPRIO. Listeners\n\
+             will fall back to the default scheduling policy.\n\
+             ************************************************************\n",
+            args.sched_priority
+        );
+    }
+
+    let (state_tx, state_rx) = mpsc::channel::<(u16, AcquisitionLifecycleState)>();
+    let (frame_tx, frame_rx) = mpsc::channel::<SinkMessage>();
+
+    // Consume completed frames, passing them on to any sinks that want
+    // them. Once done with, each frame is dropped, which returns its buffer
+    // to the listener that filled it.
+    let mut sinks = SinkRouter::new();
+    // Outputs that leave no files behind, for the manifest
+    let mut streams = Vec::new();
+    if let Some(name) = &args.shm_ring {
+        let slot_size = args.shm_slot_size.unwrap_or(geometry.max_frame_size());
+        let ring = ShmRingSink::create(name, args.shm_slots, slot_size).unwrap_or_else(|e| {
+            println!("Error: Could not create shared memory ring {name}: {e}");
+            std::process::exit(e.exit_code());
+        });
+        sinks.add(SinkFilter::All, Box::new(ring));
+        streams.push(format!("shm:{name}"));
+    }
+    if let Some(directory) = &args.output_dir {
+        let mut sink = RawFileSink::create(directory).unwrap_or_else(|e| {
+            println!(
+                "Error: Could not create output directory {}: {e}",
+                directory.display()
+            );
+            std::process::exit(MorgulError::from(e).exit_code());
+        });
+        sink.set_split_gain(args.split_gain);
+        sinks.add(SinkFilter::All, Box::new(sink));
+    }
+    if let Some(directory) = &args.tiff_dir {
+        let sink = TiffFrameSink::create(directory, geometry.clone(), args.tiff_every)
+            .unwrap_or_else(|e| {
+                println!(
+                    "Error: Could not create TIFF directory {}: {e}",
+                    directory.display()
+                );
+                std::process::exit(MorgulError::from(e).exit_code());
+            });
+        sinks.add(SinkFilter::All, Box::new(sink));
+    }
+    #[cfg(feature = "zmq")]
+    if let Some(endpoint) = &args.zmq_pub {
+        let sink = morgul::zmq::ZmqPubSink::bind(endpoint, geometry.clone()).unwrap_or_else(|e| {
+            println!("Error: Could not publish on {endpoint}: {e}");
+            std::process::exit(e.exit_code());
+        });
+        sinks.add(SinkFilter::All, Box::new(sink));
+        streams.push(format!("zmq:{endpoint}"));
+    }
+    if let Some(address) = &args.tcp_output {
+        let sink = TcpFrameSink::connect(address, args.tcp_backpressure).unwrap_or_else(|e| {
+            println!("Error: Could not connect to downstream {address}: {e}");
+            std::process::exit(e.exit_code());
+        });
+        sinks.add(SinkFilter::All, Box::new(sink));
+        streams.push(format!("tcp:{address}"));
+    }
+    if let Some(address) = args.tcp_input {
+        let listener = std::net::TcpListener::bind(address).unwrap_or_else(|e| {
+            println!("Error: Could not listen for upstream frames on {address}: {e}");
+            std::process::exit(MorgulError::from(e).exit_code());
+        });
+        let frames = frame_tx.clone();
+        thread::spawn(move || receive_upstream_frames(listener, frames));
+    }
+    let mut roi = args.roi.map(|roi| {
+        let mut extractor = RoiExtractor::new(roi, geometry.clone());
+        extractor.set_orientations(&args.orientation);
+        extractor
+    });
+    // With a region of interest, that is reoriented as it is extracted
+    let mut orienter = (roi.is_none() && !args.orientation.is_empty())
+        .then(|| Orienter::new(&args.orientation, geometry.clone()));
+    let mut stitcher = args.stitch_layout.map(|layout| {
+        DetectorStitcher::new(
+            layout,
+            geometry.clone(),
+            Duration::from_millis(args.stitch_timeout_ms),
+        )
+    });
+    let manifest_dir = args.manifest_dir.clone();
+    let pixel_stats_dir = args.pixel_stats_dir.clone();
+    let mask = args.mask.as_ref().map(|path| {
+        geometry
+            .get(args.detector as u8)
+            .ok_or(MorgulError::UnknownDetectorType(args.detector as u8))
+            .and_then(|g| PixelMask::load(path, g))
+            .unwrap_or_else(|e| {
+                println!("Error: Could not load mask {}: {e}", path.display());
+                std::process::exit(e.exit_code());
+            })
+    });
+    let mask_detector = args.detector as u8;
+    let pedestal = args.pedestal.as_ref().map(|directory| {
+        let pedestal = Pedestal::load(directory).unwrap_or_else(|e| {
+            println!(
+                "Error: Could not load pedestal from {}: {e}",
+                directory.display()
+            );
+            std::process::exit(e.exit_code());
+        });
+        if let Err(problem) = check_pedestal_size(&pedestal, directory, &geometry) {
+            println!("Error: {problem}");
+            std::process::exit(
+                MorgulError::InvalidGeometry {
+                    source: Some(directory.clone()),
+                    reason: problem,
+                }
+                .exit_code(),
+            );
+        }
+        pedestal
+    });
+    let max_loss_fraction = args.max_loss_fraction;
+    let sink_geometry = geometry.clone();
+    thread::spawn(move || {
+        // What we have seen of the current acquisition, for the manifest
+        let mut frame_numbers = HashSet::new();
+        let mut detectors = BTreeMap::new();
+        let mut pixel_stats: HashMap<_, PixelStatsAccumulator> = HashMap::new();
+        for message in frame_rx {
+            let mut frame = match message {
+                SinkMessage::Frame(frame) => frame,
+                SinkMessage::AcquisitionEnded {
+                    acquisition_number,
+                    trigger_uuid,
+                    stats,
+                } => {
+                    if let Some(stitcher) = stitcher.as_mut() {
+                        stitcher.flush(|frame| route_frame(&mut sinks, &frame));
+                    }
+                    let output_error = sinks.end_acquisition(acquisition_number).err();
+                    if let Some(e) = &output_error {
+                        println!("Error: Failed to flush acquisition {acquisition_number}: {e}");
+                    }
+                    let manifest = AcquisitionManifest {
+                        acquisition_number,
+                        trigger_uuid,
+                        outputs: sinks.take_written_files(),
+                        streams: streams.clone(),
+                        frames: frame_numbers.len(),
+                        detectors: std::mem::take(&mut detectors),
+                        stats: *stats,
+                        max_loss_fraction,
+                        output_error: output_error.map(|e| e.to_string()),
+                    };
+                    frame_numbers.clear();
+                    if let Some(directory) = &pixel_stats_dir {
+                        for ((det_type, module_id, row, column), stats) in pixel_stats.drain() {
+                            let prefix = directory.join(format!(
+                                "acquisition_{acquisition_number}_det{det_type}_module{module_id}_row{row}_col{column}"
+                            ));
+                            if let Err(e) = stats.write_npy(&prefix) {
+                                println!(
+                                    "Error: Failed to write pixel statistics {}: {e}",
+                                    prefix.display()
+                                );
+                            }
+                        }
+                    }
+                    ACQUISITIONS_FLUSHED.fetch_add(1, Ordering::Relaxed);
+                    if let Some(directory) = &manifest_dir {
+                        match manifest.write(directory) {
+                            Ok(path) => println!(
+                                "Acquisition {acquisition_number}: Wrote manifest {}",
+                                path.display()
+                            ),
+                            Err(e) => println!(
+                                "Error: Failed to write manifest for acquisition {acquisition_number}: {e}"
+                            ),
+                        }
+                    }
+                    continue;
+                }
+                SinkMessage::Sync(reply) => {
+                    let _ = reply.send(());
+                    continue;
+                }
+            };
+            frame_numbers.insert(frame.header.frame_number);
+            if let Some(geometry) = sink_geometry.get(frame.header.det_type) {
+                detectors.insert(frame.header.det_type, *geometry);
+                if pixel_stats_dir.is_some() {
+                    let header = &frame.header;
+                    let key = (header.det_type, header.module_id, header.row, header.column);
+                    if let Err(e) = pixel_stats
+                        .entry(key)
+                        .or_default()
+                        .add_frame(&frame, geometry.bit_depth)
+                    {
+                        println!(
+                            "Error: Could not add frame {} to pixel statistics: {e}",
+                            header.frame_number
+                        );
+                    }
+                }
+            }
+            if let Some(pedestal) = &pedestal
+                && frame.header.det_type == SlsDetectorType::Jungfrau as u8
+                && let Err(e) = pedestal.correct(&mut frame.data)
+            {
+                println!(
+                    "Error: Could not subtract pedestal from frame {}: {e}",
+                    frame.header.frame_number
+                );
+                continue;
+            }
+            if let Some(mask) = &mask
+                && frame.header.det_type == mask_detector
+                && let Err(e) = mask.apply(&mut frame.data)
+            {
+                println!(
+                    "Error: Could not mask frame {}: {e}",
+                    frame.header.frame_number
+                );
+                continue;
+            }
+            // If we only want a region of interest, then pass that on instead
+            let frame = match roi.as_mut().map(|roi| roi.extract(&frame)) {
+                None => frame,
+                Some(Ok(extracted)) => extracted,
+                Some(Err(e)) => {
+                    println!(
+                        "Error: Could not extract region of interest from frame {}: {e}",
+                        frame.header.frame_number
+                    );
+                    continue;
+                }
+            };
+            let frame = match orienter.as_mut() {
+                None => frame,
+                Some(orienter) => {
+                    let frame_number = frame.header.frame_number;
+                    match orienter.orient(frame) {
+                        Ok(oriented) => oriented,
+                        Err(e) => {
+                            println!("Error: Could not reorient frame {frame_number}: {e}");
+                            continue;
+                        }
+                    }
+                }
+            };
+            match stitcher.as_mut() {
+                None => route_frame(&mut sinks, &frame),
+                Some(stitcher) => {
+                    if let Err(e) =
+                        stitcher.push(&frame, |stitched| route_frame(&mut sinks, &stitched))
+                    {
+                        println!(
+                            "Error: Could not stitch frame {}: {e}",
+                            frame.header.frame_number
+                        );
+                    }
+                }
+            }
+        }
+    });
+
+    // Frames for the viewer skip the other sinks, and are never waited for
+    let viewer_tx = args.viewer_output.as_ref().map(|address| {
+        let sink = TcpFrameSink::connect(address, BackpressurePolicy::Drop).unwrap_or_else(|e| {
+            println!("Error: Could not connect to viewer {address}: {e}");
+            std::process::exit(e.exit_code());
+        });
+        let mut viewer_sinks = SinkRouter::new();
+        viewer_sinks.add(SinkFilter::All, Box::new(sink));
+        let (viewer_tx, viewer_rx) = mpsc::channel::<CompletedFrame>();
+        thread::spawn(move || {
+            for frame in viewer_rx {
+                if let Err(e) = viewer_sinks.route(&frame) {
+                    println!("Error: Lost the viewer, no longer sending it frames: {e}");
+                    return;
+                }
+            }
+        });
+        viewer_tx
+    });
+
+    let mut threads = Vec::new();
+
+    // How each listener is doing, by port
+    let mut listener_gauges = Vec::new();
+
+    let buffer_budget = args
+        .image_buffer_budget
+        .map(|limit| Arc::new(BufferBudget::new(limit)));
+
+    // Open every socket up front, so that each reuseport group is complete
+    // (and bound in a known order) before any listener starts
+    let interface_names: HashMap<_, _> = get_interfaces_in_subnet(args.subnet)
+        .into_iter()
+        .map(|(name, address)| (address, name))
+        .collect();
+    let mut sockets = Vec::with_capacity(num_listeners);
+    let requested_buffer_size = args.receive_buffer_mib * 1024 * 1024;
+    // The smallest receive buffer that the kernel gave any socket
+    let mut smallest_buffer_size = usize::MAX;
+    for port in args.udp_port..(args.udp_port + num_ports as u16) {
+        let bind_addr: SocketAddr = format!("0.0.0.0:{port}").parse().unwrap();
+        let device = args.bind_to_device.then(|| {
+            let address = interfaces[(port - args.udp_port) as usize / LISTENERS_PER_PORT];
+            interface_names[&address].as_str()
+        });
+        for (socket, buffer_size) in start_socket_group(
+            bind_addr,
+            requested_buffer_size,
+            args.sockets_per_port,
+            device,
+        )
+        .unwrap_or_else(|source| {
+            let e = MorgulError::Socket { port, source };
+            println!("Error: {e}");
+            std::process::exit(e.exit_code());
+        }) {
+            smallest_buffer_size = smallest_buffer_size.min(buffer_size);
+            sockets.push((port, socket));
+        }
+    }
+    // The kernel silently clamps the buffer, and a small one is the usual
+    // cause of dropped packets
+    if smallest_buffer_size < requested_buffer_size / 2 {
+        println!(
+            "\n\
+             ************************************************************\n\
+             Warning: Asked for {} MiB socket receive buffers, but the\n\
+             kernel only allowed {} MiB. Expect dropped packets! Raise\n\
+             net.core.rmem_max to at least {requested_buffer_size}.\n\
+             ************************************************************\n",
+            args.receive_buffer_mib,
+            smallest_buffer_size / 1024 / 1024,
+        );
+    }
+
+    for ((port, socket), address, core) in multizip((sockets, listener_addresses, core_ids)) {
+        let interface = interface_names.get(&address).cloned().unwrap_or_default();
+        let stat = state_tx.clone();
+        let frames = frame_tx.clone();
+        let mut assembler = FrameAssembler::with_alignment(
+            geometry.clone(),
+            args.image_buffers,
+            args.buffer_alignment,
+        );
+        if let Err(e) = assembler.set_expected_detector(args.detector) {
+            println!("Error: --detector {}: {e}", args.detector);
+            std::process::exit(e.exit_code());
+        }
+        assembler.set_dedup_window(args.dedup_window);
+        assembler.set_reorder_window(args.reorder_window);
+        assembler.set_measure_copy_time(args.measure_copy_bandwidth);
+        assembler.set_auto_detect_geometry(args.auto_detect_geometry);
+        assembler.set_expected_frames(args.expected_frames.clone());
+        assembler.set_frame_stride(listener_frame_stride(
+            args.frame_stride,
+            args.sockets_per_port,
+        ));
+        if let Some(budget) = &buffer_budget {
+            assembler.set_adaptive_pool(
+                budget.clone(),
+                args.min_image_buffers,
+                args.max_image_buffers.unwrap_or(budget.limit()),
+            );
+        }
+        let gauges = Arc::new(ListenerGauges::default());
+        listener_gauges.push((port, gauges.clone()));
+        let options = ListenerOptions {
+            gauges: gauges.clone(),
+            late_packet_grace: args.late_packet_grace_ms.map(Duration::from_millis),
+            late_packet_action: args.late_packets,
+            report_receive_calls: args.batch_recv.is_some(),
+            receive_in_place: args.receive_in_place,
+            adaptive_receive_buffer: args.adaptive_receive_buffer,
+            check_header_version: args.check_header_version,
+            end_timeout: EndTimeout {
+                fixed: Duration::from_millis(args.acquisition_timeout_ms),
+                multiplier: args.end_timeout_multiplier,
+                min: Duration::from_millis(args.end_timeout_min_ms),
+                max: Duration::from_millis(args.end_timeout_max_ms),
+            },
+        };
+        let inject_drop_rate = args.inject_drop_rate;
+        let batch_recv = args.batch_recv;
+        let packet_size = size_of::<SlsDetectorHeader>() + geometry.max_payload_size();
+        let (sched_policy, sched_priority) = (args.sched_policy, args.sched_priority);
+        let viewer = viewer_tx.as_ref().map(|tx| {
+            ViewerFeed::new(
+                Duration::from_millis(args.viewer_latency_ms.unwrap()),
+                tx.clone(),
+                args.buffer_alignment,
+            )
+        });
+        threads.push(thread::spawn(move || {
+            if !core_affinity::set_for_current(core) {
+                println!("{port}: Failed to set affinity to core {}", core.id);
+            } else {
+                println!("{port}: Setting affinity to CPU {}", core.id);
+            }
+            set_listener_scheduling(port, sched_policy, sched_priority);
+            let _listener_thread = gauges.start_thread();
+
+            println!(
+                "{port}: Listening to {interface} ({})",
+                socket.local_addr().unwrap()
+            );
+            let mut socket = UdpReceiver::new(socket);
+            if let Some(batch_size) = batch_recv {
+                socket.set_batch_size(batch_size, packet_size);
+            }
+            match inject_drop_rate {
+                Some(rate) => Receiver::start(
+                    port,
+                    DropInjector::new(socket, rate),
+                    stat,
+                    frames,
+                    assembler,
+                    viewer,
+                    options,
+                ),
+                None => Receiver::start(port, socket, stat, frames, assembler, viewer, options),
+            }
+        }));
+    }
+
+    // Once every listener has finished an acquisition, report on the whole
+    // thing and move on to the next acquisition number
+    let mut lifetime_stats = match &args.stats_file {
+        Some(path) => LifetimeStats::load(path).unwrap_or_else(|e| {
+            println!(
+                "Error: Could not read statistics from {}: {e}",
+                path.display()
+            );
+            std::process::exit(MorgulError::from(e).exit_code());
+        }),
+        None => LifetimeStats::default(),
+    };
+    let exporter = args.otlp_endpoint.as_ref().map(|endpoint| {
+        OtlpExporter::new(endpoint, "morgul-live").unwrap_or_else(|e| {
+            println!("Error: {e}");
+            std::process::exit(CONFIG_ERROR_EXIT_CODE);
+        })
+    });
+    #[cfg(feature = "metrics")]
+    let metrics = args.metrics_address.map(|address| {
+        let metrics = morgul::metrics::PortMetrics::new();
+        if let Err(e) = metrics.serve(address) {
+            println!("Error: Can't serve metrics on {address}: {e}");
+            std::process::exit(MorgulError::from(e).exit_code());
+        }
+        metrics
+    });
+    // When the first listener started each acquisition in progress, and
+    // the trigger that started it, if known
+    let acquisition_started = RefCell::new(HashMap::<usize, (SystemTime, Option<[u8; 12]>)>::new());
+    // Packets dropped on each port, for each acquisition in progress
+    let port_dropped = RefCell::new(HashMap::<usize, HashMap<u16, usize>>::new());
+    let mut tracker = AcquisitionTracker::new(|acquisition_number, stats| {
+        ACQUISITION_NUMBER.fetch_add(1, Ordering::Relaxed);
+        let (started, trigger_uuid) = acquisition_started
+            .borrow_mut()
+            .remove(&acquisition_number)
+            .unzip();
+        let worst_port = port_dropped
+            .borrow_mut()
+            .remove(&acquisition_number)
+            .and_then(|ports| {
+                ports
+                    .into_iter()
+                    .max_by_key(|&(port, dropped)| (dropped, port))
+            });
+        let trigger_uuid = trigger_uuid.flatten();
+        if let Some(exporter) = &exporter {
+            let mut span = AcquisitionSpan::new(
+                acquisition_number,
+                started.unwrap_or_else(SystemTime::now),
+                stats.clone(),
+            );
+            span.trigger_uuid = trigger_uuid;
+            exporter.export(&span);
+        }
+        // This follows every frame of the acquisition to the sinks
+        ACQUISITIONS_ENDED.fetch_add(1, Ordering::Relaxed);
+        let _ = frame_tx.send(SinkMessage::AcquisitionEnded {
+            acquisition_number,
+            trigger_uuid,
+            stats: Box::new(stats.clone()),
+        });
+        println!(
+            "Acquisition {acquisition_number} ended: seen {is} images, {ci} complete, {pd} packets dropped, {kd} dropped by kernel.",
+            is = stats.images_seen,
+            ci = stats.complete_images,
+            pd = stats.packets_dropped,
+            kd = stats.kernel_dropped,
+        );
+        if let Some((port, dropped)) = worst_port
+            && dropped > 0
+        {
+            println!(
+                "Acquisition {acquisition_number}: Most packets dropped on port {port}, {dropped} ({:.1}% of all dropped)",
+                100.0 * dropped as f64 / stats.packets_dropped as f64
+            );
+        }
+        if let Some(bandwidth) = stats.copy_bandwidth {
+            println!("Acquisition {acquisition_number}: Total copy bandwidth {bandwidth:.2} GB/s");
+        }
+        if let Some(utilization) = stats.cpu_utilization() {
+            println!(
+                "Acquisition {acquisition_number}: Listeners averaged {:.0}% CPU",
+                utilization * 100.0
+            );
+        }
+        if let Some(drift) = stats.clock_drift_ppm {
+            println!(
+                "Acquisition {acquisition_number}: Detector clock drift {drift:+.1} ppm relative to wall time"
+            );
+        }
+        if let Some(report) = &stats.expected_frames {
+            println!(
+                "Acquisition {acquisition_number}: {} of {} expected frames complete",
+                report.complete.len(),
+                report.complete.len() + report.incomplete.len() + report.missing.len()
+            );
+            for (name, frames) in [
+                ("Incomplete", &report.incomplete),
+                ("Missing", &report.missing),
+                ("Unexpected", &report.unexpected),
+            ] {
+                if !frames.is_empty() {
+                    println!(
+                        "Acquisition {acquisition_number}: {name} frames: {}",
+                        format_frame_ranges(frames)
+                    );
+                }
+            }
+        }
+        lifetime_stats.add_acquisition(stats);
+        if let Some(path) = &args.stats_file {
+            // Losing the totals isn't worth stopping the receiver for
+            if let Err(e) = lifetime_stats.save(path) {
+                println!(
+                    "Warning: Could not save statistics to {}: {e}",
+                    path.display()
+                );
+            }
+        }
+        println!(
+            "Lifetime: {a} acquisitions, {is} images seen, {ci} complete, {pd} packets dropped, {kd} dropped by kernel.",
+            a = lifetime_stats.acquisitions,
+            is = lifetime_stats.images_seen,
+            ci = lifetime_stats.complete_images,
+            pd = lifetime_stats.packets_dropped,
+            kd = lifetime_stats.kernel_dropped,
+        );
+    });
+    if args.on_interface_change != InterfaceChangeAction::Ignore {
+        let (subnet, include_down) = (args.subnet, args.include_down_interfaces);
+        let started_with = interfaces.clone();
+        let stat = state_tx.clone();
+        thread::spawn(move || watch_interfaces(subnet, include_down, started_with, stat));
+    }
+    if let Some(interval) = args.status_interval_s {
+        let interval = Duration::from_secs_f32(interval);
+        thread::spawn(move || report_status(interval, listener_gauges));
+    }
+    {
+        let stat = state_tx.clone();
+        thread::spawn(move || report_shutdown_signals(shutdown_signals, stat));
+    }
+    if let Some(port) = args.trigger_port {
+        thread::spawn(move || {
+            if let Err(e) = answer_readiness_queries(port) {
+                println!("Error: Stopped answering readiness queries on port {port}: {e}");
+            }
+        });
+    }
+    let listener_grace = Duration::from_secs_f64(args.listener_grace_s);
+    let mut restart_pending = false;
+    let mut shutdown_pending = false;
+    loop {
+        let message = match state_rx.recv_timeout(STRAGGLER_CHECK_INTERVAL) {
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            message => Some(message.unwrap()),
+        };
+        tracker.expire_stragglers(listener_grace);
+        if let Some(message) = message {
+            match message {
+                (_, AcquisitionLifecycleState::Starting { acquisition_number }) => {
+                    acquisition_started
+                        .borrow_mut()
+                        .entry(acquisition_number)
+                        .or_insert_with(|| {
+                            (SystemTime::now(), PENDING_TRIGGER.lock().unwrap().take())
+                        });
+                    tracker.listener_started(acquisition_number)
+                }
+                (
+                    port,
+                    AcquisitionLifecycleState::Ended {
+                        acquisition_number,
+                        stats,
+                    },
+                ) => {
+                    #[cfg(feature = "metrics")]
+                    if let Some(metrics) = &metrics {
+                        metrics.record(port, &stats);
+                    }
+                    *port_dropped
+                        .borrow_mut()
+                        .entry(acquisition_number)
+                        .or_default()
+                        .entry(port)
+                        .or_default() += stats.packets_dropped;
+                    tracker.listener_ended(acquisition_number, &stats)
+                }
+                (_, AcquisitionLifecycleState::InterfacesChanged) => {
+                    if args.on_interface_change == InterfaceChangeAction::Restart
+                        && !restart_pending
+                    {
+                        restart_pending = true;
+                        println!(
+                            "Data interfaces have changed; restarting once no acquisition is running"
+                        );
+                    }
+                }
+                (_, AcquisitionLifecycleState::ShutdownRequested { signal }) => {
+                    if shutdown_pending {
+                        println!("Stopping immediately, abandoning the acquisition in progress");
+                        std::process::exit(128 + signal);
+                    }
+                    shutdown_pending = true;
+                    if !tracker.is_idle() {
+                        println!(
+                            "Stopping once the acquisition in progress ends; signal again to stop now"
+                        );
+                    }
+                }
+            }
+        }
+        LISTENERS_IDLE.store(tracker.is_idle(), Ordering::Relaxed);
+        if restart_pending && tracker.is_idle() {
+            // Let the sinks finish with everything we've received
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if frame_tx.send(SinkMessage::Sync(reply_tx)).is_ok() {
+                let _ = reply_rx.recv();
+            }
+            println!("Exiting to restart on the new data interfaces");
+            std::process::exit(RESTART_EXIT_CODE);
+        }
+        if shutdown_pending && tracker.is_idle() {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if frame_tx.send(SinkMessage::Sync(reply_tx)).is_ok() {
+                let _ = reply_rx.recv();
+            }
+            println!("Stopped");
+            std::process::exit(0);
+        }
+        // thread::sleep(Duration::from_secs(20));
+    }
+    // #[allow(clippy::never_loop)]
+    // for thread in threads {
+    //     thread.join().unwrap();
+    // }
     // thread::spawn(f)
     // let ip = vec![
     //     "192.168.201.101",
@@ -346,3 +2888,457 @@ fn main() {
     //     "192.168.204.101",
     // ];
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn header(frame_number: u64) -> SlsDetectorHeader {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = frame_number;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = SLS_HEADER_VERSION;
+        header
+    }
+
+    #[test]
+    fn viewer_snapshots_are_marked_partial_and_sent_once() {
+        let (tx, rx) = mpsc::channel();
+        let mut viewer = ViewerFeed::new(Duration::ZERO, tx, 64);
+        viewer.send(&header(1), 0b101, false, &[1, 2, 3, 4]);
+        // Later snapshots of the same frame, and its completion, aren't sent again
+        viewer.send(&header(1), 0b111, false, &[1, 2, 3, 4]);
+        viewer.send(&header(1), 0b1111, true, &[1, 2, 3, 4]);
+        viewer.send(&header(2), 0b1111, true, &[5, 6, 7, 8]);
+
+        let frames: Vec<_> = rx.try_iter().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].header.frame_number, 1);
+        assert!(!frames[0].complete);
+        assert_eq!(frames[0].received_mask, 0b101);
+        assert_eq!(&*frames[0].data, [1, 2, 3, 4]);
+        assert_eq!(frames[1].header.frame_number, 2);
+        assert!(frames[1].complete);
+    }
+
+    #[test]
+    fn viewer_skips_frames_while_its_buffers_are_held() {
+        let (tx, rx) = mpsc::channel();
+        let mut viewer = ViewerFeed::new(Duration::ZERO, tx, 64);
+        for frame_number in 0..VIEWER_BUFFER_LENGTH as u64 + 1 {
+            viewer.send(&header(frame_number), 1, true, &[0; 8]);
+        }
+        let held: Vec<_> = rx.try_iter().collect();
+        assert_eq!(held.len(), VIEWER_BUFFER_LENGTH);
+        // Once the viewer is done with a frame, its buffer can be used again
+        drop(held);
+        viewer.send(&header(10), 1, true, &[0; 8]);
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn viewer_snapshots_reach_the_viewer_output_marked_partial() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut viewer_sinks = SinkRouter::new();
+        viewer_sinks.add(
+            SinkFilter::All,
+            Box::new(TcpFrameSink::connect(address, BackpressurePolicy::Drop).unwrap()),
+        );
+        let (tx, rx) = mpsc::channel();
+        let mut viewer = ViewerFeed::new(Duration::ZERO, tx, 64);
+        viewer.send(&header(7), 0b11, false, &[9; 16]);
+        for frame in rx.try_iter() {
+            viewer_sinks.route(&frame).unwrap();
+        }
+        viewer_sinks.flush().unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let frame = FrameReader::new(stream).next_frame().unwrap().unwrap();
+        assert_eq!(frame.header.frame_number, 7);
+        assert!(!frame.complete);
+        assert_eq!(frame.received_mask, 0b11);
+        assert_eq!(&*frame.data, [9; 16]);
+    }
+
+    #[test]
+    fn viewer_latency_needs_somewhere_to_send_frames() {
+        assert!(Args::try_parse_from(["morgul-live", "--viewer-latency-ms", "5"]).is_err());
+        let args = Args::try_parse_from([
+            "morgul-live",
+            "--viewer-latency-ms",
+            "5",
+            "--viewer-output",
+            "localhost:9000",
+        ])
+        .unwrap();
+        assert_eq!(args.viewer_output.as_deref(), Some("localhost:9000"));
+    }
+
+    #[test]
+    fn triggers_on_the_trigger_port_are_noted_for_the_next_acquisition() {
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        thread::spawn(move || answer_readiness_queries(port));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let trigger = DelugeTrigger::builder().frames(1).build();
+        // Retry until the answering thread is listening
+        let mut reply = [0u8; size_of::<ReadinessReply>()];
+        let query = ReadinessQuery::new();
+        for _ in 0..50 {
+            socket
+                .send_to(bytemuck::bytes_of(&trigger), ("127.0.0.1", port))
+                .unwrap();
+            socket
+                .send_to(bytemuck::bytes_of(&query), ("127.0.0.1", port))
+                .unwrap();
+            if socket.recv(&mut reply).is_ok() {
+                break;
+            }
+        }
+        let reply: ReadinessReply = bytemuck::pod_read_unaligned(&reply);
+        assert_eq!(reply.magic, READINESS_REPLY_MAGIC);
+        assert_eq!(reply.uuid, query.uuid);
+        // The trigger was sent first, so has already been noted
+        assert_eq!(PENDING_TRIGGER.lock().unwrap().take(), Some(trigger.uuid));
+    }
+
+    #[test]
+    fn config_file_lines_become_arguments() {
+        let text = "# comment\n\nudp-port = 30000  # trailing\nbind-to-device\nsubnet=10.0.0.0/8\n";
+        assert_eq!(
+            parse_config_file(text),
+            [
+                (3, vec!["--udp-port".to_string(), "30000".to_string()]),
+                (4, vec!["--bind-to-device".to_string()]),
+                (5, vec!["--subnet".to_string(), "10.0.0.0/8".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_config_reports_every_problem_without_binding() {
+        // Hold one of the data ports, which validation mustn't try to bind
+        let held = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = held.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!("morgul-test-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "udp-port = {port}\nsubnet = 127.0.0.0/8\nreorder-window = zero\nbogus\n\
+                 trigger-port = {}\noutput-dir = /etc/hostname/data\n",
+                port + 1
+            ),
+        )
+        .unwrap();
+        let problems = validate_config(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let has = |text: &str| problems.iter().any(|p| p.contains(text));
+        assert!(has("Line 3: invalid value 'zero' for '--reorder-window"));
+        assert!(has("Line 4: unexpected argument '--bogus'"));
+        assert!(has(&format!(
+            "--trigger-port port {} is one of the data ports",
+            port + 1
+        )));
+        assert!(has("/etc/hostname is not a directory"));
+        assert!(!has("Can't bind"));
+    }
+
+    #[test]
+    fn validate_config_accepts_a_good_file_apart_from_this_machine() {
+        let path =
+            std::env::temp_dir().join(format!("morgul-test-{}-good.conf", std::process::id()));
+        std::fs::write(&path, "subnet = 127.0.0.0/8\nreorder-window = 3\n").unwrap();
+        let problems = validate_config(&path);
+        std::fs::remove_file(&path).unwrap();
+        // Only the cores of the machine running the test can be a problem
+        assert!(problems.iter().all(|p| p.contains("cores")), "{problems:?}");
+    }
+
+    #[test]
+    fn listener_cpu_time_stops_when_the_thread_exits() {
+        let gauges = Arc::new(ListenerGauges::default());
+        assert!(gauges.cpu_time().is_none());
+        let (started, stop) = (mpsc::channel(), mpsc::channel::<()>());
+        let thread = thread::spawn({
+            let gauges = gauges.clone();
+            move || {
+                let _listener_thread = gauges.start_thread();
+                started.0.send(()).unwrap();
+                let _ = stop.1.recv();
+                panic!("Listener failed");
+            }
+        });
+        started.1.recv().unwrap();
+        assert!(gauges.cpu_time().is_some());
+        drop(stop.0);
+        assert!(thread.join().is_err());
+        assert!(gauges.cpu_time().is_none());
+    }
+
+    #[test]
+    fn pedestals_must_match_the_module_size() {
+        let directory =
+            std::env::temp_dir().join(format!("morgul-test-{}-pedestal", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let geometry = GeometryMap::with_defaults(16);
+        let write = |pixels: usize| {
+            for gain in 0..3 {
+                let path = directory.join(format!("pedestal_g{gain}.raw"));
+                std::fs::write(path, vec![0u8; pixels * 2]).unwrap();
+            }
+            Pedestal::load(&directory).unwrap()
+        };
+
+        assert!(check_pedestal_size(&write(1024 * 256), &directory, &geometry).is_ok());
+        let problem = check_pedestal_size(&write(1024 * 512), &directory, &geometry).unwrap_err();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(problem.contains("has 524288 pixels, but Jungfrau modules have 262144"));
+    }
+
+    /// Options for a listener under test, ending acquisitions after 50ms
+    fn listener_options() -> ListenerOptions {
+        ListenerOptions {
+            adaptive_receive_buffer: false,
+            check_header_version: false,
+            end_timeout: EndTimeout {
+                fixed: Duration::from_millis(50),
+                multiplier: None,
+                min: Duration::ZERO,
+                max: Duration::from_secs(1),
+            },
+            gauges: Arc::new(ListenerGauges::default()),
+            late_packet_grace: None,
+            late_packet_action: LatePacketAction::Discard,
+            report_receive_calls: false,
+            receive_in_place: false,
+        }
+    }
+
+    type LifecycleReceiver = mpsc::Receiver<(u16, AcquisitionLifecycleState)>;
+
+    /// Run a listener on a loopback transport, as it would be on a port
+    fn start_listener(
+        options: ListenerOptions,
+    ) -> (
+        morgul::transport::LoopbackSender,
+        LifecycleReceiver,
+        mpsc::Receiver<SinkMessage>,
+    ) {
+        let (sender, receiver) = morgul::transport::loopback();
+        let (state_tx, state_rx) = mpsc::channel();
+        let (frame_tx, frame_rx) = mpsc::channel();
+        let assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 4);
+        thread::spawn(move || {
+            Receiver::start(1, receiver, state_tx, frame_tx, assembler, None, options)
+        });
+        (sender, state_rx, frame_rx)
+    }
+
+    /// The payload of a packet, different for every byte of every packet of a frame
+    fn payload(frame_number: u64, packet_number: u32) -> Vec<u8> {
+        (0..8192u32)
+            .map(|i| (i / 3 + packet_number * 7 + frame_number as u32 * 13) as u8)
+            .collect()
+    }
+
+    /// Send these packets of a Jungfrau frame, with their [`payload`]s
+    fn send_packets(
+        sender: &mut morgul::transport::LoopbackSender,
+        frame_number: u64,
+        packets: impl IntoIterator<Item = u32>,
+    ) {
+        use morgul::transport::PacketSender;
+        for packet_number in packets {
+            let mut header = header(frame_number);
+            header.packet_number = packet_number;
+            let mut packet = bytemuck::bytes_of(&header).to_vec();
+            packet.extend(payload(frame_number, packet_number));
+            sender.send_packet(&packet).unwrap();
+        }
+    }
+
+    /// The acquisition number of the next lifecycle message, if it's a start
+    fn next_start(states: &LifecycleReceiver, wait: Duration) -> Option<usize> {
+        match states.recv_timeout(wait) {
+            Ok((_, AcquisitionLifecycleState::Starting { acquisition_number })) => {
+                Some(acquisition_number)
+            }
+            Ok((_, AcquisitionLifecycleState::Ended { .. })) => panic!("Unexpected end"),
+            Ok(_) | Err(_) => None,
+        }
+    }
+
+    fn wait_for_end(states: &LifecycleReceiver) -> AcquisitionStats {
+        match states.recv_timeout(Duration::from_secs(5)) {
+            Ok((_, AcquisitionLifecycleState::Ended { stats, .. })) => *stats,
+            _ => panic!("Acquisition did not end"),
+        }
+    }
+
+    /// The frame numbers, and whether complete, of the frames sent on so far
+    fn frames_sent(frames: &mpsc::Receiver<SinkMessage>) -> Vec<(u64, bool)> {
+        frames
+            .try_iter()
+            .filter_map(|message| match message {
+                SinkMessage::Frame(frame) => Some((frame.header.frame_number, frame.complete)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stragglers_in_the_grace_window_do_not_start_an_acquisition() {
+        for action in [LatePacketAction::Discard, LatePacketAction::Deliver] {
+            let options = ListenerOptions {
+                late_packet_grace: Some(Duration::from_millis(500)),
+                late_packet_action: action,
+                ..listener_options()
+            };
+            let (mut sender, states, frames) = start_listener(options);
+            for frame_number in 1..=3 {
+                send_packets(&mut sender, frame_number, 0..64);
+            }
+            assert!(next_start(&states, Duration::from_secs(5)).is_some());
+            assert_eq!(wait_for_end(&states).complete_images, 3);
+            assert_eq!(frames_sent(&frames), [(1, true), (2, true), (3, true)]);
+
+            // A frame that straggles in after the end belongs to the old acquisition
+            send_packets(&mut sender, 4, 0..64);
+            assert_eq!(next_start(&states, Duration::from_millis(200)), None);
+            thread::sleep(Duration::from_millis(500));
+            let expected: &[_] = match action {
+                LatePacketAction::Discard => &[],
+                LatePacketAction::Deliver => &[(4, true)],
+            };
+            assert_eq!(frames_sent(&frames), expected, "{action:?}");
+
+            // Once the window has closed, the next packet starts a new acquisition
+            send_packets(&mut sender, 5, 0..64);
+            assert!(next_start(&states, Duration::from_secs(5)).is_some());
+            assert_eq!(wait_for_end(&states).complete_images, 1);
+            assert_eq!(frames_sent(&frames), [(5, true)]);
+        }
+    }
+
+    #[test]
+    fn fixed_end_timeout_ignores_the_cadence() {
+        let mut timeout = CadenceTimeout::new(EndTimeout {
+            multiplier: None,
+            ..listener_options().end_timeout
+        });
+        assert_eq!(timeout.start_acquisition(), Duration::from_millis(50));
+        assert_eq!(timeout.observe(1), None);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(timeout.observe(2), None);
+    }
+
+    #[test]
+    fn end_timeout_follows_the_frame_period() {
+        let config = EndTimeout {
+            fixed: Duration::from_millis(50),
+            multiplier: Some(10.0),
+            min: Duration::from_millis(20),
+            max: Duration::from_secs(2),
+        };
+        let mut timeout = CadenceTimeout::new(config);
+        // Until frames arrive, wait as long as we would ever wait
+        assert_eq!(timeout.start_acquisition(), Duration::from_secs(2));
+        assert_eq!(timeout.observe(1), None);
+        thread::sleep(Duration::from_millis(10));
+        let following = timeout.observe(2).unwrap();
+        assert!(
+            following >= Duration::from_millis(100) && following < Duration::from_secs(2),
+            "{following:?}"
+        );
+        // Frames that aren't newer say nothing about the cadence
+        assert_eq!(timeout.observe(2), None);
+        assert_eq!(timeout.observe(1), None);
+        // A new acquisition starts again from not knowing
+        assert_eq!(timeout.start_acquisition(), Duration::from_secs(2));
+        assert_eq!(timeout.observe(100), None);
+
+        // Very fast frames are held to the minimum
+        let mut timeout = CadenceTimeout::new(EndTimeout {
+            multiplier: Some(0.001),
+            ..config
+        });
+        timeout.start_acquisition();
+        timeout.observe(1);
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(timeout.observe(2), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn a_pause_longer_than_the_end_timeout_splits_the_acquisition() {
+        for (timeout_ms, acquisitions) in [(50, 2), (1000, 1)] {
+            let mut options = listener_options();
+            options.end_timeout.fixed = Duration::from_millis(timeout_ms);
+            let (mut sender, states, frames) = start_listener(options);
+            send_packets(&mut sender, 1, 0..64);
+            thread::sleep(Duration::from_millis(200));
+            send_packets(&mut sender, 2, 0..64);
+
+            let mut images = Vec::new();
+            for _ in 0..acquisitions {
+                assert!(next_start(&states, Duration::from_secs(5)).is_some());
+                images.push(wait_for_end(&states).images_seen);
+            }
+            let expected = if acquisitions == 2 {
+                vec![1, 1]
+            } else {
+                vec![2]
+            };
+            assert_eq!(images, expected, "{timeout_ms} ms");
+            assert_eq!(frames_sent(&frames), [(1, true), (2, true)]);
+        }
+    }
+
+    #[test]
+    fn mispredicted_packets_are_restored_when_receiving_in_place() {
+        let options = ListenerOptions {
+            receive_in_place: true,
+            ..listener_options()
+        };
+        let (mut sender, states, frames) = start_listener(options);
+        // Frame 2 starting early means frame 1's later packets arrive where
+        // frame 2's next one was expected
+        send_packets(&mut sender, 1, 0..32);
+        send_packets(&mut sender, 2, 0..10);
+        send_packets(&mut sender, 1, 32..64);
+        send_packets(&mut sender, 2, 10..64);
+        // Swapped pairs are each received where the other was expected
+        let swapped = (0..32).flat_map(|pair| [pair * 2 + 1, pair * 2]);
+        send_packets(&mut sender, 3, swapped);
+
+        assert!(next_start(&states, Duration::from_secs(5)).is_some());
+        let stats = wait_for_end(&states);
+        assert_eq!(stats.complete_images, 3);
+        assert_eq!(stats.packets_received, 192);
+        assert!(
+            stats.packets_placed > 0 && stats.packets_placed < 192,
+            "{} placed",
+            stats.packets_placed
+        );
+        let mut delivered = 0;
+        for message in frames.try_iter() {
+            let SinkMessage::Frame(frame) = message else {
+                continue;
+            };
+            let frame_number = frame.header.frame_number;
+            let expected: Vec<u8> = (0..64).flat_map(|p| payload(frame_number, p)).collect();
+            assert!(frame.complete);
+            assert!(*frame.data == *expected, "frame {frame_number} scrambled");
+            delivered += 1;
+        }
+        assert_eq!(delivered, 3);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fmt, io, path::PathBuf};
+
+/// Errors that can occur when using morgul as a library
+#[derive(Debug)]
+pub enum MorgulError {
+    /// Failed to open or configure a receiving or sending socket
+    Socket { port: u16, source: io::Error },
+    /// No network interfaces matched what we were looking for
+    NoInterfaces { filter: String },
+    /// A detector geometry was inconsistent or could not be loaded
+    InvalidGeometry {
+        source: Option<PathBuf>,
+        reason: String,
+    },
+    /// A packet header could not be understood
+    MalformedHeader { reason: String },
+    /// A packet arrived from a detector type with no known geometry
+    UnknownDetectorType(u8),
+    /// A packet's payload is not the size that its declared detector type
+    /// sends for that packet
+    PayloadSizeMismatch {
+        det_type: u8,
+        expected: usize,
+        observed: usize,
+    },
+    /// Every image buffer is still held by a consumer of completed frames
+    BufferPoolExhausted,
+    /// Any other I/O failure, e.g. writing output
+    Io(io::Error),
+}
+
+/// Why a packet could not be read as an `sls_detector_header`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HeaderError {
+    /// The packet is shorter than a header
+    TooShort { length: usize },
+    /// The packet is not in memory aligned for the header fields
+    Misaligned,
+    /// The header is not of a version that we understand
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::TooShort { length } => write!(
+                f,
+                "Packet of {length} bytes is too short to hold a {} byte header",
+                size_of::<crate::SlsDetectorHeader>()
+            ),
+            HeaderError::Misaligned => write!(f, "Packet buffer is not aligned for the header"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "Unknown sls_detector_header version: {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// Why a packet could not be read as a [`crate::DelugeTrigger`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TriggerError {
+    /// The packet is not the size of a trigger
+    WrongSize { length: usize },
+    /// The packet does not start with the trigger magic
+    BadMagic,
+    /// The trigger is from a sender using another version of the protocol
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerError::WrongSize { length } => write!(
+                f,
+                "Packet of {length} bytes is not a {} byte trigger",
+                size_of::<crate::DelugeTrigger>()
+            ),
+            TriggerError::BadMagic => write!(f, "Packet is not a trigger"),
+            TriggerError::UnsupportedVersion(version) => write!(
+                f,
+                "Trigger is version {version}, but only version {} is understood",
+                crate::DELUGE_TRIGGER_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TriggerError {}
+
+impl From<HeaderError> for MorgulError {
+    fn from(value: HeaderError) -> Self {
+        MorgulError::MalformedHeader {
+            reason: value.to_string(),
+        }
+    }
+}
+
+impl MorgulError {
+    /// The process exit code a binary should use when failing with this error
+    ///
+    /// These follow the BSD `sysexits.h` conventions.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MorgulError::Socket { .. } => 71,
+            MorgulError::NoInterfaces { .. } => 69,
+            MorgulError::InvalidGeometry { .. } => 78,
+            MorgulError::MalformedHeader { .. } => 65,
+            MorgulError::UnknownDetectorType(_) => 65,
+            MorgulError::PayloadSizeMismatch { .. } => 65,
+            MorgulError::BufferPoolExhausted => 70,
+            MorgulError::Io(_) => 74,
+        }
+    }
+}
+
+impl fmt::Display for MorgulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MorgulError::Socket { port, source } => {
+                write!(f, "Could not set up socket on port {port}: {source}")
+            }
+            MorgulError::NoInterfaces { filter } => write!(
+                f,
+                "Could not find any {filter} interfaces. Have you set up the network?"
+            ),
+            MorgulError::InvalidGeometry {
+                source: Some(path),
+                reason,
+            } => write!(f, "Invalid geometry in {}: {reason}", path.display()),
+            MorgulError::InvalidGeometry {
+                source: None,
+                reason,
+            } => write!(f, "Invalid geometry: {reason}"),
+            MorgulError::MalformedHeader { reason } => write!(f, "Malformed header: {reason}"),
+            MorgulError::UnknownDetectorType(det_type) => {
+                write!(f, "No geometry known for det_type {det_type}")
+            }
+            MorgulError::PayloadSizeMismatch {
+                det_type,
+                expected,
+                observed,
+            } => {
+                let name = crate::SlsDetectorType::try_from(*det_type)
+                    .map_or(format!("det_type {det_type}"), |t| t.to_string());
+                write!(
+                    f,
+                    "Packets declare they are from {name}, which sends {expected} byte payloads, but have {observed} bytes. Is the wrong detector connected, or its firmware misconfigured?"
+                )
+            }
+            MorgulError::BufferPoolExhausted => write!(f, "Ran out of spare image buffers"),
+            MorgulError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MorgulError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MorgulError::Socket { source, .. } => Some(source),
+            MorgulError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MorgulError {
+    fn from(value: io::Error) -> Self {
+        MorgulError::Io(value)
+    }
+}
@@ -0,0 +1,287 @@
+//! C interface to the frame assembler
+//!
+//! This lets C/C++ acquisition software use morgul's packet assembly
+//! without the rest of the receiver. The matching declarations are in
+//! `include/morgul.h`.
+
+use std::{
+    ffi::{c_int, c_void},
+    ptr,
+};
+
+use crate::{
+    CompletedFrame, GeometryMap, MorgulError, SlsDetectorHeader, assembler::FrameAssembler,
+};
+
+pub const MORGUL_OK: c_int = 0;
+pub const MORGUL_ERR_NULL_POINTER: c_int = -1;
+pub const MORGUL_ERR_MALFORMED_HEADER: c_int = -2;
+pub const MORGUL_ERR_UNKNOWN_DETECTOR_TYPE: c_int = -3;
+pub const MORGUL_ERR_BUFFER_POOL_EXHAUSTED: c_int = -4;
+
+/// A frame handed to the C callback
+///
+/// The data pointer is only valid for the duration of the callback.
+#[repr(C)]
+pub struct MorgulFrame {
+    pub header: SlsDetectorHeader,
+    pub complete: bool,
+    /// Bit N is set if packet_number N was received
+    pub received_mask: u64,
+    pub data: *const u8,
+    pub data_len: usize,
+}
+
+pub type MorgulFrameCallback = extern "C" fn(user_data: *mut c_void, frame: *const MorgulFrame);
+
+/// Opaque assembler handle, as seen from C
+pub struct MorgulAssembler {
+    assembler: FrameAssembler,
+    callback: Option<MorgulFrameCallback>,
+    user_data: *mut c_void,
+}
+
+impl MorgulAssembler {
+    /// Build an emit function that passes each frame on to the callback
+    ///
+    /// The frame is dropped (and its buffer returned to the pool) as soon
+    /// as the callback returns.
+    fn emitter(
+        callback: Option<MorgulFrameCallback>,
+        user_data: *mut c_void,
+    ) -> impl FnMut(CompletedFrame) {
+        move |frame: CompletedFrame| {
+            let Some(callback) = callback else {
+                return;
+            };
+            let c_frame = MorgulFrame {
+                header: frame.header,
+                complete: frame.complete,
+                received_mask: frame.received_mask,
+                data: frame.data.as_ptr(),
+                data_len: frame.data.len(),
+            };
+            callback(user_data, &c_frame);
+        }
+    }
+}
+
+fn error_code(error: &MorgulError) -> c_int {
+    match error {
+        MorgulError::UnknownDetectorType(_) => MORGUL_ERR_UNKNOWN_DETECTOR_TYPE,
+        MorgulError::BufferPoolExhausted => MORGUL_ERR_BUFFER_POOL_EXHAUSTED,
+        _ => MORGUL_ERR_MALFORMED_HEADER,
+    }
+}
+
+/// Create an assembler using the built-in detector geometries
+///
+/// Returns NULL if `eiger_dynamic_range` is not one of 4, 8, 16 or 32.
+#[unsafe(no_mangle)]
+pub extern "C" fn morgul_assembler_new(
+    eiger_dynamic_range: usize,
+    pool_size: usize,
+) -> *mut MorgulAssembler {
+    if ![4, 8, 16, 32].contains(&eiger_dynamic_range) {
+        return ptr::null_mut();
+    }
+    let geometry = GeometryMap::with_defaults(eiger_dynamic_range);
+    Box::into_raw(Box::new(MorgulAssembler {
+        assembler: FrameAssembler::new(geometry, pool_size),
+        callback: None,
+        user_data: ptr::null_mut(),
+    }))
+}
+
+/// Destroy an assembler. Any frames still in progress are discarded.
+///
+/// # Safety
+///
+/// `assembler` must be NULL or a pointer returned by `morgul_assembler_new`
+/// that has not already been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn morgul_assembler_free(assembler: *mut MorgulAssembler) {
+    if !assembler.is_null() {
+        // SAFETY: The caller guarantees this came from morgul_assembler_new
+        drop(unsafe { Box::from_raw(assembler) });
+    }
+}
+
+/// Set the function that is called with every frame the assembler emits
+///
+/// # Safety
+///
+/// `assembler` must be a valid assembler. `user_data` is passed through to
+/// the callback untouched, and must remain valid for as long as it is set.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn morgul_assembler_set_callback(
+    assembler: *mut MorgulAssembler,
+    callback: Option<MorgulFrameCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    // SAFETY: The caller guarantees the assembler is valid
+    let Some(assembler) = (unsafe { assembler.as_mut() }) else {
+        return MORGUL_ERR_NULL_POINTER;
+    };
+    assembler.callback = callback;
+    assembler.user_data = user_data;
+    MORGUL_OK
+}
+
+/// Add one received packet to the assembler
+///
+/// The callback is invoked for any frames that this completes, or abandons
+/// as incomplete.
+///
+/// # Safety
+///
+/// `assembler` must be a valid assembler, `header` must point to a full
+/// `sls_detector_header` (it does not need to be aligned), and `data` must
+/// be valid for reads of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn morgul_push_packet(
+    assembler: *mut MorgulAssembler,
+    header: *const SlsDetectorHeader,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    // SAFETY: The caller guarantees the assembler is valid
+    let Some(assembler) = (unsafe { assembler.as_mut() }) else {
+        return MORGUL_ERR_NULL_POINTER;
+    };
+    if header.is_null() || data.is_null() {
+        return MORGUL_ERR_NULL_POINTER;
+    }
+    // SAFETY: The caller guarantees these point to valid memory. Headers
+    // are usually read straight out of a packet buffer, so may be unaligned.
+    let header = unsafe { header.read_unaligned() };
+    let payload = unsafe { std::slice::from_raw_parts(data, len) };
+
+    let emit = MorgulAssembler::emitter(assembler.callback, assembler.user_data);
+    match assembler.assembler.push_packet(&header, payload, emit) {
+        Ok(()) => MORGUL_OK,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// End the current acquisition
+///
+/// Any frames still in progress are passed to the callback as incomplete,
+/// and the assembler is ready to start a new acquisition.
+///
+/// # Safety
+///
+/// `assembler` must be a valid assembler.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn morgul_assembler_flush(assembler: *mut MorgulAssembler) -> c_int {
+    // SAFETY: The caller guarantees the assembler is valid
+    let Some(assembler) = (unsafe { assembler.as_mut() }) else {
+        return MORGUL_ERR_NULL_POINTER;
+    };
+    let emit = MorgulAssembler::emitter(assembler.callback, assembler.user_data);
+    assembler.assembler.finish_acquisition(emit);
+    MORGUL_OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SlsDetectorType;
+    use bytemuck::Zeroable;
+
+    /// (frame number, complete, data length) of every frame emitted
+    type Emitted = Vec<(u64, bool, usize)>;
+
+    extern "C" fn record(user_data: *mut c_void, frame: *const MorgulFrame) {
+        // SAFETY: The tests pass an Emitted as the user data, and the
+        // assembler passes a valid frame
+        let (emitted, frame) = unsafe { (&mut *(user_data as *mut Emitted), &*frame) };
+        emitted.push((frame.header.frame_number, frame.complete, frame.data_len));
+    }
+
+    fn header(frame_number: u64, packet_number: u32) -> SlsDetectorHeader {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = frame_number;
+        header.packet_number = packet_number;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = 2;
+        header
+    }
+
+    /// Create, push, flush and free, in the order include/morgul.h describes
+    #[test]
+    fn assembler_is_usable_through_the_c_interface() {
+        assert!(morgul_assembler_new(12, 4).is_null());
+        let assembler = morgul_assembler_new(16, 4);
+        assert!(!assembler.is_null());
+
+        let mut emitted = Emitted::new();
+        let payload = [0u8; 8192];
+        unsafe {
+            assert_eq!(
+                morgul_assembler_set_callback(ptr::null_mut(), Some(record), ptr::null_mut()),
+                MORGUL_ERR_NULL_POINTER
+            );
+            assert_eq!(
+                morgul_assembler_set_callback(
+                    assembler,
+                    Some(record),
+                    &mut emitted as *mut Emitted as *mut c_void
+                ),
+                MORGUL_OK
+            );
+
+            // One whole frame, then half of the next
+            for packet in 0..64 + 32 {
+                let header = header(1 + packet / 64, packet as u32 % 64);
+                assert_eq!(
+                    morgul_push_packet(assembler, &header, payload.as_ptr(), payload.len()),
+                    MORGUL_OK
+                );
+            }
+
+            let header = header(3, 0);
+            assert_eq!(
+                morgul_push_packet(ptr::null_mut(), &header, payload.as_ptr(), payload.len()),
+                MORGUL_ERR_NULL_POINTER
+            );
+            assert_eq!(
+                morgul_push_packet(assembler, ptr::null(), payload.as_ptr(), payload.len()),
+                MORGUL_ERR_NULL_POINTER
+            );
+            assert_eq!(
+                morgul_push_packet(assembler, &header, ptr::null(), payload.len()),
+                MORGUL_ERR_NULL_POINTER
+            );
+            let mut unknown = header;
+            unknown.det_type = 0xEE;
+            assert_eq!(
+                morgul_push_packet(assembler, &unknown, payload.as_ptr(), payload.len()),
+                MORGUL_ERR_UNKNOWN_DETECTOR_TYPE
+            );
+            // Headers straight out of a packet buffer needn't be aligned
+            let mut packet = vec![0u8; size_of::<SlsDetectorHeader>() + 1];
+            packet[1..].copy_from_slice(bytemuck::bytes_of(&unknown));
+            assert_eq!(
+                morgul_push_packet(
+                    assembler,
+                    packet[1..].as_ptr() as *const SlsDetectorHeader,
+                    payload.as_ptr(),
+                    payload.len()
+                ),
+                MORGUL_ERR_UNKNOWN_DETECTOR_TYPE
+            );
+            assert_eq!(emitted, [(1, true, 64 * 8192)]);
+
+            assert_eq!(
+                morgul_assembler_flush(ptr::null_mut()),
+                MORGUL_ERR_NULL_POINTER
+            );
+            assert_eq!(morgul_assembler_flush(assembler), MORGUL_OK);
+            assert_eq!(emitted, [(1, true, 64 * 8192), (2, false, 64 * 8192)]);
+
+            morgul_assembler_free(assembler);
+            morgul_assembler_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Decoding and correcting Jungfrau pixel values
+
+use std::path::Path;
+
+use crate::MorgulError;
+
+/// How far the gain bits are shifted up within each 16 bit pixel
+pub const GAIN_SHIFT: u32 = 14;
+
+/// The bits of each 16 bit pixel that hold the ADC value
+pub const ADC_MASK: u16 = (1 << GAIN_SHIFT) - 1;
+
+/// Separate the gain bits and ADC values of a Jungfrau frame's pixels
+///
+/// Each little-endian 16 bit pixel has the gain in its top two bits and
+/// the ADC value in the other 14, i.e. the gain is `pixel >> 14` and the
+/// ADC value is `pixel & 0x3FFF`. The gain is 0 for G0, 1 for G1 and 3 for
+/// G2; 2 is never sent. Any trailing odd byte is ignored.
+pub fn split_gain_adc(data: &[u8]) -> (Vec<u8>, Vec<u16>) {
+    data.chunks_exact(2)
+        .map(|pixel| {
+            let pixel = u16::from_le_bytes([pixel[0], pixel[1]]);
+            ((pixel >> GAIN_SHIFT) as u8, pixel & ADC_MASK)
+        })
+        .unzip()
+}
+
+/// The dark level of every pixel of a module in each gain, to subtract from images
+///
+/// Pedestals are loaded from a directory holding `pedestal_g0.raw`,
+/// `pedestal_g1.raw` and `pedestal_g2.raw`, each one frame of little-endian
+/// 16 bit ADC values in the same pixel order as the frames.
+#[derive(Debug, Clone)]
+pub struct Pedestal {
+    /// The pedestal for G0, G1 and G2
+    gains: [Vec<u16>; 3],
+}
+
+impl Pedestal {
+    pub fn load(directory: &Path) -> Result<Self, MorgulError> {
+        let load_gain = |gain: usize| -> Result<Vec<u16>, MorgulError> {
+            let data = std::fs::read(directory.join(format!("pedestal_g{gain}.raw")))?;
+            Ok(data
+                .chunks_exact(2)
+                .map(|v| u16::from_le_bytes([v[0], v[1]]))
+                .collect())
+        };
+        let gains = [load_gain(0)?, load_gain(1)?, load_gain(2)?];
+        if gains.iter().any(|g| g.len() != gains[0].len()) {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Pedestals in {} are not all the same size",
+                    directory.display()
+                ),
+            });
+        }
+        Ok(Pedestal { gains })
+    }
+
+    /// How many pixels each pedestal has
+    pub fn pixels(&self) -> usize {
+        self.gains[0].len()
+    }
+
+    /// Subtract the pedestal for each pixel's gain from its ADC value
+    ///
+    /// `frame` and `gain` are as from [`split_gain_adc`]. Values are clamped
+    /// at zero rather than going negative, and pixels with the invalid gain
+    /// of 2 are left as they are.
+    pub fn apply(&self, frame: &mut [u16], gain: &[u8]) {
+        for (i, (value, gain)) in frame.iter_mut().zip(gain).enumerate() {
+            let pedestal = match gain {
+                0 => &self.gains[0],
+                1 => &self.gains[1],
+                3 => &self.gains[2],
+                _ => continue,
+            };
+            *value = value.saturating_sub(pedestal[i]);
+        }
+    }
+
+    /// Subtract the pedestal from a frame's data in place, keeping the gain bits
+    pub fn correct(&self, data: &mut [u8]) -> Result<(), MorgulError> {
+        if data.len() != self.pixels() * 2 {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Can't subtract a {} pixel pedestal from a {} byte frame",
+                    self.pixels(),
+                    data.len()
+                ),
+            });
+        }
+        let (gain, mut adc) = split_gain_adc(data);
+        self.apply(&mut adc, &gain);
+        for ((pixel, gain), adc) in data.chunks_exact_mut(2).zip(gain).zip(adc) {
+            pixel.copy_from_slice(&(((gain as u16) << GAIN_SHIFT) | adc).to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn gain_and_adc_are_split() {
+        let mut data = pixels(&[0x0123, 0x7FFF, 0xC005, 0x8001, 0x0000]);
+        // A trailing odd byte isn't a pixel
+        data.push(0xFF);
+        let (gain, adc) = split_gain_adc(&data);
+        assert_eq!(gain, [0, 1, 3, 2, 0]);
+        assert_eq!(adc, [0x0123, 0x3FFF, 0x0005, 0x0001, 0x0000]);
+    }
+
+    /// Write pedestal files for G0, G1 and G2 into a fresh directory
+    fn pedestal_dir(name: &str, gains: [&[u16]; 3]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("morgul-test-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (gain, values) in gains.iter().enumerate() {
+            std::fs::write(dir.join(format!("pedestal_g{gain}.raw")), pixels(values)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn pedestal_for_each_pixels_gain_is_subtracted() {
+        let dir = pedestal_dir("pedestal", [&[100, 100, 100, 100], &[200; 4], &[300; 4]]);
+        let pedestal = Pedestal::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(pedestal.pixels(), 4);
+
+        let mut adc = [1000, 1000, 1000, 1000];
+        pedestal.apply(&mut adc, &[0, 1, 3, 2]);
+        // G2 is sent as gain 3, and gain 2 is invalid so left alone
+        assert_eq!(adc, [900, 800, 700, 1000]);
+        // Below the pedestal clamps at zero
+        let mut adc = [50, 250, 299, 0];
+        pedestal.apply(&mut adc, &[0, 1, 3, 3]);
+        assert_eq!(adc, [0, 50, 0, 0]);
+    }
+
+    #[test]
+    fn correcting_a_frame_keeps_its_gain_bits() {
+        let dir = pedestal_dir("correct", [&[10, 10], &[20, 20], &[30, 30]]);
+        let pedestal = Pedestal::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut data = pixels(&[0x4000 | 25, 0xC000 | 5]);
+        pedestal.correct(&mut data).unwrap();
+        assert_eq!(data, pixels(&[0x4000 | 5, 0xC000]));
+        // The frame must be the size of the pedestal
+        assert!(pedestal.correct(&mut pixels(&[1, 2, 3])).is_err());
+    }
+
+    #[test]
+    fn pedestals_must_all_be_the_same_size() {
+        let dir = pedestal_dir("mismatch", [&[1, 2], &[1, 2], &[1]]);
+        assert!(Pedestal::load(&dir).is_err());
+        std::fs::remove_file(dir.join("pedestal_g2.raw")).unwrap();
+        assert!(Pedestal::load(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
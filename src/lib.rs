@@ -1,18 +1,83 @@
-use std::net::Ipv4Addr;
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    ptr::NonNull,
+    str::FromStr,
+    sync::mpsc::Sender,
+    time::Duration,
+};
 
 use bytemuck::{Pod, Zeroable};
-use pnet::datalink;
+use pnet::{datalink, ipnetwork::IpNetwork};
 
+pub mod assembler;
+mod error;
+pub mod ffi;
+pub mod jungfrau;
+pub mod manifest;
+pub mod mask;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod orientation;
+pub mod output;
+pub mod pixel_stats;
+pub mod roi;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod shm;
+pub mod sink;
+pub mod stats;
+pub mod stitch;
+pub mod stream;
+pub mod trace;
+pub mod transport;
+#[cfg(feature = "zmq")]
+pub mod zmq;
+
+pub use error::{HeaderError, MorgulError, TriggerError};
+pub use pnet::ipnetwork::{Ipv4Network, Ipv6Network};
+
+/// How much CPU time the calling thread has used
+pub fn thread_cpu_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // This can't fail for the calling thread's own clock
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// Starts the simulated detector sending an acquisition
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Zeroable, Pod)]
 pub struct DelugeTrigger {
+    /// Always [`DELUGE_TRIGGER_MAGIC`]
+    pub magic: [u8; 8],
+    /// The layout of the rest of the trigger, [`DELUGE_TRIGGER_VERSION`]
+    pub version: u32,
+    pub _reserved: [u8; 4],
     pub frames: u128,
     pub exptime: f32,
     pub uuid: [u8; 12],
 }
+
+pub const DELUGE_TRIGGER_MAGIC: [u8; 8] = *b"MORGULTR";
+/// Changes whenever the trigger's fields do, so that senders and receivers
+/// that disagree can tell
+pub const DELUGE_TRIGGER_VERSION: u32 = 1;
+
 impl Default for DelugeTrigger {
     fn default() -> Self {
         DelugeTrigger {
+            magic: DELUGE_TRIGGER_MAGIC,
+            version: DELUGE_TRIGGER_VERSION,
+            _reserved: [0; 4],
             frames: 0,
             exptime: 0.0,
             uuid: rand::random(),
@@ -20,8 +85,130 @@ impl Default for DelugeTrigger {
     }
 }
 
+impl DelugeTrigger {
+    /// Build a trigger, starting from the default of no frames and a random uuid
+    pub fn builder() -> DelugeTriggerBuilder {
+        DelugeTriggerBuilder {
+            trigger: DelugeTrigger::default(),
+        }
+    }
+
+    /// Read a trigger as sent over the network, checking that it is one we understand
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, TriggerError> {
+        if buf.len() != size_of::<DelugeTrigger>() {
+            return Err(TriggerError::WrongSize { length: buf.len() });
+        }
+        let trigger: DelugeTrigger = bytemuck::pod_read_unaligned(buf);
+        if trigger.magic != DELUGE_TRIGGER_MAGIC {
+            return Err(TriggerError::BadMagic);
+        }
+        if trigger.version != DELUGE_TRIGGER_VERSION {
+            return Err(TriggerError::UnsupportedVersion(trigger.version));
+        }
+        Ok(trigger)
+    }
+
+    /// The uuid as 24 lowercase hex digits, for correlating logs
+    pub fn uuid_hex(&self) -> String {
+        self.uuid.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// How many frames are sent per second
+    ///
+    /// An `exptime` of zero means sending as fast as possible, which is
+    /// `f32::INFINITY`.
+    pub fn frequency_hz(&self) -> f32 {
+        1.0 / self.exptime
+    }
+
+    /// How long sending every frame should take
+    ///
+    /// This is zero if `exptime` is zero (or not a sensible time at all),
+    /// and saturates at `Duration::MAX`.
+    pub fn total_duration(&self) -> Duration {
+        let seconds = self.frames as f64 * self.exptime as f64;
+        if seconds.is_nan() || seconds <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Builds a [`DelugeTrigger`], overriding only the fields that matter
+#[derive(Debug, Clone)]
+pub struct DelugeTriggerBuilder {
+    trigger: DelugeTrigger,
+}
+
+impl DelugeTriggerBuilder {
+    pub fn frames(mut self, frames: u128) -> Self {
+        self.trigger.frames = frames;
+        self
+    }
+
+    /// Time between frames, in seconds
+    pub fn exptime(mut self, exptime: f32) -> Self {
+        self.trigger.exptime = exptime;
+        self
+    }
+
+    pub fn uuid(mut self, uuid: [u8; 12]) -> Self {
+        self.trigger.uuid = uuid;
+        self
+    }
+
+    pub fn build(self) -> DelugeTrigger {
+        self.trigger
+    }
+}
+
+/// Asks every receiver listening on the trigger port whether it is ready
+/// for a new acquisition. Each answers with a [`ReadinessReply`].
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Zeroable, Pod)]
+pub struct ReadinessQuery {
+    /// Always [`READINESS_QUERY_MAGIC`]
+    pub magic: [u8; 8],
+    /// Identifies the query, and is echoed back in the replies
+    pub uuid: [u8; 12],
+    pub _reserved: [u8; 4],
+}
+
+pub const READINESS_QUERY_MAGIC: [u8; 8] = *b"MORGULRQ";
+
+impl ReadinessQuery {
+    pub fn new() -> Self {
+        ReadinessQuery {
+            magic: READINESS_QUERY_MAGIC,
+            uuid: rand::random(),
+            _reserved: [0; 4],
+        }
+    }
+}
+
+impl Default for ReadinessQuery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A receiver's answer to a [`ReadinessQuery`], sent back to whoever asked
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Zeroable, Pod)]
+pub struct ReadinessReply {
+    /// Always [`READINESS_REPLY_MAGIC`]
+    pub magic: [u8; 8],
+    /// The uuid of the query being answered
+    pub uuid: [u8; 12],
+    /// 1 if the receiver can take a new acquisition, or 0 if it is still
+    /// busy with the last one
+    pub ready: u32,
+}
+
+pub const READINESS_REPLY_MAGIC: [u8; 8] = *b"MORGULRR";
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Zeroable, Pod)]
 pub struct SlsDetectorHeader {
     /// Frame number to which the current packet belongs to
     pub frame_number: u64,
@@ -53,6 +240,393 @@ pub struct SlsDetectorHeader {
     pub version: u8,
 }
 
+// The header is read straight off the wire, so its layout must match the
+// one the detector sends exactly
+const _: () = assert!(size_of::<SlsDetectorHeader>() == 48);
+const _: () = assert!(align_of::<SlsDetectorHeader>() == 8);
+const _: () = assert!(std::mem::offset_of!(SlsDetectorHeader, module_id) == 32);
+const _: () = assert!(std::mem::offset_of!(SlsDetectorHeader, daq_info) == 40);
+const _: () = assert!(std::mem::offset_of!(SlsDetectorHeader, version) == 47);
+
+/// The only `sls_detector_header` version that we can read
+pub const SLS_HEADER_VERSION: u8 = 2;
+
+impl SlsDetectorHeader {
+    /// Read the header at the start of a received packet
+    ///
+    /// `packet` should be only the bytes that were received, so that a
+    /// truncated packet is caught rather than read from stale buffer contents.
+    pub fn from_packet(packet: &[u8]) -> Result<&Self, HeaderError> {
+        let header = Self::from_packet_any_version(packet)?;
+        if !header.is_supported_version() {
+            return Err(HeaderError::UnsupportedVersion(header.version));
+        }
+        Ok(header)
+    }
+
+    /// Read the header at the start of a received packet, whatever its version
+    ///
+    /// For when a header of another version is better read as if it were
+    /// ours than not at all; check [`is_supported_version`](Self::is_supported_version).
+    pub fn from_packet_any_version(packet: &[u8]) -> Result<&Self, HeaderError> {
+        let header = packet
+            .get(..size_of::<SlsDetectorHeader>())
+            .ok_or(HeaderError::TooShort {
+                length: packet.len(),
+            })?;
+        bytemuck::try_from_bytes(header).map_err(|_| HeaderError::Misaligned)
+    }
+
+    /// Is the header laid out the way that we read it?
+    pub fn is_supported_version(&self) -> bool {
+        self.version == SLS_HEADER_VERSION
+    }
+
+    /// Read the header from a packet sent with its fields in network byte order
+    ///
+    /// Some firmware versions send headers this way. Unlike
+    /// [`from_packet`](Self::from_packet), this can't borrow from the packet,
+    /// as the fields have to be swapped, so the packet needs no alignment.
+    pub fn from_packet_be(packet: &[u8]) -> Result<SlsDetectorHeader, HeaderError> {
+        let header = packet
+            .get(..size_of::<SlsDetectorHeader>())
+            .ok_or(HeaderError::TooShort {
+                length: packet.len(),
+            })?;
+        let mut header: SlsDetectorHeader = bytemuck::pod_read_unaligned(header);
+        if !header.is_supported_version() {
+            return Err(HeaderError::UnsupportedVersion(header.version));
+        }
+        header.frame_number = u64::from_be(header.frame_number);
+        header.exposure_length = u32::from_be(header.exposure_length);
+        header.packet_number = u32::from_be(header.packet_number);
+        header.bunch_id = u64::from_be(header.bunch_id);
+        header.timestamp = u64::from_be(header.timestamp);
+        header.module_id = u16::from_be(header.module_id);
+        header.row = u16::from_be(header.row);
+        header.column = u16::from_be(header.column);
+        header._det_spec_2 = u16::from_be(header._det_spec_2);
+        header.daq_info = u32::from_be(header.daq_info);
+        header._det_spec_4 = u16::from_be(header._det_spec_4);
+        Ok(header)
+    }
+
+    /// The measured exposure time of the frame
+    ///
+    /// An `exposure_length` of zero (e.g. from a simulator) is `Duration::ZERO`.
+    pub fn exposure_time(&self) -> Duration {
+        Duration::from_nanos(self.exposure_length as u64 * 100)
+    }
+
+    /// When the exposure of the frame started, since the start of the measurement
+    pub fn timestamp_since_measurement(&self) -> Duration {
+        // Widened first, as the nanoseconds overflow a u64 after ~5.8 years
+        let nanos = self.timestamp as u128 * 100;
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+
+    /// The `(x, y)` pixel of the whole detector image where this module's
+    /// top-left pixel goes, for modules of `module_w` x `module_h` pixels
+    ///
+    /// Modules are laid out in a grid by their `row` and `column`, with no
+    /// gaps between them.
+    pub fn module_origin(&self, module_w: usize, module_h: usize) -> (usize, usize) {
+        (
+            self.column as usize * module_w,
+            self.row as usize * module_h,
+        )
+    }
+
+    /// The `daq_info` field, decoded as a Jungfrau sends it
+    pub fn daq_info(&self) -> DaqInfo {
+        DaqInfo(self.daq_info)
+    }
+
+    /// The type of detector that sent the packet, if it is one we know of
+    // The same error as the `TryFrom<u8>` that this forwards to
+    #[allow(clippy::result_unit_err)]
+    pub fn detector_type(&self) -> Result<SlsDetectorType, ()> {
+        SlsDetectorType::try_from(self.det_type)
+    }
+}
+
+/// The `daq_info` header field of a Jungfrau, describing how it was read out
+///
+/// Only the fields documented in
+/// https://slsdetectorgroup.github.io/devdoc/udpdetspec.html#id10 are
+/// decoded; anything else is still in [`raw`](Self::raw).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DaqInfo(pub u32);
+
+impl DaqInfo {
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    fn bit(&self, bit: u32) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+
+    /// Was the module in high gain mode?
+    pub fn high_gain(&self) -> bool {
+        self.bit(0)
+    }
+
+    /// Was the gain fixed at stage 1?
+    pub fn fix_gain_stage_1(&self) -> bool {
+        self.bit(1)
+    }
+
+    /// Was the gain fixed at stage 2?
+    pub fn fix_gain_stage_2(&self) -> bool {
+        self.bit(2)
+    }
+
+    /// Were pixels forced to switch to gain stage 1?
+    pub fn force_switch_gain_stage_1(&self) -> bool {
+        self.bit(3)
+    }
+
+    /// Were pixels forced to switch to gain stage 2?
+    pub fn force_switch_gain_stage_2(&self) -> bool {
+        self.bit(4)
+    }
+
+    /// Which storage cell the frame was read from, 0 to 15
+    pub fn storage_cell(&self) -> u8 {
+        ((self.0 >> 8) & 0xF) as u8
+    }
+}
+
+/// A one-line summary of the fields that matter when commissioning
+impl std::fmt::Display for SlsDetectorHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Frame {} packet {} from ",
+            self.frame_number, self.packet_number
+        )?;
+        match self.detector_type() {
+            Ok(det_type) => write!(f, "{det_type}")?,
+            Err(()) => write!(f, "det_type {}", self.det_type)?,
+        }
+        write!(
+            f,
+            " module {} (row {}, column {}), exposure {:.1} µs",
+            self.module_id,
+            self.row,
+            self.column,
+            self.exposure_time().as_secs_f64() * 1e6
+        )
+    }
+}
+
+/// Modules contributing to one detector frame that disagree on exposure time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExposureMismatch {
+    pub frame_number: u64,
+    /// `(module_id, exposure_length)` for every contributing module
+    pub exposures: Vec<(u16, u32)>,
+}
+
+impl std::fmt::Display for ExposureMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Frame {}: module exposure times disagree:",
+            self.frame_number
+        )?;
+        for (module_id, exposure_length) in &self.exposures {
+            write!(
+                f,
+                " module {module_id}={:.1} µs",
+                *exposure_length as f64 / 10.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that every module contributing to a composite frame measured the same exposure
+///
+/// A module reporting a different `exposure_length` to the others indicates
+/// a misconfigured detector. `tolerance` is the largest allowed spread, in
+/// the same 100 ns units as `exposure_length`.
+pub fn check_exposure_consistency<'a>(
+    headers: impl IntoIterator<Item = &'a SlsDetectorHeader>,
+    tolerance: u32,
+) -> Result<(), ExposureMismatch> {
+    let exposures: Vec<_> = headers
+        .into_iter()
+        .map(|h| (h.frame_number, h.module_id, h.exposure_length))
+        .collect();
+    let Some(min) = exposures.iter().map(|(_, _, e)| *e).min() else {
+        return Ok(());
+    };
+    let max = exposures.iter().map(|(_, _, e)| *e).max().unwrap();
+    if max - min <= tolerance {
+        return Ok(());
+    }
+    Err(ExposureMismatch {
+        frame_number: exposures[0].0,
+        exposures: exposures.iter().map(|(_, m, e)| (*m, *e)).collect(),
+    })
+}
+
+/// Default alignment of image buffers; one cache line
+pub const DEFAULT_BUFFER_ALIGNMENT: usize = 64;
+
+/// A zero-initialised byte buffer whose start address has a chosen alignment
+///
+/// Image buffers are allocated like this so that SIMD copies, and any
+/// zero-copy handoff to DMA or a GPU, can rely on the alignment.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    align: usize,
+}
+
+// SAFETY: AlignedBuffer uniquely owns its allocation, like Box<[u8]>
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a zeroed buffer of `len` bytes
+    ///
+    /// Panics if `align` is not a power of two.
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("Invalid buffer alignment");
+        let ptr = if len == 0 {
+            // Zero-sized allocations are not allowed, but must still be aligned
+            NonNull::new(std::ptr::without_provenance_mut(align)).unwrap()
+        } else {
+            // SAFETY: The layout has a nonzero size
+            NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+                .unwrap_or_else(|| alloc::handle_alloc_error(layout))
+        };
+        AlignedBuffer { ptr, len, align }
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.align
+    }
+}
+
+impl Default for AlignedBuffer {
+    fn default() -> Self {
+        AlignedBuffer::new(0, DEFAULT_BUFFER_ALIGNMENT)
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr is valid for len initialised bytes
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: ptr is valid for len initialised bytes, and we own it
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: This is the layout the buffer was allocated with
+            unsafe {
+                alloc::dealloc(
+                    self.ptr.as_ptr(),
+                    Layout::from_size_align_unchecked(self.len, self.align),
+                )
+            };
+        }
+    }
+}
+
+impl std::fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("len", &self.len)
+            .field("align", &self.align)
+            .finish()
+    }
+}
+
+/// An image buffer that is returned to the pool it came from when dropped
+///
+/// This lets a listener hand a frame's buffer to a sink without copying it,
+/// and get it back to reuse once the sink has finished with it.
+pub struct PooledBuffer {
+    data: Option<AlignedBuffer>,
+    pool: Sender<AlignedBuffer>,
+}
+
+impl PooledBuffer {
+    pub fn new(data: AlignedBuffer, pool: Sender<AlignedBuffer>) -> Self {
+        PooledBuffer {
+            data: Some(data),
+            pool,
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.data.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.data.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        // If the pool has gone away then there is nobody to reuse it
+        let _ = self.pool.send(self.data.take().unwrap());
+    }
+}
+
+/// An assembled image, as handed on to any sink
+///
+/// Frames are self-describing about whether every packet arrived, so that
+/// consumers can filter or flag partial frames explicitly instead of
+/// relying on the aggregate acquisition statistics.
+pub struct CompletedFrame {
+    /// Header of the first packet received for this frame
+    pub header: SlsDetectorHeader,
+    /// Did every packet of the frame arrive?
+    pub complete: bool,
+    /// Which packets arrived: bit N is set if packet_number N was received
+    pub received_mask: u64,
+    /// The assembled image data. Regions for missing packets are undefined.
+    pub data: PooledBuffer,
+}
+
+impl std::fmt::Debug for CompletedFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompletedFrame")
+            .field("header", &self.header)
+            .field("complete", &self.complete)
+            .field(
+                "received_mask",
+                &format_args!("{:#018x}", self.received_mask),
+            )
+            .finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, clap::ValueEnum)]
 pub enum SlsDetectorType {
     Generic = 0,
     Eiger = 1,
@@ -68,31 +642,885 @@ impl TryFrom<u8> for SlsDetectorType {
     type Error = ();
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(SlsDetectorType::Generic),
-            1 => Ok(SlsDetectorType::Eiger),
-            2 => Ok(SlsDetectorType::Gotthard),
-            3 => Ok(SlsDetectorType::Jungfrau),
-            4 => Ok(SlsDetectorType::ChipTestBoard),
-            5 => Ok(SlsDetectorType::Moench),
-            6 => Ok(SlsDetectorType::Mythen3),
-            7 => Ok(SlsDetectorType::Gotthard2),
-            _ => Err(()),
+        SlsDetectorType::ALL.get(value as usize).copied().ok_or(())
+    }
+}
+
+// ALL is indexed by the det_type value
+const _: () = {
+    let mut i = 0;
+    while i < SlsDetectorType::ALL.len() {
+        assert!(SlsDetectorType::ALL[i] as usize == i);
+        i += 1;
+    }
+};
+
+impl SlsDetectorType {
+    /// Every detector type, in order of their `det_type` values
+    pub const ALL: [SlsDetectorType; 8] = [
+        SlsDetectorType::Generic,
+        SlsDetectorType::Eiger,
+        SlsDetectorType::Gotthard,
+        SlsDetectorType::Jungfrau,
+        SlsDetectorType::ChipTestBoard,
+        SlsDetectorType::Moench,
+        SlsDetectorType::Mythen3,
+        SlsDetectorType::Gotthard2,
+    ];
+
+    pub fn all() -> impl Iterator<Item = SlsDetectorType> {
+        SlsDetectorType::ALL.into_iter()
+    }
+
+    /// The `(width, height)` of the part of a module that each UDP port
+    /// sends, in pixels
+    ///
+    /// Like everything per port here, this is as for
+    /// [`GeometryMap::with_defaults`], with the module sending through both
+    /// of its UDP interfaces.
+    pub fn port_dimensions(&self) -> Option<(usize, usize)> {
+        match self {
+            SlsDetectorType::Jungfrau => Some((1024, 256)),
+            SlsDetectorType::Eiger => Some((256, 256)),
+            SlsDetectorType::Moench => Some((400, 200)),
+            SlsDetectorType::Gotthard2 => Some((1280, 1)),
+            _ => None,
+        }
+    }
+
+    /// How many packets each UDP port sends per frame, at the default bit depth
+    pub fn packets_per_port_frame(&self) -> Option<usize> {
+        match self {
+            SlsDetectorType::Jungfrau => Some(64),
+            SlsDetectorType::Eiger => Some(32),
+            SlsDetectorType::Moench => Some(25),
+            SlsDetectorType::Gotthard2 => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Bits per pixel, unless the detector has been configured otherwise
+    pub fn default_bit_depth(&self) -> Option<usize> {
+        match self {
+            SlsDetectorType::Jungfrau
+            | SlsDetectorType::Eiger
+            | SlsDetectorType::Moench
+            | SlsDetectorType::Gotthard2 => Some(16),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SlsDetectorType::Generic => "Generic",
+            SlsDetectorType::Eiger => "Eiger",
+            SlsDetectorType::Gotthard => "Gotthard",
+            SlsDetectorType::Jungfrau => "Jungfrau",
+            SlsDetectorType::ChipTestBoard => "ChipTestBoard",
+            SlsDetectorType::Moench => "Moench",
+            SlsDetectorType::Mythen3 => "Mythen3",
+            SlsDetectorType::Gotthard2 => "Gotthard2",
+        }
+    }
+}
+
+impl std::fmt::Display for SlsDetectorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parse from the name of the detector type, ignoring case and any `-` or
+/// `_`, e.g. `jungfrau` or `chip-test-board`
+impl FromStr for SlsDetectorType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name: String = s.trim().chars().filter(|c| !"-_".contains(*c)).collect();
+        SlsDetectorType::ALL
+            .into_iter()
+            .find(|t| t.name().eq_ignore_ascii_case(&name))
+            .ok_or_else(|| format!("Unknown detector type '{s}'"))
+    }
+}
+
+/// Where each packet's payload goes in the assembled frame
+///
+/// This depends on the detector and firmware; getting it wrong scrambles
+/// the image.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum PacketLayout {
+    /// Packet N fills the Nth block of the frame
+    #[default]
+    Contiguous,
+    /// Packets arrive in reverse order, so packet 0 fills the last block
+    Reversed,
+    /// The two halves of the module are interleaved: even packets fill the
+    /// top half of the frame in order, and odd packets the bottom half
+    InterleavedHalves,
+}
+
+impl FromStr for PacketLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "contiguous" => Ok(PacketLayout::Contiguous),
+            "reversed" => Ok(PacketLayout::Reversed),
+            "interleaved" => Ok(PacketLayout::InterleavedHalves),
+            _ => Err(format!(
+                "Unknown packet layout '{s}', expected contiguous, reversed or interleaved"
+            )),
+        }
+    }
+}
+
+/// Most packets one frame can be split into, as received packets are
+/// tracked with one bit each in a `u64`
+pub const MAX_PACKETS_PER_FRAME: usize = 64;
+
+/// The shape of the data that a single UDP port receives for one frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PortGeometry {
+    /// How many packets make up one frame. At most [`MAX_PACKETS_PER_FRAME`].
+    pub packets_per_frame: usize,
+    /// Size of the data payload following the header in each packet. The
+    /// last packet of the frame may be shorter, if the image is not a whole
+    /// number of payloads.
+    pub payload_size: usize,
+    /// Width of the image received on this port, in pixels
+    pub size_x: usize,
+    /// Height of the image received on this port, in pixels
+    pub size_y: usize,
+    /// Bits per pixel
+    pub bit_depth: usize,
+    /// Where each packet goes in the frame
+    pub layout: PacketLayout,
+}
+
+impl PortGeometry {
+    /// Total size in bytes of one assembled frame
+    pub fn frame_size(&self) -> usize {
+        self.size_x * self.size_y * self.bit_depth / 8
+    }
+    /// How many payload bytes a packet carries
+    ///
+    /// This is `payload_size` for every packet, apart from whichever lands
+    /// at the end of the frame, which only carries what is left over.
+    /// `packet_number` must be less than `packets_per_frame`.
+    pub fn packet_payload_size(&self, packet_number: usize) -> usize {
+        self.payload_size
+            .min(self.frame_size() - self.packet_offset(packet_number))
+    }
+    /// Byte offset into the frame where a packet's payload belongs
+    ///
+    /// `packet_number` must be less than `packets_per_frame`.
+    pub fn packet_offset(&self, packet_number: usize) -> usize {
+        let block = match self.layout {
+            PacketLayout::Contiguous => packet_number,
+            PacketLayout::Reversed => self.packets_per_frame - 1 - packet_number,
+            PacketLayout::InterleavedHalves => {
+                packet_number / 2 + (packet_number % 2) * self.packets_per_frame / 2
+            }
+        };
+        block * self.payload_size
+    }
+    /// Which packet the byte at an offset into the frame came from
+    ///
+    /// This is the inverse of `packet_offset`.
+    pub fn packet_at_offset(&self, offset: usize) -> usize {
+        let block = offset / self.payload_size;
+        match self.layout {
+            PacketLayout::Contiguous => block,
+            PacketLayout::Reversed => self.packets_per_frame - 1 - block,
+            PacketLayout::InterleavedHalves => {
+                let half = self.packets_per_frame / 2;
+                (block % half) * 2 + block / half
+            }
+        }
+    }
+    /// The received-packet mask of a frame with every packet present
+    pub fn full_mask(&self) -> u64 {
+        u64::MAX >> (MAX_PACKETS_PER_FRAME - self.packets_per_frame)
+    }
+    /// Check that the geometry is self-consistent and one we can assemble
+    pub fn validate(&self) -> Result<(), MorgulError> {
+        let invalid = |reason| MorgulError::InvalidGeometry {
+            source: None,
+            reason,
+        };
+        if !(1..=MAX_PACKETS_PER_FRAME).contains(&self.packets_per_frame) {
+            return Err(invalid(format!(
+                "packets_per_frame must be between 1 and {MAX_PACKETS_PER_FRAME}, not {}",
+                self.packets_per_frame
+            )));
+        }
+        if self.layout == PacketLayout::InterleavedHalves
+            && !self.packets_per_frame.is_multiple_of(2)
+        {
+            return Err(invalid(format!(
+                "interleaved halves need an even number of packets, not {}",
+                self.packets_per_frame
+            )));
+        }
+        // Only the last packet may be short, and it can't be empty
+        let frame_bits = self.size_x * self.size_y * self.bit_depth;
+        let full_packets_bits = self.packets_per_frame * self.payload_size * 8;
+        if !frame_bits.is_multiple_of(8)
+            || frame_bits > full_packets_bits
+            || frame_bits <= full_packets_bits - self.payload_size * 8
+        {
+            return Err(invalid(format!(
+                "{}x{} pixels at {} bits does not fit {} packets of up to {} bytes",
+                self.size_x, self.size_y, self.bit_depth, self.packets_per_frame, self.payload_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Expected per-port frame geometry for each detector type
+///
+/// This lets one receiver handle ports carrying different detectors, by
+/// looking up the geometry from the `det_type` of each incoming packet.
+#[derive(Debug, Clone)]
+pub struct GeometryMap {
+    geometries: HashMap<SlsDetectorType, PortGeometry>,
+}
+
+impl GeometryMap {
+    /// The built-in geometries, for detectors running with two UDP interfaces
+    ///
+    /// Eiger does not report its dynamic range in the packet header, so it
+    /// must be known up front.
+    pub fn with_defaults(eiger_dynamic_range: usize) -> Self {
+        let geometries = HashMap::from([
+            (
+                SlsDetectorType::Jungfrau,
+                PortGeometry {
+                    packets_per_frame: 64,
+                    payload_size: 8192,
+                    size_x: 1024,
+                    size_y: 256,
+                    bit_depth: 16,
+                    layout: PacketLayout::Contiguous,
+                },
+            ),
+            (
+                SlsDetectorType::Eiger,
+                PortGeometry {
+                    packets_per_frame: 256 * 256 * eiger_dynamic_range / 8 / 4096,
+                    payload_size: 4096,
+                    size_x: 256,
+                    size_y: 256,
+                    bit_depth: eiger_dynamic_range,
+                    layout: PacketLayout::Contiguous,
+                },
+            ),
+            (
+                SlsDetectorType::Moench,
+                PortGeometry {
+                    packets_per_frame: 25,
+                    payload_size: 6400,
+                    size_x: 400,
+                    size_y: 200,
+                    bit_depth: 16,
+                    layout: PacketLayout::Contiguous,
+                },
+            ),
+            (
+                SlsDetectorType::Gotthard2,
+                PortGeometry {
+                    packets_per_frame: 1,
+                    payload_size: 2560,
+                    size_x: 1280,
+                    size_y: 1,
+                    bit_depth: 16,
+                    layout: PacketLayout::Contiguous,
+                },
+            ),
+        ]);
+        GeometryMap { geometries }
+    }
+
+    /// Load geometry overrides from a file, on top of the built-in defaults
+    ///
+    /// Each non-empty line that isn't a `#` comment has the form
+    ///
+    /// ```text
+    /// <det_type> <packets_per_frame> <payload_size> <size_x> <size_y> <bit_depth> [layout]
+    /// ```
+    ///
+    /// where `det_type` is the numeric detector type as sent in the header,
+    /// and the optional `layout` is one of `contiguous` (the default),
+    /// `reversed` or `interleaved`.
+    pub fn load(path: &Path, eiger_dynamic_range: usize) -> Result<Self, MorgulError> {
+        let mut map = Self::with_defaults(eiger_dynamic_range);
+        let invalid = |line_no: usize, reason: String| MorgulError::InvalidGeometry {
+            source: Some(path.to_owned()),
+            reason: format!("line {}: {reason}", line_no + 1),
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| MorgulError::InvalidGeometry {
+            source: Some(path.to_owned()),
+            reason: e.to_string(),
+        })?;
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields: Vec<_> = line.split_whitespace().collect();
+            let layout = match fields.len() {
+                7 => fields
+                    .pop()
+                    .unwrap()
+                    .parse()
+                    .map_err(|e| invalid(line_no, e))?,
+                _ => PacketLayout::Contiguous,
+            };
+            let values = fields
+                .iter()
+                .map(|v| v.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(line_no, e.to_string()))?;
+            let [
+                det_type,
+                packets_per_frame,
+                payload_size,
+                size_x,
+                size_y,
+                bit_depth,
+            ] = values[..]
+            else {
+                return Err(invalid(line_no, "Expected six or seven values".to_string()));
+            };
+            let det_type = u8::try_from(det_type)
+                .ok()
+                .and_then(|t| SlsDetectorType::try_from(t).ok())
+                .ok_or_else(|| invalid(line_no, format!("Unknown detector type {det_type}")))?;
+            let geometry = PortGeometry {
+                packets_per_frame,
+                payload_size,
+                size_x,
+                size_y,
+                bit_depth,
+                layout,
+            };
+            if let Err(MorgulError::InvalidGeometry { reason, .. }) = geometry.validate() {
+                return Err(invalid(line_no, reason));
+            }
+            map.geometries.insert(det_type, geometry);
         }
+        Ok(map)
+    }
+
+    /// The largest packet payload of any known detector type
+    pub fn max_payload_size(&self) -> usize {
+        self.geometries
+            .values()
+            .map(|g| g.payload_size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.geometries
+            .values()
+            .map(|g| g.frame_size())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Set the geometry for a detector type, replacing any existing one
+    pub fn insert(&mut self, det_type: SlsDetectorType, geometry: PortGeometry) {
+        self.geometries.insert(det_type, geometry);
+    }
+
+    /// Look up the geometry for a raw `det_type` header value
+    pub fn get(&self, det_type: u8) -> Option<&PortGeometry> {
+        SlsDetectorType::try_from(det_type)
+            .ok()
+            .and_then(|t| self.geometries.get(&t))
+    }
+}
+
+/// The subnet of every address whose first octet is `prefix`
+fn prefix_subnet(prefix: u8) -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::new(prefix, 0, 0, 0), 8).unwrap()
+}
+
+/// Find the IPv4 addresses of every running local interface whose first
+/// octet is `prefix`
+pub fn get_interface_addreses_with_prefix(prefix: u8) -> Result<Vec<Ipv4Addr>, MorgulError> {
+    get_interface_addresses_in_subnet(prefix_subnet(prefix), false)
+}
+
+/// Find the IPv4 addresses of every local interface within `subnet`
+///
+/// Unless `include_down` is set, interfaces whose link is down are left
+/// out, as nothing would ever arrive on them.
+pub fn get_interface_addresses_in_subnet(
+    subnet: Ipv4Network,
+    include_down: bool,
+) -> Result<Vec<Ipv4Addr>, MorgulError> {
+    let addresses: Vec<_> = get_interface_links_in_subnet(subnet)
+        .into_iter()
+        .filter(|&(_, up)| up || include_down)
+        .map(|(address, _)| address)
+        .collect();
+    if addresses.is_empty() {
+        return Err(MorgulError::NoInterfaces {
+            filter: if include_down {
+                subnet.to_string()
+            } else {
+                format!("running {subnet}")
+            },
+        });
     }
+    Ok(addresses)
 }
 
-pub fn get_interface_addreses_with_prefix(prefix: u8) -> Vec<Ipv4Addr> {
+/// Find the IPv6 addresses of every local interface within `subnet`, e.g.
+/// `fd00:1::/64`
+pub fn get_interface_v6_addresses_in_subnet(
+    subnet: Ipv6Network,
+) -> Result<Vec<Ipv6Addr>, MorgulError> {
     let mut addresses: Vec<_> = datalink::interfaces()
         .iter()
-        .flat_map(|x| &x.ips)
-        .flat_map(|x| match x {
-            pnet::ipnetwork::IpNetwork::V4(ip) => Some(ip),
+        .flat_map(|interface| &interface.ips)
+        .filter_map(|ip| match ip {
+            IpNetwork::V6(ip) if subnet.contains(ip.ip()) => Some(ip.ip()),
             _ => None,
         })
-        .map(|x| x.ip())
-        .filter(|x| x.octets()[0] == prefix)
         .collect();
+    if addresses.is_empty() {
+        return Err(MorgulError::NoInterfaces {
+            filter: subnet.to_string(),
+        });
+    }
     addresses.sort();
-    addresses
+    Ok(addresses)
+}
+
+/// Find every local IPv4 address whose first octet is `prefix`, with the
+/// name of the interface it is on, e.g. `("eth2", 192.168.201.1)`
+pub fn get_interfaces_with_prefix(prefix: u8) -> Vec<(String, Ipv4Addr)> {
+    get_interfaces_in_subnet(prefix_subnet(prefix))
+}
+
+/// Find every local IPv4 address within `subnet`, with the name of the
+/// interface it is on
+pub fn get_interfaces_in_subnet(subnet: Ipv4Network) -> Vec<(String, Ipv4Addr)> {
+    let mut interfaces: Vec<_> = datalink::interfaces()
+        .into_iter()
+        .flat_map(|interface| {
+            interface
+                .ips
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpNetwork::V4(ip) if subnet.contains(ip.ip()) => {
+                        Some((interface.name.clone(), ip.ip()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    // By address, as the listeners are numbered in address order
+    interfaces.sort_by_key(|(_, address)| *address);
+    interfaces
+}
+
+/// Find every local IPv4 address whose first octet is `prefix`, and
+/// whether the link it is on is currently up and running
+pub fn get_interface_links_with_prefix(prefix: u8) -> Vec<(Ipv4Addr, bool)> {
+    get_interface_links_in_subnet(prefix_subnet(prefix))
+}
+
+/// Find every local IPv4 address within `subnet`, and whether the link it
+/// is on is currently up and running
+pub fn get_interface_links_in_subnet(subnet: Ipv4Network) -> Vec<(Ipv4Addr, bool)> {
+    let mut links: Vec<_> = datalink::interfaces()
+        .iter()
+        .flat_map(|interface| {
+            let up = interface.is_up() && interface.is_running();
+            interface.ips.iter().filter_map(move |ip| match ip {
+                IpNetwork::V4(ip) if subnet.contains(ip.ip()) => Some((ip.ip(), up)),
+                _ => None,
+            })
+        })
+        .collect();
+    links.sort();
+    links
+}
+
+/// Notices changes to network interfaces, e.g. a link going down, or an
+/// address being added or removed
+///
+/// This listens for the kernel's netlink route notifications, so it only
+/// wakes up when something actually changes. It doesn't say what changed;
+/// compare [`get_interface_links_with_prefix`] before and after.
+pub struct InterfaceChangeMonitor {
+    socket: OwnedFd,
+}
+
+impl InterfaceChangeMonitor {
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: Plain socket creation; the fd is owned from here on
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a valid socket that nothing else owns
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: sockaddr_nl is plain data, for which all zeroes is valid
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR) as u32;
+        // SAFETY: address is a valid sockaddr_nl, of the size given
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                (&address as *const libc::sockaddr_nl).cast(),
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(InterfaceChangeMonitor { socket })
+    }
+
+    /// Block until an interface changes
+    ///
+    /// A flapping link sends a burst of notifications, so once one arrives
+    /// this waits for `settle` and discards the rest of the burst.
+    pub fn wait(&mut self, settle: std::time::Duration) -> io::Result<()> {
+        let mut buffer = [0u8; 8192];
+        self.recv(&mut buffer, 0)?;
+        std::thread::sleep(settle);
+        loop {
+            match self.recv(&mut buffer, libc::MSG_DONTWAIT) {
+                Ok(()) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8], flags: libc::c_int) -> io::Result<()> {
+        // SAFETY: buffer is valid for writes of its length
+        let ret = unsafe {
+            libc::recv(
+                self.socket.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                flags,
+            )
+        };
+        if ret < 0 {
+            let error = io::Error::last_os_error();
+            // If notifications overflowed, something still changed
+            if error.raw_os_error() == Some(libc::ENOBUFS) {
+                return Ok(());
+            }
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_header(module_id: u16, exposure_length: u32) -> SlsDetectorHeader {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = 42;
+        header.module_id = module_id;
+        header.exposure_length = exposure_length;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = SLS_HEADER_VERSION;
+        header
+    }
+
+    #[test]
+    fn consistent_exposures_pass() {
+        let headers: Vec<_> = (0..4).map(|m| module_header(m, 1000 + m as u32)).collect();
+        assert_eq!(check_exposure_consistency(&headers, 3), Ok(()));
+        assert_eq!(check_exposure_consistency([], 0), Ok(()));
+    }
+
+    #[test]
+    fn one_mismatched_module_is_reported() {
+        let headers = [
+            module_header(0, 1000),
+            module_header(1, 1000),
+            module_header(2, 2000),
+            module_header(3, 1000),
+        ];
+        let mismatch = check_exposure_consistency(&headers, 10).unwrap_err();
+        assert_eq!(mismatch.frame_number, 42);
+        assert_eq!(
+            mismatch.exposures,
+            [(0, 1000), (1, 1000), (2, 2000), (3, 1000)]
+        );
+        assert!(mismatch.to_string().contains("module 2=200.0 µs"));
+    }
+
+    #[test]
+    fn aligned_buffers_are_aligned_and_zeroed() {
+        for align in [1, 64, 4096] {
+            for len in [0, 1, 8192] {
+                let buffer = AlignedBuffer::new(len, align);
+                assert!((buffer.as_ptr() as usize).is_multiple_of(align));
+                assert_eq!(buffer.len(), len);
+                assert!(buffer.iter().all(|b| *b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn exposure_length_is_in_tenths_of_a_microsecond() {
+        assert_eq!(
+            module_header(0, 1000).exposure_time(),
+            Duration::from_micros(100)
+        );
+        assert_eq!(
+            module_header(0, 1).exposure_time(),
+            Duration::from_nanos(100)
+        );
+        assert_eq!(module_header(0, 0).exposure_time(), Duration::ZERO);
+        assert_eq!(
+            module_header(0, u32::MAX).exposure_time(),
+            Duration::from_nanos(u32::MAX as u64 * 100)
+        );
+    }
+
+    #[test]
+    fn timestamp_does_not_overflow() {
+        let mut header = module_header(0, 0);
+        header.timestamp = 12_345_678;
+        assert_eq!(
+            header.timestamp_since_measurement(),
+            Duration::from_nanos(1_234_567_800)
+        );
+        header.timestamp = u64::MAX;
+        assert_eq!(
+            header.timestamp_since_measurement(),
+            Duration::new(1_844_674_407_370, 955_161_500)
+        );
+    }
+
+    #[test]
+    fn big_endian_headers_have_their_fields_swapped() {
+        let mut header = module_header(0x0102, 1000);
+        header.frame_number = 0x0102_0304_0506_0708;
+        let bytes = bytemuck::bytes_of(&header);
+        let le = SlsDetectorHeader::from_packet(bytes).unwrap();
+        let be = SlsDetectorHeader::from_packet_be(bytes).unwrap();
+        assert_eq!(le.frame_number, 0x0102_0304_0506_0708);
+        assert_eq!(be.frame_number, 0x0807_0605_0403_0201);
+        assert_eq!(be.module_id, 0x0201);
+        assert_eq!(be.exposure_length, 1000u32.swap_bytes());
+        // The single byte fields are the same either way
+        assert_eq!((be.det_type, be.version), (le.det_type, le.version));
+
+        // A header really sent big-endian reads back as it was sent
+        let mut sent = header;
+        sent.frame_number = header.frame_number.to_be();
+        sent.exposure_length = header.exposure_length.to_be();
+        sent.module_id = header.module_id.to_be();
+        let read = SlsDetectorHeader::from_packet_be(bytemuck::bytes_of(&sent)).unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn detector_type_comes_from_det_type() {
+        let mut header = module_header(0, 0);
+        assert_eq!(header.detector_type(), Ok(SlsDetectorType::Jungfrau));
+        header.det_type = 1;
+        assert_eq!(header.detector_type(), Ok(SlsDetectorType::Eiger));
+        header.det_type = 200;
+        assert_eq!(header.detector_type(), Err(()));
+    }
+
+    #[test]
+    fn header_layout_matches_the_wire() {
+        assert_eq!(size_of::<SlsDetectorHeader>(), 48);
+        assert_eq!(align_of::<SlsDetectorHeader>(), 8);
+        // Read in place, a header has to be aligned for its u64 fields
+        let buffer = AlignedBuffer::new(size_of::<SlsDetectorHeader>() + 1, 8);
+        assert!(SlsDetectorHeader::from_packet_any_version(&buffer[..48]).is_ok());
+        assert_eq!(
+            SlsDetectorHeader::from_packet_any_version(&buffer[1..]),
+            Err(HeaderError::Misaligned)
+        );
+        assert_eq!(
+            SlsDetectorHeader::from_packet_any_version(&buffer[..47]),
+            Err(HeaderError::TooShort { length: 47 })
+        );
+    }
+
+    #[test]
+    fn modules_of_a_2x2_detector_are_placed_in_a_grid() {
+        let origin = |row, column| {
+            let mut header = module_header(0, 0);
+            header.row = row;
+            header.column = column;
+            header.module_origin(1024, 512)
+        };
+        assert_eq!(origin(0, 0), (0, 0));
+        assert_eq!(origin(0, 1), (1024, 0));
+        assert_eq!(origin(1, 0), (0, 512));
+        assert_eq!(origin(1, 1), (1024, 512));
+    }
+
+    #[test]
+    fn daq_info_bits_are_decoded() {
+        let info = |daq_info| {
+            let mut header = module_header(0, 0);
+            header.daq_info = daq_info;
+            header.daq_info()
+        };
+        let none = info(0);
+        assert!(!none.high_gain() && !none.fix_gain_stage_1() && !none.fix_gain_stage_2());
+        assert_eq!(none.storage_cell(), 0);
+
+        // High gain, with the gain fixed at stage 2, from storage cell 13
+        let info = info(0b1101_0000_0101);
+        assert!(info.high_gain());
+        assert!(!info.fix_gain_stage_1());
+        assert!(info.fix_gain_stage_2());
+        assert!(!info.force_switch_gain_stage_1());
+        assert!(!info.force_switch_gain_stage_2());
+        assert_eq!(info.storage_cell(), 13);
+        assert_eq!(info.raw(), 0xD05);
+
+        // Bits above the storage cell don't leak into it
+        let forced = DaqInfo(0xFFFF_F018);
+        assert!(forced.force_switch_gain_stage_1() && forced.force_switch_gain_stage_2());
+        assert!(!forced.high_gain());
+        assert_eq!(forced.storage_cell(), 0);
+    }
+
+    #[test]
+    fn identical_headers_are_equal_and_hash_the_same() {
+        use std::collections::HashSet;
+        use std::hash::{BuildHasher, RandomState};
+        let a = module_header(3, 1000);
+        let b: SlsDetectorHeader = bytemuck::pod_read_unaligned(bytemuck::bytes_of(&a));
+        let hasher = RandomState::new();
+        assert_eq!(a, b);
+        assert_eq!(hasher.hash_one(a), hasher.hash_one(b));
+        assert_eq!(HashSet::from([a, b]).len(), 1);
+        // The reserved fields are part of the wire format, so count too
+        let mut c = a;
+        c._det_spec_4 = 1;
+        assert_ne!(a, c);
+        assert_eq!(HashSet::from([a, b, c]).len(), 2);
+    }
+
+    #[test]
+    fn detector_type_names_round_trip() {
+        for det_type in SlsDetectorType::all() {
+            let name = det_type.to_string();
+            assert_eq!(name.parse(), Ok(det_type));
+            assert_eq!(name.to_lowercase().parse(), Ok(det_type));
+            assert_eq!(name.to_uppercase().parse(), Ok(det_type));
+        }
+        assert_eq!(
+            "chip-test-board".parse(),
+            Ok(SlsDetectorType::ChipTestBoard)
+        );
+        assert_eq!(" gotthard_2 ".parse(), Ok(SlsDetectorType::Gotthard2));
+        assert!("pilatus".parse::<SlsDetectorType>().is_err());
+    }
+
+    #[test]
+    fn every_detector_type_is_listed_by_its_value() {
+        assert_eq!(SlsDetectorType::all().count(), 8);
+        for det_type in SlsDetectorType::all() {
+            assert_eq!(SlsDetectorType::try_from(det_type as u8), Ok(det_type));
+        }
+        assert_eq!(SlsDetectorType::try_from(8), Err(()));
+        // Every variant known to clap is in the list
+        for value in <SlsDetectorType as clap::ValueEnum>::value_variants() {
+            assert!(SlsDetectorType::ALL.contains(value));
+        }
+    }
+
+    #[test]
+    fn uuid_is_24_lowercase_hex_digits() {
+        let trigger = DelugeTrigger::builder()
+            .uuid([
+                0x00, 0x01, 0x0A, 0x10, 0x7F, 0x80, 0xAB, 0xCD, 0xEF, 0xF0, 0xFE, 0xFF,
+            ])
+            .build();
+        assert_eq!(trigger.uuid_hex(), "00010a107f80abcdeff0feff");
+    }
+
+    #[test]
+    fn triggers_round_trip_through_bytes_and_bad_ones_are_rejected() {
+        let trigger = DelugeTrigger::builder().frames(100).exptime(0.5).build();
+        let bytes = bytemuck::bytes_of(&trigger).to_vec();
+        let read = DelugeTrigger::from_bytes(&bytes).unwrap();
+        assert_eq!(read.frames, 100);
+        assert_eq!(read.exptime, 0.5);
+        assert_eq!(read.uuid, trigger.uuid);
+        // Read from wherever it landed in the receive buffer
+        let mut offset = vec![0];
+        offset.extend_from_slice(&bytes);
+        assert!(DelugeTrigger::from_bytes(&offset[1..]).is_ok());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xFF;
+        assert_eq!(
+            DelugeTrigger::from_bytes(&bad_magic).unwrap_err(),
+            TriggerError::BadMagic
+        );
+        let mut newer = trigger;
+        newer.version = DELUGE_TRIGGER_VERSION + 1;
+        assert_eq!(
+            DelugeTrigger::from_bytes(bytemuck::bytes_of(&newer)).unwrap_err(),
+            TriggerError::UnsupportedVersion(DELUGE_TRIGGER_VERSION + 1)
+        );
+        for length in [0, bytes.len() - 1, bytes.len() + 1] {
+            let mut resized = bytes.clone();
+            resized.resize(length, 0);
+            assert_eq!(
+                DelugeTrigger::from_bytes(&resized).unwrap_err(),
+                TriggerError::WrongSize { length }
+            );
+        }
+    }
+
+    #[test]
+    fn detector_type_geometry_matches_the_default_ports() {
+        let geometry = GeometryMap::with_defaults(16);
+        for det_type in SlsDetectorType::all() {
+            let port = geometry.get(det_type as u8);
+            assert_eq!(
+                det_type.port_dimensions(),
+                port.map(|g| (g.size_x, g.size_y))
+            );
+            assert_eq!(
+                det_type.packets_per_port_frame(),
+                port.map(|g| g.packets_per_frame)
+            );
+            assert_eq!(det_type.default_bit_depth(), port.map(|g| g.bit_depth));
+        }
+    }
+
+    #[test]
+    fn header_summary_names_the_detector() {
+        let mut header = module_header(2, 1000);
+        assert!(
+            header
+                .to_string()
+                .starts_with("Frame 42 packet 0 from Jungfrau module 2 (row 0, column 0)")
+        );
+        header.det_type = 0xEE;
+        assert!(header.to_string().contains(" from det_type 238 module 2 "));
+    }
 }
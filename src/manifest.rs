@@ -0,0 +1,269 @@
+//! Describing each finished acquisition for downstream orchestration
+//!
+//! A manifest is a small JSON document written once an acquisition has
+//! ended and every frame of it has been flushed to its outputs, so that a
+//! workflow engine watching the manifest directory can act on it straight
+//! away.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    PacketLayout, PortGeometry, SlsDetectorType,
+    assembler::{AcquisitionStats, format_frame_ranges},
+    output::{GatedFile, loss_fraction},
+};
+
+/// Everything a downstream pipeline needs to know about one acquisition
+#[derive(Debug, Clone, Default)]
+pub struct AcquisitionManifest {
+    pub acquisition_number: usize,
+    /// UUID of the trigger that started the acquisition, if known
+    pub trigger_uuid: Option<[u8; 12]>,
+    /// The files that the frames were written to
+    pub outputs: Vec<PathBuf>,
+    /// Where the frames were streamed to, leaving no files behind, e.g.
+    /// `tcp:<address>`
+    pub streams: Vec<String>,
+    /// How many distinct frame numbers were received
+    pub frames: usize,
+    /// The detectors that sent frames, and the geometry of each of their ports
+    pub detectors: BTreeMap<u8, PortGeometry>,
+    /// Statistics merged across every listener
+    pub stats: AcquisitionStats,
+    /// The acquisition is only good if no more than this fraction of
+    /// packets were lost
+    pub max_loss_fraction: f64,
+    /// Why the outputs could not be finished, if they could not; the
+    /// acquisition has then failed, however few packets were lost
+    pub output_error: Option<String>,
+}
+
+impl AcquisitionManifest {
+    /// Fraction of the packets of received frames that never arrived
+    pub fn loss_fraction(&self) -> f64 {
+        loss_fraction(
+            self.stats.packets_received + self.stats.packets_dropped,
+            self.stats.packets_dropped,
+        )
+    }
+
+    /// Is the acquisition good enough to pass on to the next stage?
+    ///
+    /// It is not if too many packets were lost, any expected frames
+    /// never arrived at all, or the outputs could not be finished.
+    pub fn is_good(&self) -> bool {
+        if self.output_error.is_some() {
+            return false;
+        }
+        let missing_frames = self
+            .stats
+            .expected_frames
+            .as_ref()
+            .is_some_and(|report| !report.missing.is_empty());
+        self.loss_fraction() <= self.max_loss_fraction && !missing_frames
+    }
+
+    pub fn to_json(&self) -> String {
+        let stats = &self.stats;
+        let written = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |t| t.as_secs_f64());
+        let mut json = String::from("{\n");
+        let mut field = |name: &str, value: String| {
+            let _ = writeln!(json, "  {}: {value},", json_string(name));
+        };
+        field("acquisition_number", self.acquisition_number.to_string());
+        field("written_at", format!("{written:.3}"));
+        field(
+            "trigger_uuid",
+            self.trigger_uuid.map_or("null".to_string(), |uuid| {
+                json_string(&uuid.iter().map(|b| format!("{b:02x}")).collect::<String>())
+            }),
+        );
+        field(
+            "outputs",
+            json_list(
+                self.outputs
+                    .iter()
+                    .map(|o| json_string(&o.display().to_string())),
+            ),
+        );
+        field(
+            "streams",
+            json_list(self.streams.iter().map(|s| json_string(s))),
+        );
+        field("frames", self.frames.to_string());
+        field(
+            "detectors",
+            json_list(self.detectors.iter().map(|(&det_type, geometry)| {
+                let name = SlsDetectorType::try_from(det_type)
+                    .map_or(det_type.to_string(), |t| t.to_string());
+                format!(
+                    "{{\"det_type\": {}, \"packets_per_frame\": {}, \"payload_size\": {}, \"size_x\": {}, \"size_y\": {}, \"bit_depth\": {}, \"layout\": {}}}",
+                    json_string(&name),
+                    geometry.packets_per_frame,
+                    geometry.payload_size,
+                    geometry.size_x,
+                    geometry.size_y,
+                    geometry.bit_depth,
+                    json_string(match geometry.layout {
+                        PacketLayout::Contiguous => "contiguous",
+                        PacketLayout::Reversed => "reversed",
+                        PacketLayout::InterleavedHalves => "interleaved",
+                    }),
+                )
+            })),
+        );
+        field(
+            "stats",
+            format!(
+                "{{\"images_seen\": {}, \"complete_images\": {}, \"packets_received\": {}, \"packets_dropped\": {}, \"out_of_order\": {}, \"duplicate_frames\": {}, \"kernel_dropped\": {}}}",
+                stats.images_seen,
+                stats.complete_images,
+                stats.packets_received,
+                stats.packets_dropped,
+                stats.out_of_order,
+                stats.duplicate_frames,
+                stats.kernel_dropped,
+            ),
+        );
+        if let Some(report) = &stats.expected_frames {
+            let ranges = |frames: &BTreeSet<u64>| json_string(&format_frame_ranges(frames));
+            field(
+                "expected_frames",
+                format!(
+                    "{{\"complete\": {}, \"incomplete\": {}, \"missing\": {}, \"unexpected\": {}}}",
+                    ranges(&report.complete),
+                    ranges(&report.incomplete),
+                    ranges(&report.missing),
+                    ranges(&report.unexpected),
+                ),
+            );
+        }
+        field(
+            "clock_drift_ppm",
+            stats
+                .clock_drift_ppm
+                .map_or("null".to_string(), |drift| format!("{drift:.3}")),
+        );
+        field("loss_fraction", self.loss_fraction().to_string());
+        field(
+            "output_error",
+            self.output_error
+                .as_deref()
+                .map_or("null".to_string(), json_string),
+        );
+        // The last field has no trailing comma
+        let verdict = if self.output_error.is_some() {
+            "failed"
+        } else if self.is_good() {
+            "good"
+        } else {
+            "bad"
+        };
+        let _ = writeln!(json, "  \"verdict\": {}\n}}", json_string(verdict));
+        json
+    }
+
+    /// Write the manifest into `directory`, returning its path
+    ///
+    /// The manifest is written under a temporary name and renamed into
+    /// place, so anything watching the directory never sees a partial one.
+    pub fn write(&self, directory: &Path) -> io::Result<PathBuf> {
+        let path = directory.join(format!("acquisition_{}.json", self.acquisition_number));
+        let mut file = GatedFile::create(&path)?;
+        file.write_all(self.to_json().as_bytes())?;
+        file.finish(true)?;
+        Ok(path)
+    }
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_lists_trigger_files_and_streams() {
+        let manifest = AcquisitionManifest {
+            acquisition_number: 7,
+            trigger_uuid: Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xab, 0xff]),
+            outputs: vec![PathBuf::from("/data/acquisition_7.raw")],
+            streams: vec!["tcp:viewer:9000".to_string()],
+            frames: 10,
+            ..Default::default()
+        };
+        let json = manifest.to_json();
+        assert!(json.contains("\"acquisition_number\": 7,"));
+        assert!(json.contains("\"trigger_uuid\": \"00010203040506070809abff\","));
+        assert!(json.contains("\"outputs\": [\"/data/acquisition_7.raw\"],"));
+        assert!(json.contains("\"streams\": [\"tcp:viewer:9000\"],"));
+        assert!(json.contains("\"verdict\": \"good\""));
+
+        let unknown = AcquisitionManifest::default().to_json();
+        assert!(unknown.contains("\"trigger_uuid\": null,"));
+        assert!(unknown.contains("\"outputs\": [],"));
+    }
+
+    #[test]
+    fn manifest_of_unfinished_outputs_has_failed() {
+        let manifest = AcquisitionManifest {
+            acquisition_number: 3,
+            output_error: Some("Disk \"data\" is full".to_string()),
+            ..Default::default()
+        };
+        assert!(!manifest.is_good());
+        let json = manifest.to_json();
+        assert!(json.contains("\"output_error\": \"Disk \\\"data\\\" is full\","));
+        assert!(json.contains("\"verdict\": \"failed\""));
+
+        let finished = AcquisitionManifest::default().to_json();
+        assert!(finished.contains("\"output_error\": null,"));
+    }
+
+    #[test]
+    fn manifest_is_renamed_into_place() {
+        let directory =
+            std::env::temp_dir().join(format!("morgul-test-{}-manifest", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let manifest = AcquisitionManifest {
+            acquisition_number: 2,
+            ..Default::default()
+        };
+        let path = manifest.write(&directory).unwrap();
+        assert_eq!(path, directory.join("acquisition_2.json"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().next(),
+            Some("{")
+        );
+        assert!(!directory.join("acquisition_2.json.partial").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+//! Masking out known-bad pixels in completed frames
+
+use std::path::Path;
+
+use crate::{MorgulError, PortGeometry};
+
+/// The pixels of a module that are known to be bad, to zero before analysis
+#[derive(Debug, Clone)]
+pub struct PixelMask {
+    width: usize,
+    height: usize,
+    /// The index of every bad pixel, as `y * width + x`
+    bad: Vec<usize>,
+}
+
+impl PixelMask {
+    /// Load a mask for modules of `geometry`'s size
+    ///
+    /// A `.raw` file is an image of one byte per pixel, non-zero where the
+    /// pixel is bad, and must be exactly the size of a module. Anything
+    /// else is a text file of `x,y` pixel positions, one per line, where
+    /// blank lines and those starting with `#` are ignored.
+    pub fn load(path: &Path, geometry: &PortGeometry) -> Result<Self, MorgulError> {
+        let (width, height) = (geometry.size_x, geometry.size_y);
+        let invalid = |reason: String| MorgulError::InvalidGeometry {
+            source: None,
+            reason: format!("Mask {}: {reason}", path.display()),
+        };
+        let bad = if path.extension().is_some_and(|e| e == "raw") {
+            let image = std::fs::read(path)?;
+            if image.len() != width * height {
+                return Err(invalid(format!(
+                    "{} pixels, but modules are {width}x{height}",
+                    image.len()
+                )));
+            }
+            image
+                .iter()
+                .enumerate()
+                .filter(|(_, bad)| **bad != 0)
+                .map(|(i, _)| i)
+                .collect()
+        } else {
+            let mut bad = Vec::new();
+            for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let position: Option<(usize, usize)> = line
+                    .split_once(',')
+                    .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)));
+                let Some((x, y)) = position else {
+                    return Err(invalid(format!("line {} is not x,y: '{line}'", number + 1)));
+                };
+                if x >= width || y >= height {
+                    return Err(invalid(format!(
+                        "pixel {x},{y} is outside a {width}x{height} module"
+                    )));
+                }
+                bad.push(y * width + x);
+            }
+            bad.sort_unstable();
+            bad.dedup();
+            bad
+        };
+        Ok(PixelMask { width, height, bad })
+    }
+
+    /// How many pixels are masked
+    pub fn len(&self) -> usize {
+        self.bad.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bad.is_empty()
+    }
+
+    /// Zero every bad pixel of a module's frame data
+    pub fn apply(&self, data: &mut [u8]) -> Result<(), MorgulError> {
+        let pixels = self.width * self.height;
+        if !data.len().is_multiple_of(pixels) {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Can't mask a {} byte frame with a {}x{} mask",
+                    data.len(),
+                    self.width,
+                    self.height
+                ),
+            });
+        }
+        let pixel_size = data.len() / pixels;
+        for &pixel in &self.bad {
+            data[pixel * pixel_size..(pixel + 1) * pixel_size].fill(0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GeometryMap, SlsDetectorType};
+
+    fn jungfrau() -> PortGeometry {
+        *GeometryMap::with_defaults(16)
+            .get(SlsDetectorType::Jungfrau as u8)
+            .unwrap()
+    }
+
+    /// Write a mask file, returning its path
+    fn mask_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("morgul-test-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn listed_pixels_are_zeroed() {
+        let path = mask_file("mask.txt", b"# bad pixels\n3, 0\n\n1023,255\n3,0\n");
+        let mask = PixelMask::load(&path, &jungfrau()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mask.len(), 2);
+
+        let mut data = vec![0xAB; 1024 * 256 * 2];
+        mask.apply(&mut data).unwrap();
+        assert_eq!(data.iter().filter(|b| **b == 0).count(), 4);
+        assert_eq!(&data[4..10], [0xAB, 0xAB, 0, 0, 0xAB, 0xAB]);
+        assert_eq!(&data[data.len() - 2..], [0, 0]);
+        // Frames the wrong size for the mask are refused
+        assert!(mask.apply(&mut data[1..]).is_err());
+    }
+
+    #[test]
+    fn mask_image_marks_nonzero_pixels_bad() {
+        let mut image = vec![0u8; 1024 * 256];
+        image[1024 + 5] = 1;
+        image[7] = 0xFF;
+        let path = mask_file("mask.raw", &image);
+        let mask = PixelMask::load(&path, &jungfrau()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut data = vec![1u16; 1024 * 256];
+        mask.apply(bytemuck::cast_slice_mut(&mut data)).unwrap();
+        assert_eq!(data[7], 0);
+        assert_eq!(data[1024 + 5], 0);
+        assert_eq!(data.iter().filter(|v| **v == 0).count(), 2);
+    }
+
+    #[test]
+    fn masks_that_do_not_fit_the_module_are_rejected() {
+        let path = mask_file("small.raw", &[0; 1024 * 255]);
+        assert!(PixelMask::load(&path, &jungfrau()).is_err());
+        std::fs::remove_file(&path).unwrap();
+        for contents in ["1024,0", "0,256", "12", "x,y"] {
+            let path = mask_file("bad.txt", contents.as_bytes());
+            assert!(PixelMask::load(&path, &jungfrau()).is_err(), "{contents}");
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
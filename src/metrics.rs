@@ -0,0 +1,113 @@
+//! Serving per-port counters for Prometheus to scrape, with the `metrics` feature
+//!
+//! Counters are updated as each listener ends an acquisition, and served
+//! as Prometheus text format from `GET /metrics` on a small HTTP server.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::assembler::AcquisitionStats;
+
+/// How long to wait for a scraper to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Running totals for one port
+#[derive(Debug, Default, Clone, Copy)]
+struct PortCounters {
+    packets_dropped: usize,
+    complete_images: usize,
+    images_seen: usize,
+}
+
+/// The counters for every port that has ended an acquisition
+#[derive(Debug, Default, Clone)]
+pub struct PortMetrics {
+    ports: Arc<Mutex<BTreeMap<u16, PortCounters>>>,
+}
+
+impl PortMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the stats of one listener's acquisition to its port's counters
+    pub fn record(&self, port: u16, stats: &AcquisitionStats) {
+        let mut ports = self.ports.lock().unwrap();
+        let counters = ports.entry(port).or_default();
+        counters.packets_dropped += stats.packets_dropped;
+        counters.complete_images += stats.complete_images;
+        counters.images_seen += stats.images_seen;
+    }
+
+    /// The counters, in Prometheus text exposition format
+    pub fn to_prometheus_text(&self) -> String {
+        let ports = self.ports.lock().unwrap();
+        let mut text = String::new();
+        let mut counter = |name: &str, help: &str, value: fn(&PortCounters) -> usize| {
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} counter");
+            for (port, counters) in ports.iter() {
+                let _ = writeln!(text, "{name}{{port=\"{port}\"}} {}", value(counters));
+            }
+        };
+        counter(
+            "morgul_packets_dropped_total",
+            "Packets missing from assembled images",
+            |c| c.packets_dropped,
+        );
+        counter(
+            "morgul_complete_images_total",
+            "Images assembled with every packet",
+            |c| c.complete_images,
+        );
+        counter(
+            "morgul_images_seen_total",
+            "Images at least one packet was received for",
+            |c| c.images_seen,
+        );
+        text
+    }
+
+    /// Serve the counters on `address` from a background thread
+    pub fn serve(&self, address: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = metrics.respond(stream) {
+                    println!("Warning: Could not answer metrics request: {e}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Read the rest of the headers, so the client sees a clean close
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.to_prometheus_text()),
+            _ => ("404 Not Found", String::new()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
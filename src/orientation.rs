@@ -0,0 +1,306 @@
+//! Flipping and rotating module images into the laboratory frame
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::{
+    AlignedBuffer, CompletedFrame, DEFAULT_BUFFER_ALIGNMENT, GeometryMap, MorgulError,
+    PooledBuffer, PortGeometry, roi::RegionOfInterest,
+};
+
+/// How many reoriented frames can be held by sinks at once
+const ORIENTED_BUFFER_LENGTH: usize = 4;
+
+/// How to turn a module's image to match how it is mounted
+///
+/// The flips are applied first, then the image is rotated clockwise.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Orientation {
+    /// Mirror left to right
+    pub flip_horizontal: bool,
+    /// Mirror top to bottom
+    pub flip_vertical: bool,
+    /// Clockwise rotation in degrees: 0, 90, 180 or 270
+    pub rotation: u16,
+}
+
+/// Parse from a comma-separated list of `fliph`, `flipv`, `rot90`,
+/// `rot180` and `rot270`, or `none`
+impl FromStr for Orientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut orientation = Orientation::default();
+        for part in s.split(',').map(str::trim) {
+            match part {
+                "none" => {}
+                "fliph" => orientation.flip_horizontal = true,
+                "flipv" => orientation.flip_vertical = true,
+                "rot90" | "rot180" | "rot270" if orientation.rotation == 0 => {
+                    orientation.rotation = part[3..].parse().unwrap();
+                }
+                "rot90" | "rot180" | "rot270" => {
+                    return Err("Only one rotation can be given".to_string());
+                }
+                _ => return Err(format!("Unknown orientation '{part}'")),
+            }
+        }
+        Ok(orientation)
+    }
+}
+
+impl Orientation {
+    pub fn is_identity(&self) -> bool {
+        *self == Orientation::default()
+    }
+
+    /// The width and height of a `width` x `height` image once oriented
+    pub fn oriented_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self.rotation {
+            90 | 270 => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    /// Which pixel of the original image ends up at `(x, y)` once oriented
+    pub fn source_pixel(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        // Undo the rotation, to get back to the flipped image...
+        let (sx, sy) = match self.rotation {
+            90 => (y, height - 1 - x),
+            180 => (width - 1 - x, height - 1 - y),
+            270 => (width - 1 - y, x),
+            _ => (x, y),
+        };
+        // ...then undo the flips
+        (
+            if self.flip_horizontal {
+                width - 1 - sx
+            } else {
+                sx
+            },
+            if self.flip_vertical {
+                height - 1 - sy
+            } else {
+                sy
+            },
+        )
+    }
+
+    /// The region of the original image that ends up as `region` once oriented
+    pub fn source_region(
+        &self,
+        region: RegionOfInterest,
+        width: usize,
+        height: usize,
+    ) -> RegionOfInterest {
+        let (x0, y0) = self.source_pixel(region.x, region.y, width, height);
+        let (x1, y1) = self.source_pixel(
+            region.x + region.width - 1,
+            region.y + region.height - 1,
+            width,
+            height,
+        );
+        RegionOfInterest {
+            x: x0.min(x1),
+            y: y0.min(y1),
+            width: x0.abs_diff(x1) + 1,
+            height: y0.abs_diff(y1) + 1,
+        }
+    }
+
+    /// Write the oriented copy of a `width` x `height` image into `output`
+    pub fn apply(
+        &self,
+        input: &[u8],
+        width: usize,
+        height: usize,
+        pixel_size: usize,
+        output: &mut [u8],
+    ) {
+        let (oriented_width, _) = self.oriented_size(width, height);
+        let row_size = oriented_width * pixel_size;
+        for (y, row) in output.chunks_exact_mut(row_size).enumerate() {
+            // Without rotation or mirroring, each row is a straight copy
+            if self.rotation == 0 && !self.flip_horizontal {
+                let start = self.source_pixel(0, y, width, height).1 * row_size;
+                row.copy_from_slice(&input[start..start + row_size]);
+                continue;
+            }
+            for (x, pixel) in row.chunks_exact_mut(pixel_size).enumerate() {
+                let (sx, sy) = self.source_pixel(x, y, width, height);
+                let start = (sy * width + sx) * pixel_size;
+                pixel.copy_from_slice(&input[start..start + pixel_size]);
+            }
+        }
+    }
+}
+
+/// The orientation of one module, identified by the module_id in its headers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ModuleOrientation {
+    pub module_id: u16,
+    pub orientation: Orientation,
+}
+
+/// Parse from `<module_id>:<orientation>`, e.g. `3:flipv,rot90`
+impl FromStr for ModuleOrientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (module_id, orientation) = s
+            .split_once(':')
+            .ok_or_else(|| "Expected <module_id>:<orientation>".to_string())?;
+        Ok(ModuleOrientation {
+            module_id: module_id.trim().parse().map_err(|e| format!("{e}"))?,
+            orientation: orientation.parse()?,
+        })
+    }
+}
+
+/// The geometry of a frame's detector, if its pixels can be reoriented
+pub(crate) fn pixel_geometry(
+    geometry: &GeometryMap,
+    frame: &CompletedFrame,
+) -> Result<(PortGeometry, usize), MorgulError> {
+    let geometry = *geometry
+        .get(frame.header.det_type)
+        .ok_or(MorgulError::UnknownDetectorType(frame.header.det_type))?;
+    if !geometry.bit_depth.is_multiple_of(8) {
+        return Err(MorgulError::InvalidGeometry {
+            source: None,
+            reason: format!("Can't rearrange {} bit pixels", geometry.bit_depth),
+        });
+    }
+    Ok((geometry, geometry.bit_depth / 8))
+}
+
+/// Reorients whole frames from each module, as new frames
+///
+/// The oriented frame keeps the header, completeness and packet mask of
+/// the original. The packet mask still refers to the packets as they were
+/// sent, not to where their pixels end up.
+pub struct Orienter {
+    orientations: HashMap<u16, Orientation>,
+    geometry: GeometryMap,
+    buffer_return: Sender<AlignedBuffer>,
+    spare_buffers: Receiver<AlignedBuffer>,
+}
+
+impl Orienter {
+    pub fn new(orientations: &[ModuleOrientation], geometry: GeometryMap) -> Self {
+        let (buffer_return, spare_buffers) = mpsc::channel();
+        for _ in 0..ORIENTED_BUFFER_LENGTH {
+            buffer_return.send(AlignedBuffer::default()).unwrap();
+        }
+        Orienter {
+            orientations: orientations
+                .iter()
+                .map(|m| (m.module_id, m.orientation))
+                .collect(),
+            geometry,
+            buffer_return,
+            spare_buffers,
+        }
+    }
+
+    /// Orient a frame, or pass it back untouched if its module needs no change
+    pub fn orient(&mut self, frame: CompletedFrame) -> Result<CompletedFrame, MorgulError> {
+        let Some(orientation) = self
+            .orientations
+            .get(&frame.header.module_id)
+            .filter(|o| !o.is_identity())
+        else {
+            return Ok(frame);
+        };
+        let (geometry, pixel_size) = pixel_geometry(&self.geometry, &frame)?;
+        let mut buffer = self
+            .spare_buffers
+            .try_recv()
+            .map_err(|_| MorgulError::BufferPoolExhausted)?;
+        if buffer.len() != frame.data.len() {
+            buffer = AlignedBuffer::new(frame.data.len(), DEFAULT_BUFFER_ALIGNMENT);
+        }
+        orientation.apply(
+            &frame.data,
+            geometry.size_x,
+            geometry.size_y,
+            pixel_size,
+            &mut buffer,
+        );
+        Ok(CompletedFrame {
+            header: frame.header,
+            complete: frame.complete,
+            received_mask: frame.received_mask,
+            data: PooledBuffer::new(buffer, self.buffer_return.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SlsDetectorHeader, SlsDetectorType};
+    use bytemuck::Zeroable;
+
+    /// Orient a 3x2 image of one-byte pixels numbered 0 to 5, row by row
+    fn oriented(orientation: &str) -> Vec<u8> {
+        let orientation: Orientation = orientation.parse().unwrap();
+        let mut output = vec![0; 6];
+        orientation.apply(&[0, 1, 2, 3, 4, 5], 3, 2, 1, &mut output);
+        output
+    }
+
+    #[test]
+    fn asymmetric_pattern_is_oriented() {
+        assert_eq!(oriented("none"), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(oriented("fliph"), [2, 1, 0, 5, 4, 3]);
+        assert_eq!(oriented("flipv"), [3, 4, 5, 0, 1, 2]);
+        assert_eq!(oriented("rot90"), [3, 0, 4, 1, 5, 2]);
+        assert_eq!(oriented("rot180"), [5, 4, 3, 2, 1, 0]);
+        assert_eq!(oriented("rot270"), [2, 5, 1, 4, 0, 3]);
+        // Flipped first, then rotated
+        assert_eq!(oriented("fliph,rot90"), [5, 2, 4, 1, 3, 0]);
+        assert_eq!(oriented("fliph,flipv"), oriented("rot180"));
+    }
+
+    #[test]
+    fn only_one_rotation_is_allowed() {
+        assert!("rot90,rot180".parse::<Orientation>().is_err());
+        assert!("spin".parse::<Orientation>().is_err());
+        let module: ModuleOrientation = "3:flipv,rot270".parse().unwrap();
+        assert_eq!(module.module_id, 3);
+        assert_eq!(module.orientation.rotation, 270);
+        assert!(module.orientation.flip_vertical);
+    }
+
+    #[test]
+    fn orienter_only_touches_configured_modules() {
+        let geometry = GeometryMap::with_defaults(16);
+        let mut orienter = Orienter::new(&["1:rot180".parse().unwrap()], geometry);
+        let frame = |module_id| {
+            let mut header = SlsDetectorHeader::zeroed();
+            header.det_type = SlsDetectorType::Jungfrau as u8;
+            header.module_id = module_id;
+            let mut buffer = AlignedBuffer::new(1024 * 256 * 2, DEFAULT_BUFFER_ALIGNMENT);
+            for (i, pixel) in buffer.chunks_exact_mut(2).enumerate() {
+                pixel.copy_from_slice(&(i as u16).to_le_bytes());
+            }
+            CompletedFrame {
+                header,
+                complete: true,
+                received_mask: u64::MAX,
+                data: PooledBuffer::new(buffer, mpsc::channel().0),
+            }
+        };
+        let untouched = orienter.orient(frame(0)).unwrap();
+        assert_eq!(&untouched.data[..4], [0, 0, 1, 0]);
+        let turned = orienter.orient(frame(1)).unwrap();
+        let last = (1024 * 256 - 1) as u16;
+        assert_eq!(&turned.data[..2], last.to_le_bytes());
+        assert_eq!(&turned.data[turned.data.len() - 2..], [0, 0]);
+        assert_eq!(turned.received_mask, u64::MAX);
+    }
+}
@@ -0,0 +1,389 @@
+//! Writing completed frames to disk
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    CompletedFrame, GeometryMap, MorgulError, SlsDetectorType, jungfrau::split_gain_adc,
+    sink::FrameSink,
+};
+
+/// A file that is only kept if the acquisition written to it turns out to be good
+///
+/// Data is written to a temporary `.partial` file alongside the destination,
+/// so that an acquisition of any size can be gated without holding it in
+/// memory. Once the verdict is known, [`GatedFile::finish`] either renames
+/// it into place or deletes it.
+pub struct GatedFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl GatedFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".partial");
+        let temp_path = PathBuf::from(temp_path);
+        Ok(GatedFile {
+            path: path.to_owned(),
+            file: BufWriter::new(File::create(&temp_path)?),
+            temp_path,
+        })
+    }
+
+    /// The path that the file will have if it is kept
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Close the file, and either move it into place or delete it
+    ///
+    /// Returns the final path if the file was kept.
+    pub fn finish(self, keep: bool) -> io::Result<Option<PathBuf>> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        if !keep {
+            drop(file);
+            std::fs::remove_file(&self.temp_path)?;
+            return Ok(None);
+        }
+        file.sync_all()?;
+        std::fs::rename(&self.temp_path, &self.path)?;
+        Ok(Some(self.path))
+    }
+}
+
+impl Write for GatedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the raw data of every frame to one file per acquisition
+///
+/// Frames are concatenated, in the order they were completed, with no
+/// headers. The acquisition number isn't known until it ends, so frames
+/// go into `acquisition.raw.partial` until then, and the file is renamed
+/// to `acquisition_<n>.raw`.
+///
+/// With [`RawFileSink::set_split_gain`], Jungfrau frames are written as
+/// their 16 bit ADC values, with the gain bits of each pixel as one byte
+/// each in a separate `acquisition_<n>_gain.raw`.
+pub struct RawFileSink {
+    directory: PathBuf,
+    file: Option<BufWriter<File>>,
+    split_gain: bool,
+    gain_file: Option<BufWriter<File>>,
+    written: Vec<PathBuf>,
+}
+
+impl RawFileSink {
+    /// Write into `directory`, creating it if needed
+    pub fn create(directory: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        Ok(RawFileSink {
+            directory: directory.to_owned(),
+            file: None,
+            split_gain: false,
+            gain_file: None,
+            written: Vec::new(),
+        })
+    }
+
+    /// Write the gain bits of Jungfrau frames to their own file
+    pub fn set_split_gain(&mut self, split_gain: bool) {
+        self.split_gain = split_gain;
+    }
+
+    fn partial_path(&self) -> PathBuf {
+        self.directory.join("acquisition.raw.partial")
+    }
+
+    fn gain_partial_path(&self) -> PathBuf {
+        self.directory.join("acquisition_gain.raw.partial")
+    }
+}
+
+/// Close a file, making sure everything is on disk before it looks finished
+fn finish_file(file: BufWriter<File>, from: &Path, to: &Path) -> Result<(), MorgulError> {
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(from, to)?;
+    Ok(())
+}
+
+impl FrameSink for RawFileSink {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(BufWriter::new(File::create(self.partial_path())?)),
+        };
+        if !self.split_gain || frame.header.det_type != SlsDetectorType::Jungfrau as u8 {
+            file.write_all(&frame.data)?;
+            return Ok(());
+        }
+        let (gain, adc) = split_gain_adc(&frame.data);
+        for value in adc {
+            file.write_all(&value.to_le_bytes())?;
+        }
+        let gain_file = match &mut self.gain_file {
+            Some(file) => file,
+            None => self
+                .gain_file
+                .insert(BufWriter::new(File::create(self.gain_partial_path())?)),
+        };
+        gain_file.write_all(&gain)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MorgulError> {
+        for file in [&mut self.file, &mut self.gain_file].into_iter().flatten() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn end_acquisition(&mut self, acquisition_number: usize) -> Result<(), MorgulError> {
+        if let Some(file) = self.gain_file.take() {
+            let path = self
+                .directory
+                .join(format!("acquisition_{acquisition_number}_gain.raw"));
+            finish_file(file, &self.gain_partial_path(), &path)?;
+            self.written.push(path);
+        }
+        if let Some(file) = self.file.take() {
+            let path = self
+                .directory
+                .join(format!("acquisition_{acquisition_number}.raw"));
+            finish_file(file, &self.partial_path(), &path)?;
+            self.written.push(path);
+        }
+        Ok(())
+    }
+
+    fn take_written_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.written)
+    }
+}
+
+/// Writes every Nth frame as a grayscale TIFF, for quick visual checks
+///
+/// Frames are chosen by frame number, so that every port writes the same
+/// frames, and each goes in its own
+/// `det<det_type>_module<id>_row<row>_col<column>_frame<n>.tif`. The image
+/// is the shape of the port's geometry, so frames reshaped on the way here,
+/// e.g. by a region of interest, can't be written.
+pub struct TiffFrameSink {
+    directory: PathBuf,
+    geometry: GeometryMap,
+    every: u64,
+    written: Vec<PathBuf>,
+}
+
+impl TiffFrameSink {
+    /// Write every `every`th frame into `directory`, creating it if needed
+    pub fn create(directory: &Path, geometry: GeometryMap, every: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        Ok(TiffFrameSink {
+            directory: directory.to_owned(),
+            geometry,
+            every: every.max(1),
+            written: Vec::new(),
+        })
+    }
+}
+
+impl FrameSink for TiffFrameSink {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        let header = &frame.header;
+        if !header.frame_number.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        let unwritable =
+            |reason: String| MorgulError::Io(io::Error::new(io::ErrorKind::InvalidData, reason));
+        let geometry = self
+            .geometry
+            .get(header.det_type)
+            .ok_or(MorgulError::UnknownDetectorType(header.det_type))?;
+        if frame.data.len() != geometry.frame_size() {
+            return Err(unwritable(format!(
+                "Can't write a {} byte frame as a {}x{} TIFF",
+                frame.data.len(),
+                geometry.size_x,
+                geometry.size_y
+            )));
+        }
+        if ![8, 16, 32].contains(&geometry.bit_depth) {
+            return Err(unwritable(format!(
+                "Can't write {} bit pixels as a TIFF",
+                geometry.bit_depth
+            )));
+        }
+        let path = self.directory.join(format!(
+            "det{}_module{}_row{}_col{}_frame{}.tif",
+            header.det_type, header.module_id, header.row, header.column, header.frame_number
+        ));
+        let mut file = GatedFile::create(&path)?;
+        write_tiff(
+            &mut file,
+            geometry.size_x as u32,
+            geometry.size_y as u32,
+            geometry.bit_depth as u16,
+            &frame.data,
+        )?;
+        self.written.extend(file.finish(true)?);
+        Ok(())
+    }
+
+    fn take_written_files(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.written)
+    }
+}
+
+/// Write a little-endian, uncompressed, single-strip grayscale TIFF
+///
+/// `data` holds unsigned little-endian pixels, row by row.
+fn write_tiff(
+    writer: &mut impl Write,
+    width: u32,
+    height: u32,
+    bits: u16,
+    data: &[u8],
+) -> io::Result<()> {
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+    // Sorted by tag, as TIFF requires
+    let entries: [(u16, u16, u32); 10] = [
+        (256, LONG, width),             // ImageWidth
+        (257, LONG, height),            // ImageLength
+        (258, SHORT, bits as u32),      // BitsPerSample
+        (259, SHORT, 1),                // Compression: none
+        (262, SHORT, 1),                // PhotometricInterpretation: black is zero
+        (273, LONG, 0),                 // StripOffsets, filled in below
+        (277, SHORT, 1),                // SamplesPerPixel
+        (278, LONG, height),            // RowsPerStrip
+        (279, LONG, data.len() as u32), // StripByteCounts
+        (339, SHORT, 1),                // SampleFormat: unsigned integer
+    ];
+    let data_offset = 8 + 2 + entries.len() as u32 * 12 + 4;
+
+    writer.write_all(b"II")?;
+    writer.write_all(&42u16.to_le_bytes())?;
+    writer.write_all(&8u32.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u16).to_le_bytes())?;
+    for (tag, kind, value) in entries {
+        let value = if tag == 273 { data_offset } else { value };
+        writer.write_all(&tag.to_le_bytes())?;
+        writer.write_all(&kind.to_le_bytes())?;
+        writer.write_all(&1u32.to_le_bytes())?;
+        // Values smaller than four bytes are left-justified
+        match kind {
+            SHORT => {
+                writer.write_all(&(value as u16).to_le_bytes())?;
+                writer.write_all(&[0, 0])?;
+            }
+            _ => writer.write_all(&value.to_le_bytes())?,
+        }
+    }
+    // No further images
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Fraction of expected packets that never arrived, or 0 if none were expected
+pub fn loss_fraction(packets_expected: usize, packets_dropped: usize) -> f64 {
+    if packets_expected == 0 {
+        0.0
+    } else {
+        packets_dropped as f64 / packets_expected as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AlignedBuffer, PooledBuffer, SlsDetectorHeader};
+    use bytemuck::Zeroable;
+
+    /// An empty directory for one test to write into
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("morgul-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn frame(frame_number: u64, data: &[u8]) -> CompletedFrame {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = frame_number;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        let mut buffer = AlignedBuffer::new(data.len(), 64);
+        buffer.copy_from_slice(data);
+        CompletedFrame {
+            header,
+            complete: true,
+            received_mask: u64::MAX,
+            data: PooledBuffer::new(buffer, std::sync::mpsc::channel().0),
+        }
+    }
+
+    #[test]
+    fn raw_sink_reports_the_files_it_finished() {
+        let directory = test_directory("raw-written");
+        let mut sink = RawFileSink::create(&directory).unwrap();
+        sink.set_split_gain(true);
+        sink.write_frame(&frame(1, &[0x01, 0x40, 0x02, 0x80]))
+            .unwrap();
+        assert!(sink.take_written_files().is_empty());
+        sink.end_acquisition(3).unwrap();
+
+        let written = sink.take_written_files();
+        assert_eq!(
+            written,
+            [
+                directory.join("acquisition_3_gain.raw"),
+                directory.join("acquisition_3.raw")
+            ]
+        );
+        assert_eq!(std::fs::read(&written[0]).unwrap(), [1, 2]);
+        assert_eq!(std::fs::read(&written[1]).unwrap(), [1, 0, 2, 0]);
+        // Each file is only reported once
+        sink.end_acquisition(4).unwrap();
+        assert!(sink.take_written_files().is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn tiff_sink_reports_every_image_written() {
+        let directory = test_directory("tiff-written");
+        let geometry = GeometryMap::with_defaults(16);
+        let size = geometry
+            .get(SlsDetectorType::Jungfrau as u8)
+            .unwrap()
+            .frame_size();
+        let mut sink = TiffFrameSink::create(&directory, geometry, 2).unwrap();
+        for frame_number in 1..=4 {
+            sink.write_frame(&frame(frame_number, &vec![0; size]))
+                .unwrap();
+        }
+        let written = sink.take_written_files();
+        assert_eq!(
+            written,
+            [
+                directory.join("det3_module0_row0_col0_frame2.tif"),
+                directory.join("det3_module0_row0_col0_frame4.tif")
+            ]
+        );
+        assert!(written.iter().all(|path| path.exists()));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Per-pixel statistics accumulated over an acquisition, e.g. for noise maps
+
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{CompletedFrame, MorgulError, output::GatedFile};
+
+/// Accumulates the mean and variance of every pixel over many frames
+///
+/// This uses Welford's online algorithm, which stays numerically stable
+/// however many frames are added, unlike summing values and their squares.
+/// Only complete frames are used, as missing packets would look like
+/// (very noisy) pixel values.
+#[derive(Debug, Clone, Default)]
+pub struct PixelStatsAccumulator {
+    count: u64,
+    mean: Vec<f64>,
+    /// Sum of squared differences from the current mean
+    m2: Vec<f64>,
+}
+
+impl PixelStatsAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many frames have been added
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Add the pixels of a frame with pixels of `bit_depth` bits
+    ///
+    /// Every frame must have the same number of pixels.
+    pub fn add_frame(
+        &mut self,
+        frame: &CompletedFrame,
+        bit_depth: usize,
+    ) -> Result<(), MorgulError> {
+        if !frame.complete {
+            return Ok(());
+        }
+        let pixels: Vec<f64> = match bit_depth {
+            8 => frame.data.iter().map(|&v| v as f64).collect(),
+            16 => frame
+                .data
+                .chunks_exact(2)
+                .map(|v| u16::from_le_bytes([v[0], v[1]]) as f64)
+                .collect(),
+            32 => frame
+                .data
+                .chunks_exact(4)
+                .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as f64)
+                .collect(),
+            _ => {
+                return Err(MorgulError::InvalidGeometry {
+                    source: None,
+                    reason: format!("Can't accumulate statistics of {bit_depth} bit pixels"),
+                });
+            }
+        };
+        self.add_pixels(&pixels)
+    }
+
+    /// Add one frame, given as the value of every pixel
+    pub fn add_pixels(&mut self, pixels: &[f64]) -> Result<(), MorgulError> {
+        if self.count == 0 {
+            self.mean = vec![0.0; pixels.len()];
+            self.m2 = vec![0.0; pixels.len()];
+        } else if pixels.len() != self.mean.len() {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Frame has {} pixels, but earlier frames had {}",
+                    pixels.len(),
+                    self.mean.len()
+                ),
+            });
+        }
+        self.count += 1;
+        let n = self.count as f64;
+        for ((&value, mean), m2) in pixels.iter().zip(&mut self.mean).zip(&mut self.m2) {
+            let delta = value - *mean;
+            *mean += delta / n;
+            *m2 += delta * (value - *mean);
+        }
+        Ok(())
+    }
+
+    /// The mean of every pixel
+    pub fn mean(&self) -> &[f64] {
+        &self.mean
+    }
+
+    /// The (sample) variance of every pixel, or zeroes with fewer than two frames
+    pub fn variance(&self) -> Vec<f64> {
+        if self.count < 2 {
+            return vec![0.0; self.m2.len()];
+        }
+        let n = (self.count - 1) as f64;
+        self.m2.iter().map(|m2| m2 / n).collect()
+    }
+
+    /// Write the mean and variance maps as `<prefix>_mean.npy` and `<prefix>_variance.npy`
+    pub fn write_npy(&self, prefix: &Path) -> io::Result<()> {
+        let with_suffix = |suffix: &str| {
+            let mut path = prefix.as_os_str().to_owned();
+            path.push(suffix);
+            path
+        };
+        write_npy(Path::new(&with_suffix("_mean.npy")), &self.mean)?;
+        write_npy(Path::new(&with_suffix("_variance.npy")), &self.variance())
+    }
+}
+
+/// Write a one-dimensional array of doubles as a numpy `.npy` file
+pub fn write_npy(path: &Path, values: &[f64]) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({},), }}",
+        values.len()
+    );
+    // The magic, version and header length take 10 bytes, and the whole
+    // header must end in a newline at a multiple of 64 bytes
+    let padding = (10 + header.len() + 1).next_multiple_of(64) - (10 + header.len() + 1);
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    let mut file = GatedFile::create(path)?;
+    file.write_all(b"\x93NUMPY\x01\x00")?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    for value in values {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    file.finish(true)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AlignedBuffer, PooledBuffer, SlsDetectorHeader};
+    use bytemuck::Zeroable;
+
+    /// Two-pass mean and sample variance, to check the online ones against
+    fn batch_stats(values: &[f64]) -> (f64, f64) {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, variance)
+    }
+
+    #[test]
+    fn online_variance_matches_batch() {
+        // A large offset with small noise is where summing squares falls apart
+        let frames: Vec<[f64; 3]> = (0..10_000u64)
+            .map(|i| {
+                let noise = ((i * 7919) % 1000) as f64 / 10.0;
+                [1e9 + noise, noise, 5.0 + (i % 2) as f64]
+            })
+            .collect();
+        let mut accumulator = PixelStatsAccumulator::new();
+        for frame in &frames {
+            accumulator.add_pixels(frame).unwrap();
+        }
+        assert_eq!(accumulator.count(), 10_000);
+        let variance = accumulator.variance();
+        for pixel in 0..3 {
+            let values: Vec<f64> = frames.iter().map(|f| f[pixel]).collect();
+            let (mean, expected) = batch_stats(&values);
+            assert!((accumulator.mean()[pixel] - mean).abs() <= 1e-6 * mean.abs().max(1.0));
+            assert!(
+                (variance[pixel] - expected).abs() <= 1e-9 * expected,
+                "pixel {pixel}: {} != {expected}",
+                variance[pixel]
+            );
+        }
+    }
+
+    #[test]
+    fn frames_are_decoded_and_incomplete_ones_skipped() {
+        let frame = |values: &[u16], complete: bool| {
+            let mut buffer = AlignedBuffer::new(values.len() * 2, 64);
+            for (out, value) in buffer.chunks_exact_mut(2).zip(values) {
+                out.copy_from_slice(&value.to_le_bytes());
+            }
+            CompletedFrame {
+                header: SlsDetectorHeader::zeroed(),
+                complete,
+                received_mask: 0,
+                data: PooledBuffer::new(buffer, std::sync::mpsc::channel().0),
+            }
+        };
+        let mut accumulator = PixelStatsAccumulator::new();
+        accumulator
+            .add_frame(&frame(&[10, 1000], true), 16)
+            .unwrap();
+        accumulator
+            .add_frame(&frame(&[60000, 0], false), 16)
+            .unwrap();
+        accumulator
+            .add_frame(&frame(&[20, 3000], true), 16)
+            .unwrap();
+        assert_eq!(accumulator.count(), 2);
+        assert_eq!(accumulator.mean(), [15.0, 2000.0]);
+        assert_eq!(accumulator.variance(), [50.0, 2e6]);
+        assert!(accumulator.add_frame(&frame(&[1], true), 16).is_err());
+        assert!(accumulator.add_frame(&frame(&[1, 2], true), 12).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Cutting a region of interest out of completed frames
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::{
+    AlignedBuffer, CompletedFrame, DEFAULT_BUFFER_ALIGNMENT, GeometryMap, MorgulError,
+    PooledBuffer,
+    orientation::{ModuleOrientation, Orientation, pixel_geometry},
+};
+
+/// How many extracted frames can be held by sinks at once
+const ROI_BUFFER_LENGTH: usize = 4;
+
+/// A rectangle of pixels within the image from one port
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RegionOfInterest {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Parse from `x,y,width,height`
+impl FromStr for RegionOfInterest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let [x, y, width, height] = values[..] else {
+            return Err("Expected x,y,width,height".to_string());
+        };
+        if width == 0 || height == 0 {
+            return Err("Region of interest must not be empty".to_string());
+        }
+        Ok(RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// Extracts a region of interest from each frame, as a new smaller frame
+///
+/// The extracted frame keeps the header, completeness and packet mask of
+/// the original, but its data is only the ROI, row-major at the original
+/// bit depth. Pixels that came from packets which never arrived are set to
+/// all ones (e.g. 0xFFFF at 16 bits) to mark them invalid.
+///
+/// If modules are reoriented, the region is in the oriented image, and the
+/// extracted frame is oriented to match.
+pub struct RoiExtractor {
+    roi: RegionOfInterest,
+    geometry: GeometryMap,
+    orientations: HashMap<u16, Orientation>,
+    /// The region in the module's original orientation, before reorienting
+    unoriented: Vec<u8>,
+    buffer_return: Sender<AlignedBuffer>,
+    spare_buffers: Receiver<AlignedBuffer>,
+}
+
+impl RoiExtractor {
+    pub fn new(roi: RegionOfInterest, geometry: GeometryMap) -> Self {
+        let (buffer_return, spare_buffers) = mpsc::channel();
+        for _ in 0..ROI_BUFFER_LENGTH {
+            buffer_return.send(AlignedBuffer::default()).unwrap();
+        }
+        RoiExtractor {
+            roi,
+            geometry,
+            orientations: HashMap::new(),
+            unoriented: Vec::new(),
+            buffer_return,
+            spare_buffers,
+        }
+    }
+
+    /// Take the region from each module in this orientation
+    pub fn set_orientations(&mut self, orientations: &[ModuleOrientation]) {
+        self.orientations = orientations
+            .iter()
+            .map(|m| (m.module_id, m.orientation))
+            .collect();
+    }
+
+    pub fn extract(&mut self, frame: &CompletedFrame) -> Result<CompletedFrame, MorgulError> {
+        let (geometry, pixel_size) = pixel_geometry(&self.geometry, frame)?;
+        let orientation = self
+            .orientations
+            .get(&frame.header.module_id)
+            .copied()
+            .unwrap_or_default();
+        let (oriented_x, oriented_y) = orientation.oriented_size(geometry.size_x, geometry.size_y);
+        let roi = self.roi;
+        if roi.x + roi.width > oriented_x || roi.y + roi.height > oriented_y {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Region of interest {}x{}+{}+{} does not fit in a {oriented_x}x{oriented_y} image",
+                    roi.width, roi.height, roi.x, roi.y
+                ),
+            });
+        }
+        // Where the region comes from, before the module is reoriented
+        let RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+        } = orientation.source_region(roi, geometry.size_x, geometry.size_y);
+        let row_size = width * pixel_size;
+
+        let mut buffer = self
+            .spare_buffers
+            .try_recv()
+            .map_err(|_| MorgulError::BufferPoolExhausted)?;
+        if buffer.len() != row_size * height {
+            buffer = AlignedBuffer::new(row_size * height, DEFAULT_BUFFER_ALIGNMENT);
+        }
+        // Without reorienting, extract straight into the output
+        let extracted: &mut [u8] = if orientation.is_identity() {
+            &mut buffer
+        } else {
+            self.unoriented.resize(row_size * height, 0);
+            &mut self.unoriented
+        };
+
+        for (row, out) in extracted.chunks_exact_mut(row_size).enumerate() {
+            let start = ((y + row) * geometry.size_x + x) * pixel_size;
+            out.copy_from_slice(&frame.data[start..start + row_size]);
+            if frame.complete {
+                continue;
+            }
+            // Mark anything that came from a missing packet as invalid
+            for (offset, byte) in (start..start + row_size).zip(out.iter_mut()) {
+                let packet = geometry.packet_at_offset(offset);
+                if frame.received_mask & (1 << packet) == 0 {
+                    *byte = 0xFF;
+                }
+            }
+        }
+        if !orientation.is_identity() {
+            orientation.apply(&self.unoriented, width, height, pixel_size, &mut buffer);
+        }
+
+        Ok(CompletedFrame {
+            header: frame.header,
+            complete: frame.complete,
+            received_mask: frame.received_mask,
+            data: PooledBuffer::new(buffer, self.buffer_return.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SlsDetectorHeader, SlsDetectorType, orientation::Orienter};
+    use bytemuck::Zeroable;
+
+    /// A Jungfrau frame from `module_id`, with each 16 bit pixel numbered in order
+    fn frame(module_id: u16) -> CompletedFrame {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.module_id = module_id;
+        let mut buffer = AlignedBuffer::new(1024 * 256 * 2, DEFAULT_BUFFER_ALIGNMENT);
+        for (i, pixel) in buffer.chunks_exact_mut(2).enumerate() {
+            pixel.copy_from_slice(&(i as u16).to_le_bytes());
+        }
+        CompletedFrame {
+            header,
+            complete: true,
+            received_mask: u64::MAX,
+            data: PooledBuffer::new(buffer, mpsc::channel().0),
+        }
+    }
+
+    /// Cut `roi` out of a whole image `width` pixels wide, at 16 bits
+    fn crop(data: &[u8], width: usize, roi: RegionOfInterest) -> Vec<u8> {
+        (roi.y..roi.y + roi.height)
+            .flat_map(|y| {
+                let start = (y * width + roi.x) * 2;
+                data[start..start + roi.width * 2].iter().copied()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn region_is_taken_from_the_oriented_image() {
+        let roi: RegionOfInterest = "10,20,30,40".parse().unwrap();
+        for orientation in ["none", "fliph", "flipv", "rot90", "rot180", "fliph,rot270"] {
+            let module: ModuleOrientation = format!("0:{orientation}").parse().unwrap();
+            let mut extractor = RoiExtractor::new(roi, GeometryMap::with_defaults(16));
+            extractor.set_orientations(&[module]);
+            let extracted = extractor.extract(&frame(0)).unwrap();
+
+            let mut orienter = Orienter::new(&[module], GeometryMap::with_defaults(16));
+            let whole = orienter.orient(frame(0)).unwrap();
+            let (width, _) = module.orientation.oriented_size(1024, 256);
+            assert_eq!(
+                &*extracted.data,
+                crop(&whole.data, width, roi),
+                "{orientation}"
+            );
+        }
+    }
+
+    #[test]
+    fn region_must_fit_the_oriented_image() {
+        // Fits across the unrotated module, but not once it is on its side
+        let roi: RegionOfInterest = "600,0,100,10".parse().unwrap();
+        let mut extractor = RoiExtractor::new(roi, GeometryMap::with_defaults(16));
+        assert!(extractor.extract(&frame(0)).is_ok());
+        extractor.set_orientations(&["0:rot90".parse().unwrap()]);
+        assert!(extractor.extract(&frame(0)).is_err());
+    }
+
+    #[test]
+    fn missing_packets_are_marked_in_the_region() {
+        let roi: RegionOfInterest = "0,0,4,16".parse().unwrap();
+        let mut extractor = RoiExtractor::new(roi, GeometryMap::with_defaults(16));
+        let mut incomplete = frame(0);
+        // Each Jungfrau packet is 4 rows, so only the first 8 rows arrived
+        incomplete.complete = false;
+        incomplete.received_mask = 0b11;
+        let extracted = extractor.extract(&incomplete).unwrap();
+        let row_size = 4 * 2;
+        assert_eq!(
+            &extracted.data[..8 * row_size],
+            crop(&frame(0).data, 1024, "0,0,4,8".parse().unwrap())
+        );
+        assert!(extracted.data[8 * row_size..].iter().all(|b| *b == 0xFF));
+    }
+}
@@ -0,0 +1,558 @@
+//! Serde support for the wire structs, with the `serde` feature
+//!
+//! These are written out by hand, rather than derived, so that the structs
+//! can stay plain `#[repr(C)]` wire layouts. The reserved `_det_spec_*`
+//! header fields are kept, as `det_spec_2` and `det_spec_4`, so that a
+//! header survives a round trip unchanged, and trigger UUIDs are written
+//! as hex strings.
+
+use std::fmt;
+
+use bytemuck::Zeroable;
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::SerializeStruct,
+};
+
+use crate::{DelugeTrigger, SlsDetectorHeader};
+
+const HEADER_FIELDS: &[&str] = &[
+    "frame_number",
+    "exposure_length",
+    "packet_number",
+    "bunch_id",
+    "timestamp",
+    "module_id",
+    "row",
+    "column",
+    "det_spec_2",
+    "daq_info",
+    "det_spec_4",
+    "det_type",
+    "version",
+];
+
+impl Serialize for SlsDetectorHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("SlsDetectorHeader", HEADER_FIELDS.len())?;
+        s.serialize_field("frame_number", &self.frame_number)?;
+        s.serialize_field("exposure_length", &self.exposure_length)?;
+        s.serialize_field("packet_number", &self.packet_number)?;
+        s.serialize_field("bunch_id", &self.bunch_id)?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        s.serialize_field("module_id", &self.module_id)?;
+        s.serialize_field("row", &self.row)?;
+        s.serialize_field("column", &self.column)?;
+        s.serialize_field("det_spec_2", &self._det_spec_2)?;
+        s.serialize_field("daq_info", &self.daq_info)?;
+        s.serialize_field("det_spec_4", &self._det_spec_4)?;
+        s.serialize_field("det_type", &self.det_type)?;
+        s.serialize_field("version", &self.version)?;
+        s.end()
+    }
+}
+
+struct HeaderVisitor;
+
+impl<'de> Visitor<'de> for HeaderVisitor {
+    type Value = SlsDetectorHeader;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an sls_detector_header")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let field = |index: usize| de::Error::invalid_length(index, &self);
+        Ok(SlsDetectorHeader {
+            frame_number: seq.next_element()?.ok_or_else(|| field(0))?,
+            exposure_length: seq.next_element()?.ok_or_else(|| field(1))?,
+            packet_number: seq.next_element()?.ok_or_else(|| field(2))?,
+            bunch_id: seq.next_element()?.ok_or_else(|| field(3))?,
+            timestamp: seq.next_element()?.ok_or_else(|| field(4))?,
+            module_id: seq.next_element()?.ok_or_else(|| field(5))?,
+            row: seq.next_element()?.ok_or_else(|| field(6))?,
+            column: seq.next_element()?.ok_or_else(|| field(7))?,
+            _det_spec_2: seq.next_element()?.ok_or_else(|| field(8))?,
+            daq_info: seq.next_element()?.ok_or_else(|| field(9))?,
+            _det_spec_4: seq.next_element()?.ok_or_else(|| field(10))?,
+            det_type: seq.next_element()?.ok_or_else(|| field(11))?,
+            version: seq.next_element()?.ok_or_else(|| field(12))?,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        // Reserved fields may be left out; anything else must be there
+        let mut header = SlsDetectorHeader::zeroed();
+        let mut seen = [false; HEADER_FIELDS.len()];
+        while let Some(key) = map.next_key::<String>()? {
+            let Some(index) = HEADER_FIELDS.iter().position(|f| *f == key) else {
+                return Err(de::Error::unknown_field(&key, HEADER_FIELDS));
+            };
+            if seen[index] {
+                return Err(de::Error::duplicate_field(HEADER_FIELDS[index]));
+            }
+            seen[index] = true;
+            match index {
+                0 => header.frame_number = map.next_value()?,
+                1 => header.exposure_length = map.next_value()?,
+                2 => header.packet_number = map.next_value()?,
+                3 => header.bunch_id = map.next_value()?,
+                4 => header.timestamp = map.next_value()?,
+                5 => header.module_id = map.next_value()?,
+                6 => header.row = map.next_value()?,
+                7 => header.column = map.next_value()?,
+                8 => header._det_spec_2 = map.next_value()?,
+                9 => header.daq_info = map.next_value()?,
+                10 => header._det_spec_4 = map.next_value()?,
+                11 => header.det_type = map.next_value()?,
+                _ => header.version = map.next_value()?,
+            }
+        }
+        if let Some(index) = (0..HEADER_FIELDS.len()).find(|&i| !seen[i] && i != 8 && i != 10) {
+            return Err(de::Error::missing_field(HEADER_FIELDS[index]));
+        }
+        Ok(header)
+    }
+}
+
+impl<'de> Deserialize<'de> for SlsDetectorHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("SlsDetectorHeader", HEADER_FIELDS, HeaderVisitor)
+    }
+}
+
+const TRIGGER_FIELDS: &[&str] = &["frames", "exptime", "uuid"];
+
+impl Serialize for DelugeTrigger {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("DelugeTrigger", TRIGGER_FIELDS.len())?;
+        s.serialize_field("frames", &self.frames)?;
+        s.serialize_field("exptime", &self.exptime)?;
+        s.serialize_field("uuid", &self.uuid_hex())?;
+        s.end()
+    }
+}
+
+fn parse_uuid<E: de::Error>(hex: &str) -> Result<[u8; 12], E> {
+    let invalid = || E::invalid_value(de::Unexpected::Str(hex), &"24 hex digits");
+    if hex.len() != 24 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut uuid = [0u8; 12];
+    for (byte, digits) in uuid.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
+    Ok(uuid)
+}
+
+struct TriggerVisitor;
+
+impl<'de> Visitor<'de> for TriggerVisitor {
+    type Value = DelugeTrigger;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a deluge trigger")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let field = |index: usize| de::Error::invalid_length(index, &self);
+        let frames = seq.next_element()?.ok_or_else(|| field(0))?;
+        let exptime = seq.next_element()?.ok_or_else(|| field(1))?;
+        let uuid: String = seq.next_element()?.ok_or_else(|| field(2))?;
+        Ok(DelugeTrigger {
+            frames,
+            exptime,
+            uuid: parse_uuid(&uuid)?,
+            ..DelugeTrigger::default()
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (mut frames, mut exptime, mut uuid) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "frames" => frames = Some(map.next_value()?),
+                "exptime" => exptime = Some(map.next_value()?),
+                "uuid" => uuid = Some(parse_uuid(&map.next_value::<String>()?)?),
+                _ => return Err(de::Error::unknown_field(&key, TRIGGER_FIELDS)),
+            }
+        }
+        Ok(DelugeTrigger {
+            frames: frames.ok_or_else(|| de::Error::missing_field("frames"))?,
+            exptime: exptime.ok_or_else(|| de::Error::missing_field("exptime"))?,
+            uuid: uuid.ok_or_else(|| de::Error::missing_field("uuid"))?,
+            ..DelugeTrigger::default()
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for DelugeTrigger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("DelugeTrigger", TRIGGER_FIELDS, TriggerVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use serde::{
+        de::value::Error,
+        ser::{self, Impossible},
+    };
+
+    use super::*;
+    use crate::SlsDetectorType;
+
+    /// Writes just enough JSON for the wire structs, as there's no
+    /// serde_json to round trip them through
+    struct JsonWriter<'a>(&'a mut String);
+
+    struct JsonStruct<'a> {
+        out: &'a mut String,
+        first: bool,
+    }
+
+    fn unsupported<T>() -> Result<T, Error> {
+        Err(ser::Error::custom("not used by the wire structs"))
+    }
+
+    impl<'a> Serializer for JsonWriter<'a> {
+        type Ok = ();
+        type Error = Error;
+        type SerializeSeq = Impossible<(), Error>;
+        type SerializeTuple = Impossible<(), Error>;
+        type SerializeTupleStruct = Impossible<(), Error>;
+        type SerializeTupleVariant = Impossible<(), Error>;
+        type SerializeMap = Impossible<(), Error>;
+        type SerializeStruct = JsonStruct<'a>;
+        type SerializeStructVariant = Impossible<(), Error>;
+
+        fn serialize_bool(self, v: bool) -> Result<(), Error> {
+            write!(self.0, "{v}").map_err(ser::Error::custom)
+        }
+        fn serialize_i8(self, v: i8) -> Result<(), Error> {
+            self.serialize_i64(v.into())
+        }
+        fn serialize_i16(self, v: i16) -> Result<(), Error> {
+            self.serialize_i64(v.into())
+        }
+        fn serialize_i32(self, v: i32) -> Result<(), Error> {
+            self.serialize_i64(v.into())
+        }
+        fn serialize_i64(self, v: i64) -> Result<(), Error> {
+            write!(self.0, "{v}").map_err(ser::Error::custom)
+        }
+        fn serialize_u8(self, v: u8) -> Result<(), Error> {
+            self.serialize_u128(v.into())
+        }
+        fn serialize_u16(self, v: u16) -> Result<(), Error> {
+            self.serialize_u128(v.into())
+        }
+        fn serialize_u32(self, v: u32) -> Result<(), Error> {
+            self.serialize_u128(v.into())
+        }
+        fn serialize_u64(self, v: u64) -> Result<(), Error> {
+            self.serialize_u128(v.into())
+        }
+        fn serialize_u128(self, v: u128) -> Result<(), Error> {
+            write!(self.0, "{v}").map_err(ser::Error::custom)
+        }
+        fn serialize_f32(self, v: f32) -> Result<(), Error> {
+            // Always with a point, so that it reads back as a float
+            write!(self.0, "{v:?}").map_err(ser::Error::custom)
+        }
+        fn serialize_f64(self, v: f64) -> Result<(), Error> {
+            write!(self.0, "{v:?}").map_err(ser::Error::custom)
+        }
+        fn serialize_char(self, v: char) -> Result<(), Error> {
+            self.serialize_str(&v.to_string())
+        }
+        fn serialize_str(self, v: &str) -> Result<(), Error> {
+            write!(self.0, "\"{v}\"").map_err(ser::Error::custom)
+        }
+        fn serialize_bytes(self, _: &[u8]) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_none(self) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_unit(self) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_unit_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+        ) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            _: &T,
+        ) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: &T,
+        ) -> Result<(), Error> {
+            unsupported()
+        }
+        fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+            unsupported()
+        }
+        fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+            unsupported()
+        }
+        fn serialize_tuple_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleStruct, Error> {
+            unsupported()
+        }
+        fn serialize_tuple_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleVariant, Error> {
+            unsupported()
+        }
+        fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+            unsupported()
+        }
+        fn serialize_struct(self, _: &'static str, _: usize) -> Result<JsonStruct<'a>, Error> {
+            self.0.push('{');
+            Ok(JsonStruct {
+                out: self.0,
+                first: true,
+            })
+        }
+        fn serialize_struct_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeStructVariant, Error> {
+            unsupported()
+        }
+    }
+
+    impl SerializeStruct for JsonStruct<'_> {
+        type Ok = ();
+        type Error = Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error> {
+            if !self.first {
+                self.out.push(',');
+            }
+            self.first = false;
+            write!(self.out, "\"{key}\":").map_err(ser::Error::custom)?;
+            value.serialize(JsonWriter(self.out))
+        }
+
+        fn end(self) -> Result<(), Error> {
+            self.out.push('}');
+            Ok(())
+        }
+    }
+
+    /// Reads back what [`JsonWriter`] writes
+    struct JsonReader<'de> {
+        rest: &'de str,
+    }
+
+    impl<'de> JsonReader<'de> {
+        fn take(&mut self, len: usize) -> &'de str {
+            let (taken, rest) = self.rest.split_at(len);
+            self.rest = rest.trim_start();
+            taken
+        }
+
+        fn expect(&mut self, c: char) -> Result<(), Error> {
+            if !self.rest.starts_with(c) {
+                return Err(de::Error::custom(format!(
+                    "Expected '{c}' at {}",
+                    self.rest
+                )));
+            }
+            self.take(1);
+            Ok(())
+        }
+    }
+
+    impl<'de> Deserializer<'de> for &mut JsonReader<'de> {
+        type Error = Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            if self.rest.starts_with('{') {
+                self.take(1);
+                return visitor.visit_map(self);
+            }
+            if self.rest.starts_with('"') {
+                self.take(1);
+                let end = self
+                    .rest
+                    .find('"')
+                    .ok_or_else(|| de::Error::custom("No end quote"))?;
+                let s = self.take(end);
+                self.expect('"')?;
+                return visitor.visit_str(s);
+            }
+            let end = self.rest.find([',', '}']).unwrap_or(self.rest.len());
+            let number = self.take(end).trim();
+            if number.contains(['.', 'e', 'E']) {
+                visitor.visit_f64(number.parse().map_err(de::Error::custom)?)
+            } else if number.starts_with('-') {
+                visitor.visit_i64(number.parse().map_err(de::Error::custom)?)
+            } else {
+                let number: u128 = number.parse().map_err(de::Error::custom)?;
+                match u64::try_from(number) {
+                    Ok(number) => visitor.visit_u64(number),
+                    Err(_) => visitor.visit_u128(number),
+                }
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map struct enum identifier ignored_any
+        }
+    }
+
+    impl<'de> MapAccess<'de> for JsonReader<'de> {
+        type Error = Error;
+
+        fn next_key_seed<K: de::DeserializeSeed<'de>>(
+            &mut self,
+            seed: K,
+        ) -> Result<Option<K::Value>, Error> {
+            if self.rest.starts_with('}') {
+                self.take(1);
+                return Ok(None);
+            }
+            if self.rest.starts_with(',') {
+                self.take(1);
+            }
+            let key = seed.deserialize(&mut *self)?;
+            self.expect(':')?;
+            Ok(Some(key))
+        }
+
+        fn next_value_seed<V: de::DeserializeSeed<'de>>(
+            &mut self,
+            seed: V,
+        ) -> Result<V::Value, Error> {
+            seed.deserialize(&mut *self)
+        }
+    }
+
+    fn to_json(value: &impl Serialize) -> Vec<u8> {
+        let mut json = String::new();
+        value.serialize(JsonWriter(&mut json)).unwrap();
+        json.into_bytes()
+    }
+
+    fn from_json<'de, T: Deserialize<'de>>(json: &'de [u8]) -> Result<T, Error> {
+        let rest = std::str::from_utf8(json).map_err(de::Error::custom)?;
+        T::deserialize(&mut JsonReader { rest })
+    }
+
+    #[test]
+    fn header_round_trips_through_json() {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = u64::MAX - 1;
+        header.exposure_length = 1000;
+        header.packet_number = 63;
+        header.bunch_id = 7;
+        header.timestamp = 123_456_789;
+        header.module_id = 12;
+        header.row = 1;
+        header.column = 2;
+        header._det_spec_2 = 0xBEEF;
+        header.daq_info = 0x0F01;
+        header._det_spec_4 = 0xCAFE;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = 2;
+
+        let json = to_json(&header);
+        let text = std::str::from_utf8(&json).unwrap();
+        assert!(
+            text.contains("\"frame_number\":18446744073709551614"),
+            "{text}"
+        );
+        assert!(text.contains("\"det_spec_2\":48879"), "{text}");
+        assert!(!text.contains("_det_spec"), "{text}");
+        assert_eq!(from_json::<SlsDetectorHeader>(&json).unwrap(), header);
+    }
+
+    #[test]
+    fn header_reserved_fields_are_optional() {
+        let json = br#"{"frame_number":5,"exposure_length":0,"packet_number":1,"bunch_id":0,
+            "timestamp":0,"module_id":0,"row":0,"column":0,"daq_info":0,"det_type":3,"version":2}"#;
+        let header: SlsDetectorHeader = from_json(json).unwrap();
+        assert_eq!(header.frame_number, 5);
+        assert_eq!(header._det_spec_2, 0);
+        // But the real fields aren't
+        let json = br#"{"frame_number":5}"#;
+        assert!(from_json::<SlsDetectorHeader>(json).is_err());
+    }
+
+    #[test]
+    fn trigger_round_trips_with_a_hex_uuid() {
+        let trigger = DelugeTrigger::builder()
+            .frames(u64::MAX as u128 + 10)
+            .exptime(0.001)
+            .uuid([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xAB, 0xFF])
+            .build();
+        let json = to_json(&trigger);
+        assert_eq!(
+            std::str::from_utf8(&json).unwrap(),
+            r#"{"frames":18446744073709551625,"exptime":0.001,"uuid":"00010203040506070809abff"}"#
+        );
+        let back: DelugeTrigger = from_json(&json).unwrap();
+        assert_eq!(back.frames, trigger.frames);
+        assert_eq!(back.exptime, trigger.exptime);
+        assert_eq!(back.uuid, trigger.uuid);
+        // The wire fields are filled in, so it can be sent on
+        assert!(DelugeTrigger::from_bytes(bytemuck::bytes_of(&back)).is_ok());
+    }
+
+    #[test]
+    fn trigger_uuid_must_be_24_hex_digits() {
+        for uuid in [
+            "0001",
+            "zz0102030405060708090aff",
+            "000102030405060708090abcde",
+        ] {
+            let json = format!(r#"{{"frames":1,"exptime":0.5,"uuid":"{uuid}"}}"#);
+            assert!(
+                from_json::<DelugeTrigger>(json.as_bytes()).is_err(),
+                "{uuid}"
+            );
+        }
+    }
+}
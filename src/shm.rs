@@ -0,0 +1,228 @@
+//! Sharing completed frames with other processes through shared memory
+//!
+//! Frames are written into a ring of fixed-size slots in a POSIX shared
+//! memory segment, which any process on the host can map read-only. There
+//! is no flow control: a reader that falls behind by more than the number
+//! of slots simply misses frames.
+//!
+//! # Layout
+//!
+//! All integers are native-endian. The segment starts with a 64-byte ring
+//! header:
+//!
+//! | Offset | Type  | Field                                             |
+//! |--------|-------|---------------------------------------------------|
+//! | 0      | u64   | Magic, [`SHM_RING_MAGIC`]                         |
+//! | 8      | u32   | Layout version, [`SHM_RING_VERSION`]              |
+//! | 12     | u32   | Number of slots                                   |
+//! | 16     | u64   | Data capacity of each slot, in bytes              |
+//! | 24     | u64   | Stride between slots, in bytes                    |
+//! | 32     | u64   | Write index: how many frames have been written    |
+//!
+//! Slot `i` starts at `64 + i * stride`, with a 128-byte slot header:
+//!
+//! | Offset | Type                | Field                                 |
+//! |--------|---------------------|---------------------------------------|
+//! | 0      | u64                 | Sequence number                       |
+//! | 8      | u64                 | Length of the frame data, in bytes    |
+//! | 16     | u64                 | Received-packet mask                  |
+//! | 24     | u64                 | Write time, ns since the UNIX epoch   |
+//! | 32     | u8                  | 1 if every packet arrived, else 0     |
+//! | 40     | sls_detector_header | Header of the frame                   |
+//!
+//! followed by the frame data, which always starts 64-byte aligned.
+//!
+//! # Reading
+//!
+//! Frame `n` (counting from zero) is written to slot `n % slots`. While
+//! it is being written the slot sequence number is `2n + 1`, and once it
+//! is complete it is `2n + 2`; the write index is then advanced to `n + 1`.
+//! To read the newest frame without tearing:
+//!
+//! 1. Load the write index `w` (acquire). If zero, nothing is written yet.
+//! 2. For frame `n = w - 1`, load the slot sequence number `s1` (acquire).
+//!    If `s1 != 2n + 2`, the slot is being rewritten; go back to 1.
+//! 3. Copy the slot header fields and data out.
+//! 4. Issue an acquire fence, and load the sequence number `s2` again. If
+//!    `s2 != s1`, the copy may be torn; discard it and go back to 1.
+
+use std::{
+    ffi::CString,
+    io,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU64, Ordering, fence},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{CompletedFrame, MorgulError, SlsDetectorHeader, sink::FrameSink};
+
+/// Identifies a morgul shared memory ring: `MORGULSH` in ASCII
+pub const SHM_RING_MAGIC: u64 = u64::from_le_bytes(*b"MORGULSH");
+pub const SHM_RING_VERSION: u32 = 1;
+
+const RING_HEADER_SIZE: usize = 64;
+const SLOT_HEADER_SIZE: usize = 128;
+const WRITE_INDEX_OFFSET: usize = 32;
+const SLOT_FRAME_HEADER_OFFSET: usize = 40;
+
+/// Writes completed frames into a shared memory ring for on-host consumers
+pub struct ShmRingSink {
+    name: CString,
+    base: NonNull<u8>,
+    mapped_size: usize,
+    slot_count: usize,
+    slot_size: usize,
+    stride: usize,
+    /// How many frames have been written
+    written: u64,
+}
+
+// SAFETY: The mapping is owned by this sink, and only written through it
+unsafe impl Send for ShmRingSink {}
+
+impl ShmRingSink {
+    /// Create (or replace) the shared memory segment `name`
+    ///
+    /// `name` is a POSIX shared memory name, e.g. `/morgul`, and will appear
+    /// under `/dev/shm`. Each of the `slot_count` slots holds a frame of up
+    /// to `slot_size` bytes.
+    pub fn create(name: &str, slot_count: usize, slot_size: usize) -> Result<Self, MorgulError> {
+        if slot_count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Shared memory ring needs at least one slot",
+            )
+            .into());
+        }
+        let c_name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stride = (SLOT_HEADER_SIZE + slot_size).next_multiple_of(64);
+        let mapped_size = RING_HEADER_SIZE + slot_count * stride;
+
+        // SAFETY: c_name is a valid NUL-terminated string
+        let fd = unsafe {
+            libc::shm_open(
+                c_name.as_ptr(),
+                libc::O_CREAT | libc::O_RDWR | libc::O_TRUNC,
+                0o644,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: fd is a shared memory object we just opened, and is
+        // closed exactly once. The mapping stays valid after closing it.
+        let base = unsafe {
+            let base = if libc::ftruncate(fd, mapped_size as libc::off_t) == 0 {
+                libc::mmap(
+                    ptr::null_mut(),
+                    mapped_size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            } else {
+                libc::MAP_FAILED
+            };
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+            if base == libc::MAP_FAILED {
+                libc::shm_unlink(c_name.as_ptr());
+                return Err(error.into());
+            }
+            NonNull::new(base.cast::<u8>()).unwrap()
+        };
+
+        let ring = ShmRingSink {
+            name: c_name,
+            base,
+            mapped_size,
+            slot_count,
+            slot_size,
+            stride,
+            written: 0,
+        };
+        // The segment is zero-filled, so only the ring header needs writing
+        // SAFETY: All offsets are inside the ring header, and suitably aligned
+        unsafe {
+            let base = ring.base.as_ptr();
+            base.cast::<u64>().write(SHM_RING_MAGIC);
+            base.add(8).cast::<u32>().write(SHM_RING_VERSION);
+            base.add(12).cast::<u32>().write(slot_count as u32);
+            base.add(16).cast::<u64>().write(slot_size as u64);
+            base.add(24).cast::<u64>().write(stride as u64);
+        }
+        Ok(ring)
+    }
+
+    fn write_index(&self) -> &AtomicU64 {
+        // SAFETY: The offset is inside the mapping and 8-byte aligned
+        unsafe { AtomicU64::from_ptr(self.base.as_ptr().add(WRITE_INDEX_OFFSET).cast()) }
+    }
+}
+
+impl FrameSink for ShmRingSink {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        if frame.data.len() > self.slot_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame of {} bytes does not fit in shared memory slots of {} bytes",
+                    frame.data.len(),
+                    self.slot_size
+                ),
+            )
+            .into());
+        }
+        let n = self.written;
+        let written_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_nanos() as u64);
+        // SAFETY: The slot lies inside the mapping, every offset is aligned
+        // for its type, and the data fits in the slot. Readers may race with
+        // these plain writes, which the sequence number lets them detect.
+        unsafe {
+            let slot = self
+                .base
+                .as_ptr()
+                .add(RING_HEADER_SIZE + (n as usize % self.slot_count) * self.stride);
+            let sequence = AtomicU64::from_ptr(slot.cast());
+            sequence.store(2 * n + 1, Ordering::Relaxed);
+            fence(Ordering::Release);
+
+            slot.add(8)
+                .cast::<u64>()
+                .write_volatile(frame.data.len() as u64);
+            slot.add(16)
+                .cast::<u64>()
+                .write_volatile(frame.received_mask);
+            slot.add(24).cast::<u64>().write_volatile(written_ns);
+            slot.add(32).write_volatile(frame.complete as u8);
+            slot.add(SLOT_FRAME_HEADER_OFFSET)
+                .cast::<SlsDetectorHeader>()
+                .write_volatile(frame.header);
+            ptr::copy_nonoverlapping(
+                frame.data.as_ptr(),
+                slot.add(SLOT_HEADER_SIZE),
+                frame.data.len(),
+            );
+
+            sequence.store(2 * n + 2, Ordering::Release);
+        }
+        self.written += 1;
+        self.write_index().store(self.written, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for ShmRingSink {
+    fn drop(&mut self) {
+        // Processes that still have the segment mapped keep it until they unmap
+        // SAFETY: base and mapped_size describe our mapping, and name is valid
+        unsafe {
+            libc::munmap(self.base.as_ptr().cast(), self.mapped_size);
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Passing completed frames on to wherever they are going
+
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
+
+use crate::{CompletedFrame, MorgulError, SlsDetectorType};
+
+/// Somewhere that completed frames can be sent, e.g. a file or a stream
+pub trait FrameSink: Send {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError>;
+
+    /// Make sure everything written so far has reached its destination
+    fn flush(&mut self) -> Result<(), MorgulError> {
+        Ok(())
+    }
+
+    /// Every frame of an acquisition has been written
+    fn end_acquisition(&mut self, _acquisition_number: usize) -> Result<(), MorgulError> {
+        self.flush()
+    }
+
+    /// The files finished since this was last called, e.g. those of the
+    /// acquisition that just ended
+    fn take_written_files(&mut self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+}
+
+/// Which frames a sink should receive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SinkFilter {
+    /// Every frame, regardless of where it came from
+    #[default]
+    All,
+    /// Only frames from these detector types
+    DetectorTypes(HashSet<SlsDetectorType>),
+}
+
+impl SinkFilter {
+    pub fn accepts(&self, frame: &CompletedFrame) -> bool {
+        match self {
+            SinkFilter::All => true,
+            SinkFilter::DetectorTypes(types) => frame
+                .header
+                .detector_type()
+                .is_ok_and(|det_type| types.contains(&det_type)),
+        }
+    }
+}
+
+/// Parse a filter from `all`, or a comma-separated list of det_type values,
+/// as numbers or names
+impl FromStr for SinkFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "all" {
+            return Ok(SinkFilter::All);
+        }
+        s.split(',')
+            .map(|part| match part.trim().parse::<u8>() {
+                Ok(v) => SlsDetectorType::try_from(v)
+                    .map_err(|_| format!("Unknown detector type: {part}")),
+                Err(_) => part.parse(),
+            })
+            .collect::<Result<_, _>>()
+            .map(SinkFilter::DetectorTypes)
+    }
+}
+
+/// Fans completed frames out to a set of sinks, according to their filters
+///
+/// With no filters given, every frame goes to every sink.
+#[derive(Default)]
+pub struct SinkRouter {
+    routes: Vec<(SinkFilter, Box<dyn FrameSink>)>,
+}
+
+impl SinkRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send frames matching `filter` to `sink`
+    pub fn add(&mut self, filter: SinkFilter, sink: Box<dyn FrameSink>) {
+        self.routes.push((filter, sink));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Pass a frame on to every sink that wants it
+    ///
+    /// Every matching sink is tried, even if an earlier one fails; the
+    /// first error is returned.
+    pub fn route(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        let mut result = Ok(());
+        for (filter, sink) in &mut self.routes {
+            if filter.accepts(frame)
+                && let Err(e) = sink.write_frame(frame)
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Flush every sink, returning the first error
+    pub fn flush(&mut self) -> Result<(), MorgulError> {
+        let mut result = Ok(());
+        for (_, sink) in &mut self.routes {
+            if let Err(e) = sink.flush()
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Tell every sink that an acquisition has ended, returning the first error
+    pub fn end_acquisition(&mut self, acquisition_number: usize) -> Result<(), MorgulError> {
+        let mut result = Ok(());
+        for (_, sink) in &mut self.routes {
+            if let Err(e) = sink.end_acquisition(acquisition_number)
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// The files finished by every sink since this was last called
+    pub fn take_written_files(&mut self) -> Vec<PathBuf> {
+        self.routes
+            .iter_mut()
+            .flat_map(|(_, sink)| sink.take_written_files())
+            .collect()
+    }
+}
@@ -0,0 +1,91 @@
+//! Counters that accumulate over the lifetime of an installation
+
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{assembler::AcquisitionStats, output::GatedFile};
+
+/// Running totals across every acquisition, kept across restarts
+///
+/// These are persisted as a small text file of `<name> <value>` lines, so
+/// that they keep counting up monotonically when the receiver restarts.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LifetimeStats {
+    pub acquisitions: u64,
+    pub images_seen: u64,
+    pub complete_images: u64,
+    pub packets_dropped: u64,
+    pub kernel_dropped: u64,
+    pub duplicate_frames: u64,
+    pub unknown_det_type_packets: u64,
+}
+
+impl LifetimeStats {
+    /// Load the saved totals. If there is no file yet, start from zero.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let mut stats = Self::default();
+        for line in contents.lines() {
+            let Some((name, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let value: u64 = value
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{name}: {e}")))?;
+            match name {
+                "acquisitions" => stats.acquisitions = value,
+                "images_seen" => stats.images_seen = value,
+                "complete_images" => stats.complete_images = value,
+                "packets_dropped" => stats.packets_dropped = value,
+                "kernel_dropped" => stats.kernel_dropped = value,
+                "duplicate_frames" => stats.duplicate_frames = value,
+                "unknown_det_type_packets" => stats.unknown_det_type_packets = value,
+                // Probably from a newer version, so leave it be
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Save the totals, atomically replacing any previous file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = GatedFile::create(path)?;
+        write!(
+            file,
+            "acquisitions {}\n\
+             images_seen {}\n\
+             complete_images {}\n\
+             packets_dropped {}\n\
+             kernel_dropped {}\n\
+             duplicate_frames {}\n\
+             unknown_det_type_packets {}\n",
+            self.acquisitions,
+            self.images_seen,
+            self.complete_images,
+            self.packets_dropped,
+            self.kernel_dropped,
+            self.duplicate_frames,
+            self.unknown_det_type_packets,
+        )?;
+        file.finish(true)?;
+        Ok(())
+    }
+
+    /// Add a finished acquisition to the totals
+    pub fn add_acquisition(&mut self, stats: &AcquisitionStats) {
+        self.acquisitions += 1;
+        self.images_seen += stats.images_seen as u64;
+        self.complete_images += stats.complete_images as u64;
+        self.packets_dropped += stats.packets_dropped as u64;
+        self.kernel_dropped += stats.kernel_dropped as u64;
+        self.duplicate_frames += stats.duplicate_frames as u64;
+        self.unknown_det_type_packets += stats.unknown_det_type_packets as u64;
+    }
+}
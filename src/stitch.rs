@@ -0,0 +1,409 @@
+//! Stitching the images from every module into one full-detector image
+
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    AlignedBuffer, CompletedFrame, DEFAULT_BUFFER_ALIGNMENT, GeometryMap, MorgulError,
+    PooledBuffer, SlsDetectorHeader, orientation::pixel_geometry,
+};
+
+/// How many stitched images can be in progress or held by sinks at once
+const STITCH_BUFFER_LENGTH: usize = 8;
+
+/// How the modules are arranged, by the `row` and `column` in their headers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ModuleLayout {
+    pub columns: usize,
+    pub rows: usize,
+}
+
+/// Parse from `<columns>x<rows>`, e.g. `2x4`
+impl FromStr for ModuleLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((columns, rows)) = s.split_once('x') else {
+            return Err("Expected <columns>x<rows>".to_string());
+        };
+        let columns: usize = columns.trim().parse().map_err(|e| format!("{e}"))?;
+        let rows: usize = rows.trim().parse().map_err(|e| format!("{e}"))?;
+        if columns == 0 || rows == 0 {
+            return Err("Module layout must not be empty".to_string());
+        }
+        Ok(ModuleLayout { columns, rows })
+    }
+}
+
+impl ModuleLayout {
+    /// How many modules make up the whole detector
+    pub fn modules(&self) -> usize {
+        self.columns * self.rows
+    }
+}
+
+/// A full-detector image that is still waiting for some modules
+struct PendingImage {
+    header: SlsDetectorHeader,
+    buffer: AlignedBuffer,
+    /// Which modules have arrived, by `row * columns + column`
+    received: Vec<bool>,
+    complete: bool,
+    received_mask: u64,
+    started: Instant,
+}
+
+/// Combines the frames from every module that share a frame number
+///
+/// Each module's image is placed by its `row` and `column`, at
+/// [`SlsDetectorHeader::module_origin`], and the full image is passed on
+/// once every module in the layout has arrived. If some never do, the
+/// image is passed on anyway after a timeout, or when too many newer
+/// images are waiting, as incomplete and with the missing modules set to
+/// all ones (e.g. 0xFFFF at 16 bits), as are the pixels of missing packets.
+///
+/// The stitched frame has the header of the first module to arrive, moved
+/// to row and column zero. Its packet mask has only the packets that
+/// arrived for every module.
+///
+/// A module's frame that arrives after its image has been passed on is
+/// too late to be used, and is dropped and counted.
+pub struct DetectorStitcher {
+    layout: ModuleLayout,
+    geometry: GeometryMap,
+    timeout: Duration,
+    pending: BTreeMap<u64, PendingImage>,
+    /// The newest frame number passed on so far this acquisition
+    emitted: Option<u64>,
+    /// How many images have been passed on without every module
+    incomplete: usize,
+    /// How many module frames arrived after their image was passed on
+    late: usize,
+    buffer_return: Sender<AlignedBuffer>,
+    spare_buffers: Receiver<AlignedBuffer>,
+}
+
+impl DetectorStitcher {
+    pub fn new(layout: ModuleLayout, geometry: GeometryMap, timeout: Duration) -> Self {
+        let (buffer_return, spare_buffers) = mpsc::channel();
+        for _ in 0..STITCH_BUFFER_LENGTH {
+            buffer_return.send(AlignedBuffer::default()).unwrap();
+        }
+        DetectorStitcher {
+            layout,
+            geometry,
+            timeout,
+            pending: BTreeMap::new(),
+            emitted: None,
+            incomplete: 0,
+            late: 0,
+            buffer_return,
+            spare_buffers,
+        }
+    }
+
+    /// How many images have been passed on with modules missing
+    pub fn incomplete(&self) -> usize {
+        self.incomplete
+    }
+
+    /// How many module frames have been dropped for arriving too late
+    pub fn late(&self) -> usize {
+        self.late
+    }
+
+    /// Add one module's frame, calling `emit` with any full images that are done
+    ///
+    /// As well as the image this frame completes, this passes on any that
+    /// have waited longer than the timeout.
+    pub fn push(
+        &mut self,
+        frame: &CompletedFrame,
+        mut emit: impl FnMut(CompletedFrame),
+    ) -> Result<(), MorgulError> {
+        let (geometry, pixel_size) = pixel_geometry(&self.geometry, frame)?;
+        let header = &frame.header;
+        let (row, column) = (header.row as usize, header.column as usize);
+        if row >= self.layout.rows || column >= self.layout.columns {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Module at row {row} column {column} is outside the {}x{} module layout",
+                    self.layout.columns, self.layout.rows
+                ),
+            });
+        }
+        let image_width = self.layout.columns * geometry.size_x;
+        let image_size = image_width * self.layout.rows * geometry.size_y * pixel_size;
+
+        if !self.pending.contains_key(&header.frame_number)
+            && self
+                .emitted
+                .is_some_and(|emitted| header.frame_number <= emitted)
+        {
+            self.late += 1;
+            if self.late.is_power_of_two() {
+                println!(
+                    "Warning: Dropped frame {} from the module at row {row} column {column}, which arrived after its image was passed on ({} late so far)",
+                    header.frame_number, self.late
+                );
+            }
+            self.expire(Instant::now(), emit);
+            return Ok(());
+        }
+        if !self.pending.contains_key(&header.frame_number) {
+            let buffer = self.spare_buffer(image_size, &mut emit)?;
+            let mut image_header = *header;
+            image_header.row = 0;
+            image_header.column = 0;
+            self.pending.insert(
+                header.frame_number,
+                PendingImage {
+                    header: image_header,
+                    buffer,
+                    received: vec![false; self.layout.modules()],
+                    complete: true,
+                    received_mask: u64::MAX,
+                    started: Instant::now(),
+                },
+            );
+        }
+        let image = self.pending.get_mut(&header.frame_number).unwrap();
+        let module = row * self.layout.columns + column;
+        if image.received[module] {
+            return Err(MorgulError::InvalidGeometry {
+                source: None,
+                reason: format!(
+                    "Frame {} from the module at row {row} column {column} arrived twice",
+                    header.frame_number
+                ),
+            });
+        }
+        image.received[module] = true;
+        image.complete &= frame.complete;
+        image.received_mask &= frame.received_mask;
+
+        let (origin_x, origin_y) = header.module_origin(geometry.size_x, geometry.size_y);
+        let row_size = geometry.size_x * pixel_size;
+        for (y, source) in frame.data.chunks_exact(row_size).enumerate() {
+            let start = ((origin_y + y) * image_width + origin_x) * pixel_size;
+            let out = &mut image.buffer[start..start + row_size];
+            out.copy_from_slice(source);
+            if frame.complete {
+                continue;
+            }
+            // Mark anything that came from a missing packet as invalid
+            for (offset, byte) in (y * row_size..(y + 1) * row_size).zip(out.iter_mut()) {
+                let packet = geometry.packet_at_offset(offset);
+                if frame.received_mask & (1 << packet) == 0 {
+                    *byte = 0xFF;
+                }
+            }
+        }
+
+        if image.received.iter().all(|r| *r) {
+            let image = self.pending.remove(&header.frame_number).unwrap();
+            emit(self.finish(image));
+        }
+        self.expire(Instant::now(), emit);
+        Ok(())
+    }
+
+    /// Pass on every image that has waited for its modules since before `now - timeout`
+    ///
+    /// Modules can start images out of frame order, so every image waiting
+    /// is checked, not just the oldest by frame number.
+    pub fn expire(&mut self, now: Instant, mut emit: impl FnMut(CompletedFrame)) {
+        let expired = self
+            .pending
+            .extract_if(.., |_, image| {
+                now.duration_since(image.started) >= self.timeout
+            })
+            .collect::<Vec<_>>();
+        for (_, image) in expired {
+            emit(self.finish(image));
+        }
+    }
+
+    /// Pass on every image still waiting, e.g. at the end of an acquisition
+    ///
+    /// Frame numbers can start again after this, so nothing later is
+    /// treated as late.
+    pub fn flush(&mut self, mut emit: impl FnMut(CompletedFrame)) {
+        while let Some((_, image)) = self.pending.pop_first() {
+            emit(self.finish(image));
+        }
+        self.emitted = None;
+    }
+
+    /// A buffer for a new image, passing on the oldest waiting one if none are free
+    fn spare_buffer(
+        &mut self,
+        size: usize,
+        emit: &mut impl FnMut(CompletedFrame),
+    ) -> Result<AlignedBuffer, MorgulError> {
+        let mut buffer = match self.spare_buffers.try_recv() {
+            Ok(buffer) => buffer,
+            Err(_) => {
+                let (_, oldest) = self
+                    .pending
+                    .pop_first()
+                    .ok_or(MorgulError::BufferPoolExhausted)?;
+                emit(self.finish(oldest));
+                self.spare_buffers
+                    .try_recv()
+                    .map_err(|_| MorgulError::BufferPoolExhausted)?
+            }
+        };
+        if buffer.len() != size {
+            buffer = AlignedBuffer::new(size, DEFAULT_BUFFER_ALIGNMENT);
+        }
+        buffer.fill(0xFF);
+        Ok(buffer)
+    }
+
+    fn finish(&mut self, image: PendingImage) -> CompletedFrame {
+        let frame_number = image.header.frame_number;
+        self.emitted = Some(self.emitted.map_or(frame_number, |e| e.max(frame_number)));
+        let all_modules = image.received.iter().all(|r| *r);
+        if !all_modules {
+            self.incomplete += 1;
+            if self.incomplete.is_power_of_two() {
+                println!(
+                    "Warning: Stitched frame {} with only {} of {} modules ({} incomplete so far)",
+                    image.header.frame_number,
+                    image.received.iter().filter(|r| **r).count(),
+                    image.received.len(),
+                    self.incomplete
+                );
+            }
+        }
+        CompletedFrame {
+            header: image.header,
+            complete: image.complete && all_modules,
+            received_mask: if all_modules { image.received_mask } else { 0 },
+            data: PooledBuffer::new(image.buffer, self.buffer_return.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SlsDetectorType;
+    use bytemuck::Zeroable;
+
+    /// Long enough that nothing expires unless a test asks it to
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn stitcher() -> DetectorStitcher {
+        let layout = ModuleLayout {
+            columns: 2,
+            rows: 1,
+        };
+        DetectorStitcher::new(layout, GeometryMap::with_defaults(16), TIMEOUT)
+    }
+
+    /// A whole Jungfrau port image, with every pixel set to `value`
+    fn module_frame(frame_number: u64, column: u16, value: u8) -> CompletedFrame {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.frame_number = frame_number;
+        header.column = column;
+        let mut buffer = AlignedBuffer::new(1024 * 256 * 2, DEFAULT_BUFFER_ALIGNMENT);
+        buffer.fill(value);
+        CompletedFrame {
+            header,
+            complete: true,
+            received_mask: u64::MAX,
+            data: PooledBuffer::new(buffer, mpsc::channel().0),
+        }
+    }
+
+    /// Push frames, returning (frame number, complete) for each image passed on
+    fn push(stitcher: &mut DetectorStitcher, frames: &[(u64, u16)]) -> Vec<(u64, bool)> {
+        let mut emitted = Vec::new();
+        for &(frame_number, column) in frames {
+            let frame = module_frame(frame_number, column, column as u8 + 1);
+            stitcher
+                .push(&frame, |image| {
+                    emitted.push((image.header.frame_number, image.complete))
+                })
+                .unwrap();
+        }
+        emitted
+    }
+
+    #[test]
+    fn modules_are_stitched_side_by_side() {
+        let mut stitcher = stitcher();
+        let mut images = Vec::new();
+        for column in [0, 1] {
+            stitcher
+                .push(&module_frame(1, column, column as u8 + 1), |image| {
+                    images.push(image)
+                })
+                .unwrap();
+        }
+        assert_eq!(images.len(), 1);
+        let image = &images[0];
+        assert!(image.complete);
+        assert_eq!(image.data.len(), 2048 * 256 * 2);
+        // Each row is the first module's row, then the second's
+        for row in image.data.chunks_exact(2048 * 2) {
+            assert!(row[..1024 * 2].iter().all(|&b| b == 1));
+            assert!(row[1024 * 2..].iter().all(|&b| b == 2));
+        }
+    }
+
+    #[test]
+    fn modules_can_arrive_in_any_order() {
+        let mut stitcher = stitcher();
+        let emitted = push(&mut stitcher, &[(1, 1), (2, 1), (1, 0), (2, 0)]);
+        assert_eq!(emitted, [(1, true), (2, true)]);
+        assert_eq!(stitcher.incomplete(), 0);
+    }
+
+    #[test]
+    fn late_modules_are_dropped() {
+        let mut stitcher = stitcher();
+        assert!(push(&mut stitcher, &[(1, 0)]).is_empty());
+        let mut emitted = Vec::new();
+        stitcher.expire(Instant::now() + TIMEOUT, |image| {
+            emitted.push((image.header.frame_number, image.complete))
+        });
+        assert_eq!(emitted, [(1, false)]);
+
+        // Too late to be part of frame 1, which mustn't be started again
+        assert!(push(&mut stitcher, &[(1, 1)]).is_empty());
+        assert_eq!(stitcher.late(), 1);
+        let mut flushed = 0;
+        stitcher.flush(|_| flushed += 1);
+        assert_eq!(flushed, 0);
+
+        // A new acquisition can use the same frame numbers
+        assert_eq!(push(&mut stitcher, &[(1, 0), (1, 1)]), [(1, true)]);
+        assert_eq!(stitcher.late(), 1);
+    }
+
+    #[test]
+    fn images_expire_by_when_they_started() {
+        let mut stitcher = stitcher();
+        push(&mut stitcher, &[(5, 0)]);
+        let between = Instant::now();
+        std::thread::sleep(Duration::from_millis(2));
+        // Lower numbered, but started later, so not yet expired
+        push(&mut stitcher, &[(3, 0)]);
+
+        let mut emitted = Vec::new();
+        stitcher.expire(between + TIMEOUT, |image| {
+            emitted.push(image.header.frame_number)
+        });
+        assert_eq!(emitted, [5]);
+        assert_eq!(stitcher.incomplete(), 1);
+    }
+}
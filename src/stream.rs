@@ -0,0 +1,313 @@
+//! Streaming completed frames to another morgul over TCP
+//!
+//! Unlike the raw detector packets, which travel over lossy UDP, this
+//! passes on assembled frames over a reliable connection, so that a
+//! downstream morgul (or analysis node) receives exactly the frames that
+//! were assembled here.
+//!
+//! # Wire format
+//!
+//! The stream is a sequence of frames, each of which is, little-endian:
+//!
+//! | Size              | Field                                             |
+//! |-------------------|---------------------------------------------------|
+//! | 4                 | Magic, [`FRAME_MAGIC`]                            |
+//! | 1                 | 1 if every packet arrived, else 0                 |
+//! | 3                 | Reserved, zero                                    |
+//! | 8                 | Received-packet mask                              |
+//! | 48                | The `sls_detector_header` of the frame            |
+//! | 8                 | Length of the frame data, in bytes                |
+//! | length            | The frame data                                    |
+
+use std::{
+    io::{self, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+    thread,
+};
+
+use bytemuck::Zeroable;
+
+use crate::{
+    AlignedBuffer, CompletedFrame, DEFAULT_BUFFER_ALIGNMENT, MorgulError, PooledBuffer,
+    SlsDetectorHeader, sink::FrameSink,
+};
+
+/// Starts every frame in the stream: `MGFR` in ASCII
+pub const FRAME_MAGIC: u32 = u32::from_le_bytes(*b"MGFR");
+
+/// The longest frame data a [`FrameReader`] will accept, in bytes
+///
+/// This is much more than a whole stitched detector image; anything longer
+/// is a corrupt or hostile stream, not something to allocate memory for.
+pub const MAX_FRAME_LENGTH: usize = 256 * 1024 * 1024;
+
+/// How many frames can wait to be sent before back-pressure applies
+const SEND_QUEUE_LENGTH: usize = 64;
+
+impl CompletedFrame {
+    /// Write the frame in the stream wire format
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&FRAME_MAGIC.to_le_bytes())?;
+        writer.write_all(&[self.complete as u8, 0, 0, 0])?;
+        writer.write_all(&self.received_mask.to_le_bytes())?;
+        writer.write_all(bytemuck::bytes_of(&self.header))?;
+        writer.write_all(&(self.data.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.data)
+    }
+}
+
+/// What to do when the downstream end can't keep up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Wait for the downstream end, holding up everything behind us
+    #[default]
+    Block,
+    /// Discard frames that there is no room to queue, and count them
+    Drop,
+}
+
+/// Parse from `block` or `drop`
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(BackpressurePolicy::Block),
+            "drop" => Ok(BackpressurePolicy::Drop),
+            _ => Err(format!("Unknown backpressure policy '{s}'")),
+        }
+    }
+}
+
+enum Outgoing {
+    Frame(Vec<u8>),
+    /// Reply once everything before this has been written to the socket
+    Flush(Sender<io::Result<()>>),
+}
+
+/// Sends completed frames to a downstream morgul over TCP
+///
+/// Frames are sent from a separate thread, through a short queue, so that
+/// the network only holds up the caller once the queue is full; what then
+/// happens depends on the [`BackpressurePolicy`].
+pub struct TcpFrameSink {
+    queue: SyncSender<Outgoing>,
+    policy: BackpressurePolicy,
+    /// How many frames were discarded because the queue was full
+    dropped: usize,
+}
+
+impl TcpFrameSink {
+    pub fn connect(
+        address: impl ToSocketAddrs,
+        policy: BackpressurePolicy,
+    ) -> Result<Self, MorgulError> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let (queue, outgoing) = mpsc::sync_channel(SEND_QUEUE_LENGTH);
+        thread::spawn(move || send_frames(BufWriter::new(stream), outgoing));
+        Ok(TcpFrameSink {
+            queue,
+            policy,
+            dropped: 0,
+        })
+    }
+
+    /// How many frames have been discarded because the downstream end was too slow
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Write queued frames to the stream, until it or the queue fails
+fn send_frames(mut stream: BufWriter<TcpStream>, outgoing: Receiver<Outgoing>) {
+    for message in outgoing {
+        match message {
+            Outgoing::Frame(frame) => {
+                if let Err(e) = stream.write_all(&frame) {
+                    println!("Error: Lost connection to downstream: {e}");
+                    return;
+                }
+            }
+            Outgoing::Flush(reply) => {
+                let _ = reply.send(stream.flush());
+            }
+        }
+    }
+}
+
+fn disconnected() -> MorgulError {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "Downstream connection has closed",
+    )
+    .into()
+}
+
+impl FrameSink for TcpFrameSink {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        let mut bytes = Vec::with_capacity(frame.data.len() + 80);
+        frame.write_to(&mut bytes)?;
+        match self.policy {
+            BackpressurePolicy::Block => self
+                .queue
+                .send(Outgoing::Frame(bytes))
+                .map_err(|_| disconnected()),
+            BackpressurePolicy::Drop => match self.queue.try_send(Outgoing::Frame(bytes)) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    if self.dropped.is_power_of_two() {
+                        println!(
+                            "Warning: Downstream is not keeping up; {} frame(s) dropped",
+                            self.dropped
+                        );
+                    }
+                    Ok(())
+                }
+                Err(TrySendError::Disconnected(_)) => Err(disconnected()),
+            },
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), MorgulError> {
+        let (reply, result) = mpsc::channel();
+        self.queue
+            .send(Outgoing::Flush(reply))
+            .map_err(|_| disconnected())?;
+        result.recv().map_err(|_| disconnected())??;
+        Ok(())
+    }
+}
+
+/// Reads the frames sent by a [`TcpFrameSink`]
+///
+/// Each frame read owns a buffer that goes back to this reader's pool when
+/// it is dropped, to be reused for a later frame.
+pub struct FrameReader<R> {
+    reader: R,
+    buffer_return: Sender<AlignedBuffer>,
+    spare_buffers: Receiver<AlignedBuffer>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        let (buffer_return, spare_buffers) = mpsc::channel();
+        FrameReader {
+            reader,
+            buffer_return,
+            spare_buffers,
+        }
+    }
+
+    /// Read the next frame, or None if the stream ended cleanly between frames
+    pub fn next_frame(&mut self) -> Result<Option<CompletedFrame>, MorgulError> {
+        let mut prefix = [0u8; 16];
+        match self.reader.read_exact(&mut prefix[..4]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.reader.read_exact(&mut prefix[4..])?;
+        let magic = u32::from_le_bytes(prefix[..4].try_into().unwrap());
+        if magic != FRAME_MAGIC {
+            return Err(MorgulError::MalformedHeader {
+                reason: format!("Bad frame magic {magic:#010x} in stream"),
+            });
+        }
+        let complete = prefix[4] != 0;
+        let received_mask = u64::from_le_bytes(prefix[8..].try_into().unwrap());
+
+        let mut header = SlsDetectorHeader::zeroed();
+        self.reader
+            .read_exact(bytemuck::bytes_of_mut(&mut header))?;
+        let mut length = [0u8; 8];
+        self.reader.read_exact(&mut length)?;
+        let length = u64::from_le_bytes(length);
+        let length = match usize::try_from(length) {
+            Ok(length) if length <= MAX_FRAME_LENGTH => length,
+            _ => {
+                return Err(MorgulError::MalformedHeader {
+                    reason: format!(
+                        "Frame of {length} bytes in stream is longer than the {MAX_FRAME_LENGTH} allowed"
+                    ),
+                });
+            }
+        };
+
+        let mut buffer = self.spare_buffers.try_recv().unwrap_or_default();
+        if buffer.len() != length {
+            buffer = AlignedBuffer::new(length, DEFAULT_BUFFER_ALIGNMENT);
+        }
+        self.reader.read_exact(&mut buffer)?;
+        Ok(Some(CompletedFrame {
+            header,
+            complete,
+            received_mask,
+            data: PooledBuffer::new(buffer, self.buffer_return.clone()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn frame(frame_number: u64, length: usize) -> CompletedFrame {
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = frame_number;
+        let mut buffer = AlignedBuffer::new(length, DEFAULT_BUFFER_ALIGNMENT);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        CompletedFrame {
+            header,
+            complete: frame_number.is_multiple_of(2),
+            received_mask: frame_number,
+            data: PooledBuffer::new(buffer, mpsc::channel().0),
+        }
+    }
+
+    #[test]
+    fn frames_are_read_as_they_were_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink =
+            TcpFrameSink::connect(listener.local_addr().unwrap(), BackpressurePolicy::Block)
+                .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let sent = [frame(1, 4096), frame(2, 100), frame(3, 0)];
+        for frame in &sent {
+            sink.write_frame(frame).unwrap();
+        }
+        sink.flush().unwrap();
+        drop(sink);
+
+        let mut reader = FrameReader::new(stream);
+        for expected in &sent {
+            let frame = reader.next_frame().unwrap().unwrap();
+            assert_eq!(frame.header.frame_number, expected.header.frame_number);
+            assert_eq!(frame.complete, expected.complete);
+            assert_eq!(frame.received_mask, expected.received_mask);
+            assert_eq!(&frame.data[..], &expected.data[..]);
+        }
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut bytes = Vec::new();
+        frame(1, 8).write_to(&mut bytes).unwrap();
+        // Replace the length, just before the data, with one far too long
+        let length_at = bytes.len() - 8 - 8;
+        bytes[length_at..length_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let mut reader = FrameReader::new(&bytes[..]);
+        assert!(matches!(
+            reader.next_frame(),
+            Err(MorgulError::MalformedHeader { .. })
+        ));
+    }
+}
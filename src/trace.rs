@@ -0,0 +1,242 @@
+//! Exporting acquisitions as OpenTelemetry traces
+//!
+//! Each acquisition becomes one span, from the first packet any listener
+//! received to the end of the acquisition, with an event for each kind of
+//! loss or error seen during it. Spans are sent to an OTLP collector as
+//! JSON over HTTP (the `/v1/traces` endpoint, usually on port 4318).
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{MorgulError, assembler::AcquisitionStats, manifest::json_string};
+
+/// How long to wait for the collector before giving up on a span
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One acquisition, as a span
+#[derive(Debug, Clone)]
+pub struct AcquisitionSpan {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub acquisition_number: usize,
+    /// UUID of the trigger that started the acquisition, if known, so that
+    /// downstream spans can be correlated with this one
+    pub trigger_uuid: Option<[u8; 12]>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// Statistics merged across every listener
+    pub stats: AcquisitionStats,
+}
+
+impl AcquisitionSpan {
+    /// A span, in a new trace, for an acquisition that has ended
+    pub fn new(acquisition_number: usize, start: SystemTime, stats: AcquisitionStats) -> Self {
+        AcquisitionSpan {
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            acquisition_number,
+            trigger_uuid: None,
+            start,
+            end: SystemTime::now(),
+            stats,
+        }
+    }
+
+    /// Any losses or errors, as (event name, count)
+    ///
+    /// Only the totals are known by the end of the acquisition, so these
+    /// are all timestamped at the end of the span.
+    fn events(&self) -> Vec<(&'static str, usize)> {
+        let stats = &self.stats;
+        [
+            ("packets_dropped", stats.packets_dropped),
+            ("kernel_dropped", stats.kernel_dropped),
+            ("missing_frames", stats.missing_frames),
+            ("out_of_order", stats.out_of_order),
+            ("duplicate_frames", stats.duplicate_frames),
+            ("unknown_det_type_packets", stats.unknown_det_type_packets),
+            ("payload_mismatch_packets", stats.payload_mismatch_packets),
+            ("malformed_packets", stats.malformed_packets),
+            ("no_buffer_packets", stats.no_buffer_packets),
+        ]
+        .into_iter()
+        .filter(|&(_, count)| count > 0)
+        .collect()
+    }
+
+    /// The span as an OTLP/JSON `ExportTraceServiceRequest`
+    pub fn to_otlp_json(&self, service_name: &str) -> String {
+        let stats = &self.stats;
+        let mut attributes = vec![
+            int_attribute("morgul.acquisition_number", self.acquisition_number),
+            int_attribute("morgul.images_seen", stats.images_seen),
+            int_attribute("morgul.complete_images", stats.complete_images),
+            int_attribute("morgul.packets_received", stats.packets_received),
+            int_attribute("morgul.packets_dropped", stats.packets_dropped),
+        ];
+        if let Some(uuid) = self.trigger_uuid {
+            attributes.push(attribute(
+                "morgul.trigger_uuid",
+                &format!("{{\"stringValue\": {}}}", json_string(&hex(&uuid))),
+            ));
+        }
+        let end = unix_nanos(self.end);
+        let events = self
+            .events()
+            .into_iter()
+            .map(|(name, count)| {
+                format!(
+                    "{{\"timeUnixNano\": \"{end}\", \"name\": {}, \"attributes\": [{}]}}",
+                    json_string(name),
+                    int_attribute("count", count)
+                )
+            })
+            .collect::<Vec<_>>();
+        // Losing data is the error that matters to anything downstream
+        let status = if stats.packets_dropped > 0 || stats.kernel_dropped > 0 {
+            "{\"code\": 2, \"message\": \"Packets were lost\"}"
+        } else {
+            "{\"code\": 1}"
+        };
+
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"resourceSpans\": [{{\"resource\": {{\"attributes\": [{}]}}, \"scopeSpans\": [{{\"scope\": {{\"name\": \"morgul\", \"version\": {}}}, \"spans\": [{{",
+            attribute(
+                "service.name",
+                &format!("{{\"stringValue\": {}}}", json_string(service_name))
+            ),
+            json_string(env!("CARGO_PKG_VERSION")),
+        );
+        let _ = write!(
+            json,
+            "\"traceId\": \"{}\", \"spanId\": \"{}\", \"name\": \"acquisition\", \"kind\": 1, \"startTimeUnixNano\": \"{}\", \"endTimeUnixNano\": \"{end}\", \"attributes\": [{}], \"events\": [{}], \"status\": {status}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            unix_nanos(self.start),
+            attributes.join(", "),
+            events.join(", "),
+        );
+        json.push_str("}]}]}]}");
+        json
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos())
+}
+
+fn attribute(key: &str, value: &str) -> String {
+    format!("{{\"key\": {}, \"value\": {value}}}", json_string(key))
+}
+
+/// OTLP/JSON carries 64-bit integers as strings
+fn int_attribute(key: &str, value: usize) -> String {
+    attribute(key, &format!("{{\"intValue\": \"{value}\"}}"))
+}
+
+/// Sends spans to an OTLP/HTTP collector in the background
+///
+/// Exporting never holds up the caller; if the collector is unreachable,
+/// the span is reported as lost and dropped.
+pub struct OtlpExporter {
+    spans: Sender<String>,
+    service_name: String,
+}
+
+impl OtlpExporter {
+    /// Export to `endpoint`, e.g. `http://collector:4318/v1/traces`
+    ///
+    /// Only plain HTTP is supported.
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self, MorgulError> {
+        let (host, path) = parse_http_url(endpoint)?;
+        let (spans, outgoing) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for body in outgoing {
+                if let Err(e) = post(&host, &path, &body) {
+                    println!("Warning: Could not export trace to {host}: {e}");
+                }
+            }
+        });
+        Ok(OtlpExporter {
+            spans,
+            service_name: service_name.to_string(),
+        })
+    }
+
+    pub fn export(&self, span: &AcquisitionSpan) {
+        let _ = self.spans.send(span.to_otlp_json(&self.service_name));
+    }
+}
+
+/// Split `http://host[:port]/path` into `host:port` and `/path`
+fn parse_http_url(url: &str) -> Result<(String, String), MorgulError> {
+    let invalid = || {
+        MorgulError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Expected an http://host[:port]/path endpoint, got '{url}'"),
+        ))
+    };
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+    let path = if path.is_empty() { "/v1/traces" } else { path };
+    Ok((host, path.to_string()))
+}
+
+/// POST a JSON body, and check that the collector accepted it
+fn post(host: &str, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(host)?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Collector replied {}",
+            status_line.trim()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_carry_the_trigger_uuid() {
+        let mut span = AcquisitionSpan::new(3, SystemTime::now(), AcquisitionStats::default());
+        assert!(!span.to_otlp_json("morgul").contains("morgul.trigger_uuid"));
+
+        span.trigger_uuid = Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0xff]);
+        let json = span.to_otlp_json("morgul");
+        assert!(json.contains(
+            "{\"key\": \"morgul.trigger_uuid\", \"value\": {\"stringValue\": \"000102030405060708090aff\"}}"
+        ));
+    }
+}
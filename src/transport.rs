@@ -0,0 +1,602 @@
+//! Sending and receiving detector packets
+//!
+//! Packets normally travel over UDP, but the loopback transport connects a
+//! sender and receiver in the same process, so that the whole send and
+//! assembly path can be driven without any network.
+
+use std::{
+    io::{self, IoSliceMut},
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytemuck::bytes_of;
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        ControlMessageOwned, MsgFlags, MultiHeaders, RecvMsg, SockaddrStorage, recvmmsg, recvmsg,
+    },
+};
+
+use socket2::SockRef;
+
+use crate::{DelugeTrigger, SlsDetectorHeader};
+
+/// A single packet that was received
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedPacket {
+    /// How many bytes of the buffer were filled
+    pub len: usize,
+    /// The socket's cumulative drop counter, if the packet carried one
+    pub drop_counter: Option<u32>,
+}
+
+/// How many packets a receiver has taken from the kernel, and in how many system calls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiveCounts {
+    pub packets: usize,
+    pub calls: usize,
+}
+
+/// Somewhere that packets can be received from
+pub trait PacketReceiver {
+    /// Receive one packet into `buffer`
+    ///
+    /// Returns None if the timeout expired before a packet arrived.
+    fn recv_packet(&mut self, buffer: &mut [u8]) -> io::Result<Option<ReceivedPacket>>;
+
+    /// Receive one packet, filling each of `buffers` in turn
+    ///
+    /// This lets the payload land straight in its image, past a separate
+    /// buffer for the header. By default the packet is received whole and
+    /// then copied into the buffers, which only saves anything if the
+    /// receiver can scatter it as it arrives.
+    fn recv_packet_vectored(
+        &mut self,
+        buffers: &mut [IoSliceMut<'_>],
+    ) -> io::Result<Option<ReceivedPacket>> {
+        let mut packet = vec![0; buffers.iter().map(|b| b.len()).sum()];
+        let received = self.recv_packet(&mut packet)?;
+        if let Some(received) = received {
+            scatter(&packet[..received.len], buffers);
+        }
+        Ok(received)
+    }
+
+    /// How long `recv_packet` waits for a packet. None waits forever.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Read the current value of the cumulative drop counter
+    fn read_drops(&self) -> io::Result<u32> {
+        Ok(0)
+    }
+
+    /// Try to enlarge the receive queue, e.g. because it overflowed
+    ///
+    /// Returns the new size in bytes, or None if it can't grow any further.
+    fn grow_receive_buffer(&mut self) -> io::Result<Option<usize>> {
+        Ok(None)
+    }
+
+    /// How many packets and receive calls so far, if they are counted
+    fn receive_counts(&self) -> Option<ReceiveCounts> {
+        None
+    }
+}
+
+/// Copy `data` into each of `buffers` in turn, until it runs out
+fn scatter(mut data: &[u8], buffers: &mut [IoSliceMut<'_>]) {
+    for buffer in buffers {
+        let len = data.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+}
+
+/// Read the largest socket receive buffer the kernel allows, `net.core.rmem_max`
+pub fn read_rmem_max() -> io::Result<usize> {
+    std::fs::read_to_string("/proc/sys/net/core/rmem_max")?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Somewhere that packets can be sent to
+pub trait PacketSender {
+    fn send_packet(&mut self, packet: &[u8]) -> io::Result<()>;
+}
+
+/// Receives packets from a UDP socket, along with its kernel drop counter
+///
+/// The socket should have `SO_RXQ_OVFL` enabled for drop counts to be reported.
+///
+/// By default each packet is read with its own `recvmsg` call. With
+/// [`UdpReceiver::set_batch_size`], `recvmmsg` instead reads every packet
+/// already queued, up to the batch size, in one call, and they are handed
+/// out one at a time from there. That costs a copy of each packet, so it
+/// only pays off where the system call is the more expensive; compare the
+/// two with `cargo bench --bench batch_recv` on the receiving machine.
+pub struct UdpReceiver {
+    socket: UdpSocket,
+    cmsgspace: Vec<u8>,
+    batch: Option<ReceiveBatch>,
+    counts: ReceiveCounts,
+}
+
+/// Packets read together by one `recvmmsg` call
+struct ReceiveBatch {
+    headers: MultiHeaders<SockaddrStorage>,
+    /// Room for every packet of the batch, one after another
+    buffers: Vec<u8>,
+    packet_size: usize,
+    received: Vec<ReceivedPacket>,
+    /// The next packet in `received` to hand out
+    next: usize,
+}
+
+impl UdpReceiver {
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpReceiver {
+            socket,
+            cmsgspace: nix::cmsg_space!(libc::c_uint),
+            batch: None,
+            counts: ReceiveCounts::default(),
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Read up to `batch_size` packets of at most `packet_size` bytes per system call
+    ///
+    /// A batch size of one goes back to a `recvmsg` call per packet. Any
+    /// packets from an earlier batch that weren't handed out yet are lost.
+    pub fn set_batch_size(&mut self, batch_size: usize, packet_size: usize) {
+        self.batch = (batch_size > 1).then(|| ReceiveBatch {
+            headers: MultiHeaders::preallocate(batch_size, Some(self.cmsgspace.clone())),
+            buffers: vec![0; batch_size * packet_size],
+            packet_size,
+            received: Vec::with_capacity(batch_size),
+            next: 0,
+        });
+    }
+
+    /// Wait for at least one packet, and read every one queued up to the batch size
+    ///
+    /// Returns false if the timeout expired before a packet arrived.
+    fn fill_batch(&mut self) -> io::Result<bool> {
+        let ReceiveBatch {
+            headers,
+            buffers,
+            packet_size,
+            received,
+            next,
+        } = self.batch.as_mut().unwrap();
+        let mut iovs: Vec<_> = buffers
+            .chunks_exact_mut(*packet_size)
+            .map(|buffer| [IoSliceMut::new(buffer)])
+            .collect();
+        self.counts.calls += 1;
+        // Only the first packet is waited for; after that, take what's there
+        let messages = match recvmmsg(
+            self.socket.as_raw_fd(),
+            headers,
+            iovs.iter_mut(),
+            MsgFlags::MSG_WAITFORONE,
+            None,
+        ) {
+            Ok(messages) => messages,
+            Err(Errno::EAGAIN) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        received.clear();
+        *next = 0;
+        for msg in messages {
+            received.push(received_packet(&msg)?);
+        }
+        Ok(true)
+    }
+}
+
+/// The length and drop counter of a packet read by `recvmsg` or `recvmmsg`
+fn received_packet(msg: &RecvMsg<'_, '_, SockaddrStorage>) -> io::Result<ReceivedPacket> {
+    let mut drop_counter = None;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::RxqOvfl(count) = cmsg {
+            drop_counter = Some(count);
+        }
+    }
+    Ok(ReceivedPacket {
+        len: msg.bytes,
+        drop_counter,
+    })
+}
+
+impl PacketReceiver for UdpReceiver {
+    fn recv_packet(&mut self, buffer: &mut [u8]) -> io::Result<Option<ReceivedPacket>> {
+        self.recv_packet_vectored(&mut [IoSliceMut::new(buffer)])
+    }
+
+    /// Like [`PacketReceiver::recv_packet`], but without batching, the
+    /// kernel scatters the packet straight into `buffers`
+    fn recv_packet_vectored(
+        &mut self,
+        buffers: &mut [IoSliceMut<'_>],
+    ) -> io::Result<Option<ReceivedPacket>> {
+        if let Some(batch) = &self.batch {
+            if batch.next == batch.received.len() && !self.fill_batch()? {
+                return Ok(None);
+            }
+            let batch = self.batch.as_mut().unwrap();
+            let packet = batch.received[batch.next];
+            let start = batch.next * batch.packet_size;
+            batch.next += 1;
+            // Like UDP, anything that doesn't fit in the buffers is lost
+            let len = packet.len.min(buffers.iter().map(|b| b.len()).sum());
+            scatter(&batch.buffers[start..start + len], buffers);
+            self.counts.packets += 1;
+            return Ok(Some(ReceivedPacket { len, ..packet }));
+        }
+
+        self.counts.calls += 1;
+        let msg = match recvmsg::<SockaddrStorage>(
+            self.socket.as_raw_fd(),
+            buffers,
+            Some(&mut self.cmsgspace),
+            MsgFlags::empty(),
+        ) {
+            Ok(msg) => msg,
+            Err(Errno::EAGAIN) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        self.counts.packets += 1;
+        Ok(Some(received_packet(&msg)?))
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn read_drops(&self) -> io::Result<u32> {
+        let mut meminfo = [0u32; libc::SK_MEMINFO_DROPS as usize + 1];
+        let mut len = size_of_val(&meminfo) as libc::socklen_t;
+        // SAFETY: meminfo is valid for writes of len bytes, and the kernel
+        // truncates its output to the length we give it.
+        let ret = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_MEMINFO,
+                meminfo.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(meminfo[libc::SK_MEMINFO_DROPS as usize])
+    }
+
+    /// Double the socket receive buffer, up to `rmem_max`
+    fn grow_receive_buffer(&mut self) -> io::Result<Option<usize>> {
+        let socket = SockRef::from(&self.socket);
+        let rmem_max = read_rmem_max()?;
+        // Linux reports double the requested size, to allow for overhead
+        let current = socket.recv_buffer_size()? / 2;
+        if current >= rmem_max {
+            return Ok(None);
+        }
+        socket.set_recv_buffer_size((current * 2).min(rmem_max))?;
+        Ok(Some(socket.recv_buffer_size()? / 2))
+    }
+
+    fn receive_counts(&self) -> Option<ReceiveCounts> {
+        Some(self.counts)
+    }
+}
+
+/// Discards a fraction of the packets from another receiver
+///
+/// This simulates packet loss for testing, without needing a lossy network.
+/// Drops are spaced evenly rather than at random, so that a given rate
+/// always discards the same packets.
+pub struct DropInjector<R> {
+    inner: R,
+    rate: f64,
+    /// How many packets we are due to drop, accumulated from the rate
+    owed: f64,
+}
+
+impl<R: PacketReceiver> DropInjector<R> {
+    /// Drop `rate` (between 0 and 1) of the packets received from `inner`
+    pub fn new(inner: R, rate: f64) -> Self {
+        DropInjector {
+            inner,
+            rate: rate.clamp(0.0, 1.0),
+            owed: 0.0,
+        }
+    }
+}
+
+impl<R: PacketReceiver> PacketReceiver for DropInjector<R> {
+    fn recv_packet(&mut self, buffer: &mut [u8]) -> io::Result<Option<ReceivedPacket>> {
+        self.recv_packet_vectored(&mut [IoSliceMut::new(buffer)])
+    }
+
+    fn recv_packet_vectored(
+        &mut self,
+        buffers: &mut [IoSliceMut<'_>],
+    ) -> io::Result<Option<ReceivedPacket>> {
+        loop {
+            let Some(packet) = self.inner.recv_packet_vectored(buffers)? else {
+                return Ok(None);
+            };
+            self.owed += self.rate;
+            if self.owed < 1.0 {
+                return Ok(Some(packet));
+            }
+            self.owed -= 1.0;
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn read_drops(&self) -> io::Result<u32> {
+        self.inner.read_drops()
+    }
+
+    fn grow_receive_buffer(&mut self) -> io::Result<Option<usize>> {
+        self.inner.grow_receive_buffer()
+    }
+
+    fn receive_counts(&self) -> Option<ReceiveCounts> {
+        self.inner.receive_counts()
+    }
+}
+
+/// Sends packets from a UDP socket to one target address
+pub struct UdpSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl UdpSender {
+    pub fn new(socket: UdpSocket, target: SocketAddr) -> Self {
+        UdpSender { socket, target }
+    }
+}
+
+impl PacketSender for UdpSender {
+    fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.socket.send_to(packet, self.target)?;
+        Ok(())
+    }
+}
+
+/// The sending half of an in-process loopback transport
+#[derive(Clone)]
+pub struct LoopbackSender {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+/// The receiving half of an in-process loopback transport
+pub struct LoopbackReceiver {
+    rx: mpsc::Receiver<Vec<u8>>,
+    timeout: Option<Duration>,
+}
+
+/// Create a connected sender and receiver that pass packets in memory
+pub fn loopback() -> (LoopbackSender, LoopbackReceiver) {
+    let (tx, rx) = mpsc::channel();
+    (
+        LoopbackSender { tx },
+        LoopbackReceiver { rx, timeout: None },
+    )
+}
+
+impl PacketSender for LoopbackSender {
+    fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(packet.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl PacketReceiver for LoopbackReceiver {
+    fn recv_packet(&mut self, buffer: &mut [u8]) -> io::Result<Option<ReceivedPacket>> {
+        self.recv_packet_vectored(&mut [IoSliceMut::new(buffer)])
+    }
+
+    fn recv_packet_vectored(
+        &mut self,
+        buffers: &mut [IoSliceMut<'_>],
+    ) -> io::Result<Option<ReceivedPacket>> {
+        let packet = match self.timeout {
+            None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(timeout) => self.rx.recv_timeout(timeout),
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            // Once every sender is gone, nothing more will arrive. If we
+            // would only have waited a while, that is just a timeout; if we
+            // would have waited forever, it's an error.
+            Err(RecvTimeoutError::Disconnected) if self.timeout.is_some() => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+        };
+        // Like UDP, anything that doesn't fit in the buffers is lost
+        let len = packet.len().min(buffers.iter().map(|b| b.len()).sum());
+        scatter(&packet[..len], buffers);
+        Ok(Some(ReceivedPacket {
+            len,
+            drop_counter: None,
+        }))
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+/// Send the frames requested by a trigger, paced at the trigger's exposure time
+///
+/// `header` is the header of the first packet to send. Its frame number is
+/// advanced past the frames that were sent, ready for the next trigger.
+pub fn send_frames(
+    sender: &mut impl PacketSender,
+    trigger: &DelugeTrigger,
+    header: &mut SlsDetectorHeader,
+    packets_per_frame: usize,
+    payload_size: usize,
+) -> io::Result<()> {
+    // Frame numbers are only 64 bits in the header
+    let frames = u64::try_from(trigger.frames)
+        .ok()
+        .filter(|frames| header.frame_number.checked_add(*frames).is_some())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can't send {} frames on from frame {}",
+                    trigger.frames, header.frame_number
+                ),
+            )
+        })?;
+    let mut buff = vec![0u8; payload_size + size_of::<SlsDetectorHeader>()];
+    let start_acq = Instant::now();
+    for image_num in 0..frames {
+        let acq_elapsed = (Instant::now() - start_acq).as_secs_f32();
+        if acq_elapsed < image_num as f32 * trigger.exptime {
+            thread::sleep(Duration::from_secs_f32(
+                image_num as f32 * trigger.exptime - acq_elapsed,
+            ));
+        }
+        for _ in 0..packets_per_frame {
+            buff[..size_of::<SlsDetectorHeader>()].copy_from_slice(bytes_of(header));
+
+            sender.send_packet(&buff)?;
+            header.packet_number += 1;
+        }
+
+        header.frame_number += 1;
+        header.packet_number = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GeometryMap, SlsDetectorType, assembler::FrameAssembler};
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn loopback_trigger_to_assembled_frames() {
+        let geometry = *GeometryMap::with_defaults(16)
+            .get(SlsDetectorType::Jungfrau as u8)
+            .unwrap();
+        let (mut sender, mut receiver) = loopback();
+        let trigger = DelugeTrigger::builder().frames(5).exptime(0.0).build();
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = 1;
+        header.det_type = SlsDetectorType::Jungfrau as u8;
+        header.version = 2;
+        let sending = thread::spawn(move || {
+            send_frames(
+                &mut sender,
+                &trigger,
+                &mut header,
+                geometry.packets_per_frame,
+                geometry.payload_size,
+            )
+            .unwrap();
+            header
+        });
+
+        let mut assembler = FrameAssembler::new(GeometryMap::with_defaults(16), 8);
+        let mut frames = Vec::new();
+        let mut buffer = vec![0u8; size_of::<SlsDetectorHeader>() + geometry.payload_size];
+        receiver
+            .set_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        while let Some(packet) = receiver.recv_packet(&mut buffer).unwrap() {
+            let (header, payload) = buffer[..packet.len].split_at(size_of::<SlsDetectorHeader>());
+            let header: SlsDetectorHeader = bytemuck::pod_read_unaligned(header);
+            assembler
+                .push_packet(&header, payload, |f| frames.push(f))
+                .unwrap();
+        }
+        let stats = assembler.finish_acquisition(|f| frames.push(f));
+        let next_header = sending.join().unwrap();
+
+        assert_eq!(next_header.frame_number, 6);
+        let frame_numbers: Vec<u64> = frames.iter().map(|f| f.header.frame_number).collect();
+        assert_eq!(frame_numbers, [1, 2, 3, 4, 5]);
+        assert!(frames.iter().all(|f| f.complete));
+        assert_eq!(stats.images_seen, 5);
+        assert_eq!(stats.complete_images, 5);
+        assert_eq!(stats.packets_received, 5 * geometry.packets_per_frame);
+        assert_eq!(stats.packets_dropped, 0);
+        assert_eq!(stats.missing_frames, 0);
+    }
+
+    #[test]
+    fn loopback_truncates_like_udp() {
+        let (mut sender, mut receiver) = loopback();
+        sender.send_packet(&[1, 2, 3, 4, 5, 6]).unwrap();
+        drop(sender);
+        let (mut head, mut tail) = ([0u8; 2], [0u8; 3]);
+        let packet = receiver
+            .recv_packet_vectored(&mut [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.len, 5);
+        assert_eq!((head, tail), ([1, 2], [3, 4, 5]));
+        // With every sender gone, waiting forever is an error
+        assert!(receiver.recv_packet(&mut [0; 8]).is_err());
+    }
+
+    /// Send `count` packets, each filled with its index, to a new UDP receiver
+    fn udp_packets(count: usize, batch_size: usize) -> UdpReceiver {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut receiver = UdpReceiver::new(socket);
+        receiver.set_batch_size(batch_size, 64);
+        receiver
+            .set_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut sender = UdpSender::new(UdpSocket::bind("127.0.0.1:0").unwrap(), address);
+        for i in 0..count {
+            sender.send_packet(&[i as u8; 64]).unwrap();
+        }
+        receiver
+    }
+
+    #[test]
+    fn batched_receive_gets_every_packet_in_fewer_calls() {
+        for batch_size in [1, 8] {
+            let mut receiver = udp_packets(20, batch_size);
+            let mut buffer = [0; 64];
+            for i in 0..20 {
+                let packet = receiver.recv_packet(&mut buffer).unwrap().unwrap();
+                assert_eq!(packet.len, 64);
+                assert_eq!(buffer, [i as u8; 64]);
+            }
+            assert!(receiver.recv_packet(&mut buffer).unwrap().is_none());
+            let counts = receiver.receive_counts().unwrap();
+            assert_eq!(counts.packets, 20);
+            // Everything was queued before the first call, so each batch is
+            // as full as it can be, then one more call timed out
+            assert_eq!(counts.calls, 20usize.div_ceil(batch_size) + 1);
+        }
+    }
+}
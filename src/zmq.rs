@@ -0,0 +1,385 @@
+//! Publishing completed frames over ZeroMQ, like slsReceiver, with the `zmq` feature
+//!
+//! Each frame is sent as a two-part message: a JSON header using the same
+//! keys as slsReceiver's, then the frame data. Tools that already watch an
+//! slsReceiver stream can subscribe to this instead. At the end of every
+//! acquisition, a header-only message with `"data": 0` is sent, as
+//! slsReceiver does.
+//!
+//! This speaks just enough of ZMTP 3.0 to act as a PUB socket: the NULL
+//! security mechanism, and no filtering on the publishing side, which
+//! subscribers do themselves. Like a PUB socket, a subscriber that can't
+//! keep up misses frames rather than holding up the others.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{CompletedFrame, GeometryMap, MorgulError, sink::FrameSink};
+
+/// How many messages can wait for each subscriber before they are dropped
+const SUBSCRIBER_QUEUE_LENGTH: usize = 16;
+
+/// How long a new subscriber has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A multipart message, shared between every subscriber's queue
+type Message = Arc<Vec<Vec<u8>>>;
+
+/// Publishes completed frames to any number of ZeroMQ SUB sockets
+pub struct ZmqPubSink {
+    address: SocketAddr,
+    subscribers: Arc<Mutex<Vec<SyncSender<Message>>>>,
+    geometry: GeometryMap,
+    /// Frames sent so far this acquisition
+    frame_index: usize,
+    /// How many times a message was not queued for a slow subscriber
+    dropped: usize,
+}
+
+impl ZmqPubSink {
+    /// Listen for subscribers on `endpoint`
+    ///
+    /// As well as `host:port`, this accepts ZeroMQ-style `tcp://*:port`
+    /// endpoints. Frame shapes are taken from `geometry`.
+    pub fn bind(endpoint: &str, geometry: GeometryMap) -> Result<Self, MorgulError> {
+        let address = parse_endpoint(endpoint)?;
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let accepted = subscribers.clone();
+        thread::spawn(move || accept_subscribers(listener, accepted));
+        Ok(ZmqPubSink {
+            address,
+            subscribers,
+            geometry,
+            frame_index: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Where subscribers can connect, with the port chosen if it was 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// How many times a message was dropped because a subscriber was too slow
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Queue a message for every subscriber, forgetting any that have gone
+    fn publish(&mut self, message: Vec<Vec<u8>>) {
+        let message = Arc::new(message);
+        let mut dropped = 0;
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        for _ in 0..dropped {
+            self.dropped += 1;
+            if self.dropped.is_power_of_two() {
+                println!(
+                    "Warning: A ZeroMQ subscriber is not keeping up; {} message(s) dropped",
+                    self.dropped
+                );
+            }
+        }
+    }
+
+    /// The slsReceiver JSON header for a frame
+    fn header_json(&self, frame: &CompletedFrame) -> String {
+        let header = &frame.header;
+        // Frames reshaped on the way here, e.g. by a region of interest,
+        // no longer match their geometry, so are described as one row
+        let (shape, bit_depth) = match self.geometry.get(header.det_type) {
+            Some(g) if g.frame_size() == frame.data.len() => ((g.size_x, g.size_y), g.bit_depth),
+            Some(g) => ((frame.data.len() * 8 / g.bit_depth, 1), g.bit_depth),
+            None => ((frame.data.len(), 1), 8),
+        };
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"jsonversion\": 4, \"bitmode\": {bit_depth}, \"fileIndex\": 0, \"detshape\": [1, 1], \"shape\": [{}, {}], \"size\": {}, \"acqIndex\": {}, \"frameIndex\": {}, \"progress\": 0, \"fname\": \"\", \"data\": 1, \"completeImage\": {}, ",
+            shape.0,
+            shape.1,
+            frame.data.len(),
+            header.frame_number,
+            self.frame_index,
+            frame.complete as u8,
+        );
+        let _ = write!(
+            json,
+            "\"frameNumber\": {}, \"expLength\": {}, \"packetNumber\": {}, \"detSpec1\": {}, \"timestamp\": {}, \"modId\": {}, \"row\": {}, \"column\": {}, \"detSpec2\": {}, \"detSpec3\": {}, \"detSpec4\": {}, \"detType\": {}, \"version\": {}, \"flipRows\": 0, \"quad\": 0, \"addJsonHeader\": {{}}}}",
+            header.frame_number,
+            header.exposure_length,
+            header.packet_number,
+            header.bunch_id,
+            header.timestamp,
+            header.module_id,
+            header.row,
+            header.column,
+            header._det_spec_2,
+            header.daq_info,
+            header._det_spec_4,
+            header.det_type,
+            header.version,
+        );
+        json
+    }
+}
+
+impl FrameSink for ZmqPubSink {
+    fn write_frame(&mut self, frame: &CompletedFrame) -> Result<(), MorgulError> {
+        let header = self.header_json(frame).into_bytes();
+        self.frame_index += 1;
+        self.publish(vec![header, frame.data.to_vec()]);
+        Ok(())
+    }
+
+    fn end_acquisition(&mut self, _acquisition_number: usize) -> Result<(), MorgulError> {
+        self.frame_index = 0;
+        self.publish(vec![b"{\"jsonversion\": 4, \"data\": 0}".to_vec()]);
+        Ok(())
+    }
+}
+
+/// Accept `tcp://host:port`, with `*` for every interface, or `host:port`
+fn parse_endpoint(endpoint: &str) -> Result<SocketAddr, MorgulError> {
+    let address = endpoint.strip_prefix("tcp://").unwrap_or(endpoint);
+    let address = match address.strip_prefix("*:") {
+        Some(port) => format!("0.0.0.0:{port}"),
+        None => address.to_string(),
+    };
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Can't bind to ZeroMQ endpoint '{endpoint}'"),
+        )
+        .into()
+    })
+}
+
+fn accept_subscribers(listener: TcpListener, subscribers: Arc<Mutex<Vec<SyncSender<Message>>>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Error: Failed to accept ZeroMQ subscriber: {e}");
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or("unknown".to_string(), |a| a.to_string());
+        if let Err(e) = handshake(&stream) {
+            println!("Warning: ZeroMQ handshake with {peer} failed: {e}");
+            continue;
+        }
+        let (queue, outgoing) = mpsc::sync_channel(SUBSCRIBER_QUEUE_LENGTH);
+        subscribers.lock().unwrap().push(queue);
+        thread::spawn(move || send_messages(stream, outgoing, peer));
+    }
+}
+
+/// Exchange ZMTP 3.0 greetings and READY commands with a new subscriber
+fn handshake(mut stream: &TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xFF;
+    greeting[9] = 0x7F;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    stream.write_all(&greeting)?;
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"PUB");
+    let mut writer = BufWriter::new(stream);
+    write_frame(&mut writer, &ready, ZMTP_COMMAND)?;
+    writer.flush()?;
+    drop(writer);
+
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xFF || peer[9] != 0x7F || peer[10] < 3 {
+        return Err(invalid("not a ZMTP 3 peer"));
+    }
+    if &peer[12..32] != b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0" {
+        return Err(invalid("only the NULL security mechanism is supported"));
+    }
+    let mut flags = [0u8];
+    stream.read_exact(&mut flags)?;
+    let size = if flags[0] & ZMTP_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8];
+        stream.read_exact(&mut size)?;
+        size[0] as u64
+    };
+    let mut command = Vec::new();
+    stream.take(size).read_to_end(&mut command)?;
+    if flags[0] & ZMTP_COMMAND == 0 || !command.starts_with(b"\x05READY") {
+        return Err(invalid("expected a READY command"));
+    }
+    stream.set_read_timeout(None)
+}
+
+const ZMTP_MORE: u8 = 0x01;
+const ZMTP_LONG: u8 = 0x02;
+const ZMTP_COMMAND: u8 = 0x04;
+
+fn write_frame(writer: &mut impl Write, body: &[u8], flags: u8) -> io::Result<()> {
+    if body.len() > u8::MAX as usize {
+        writer.write_all(&[flags | ZMTP_LONG])?;
+        writer.write_all(&(body.len() as u64).to_be_bytes())?;
+    } else {
+        writer.write_all(&[flags, body.len() as u8])?;
+    }
+    writer.write_all(body)
+}
+
+/// Send queued messages to one subscriber, until it disconnects
+fn send_messages(stream: TcpStream, outgoing: Receiver<Message>, peer: String) {
+    let mut writer = BufWriter::new(stream);
+    for message in outgoing {
+        let last = message.len() - 1;
+        for (i, part) in message.iter().enumerate() {
+            let flags = if i < last { ZMTP_MORE } else { 0 };
+            if let Err(e) = write_frame(&mut writer, part, flags) {
+                println!("ZeroMQ subscriber {peer} disconnected: {e}");
+                return;
+            }
+        }
+        if let Err(e) = writer.flush() {
+            println!("ZeroMQ subscriber {peer} disconnected: {e}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AlignedBuffer, DEFAULT_BUFFER_ALIGNMENT, PooledBuffer, SlsDetectorHeader};
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn frames_are_short_up_to_255_bytes() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, b"abc", ZMTP_MORE).unwrap();
+        assert_eq!(bytes, [ZMTP_MORE, 3, b'a', b'b', b'c']);
+
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &[7; 255], 0).unwrap();
+        assert_eq!(bytes[..2], [0, 255]);
+        assert_eq!(bytes.len(), 2 + 255);
+
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &[7; 256], ZMTP_COMMAND).unwrap();
+        assert_eq!(bytes[0], ZMTP_COMMAND | ZMTP_LONG);
+        assert_eq!(bytes[1..9], 256u64.to_be_bytes());
+        assert_eq!(bytes.len(), 9 + 256);
+    }
+
+    /// Read one ZMTP frame, returning its flags and body
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut flags = [0u8];
+        stream.read_exact(&mut flags).unwrap();
+        let size = if flags[0] & ZMTP_LONG != 0 {
+            let mut size = [0u8; 8];
+            stream.read_exact(&mut size).unwrap();
+            u64::from_be_bytes(size) as usize
+        } else {
+            let mut size = [0u8];
+            stream.read_exact(&mut size).unwrap();
+            size[0] as usize
+        };
+        let mut body = vec![0; size];
+        stream.read_exact(&mut body).unwrap();
+        (flags[0], body)
+    }
+
+    #[test]
+    fn subscriber_receives_frames_after_handshake() {
+        let mut sink =
+            ZmqPubSink::bind("tcp://127.0.0.1:0", GeometryMap::with_defaults(16)).unwrap();
+        let mut subscriber = TcpStream::connect(sink.local_addr()).unwrap();
+        subscriber
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // A ZMTP 3.0 greeting, for the NULL mechanism, as a client
+        let mut greeting = [0u8; 64];
+        subscriber.read_exact(&mut greeting).unwrap();
+        assert_eq!(
+            greeting[..16],
+            [
+                0xFF, 0, 0, 0, 0, 0, 0, 0, 0, 0x7F, 3, 0, b'N', b'U', b'L', b'L'
+            ]
+        );
+        assert!(greeting[16..].iter().all(|&b| b == 0));
+        subscriber.write_all(&greeting).unwrap();
+
+        let (flags, ready) = read_frame(&mut subscriber);
+        assert_eq!(flags, ZMTP_COMMAND);
+        assert_eq!(ready, b"\x05READY\x0bSocket-Type\x00\x00\x00\x03PUB");
+        let mut reply = Vec::new();
+        let ready = b"\x05READY\x0bSocket-Type\x00\x00\x00\x03SUB";
+        write_frame(&mut reply, ready, ZMTP_COMMAND).unwrap();
+        // A subscription to everything, as a SUB socket sends
+        write_frame(&mut reply, &[1], 0).unwrap();
+        subscriber.write_all(&reply).unwrap();
+
+        // Messages are only queued for subscribers that have finished the handshake
+        while sink.subscribers.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut header = SlsDetectorHeader::zeroed();
+        header.frame_number = 42;
+        let mut buffer = AlignedBuffer::new(300, DEFAULT_BUFFER_ALIGNMENT);
+        buffer.fill(9);
+        let frame = CompletedFrame {
+            header,
+            complete: true,
+            received_mask: u64::MAX,
+            data: PooledBuffer::new(buffer, mpsc::channel().0),
+        };
+        sink.write_frame(&frame).unwrap();
+        sink.end_acquisition(1).unwrap();
+
+        let (flags, json) = read_frame(&mut subscriber);
+        assert_eq!(flags & ZMTP_MORE, ZMTP_MORE);
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"frameNumber\": 42,"), "{json}");
+        assert!(json.contains("\"size\": 300,"), "{json}");
+        let (flags, data) = read_frame(&mut subscriber);
+        assert_eq!(flags, ZMTP_LONG);
+        assert_eq!(data, [9; 300]);
+
+        let (flags, end) = read_frame(&mut subscriber);
+        assert_eq!(flags, 0);
+        assert_eq!(end, b"{\"jsonversion\": 4, \"data\": 0}");
+        assert_eq!(sink.dropped(), 0);
+    }
+}